@@ -0,0 +1,136 @@
+// Copyright 2023 Greptime Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! the benchmarks of parsing, run them with `cargo bench`, see `benches/README.md`.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use promql_parser::parser::{parse, parse_with_options, ParseOptions};
+
+fn selectors(c: &mut Criterion) {
+    let queries = [
+        ("metric", "up"),
+        (
+            "matchers",
+            r#"http_requests_total{job="api", code=~"5..", method!="GET"}"#,
+        ),
+        (
+            "matrix",
+            r#"rate(http_requests_total{job="api"}[5m] offset 1h)"#,
+        ),
+    ];
+    let mut group = c.benchmark_group("selectors");
+    for (name, query) in queries {
+        group.throughput(Throughput::Bytes(query.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(name), query, |b, q| {
+            b.iter(|| parse(black_box(q)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+/// `foo0 + (foo1 * (foo2 - ...))`, each level is a binary expr in parentheses.
+fn nested_binary(depth: usize) -> String {
+    let ops = ["+", "*", "-", "/"];
+    let mut query = format!("foo{depth}");
+    for i in (0..depth).rev() {
+        query = format!("foo{i} {} ({query})", ops[i % ops.len()]);
+    }
+    query
+}
+
+fn binaries(c: &mut Criterion) {
+    let mut group = c.benchmark_group("nested_binary");
+    for depth in [10, 50, 200] {
+        let query = nested_binary(depth);
+        group.throughput(Throughput::Bytes(query.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(depth), &query, |b, q| {
+            b.iter(|| parse(black_box(q)).unwrap())
+        });
+    }
+    // thousands of `or` are wide instead of deep
+    let query = vec!["foo"; 1000].join(" or ");
+    group.throughput(Throughput::Bytes(query.len() as u64));
+    group.bench_function("or_1000", |b| b.iter(|| parse(black_box(&query)).unwrap()));
+    group.finish();
+}
+
+fn subqueries(c: &mut Criterion) {
+    let queries = [
+        ("single", "max_over_time(rate(foo[5m])[1h:1m])"),
+        (
+            "nested",
+            "min_over_time(max_over_time(rate(foo[5m])[1h:1m] offset 1h)[1d:10m] @ end())",
+        ),
+        (
+            "aggregated",
+            r#"topk(5, sum by (job) (avg_over_time(rate(http_requests_total{code=~"5.."}[5m])[30m:1m])))"#,
+        ),
+    ];
+    let mut group = c.benchmark_group("subqueries");
+    for (name, query) in queries {
+        group.throughput(Throughput::Bytes(query.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(name), query, |b, q| {
+            b.iter(|| parse(black_box(q)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+/// the queries of a rule set, like recording rules and alerts of many services.
+fn rule_corpus(size: usize) -> Vec<String> {
+    let templates = [
+        r#"sum by (job, instance) (rate(http_requests_total{job="svc{i}"}[5m]))"#,
+        r#"histogram_quantile(0.99, sum by (le) (rate(http_request_duration_seconds_bucket{job="svc{i}"}[5m])))"#,
+        r#"sum(rate(http_requests_total{job="svc{i}", code=~"5.."}[5m])) / sum(rate(http_requests_total{job="svc{i}"}[5m])) > 0.01"#,
+        r#"up{job="svc{i}"} == 0"#,
+        r#"predict_linear(node_filesystem_free_bytes{job="svc{i}"}[6h], 4 * 3600) < 0"#,
+        r#"avg_over_time(sum by (pod) (container_memory_working_set_bytes{namespace="ns{i}"})[1h:5m])"#,
+    ];
+    (0..size)
+        .map(|i| templates[i % templates.len()].replace("{i}", &i.to_string()))
+        .collect()
+}
+
+fn rules(c: &mut Criterion) {
+    let mut group = c.benchmark_group("rule_corpus");
+    group.sample_size(20);
+    for size in [1_000, 10_000] {
+        let corpus = rule_corpus(size);
+        group.throughput(Throughput::Elements(size as u64));
+        group.bench_with_input(BenchmarkId::new("parse", size), &corpus, |b, corpus| {
+            b.iter(|| {
+                for query in corpus {
+                    parse(black_box(query)).unwrap();
+                }
+            })
+        });
+    }
+    // the limits of the options are checked while parsing
+    let corpus = rule_corpus(1_000);
+    let options = ParseOptions::new()
+        .with_max_tokens(1_000)
+        .with_max_size(1 << 20);
+    group.throughput(Throughput::Elements(corpus.len() as u64));
+    group.bench_function("parse_with_options/1000", |b| {
+        b.iter(|| {
+            for query in &corpus {
+                parse_with_options(black_box(query), &options).unwrap();
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, selectors, binaries, subqueries, rules);
+criterion_main!(benches);
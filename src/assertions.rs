@@ -0,0 +1,125 @@
+// Copyright 2023 Greptime Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! compile time checks that the public types are `Send + Sync + 'static`, so
+//! parsed queries, also with [`Extension`] nodes, can be shared between threads
+//! and async tasks. A change breaking this fails to build instead of breaking
+//! the users.
+
+use crate::label::{MatchOp, MatchOpKind, Matcher, Matchers, NameValidationScheme};
+use crate::parser::token::{Token, TokenType};
+use crate::parser::*;
+use crate::util::annotations::Annotations;
+use crate::util::capabilities::Capability;
+use crate::util::complete::LabelValueContext;
+use crate::util::conversion::{Conversion, ConversionKind};
+use crate::util::diagnostic::{Diagnostic, Diagnostics, Related, Severity};
+use crate::util::escape::EscapingScheme;
+use crate::util::format::{FormatConfig, OperatorPosition};
+use crate::util::intern::{ExprCache, ExprId};
+use crate::util::lint::{Lint, LintKind, LintOptions};
+use crate::util::propagation::LabelSet;
+use crate::util::pushdown::{FilterOp, LabelFilter, PushdownHints, RegexFilter, SelectorFilter};
+use crate::util::rewrite::ResolvedAt;
+use crate::util::schedule::SelectorSchedule;
+use crate::util::selectivity::SeriesEstimate;
+use crate::util::source_map::SourceMap;
+use crate::util::step::StepCheck;
+use crate::util::subquery::SubquerySteps;
+use crate::util::summary::Summary;
+use crate::util::template::{ParamType, ParamValue, QueryTemplate};
+
+const fn assert_shared<T: Send + Sync + 'static>() {}
+
+const _: () = {
+    // the ast
+    assert_shared::<Expr>();
+    assert_shared::<EvalStmt>();
+    assert_shared::<AggregateExpr>();
+    assert_shared::<UnaryExpr>();
+    assert_shared::<BinaryExpr>();
+    assert_shared::<ParenExpr>();
+    assert_shared::<SubqueryExpr>();
+    assert_shared::<NumberLiteral>();
+    assert_shared::<StringLiteral>();
+    assert_shared::<VectorSelector>();
+    assert_shared::<VectorSelectorBuilder>();
+    assert_shared::<MatrixSelector>();
+    assert_shared::<Call>();
+    assert_shared::<Extension>();
+    assert_shared::<LabelModifier>();
+    assert_shared::<VectorMatchCardinality>();
+    assert_shared::<BinModifier>();
+    assert_shared::<Offset>();
+    assert_shared::<SubqueryStep>();
+    assert_shared::<AtModifier>();
+    assert_shared::<MetricMatch>();
+    assert_shared::<Function>();
+    assert_shared::<FunctionArgs>();
+    assert_shared::<MatchOp>();
+    assert_shared::<MatchOpKind>();
+    assert_shared::<Matcher>();
+    assert_shared::<Matchers>();
+
+    // parsing and its results, errors are strings or diagnostics
+    assert_shared::<ParseOptions>();
+    assert_shared::<CancellationToken>();
+    assert_shared::<NameValidationScheme>();
+    assert_shared::<ParseStats>();
+    assert_shared::<Spans>();
+    assert_shared::<NodeId>();
+    assert_shared::<FoundNode<'static>>();
+    assert_shared::<Token>();
+    assert_shared::<TokenType>();
+    assert_shared::<ValueType>();
+    assert_shared::<Diagnostic>();
+    assert_shared::<Diagnostics>();
+    assert_shared::<Related>();
+    assert_shared::<Severity>();
+
+    // the utilities
+    assert_shared::<Annotations>();
+    assert_shared::<Capability>();
+    assert_shared::<LabelValueContext>();
+    assert_shared::<Conversion<'static>>();
+    assert_shared::<ConversionKind>();
+    assert_shared::<EscapingScheme>();
+    assert_shared::<FormatConfig>();
+    assert_shared::<OperatorPosition>();
+    assert_shared::<ExprCache>();
+    assert_shared::<ExprId>();
+    assert_shared::<Lint>();
+    assert_shared::<LintKind>();
+    assert_shared::<LintOptions>();
+    assert_shared::<LabelSet>();
+    assert_shared::<FilterOp>();
+    assert_shared::<LabelFilter>();
+    assert_shared::<PushdownHints>();
+    assert_shared::<RegexFilter>();
+    assert_shared::<SelectorFilter>();
+    assert_shared::<ResolvedAt>();
+    assert_shared::<SelectorSchedule>();
+    assert_shared::<SeriesEstimate>();
+    assert_shared::<SourceMap>();
+    assert_shared::<StepCheck>();
+    assert_shared::<SubquerySteps>();
+    assert_shared::<Summary>();
+    assert_shared::<ParamType>();
+    assert_shared::<ParamValue>();
+    assert_shared::<QueryTemplate>();
+    #[cfg(feature = "proto")]
+    assert_shared::<crate::parser::proto::Expr>();
+    #[cfg(feature = "rules")]
+    assert_shared::<crate::util::rules::RuleDiagnostic>();
+};
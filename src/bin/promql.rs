@@ -0,0 +1,375 @@
+// Copyright 2023 Greptime Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! command line tool to check, format and inspect PromQL queries.
+
+use std::collections::BTreeSet;
+use std::io::Read;
+use std::process::ExitCode;
+
+use promql_parser::parser::{self, Expr, ParseOptions};
+use promql_parser::util::format::{prettify, FormatConfig};
+use promql_parser::util::lint::LintOptions;
+use promql_parser::util::rules::lint_rules;
+
+const USAGE: &str = "usage: promql <check|fmt|ast|selectors> [options] [query...]
+       promql rules [options] <file...>
+
+queries are read from the arguments, from the files given by -f, or from stdin.
+rules lints every query in the Prometheus rule files.
+
+options:
+  -f, --file <path>      read a query from the file
+  -w, --width <n>        the max line width of fmt, defaults to 100
+      --experimental     accept the experimental functions
+  -h, --help             print this help";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Command {
+    Check,
+    Fmt,
+    Ast,
+    Selectors,
+    Rules,
+}
+
+/// a query with where it was read from, like `<arg 1>` or a file path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Query {
+    source: String,
+    text: String,
+}
+
+impl Query {
+    /// the text which is parsed, without surrounding whitespace.
+    fn trimmed(&self) -> &str {
+        self.text.trim()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Args {
+    command: Option<Command>,
+    files: Vec<String>,
+    queries: Vec<String>,
+    width: Option<usize>,
+    experimental: bool,
+    help: bool,
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Args, String> {
+    let mut parsed = Args::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => parsed.help = true,
+            "--experimental" => parsed.experimental = true,
+            "-f" | "--file" => {
+                let path = args.next().ok_or(format!("missing value for {arg}"))?;
+                parsed.files.push(path);
+            }
+            "-w" | "--width" => {
+                let width = args.next().ok_or(format!("missing value for {arg}"))?;
+                let width = width
+                    .parse()
+                    .map_err(|_| format!("invalid width {width}"))?;
+                parsed.width = Some(width);
+            }
+            s if s.starts_with('-') && s.len() > 1 => return Err(format!("unknown option {s}")),
+            _ if parsed.command.is_none() => {
+                parsed.command = Some(match arg.as_str() {
+                    "check" => Command::Check,
+                    "fmt" => Command::Fmt,
+                    "ast" => Command::Ast,
+                    "selectors" => Command::Selectors,
+                    "rules" => Command::Rules,
+                    s => return Err(format!("unknown command {s}")),
+                })
+            }
+            _ => parsed.queries.push(arg),
+        }
+    }
+    Ok(parsed)
+}
+
+fn read_queries(args: &Args) -> Result<Vec<Query>, String> {
+    let mut queries: Vec<Query> = args
+        .queries
+        .iter()
+        .enumerate()
+        .map(|(i, text)| Query {
+            source: format!("<arg {}>", i + 1),
+            text: text.clone(),
+        })
+        .collect();
+    for path in &args.files {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+        queries.push(Query {
+            source: path.clone(),
+            text,
+        });
+    }
+    if queries.is_empty() {
+        let mut text = String::new();
+        std::io::stdin()
+            .read_to_string(&mut text)
+            .map_err(|e| format!("<stdin>: {e}"))?;
+        queries.push(Query {
+            source: "<stdin>".to_string(),
+            text,
+        });
+    }
+    Ok(queries)
+}
+
+/// render the error with the query, and a caret under the reported position if any.
+fn diagnostic(query: &Query, err: &str) -> String {
+    let mut out = format!("error: {err}\n --> {}\n", query.source);
+    let pos = err
+        .rsplit_once("at position ")
+        .and_then(|(_, rest)| {
+            let digits: String = rest.chars().take_while(char::is_ascii_digit).collect();
+            digits.parse::<usize>().ok()
+        })
+        .filter(|pos| *pos <= query.trimmed().len());
+    let Some(pos) = pos else {
+        return out;
+    };
+    // positions are reported in the trimmed text which was parsed, while the
+    // line and column are shown in the text as it was read.
+    let pos = pos + query.text.len() - query.text.trim_start().len();
+    if !query.text.is_char_boundary(pos) {
+        return out;
+    }
+    let line_start = query.text[..pos].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = query.text[pos..]
+        .find('\n')
+        .map_or(query.text.len(), |i| pos + i);
+    let line_no = query.text[..line_start].matches('\n').count() + 1;
+    let column = query.text[line_start..pos].chars().count();
+    let gutter = " ".repeat(line_no.to_string().len());
+    out.push_str(&format!(
+        "{gutter} |\n{line_no} | {}\n{gutter} | {}^\n",
+        &query.text[line_start..line_end],
+        " ".repeat(column)
+    ));
+    out
+}
+
+fn selectors(expr: &Expr, out: &mut BTreeSet<String>) {
+    match expr {
+        Expr::VectorSelector(vs) => {
+            out.insert(vs.to_string());
+        }
+        Expr::MatrixSelector(ms) => {
+            out.insert(ms.vector_selector.to_string());
+        }
+        _ => {}
+    }
+    for child in expr.children() {
+        selectors(child, out);
+    }
+}
+
+fn run(command: Command, expr: &Expr, args: &Args) -> Result<String, String> {
+    match command {
+        Command::Check | Command::Rules => Ok("ok".to_string()),
+        Command::Fmt => {
+            let config = FormatConfig::default();
+            let config = match args.width {
+                Some(width) => config.with_max_width(width),
+                None => config,
+            };
+            Ok(prettify(expr, &config))
+        }
+        Command::Ast => serde_json::to_string_pretty(expr).map_err(|e| e.to_string()),
+        Command::Selectors => {
+            let mut out = BTreeSet::new();
+            selectors(expr, &mut out);
+            Ok(out.into_iter().collect::<Vec<_>>().join("\n"))
+        }
+    }
+}
+
+/// lint the rule files, printing a line for each finding, false if any.
+fn check_rules(paths: &[String]) -> Result<bool, String> {
+    if paths.is_empty() {
+        return Err("missing rule files".to_string());
+    }
+    let mut clean = true;
+    for path in paths {
+        let content = std::fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+        let diagnostics =
+            lint_rules(&content, &LintOptions::default()).map_err(|e| format!("{path}:{e}"))?;
+        for diagnostic in diagnostics {
+            clean = false;
+            println!("{path}:{diagnostic}");
+        }
+    }
+    Ok(clean)
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("error: {e}\n\n{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+    let command = match args.command {
+        Some(command) if !args.help => command,
+        _ => {
+            println!("{USAGE}");
+            return if args.help {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::from(2)
+            };
+        }
+    };
+    if command == Command::Rules {
+        let paths: Vec<String> = args.files.iter().chain(&args.queries).cloned().collect();
+        return match check_rules(&paths) {
+            Ok(true) => ExitCode::SUCCESS,
+            Ok(false) => ExitCode::FAILURE,
+            Err(e) => {
+                eprintln!("error: {e}");
+                ExitCode::from(2)
+            }
+        };
+    }
+    let queries = match read_queries(&args) {
+        Ok(queries) => queries,
+        Err(e) => {
+            eprintln!("error: {e}");
+            return ExitCode::from(2);
+        }
+    };
+
+    let options = ParseOptions::default().with_experimental_functions(args.experimental);
+    let mut failed = false;
+    for query in &queries {
+        // queries which do not type check can still be formatted
+        let parsed = match command {
+            Command::Fmt => parser::parse_syntax(query.trimmed()),
+            _ => parser::parse_with_options(query.trimmed(), &options),
+        };
+        let result = parsed.and_then(|expr| run(command, &expr, &args));
+        match result {
+            Ok(out) if command == Command::Check => println!("{}: {out}", query.source),
+            Ok(out) => println!("{out}"),
+            Err(e) => {
+                failed = true;
+                eprint!("{}", diagnostic(query, &e));
+            }
+        }
+    }
+    if failed {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Result<Args, String> {
+        parse_args(args.iter().map(|s| s.to_string()))
+    }
+
+    fn query(text: &str) -> Query {
+        Query {
+            source: "<arg 1>".to_string(),
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let parsed = args(&["fmt", "-w", "20", "foo", "--experimental", "-f", "q.promql"]).unwrap();
+        assert_eq!(parsed.command, Some(Command::Fmt));
+        assert_eq!(parsed.width, Some(20));
+        assert_eq!(parsed.queries, vec!["foo"]);
+        assert_eq!(parsed.files, vec!["q.promql"]);
+        assert!(parsed.experimental);
+
+        let parsed = args(&["rules", "a.yml", "b.yml"]).unwrap();
+        assert_eq!(parsed.command, Some(Command::Rules));
+        assert_eq!(parsed.queries, vec!["a.yml", "b.yml"]);
+
+        assert_eq!(args(&["lint"]).unwrap_err(), "unknown command lint");
+        assert_eq!(args(&["fmt", "--bad"]).unwrap_err(), "unknown option --bad");
+        assert_eq!(args(&["fmt", "-w"]).unwrap_err(), "missing value for -w");
+        assert_eq!(args(&["fmt", "-w", "x"]).unwrap_err(), "invalid width x");
+    }
+
+    #[test]
+    fn test_run() {
+        let parsed = args(&["selectors"]).unwrap();
+        let expr =
+            parser::parse(r#"sum(rate(foo{job="a"}[5m])) / sum(rate(foo{job="a"}[1h])) + bar"#)
+                .unwrap();
+        assert_eq!(
+            run(Command::Selectors, &expr, &parsed).unwrap(),
+            "bar\nfoo{job=\"a\"}"
+        );
+        assert_eq!(run(Command::Check, &expr, &parsed).unwrap(), "ok");
+
+        let parsed = args(&["fmt", "-w", "20"]).unwrap();
+        let expr = parser::parse("sum by (job) (rate(foo[5m]))").unwrap();
+        assert_eq!(
+            run(Command::Fmt, &expr, &parsed).unwrap(),
+            "sum by (job) (\n  rate(foo[5m])\n)"
+        );
+
+        let json = run(Command::Ast, &expr, &parsed).unwrap();
+        assert!(json.contains("\"rate\""), "{json}");
+    }
+
+    #[test]
+    fn test_diagnostic() {
+        let q = query("sum(foo{a=\"b\"");
+        let err = parser::parse(&q.text).unwrap_err();
+        assert_eq!(
+            diagnostic(&q, &err),
+            "error: unexpected end of input inside braces, unclosed left brace at position 7\n --> <arg 1>\n  |\n1 | sum(foo{a=\"b\"\n  |        ^\n"
+        );
+
+        let q = query("foo +\n  rate(bar");
+        let err = parser::parse(&q.text).unwrap_err();
+        assert!(
+            diagnostic(&q, &err).ends_with("2 |   rate(bar\n  |       ^\n"),
+            "{}",
+            diagnostic(&q, &err)
+        );
+
+        let q = query("\n  sum(foo{a=\"b\"\n");
+        let err = parser::parse(q.trimmed()).unwrap_err();
+        assert!(
+            diagnostic(&q, &err).ends_with("2 |   sum(foo{a=\"b\"\n  |          ^\n"),
+            "{}",
+            diagnostic(&q, &err)
+        );
+
+        let q = query("rate(foo)");
+        let err = parser::parse(&q.text).unwrap_err();
+        assert_eq!(
+            diagnostic(&q, &err),
+            "error: expected type matrix in call to function 'rate', got vector\n --> <arg 1>\n"
+        );
+    }
+}
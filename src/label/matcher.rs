@@ -12,12 +12,19 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display};
 use std::hash::{Hash, Hasher};
+use std::ops::Range;
+use std::str::FromStr;
+use std::sync::{Mutex, PoisonError};
 
-use crate::label::METRIC_NAME;
+use crate::label::{NameValidationScheme, METRIC_NAME};
+use crate::parser::quote;
 use crate::parser::token::{TokenId, T_EQL, T_EQL_REGEX, T_NEQ, T_NEQ_REGEX};
+use lazy_static::lazy_static;
 use regex::Regex;
+use regex_syntax::hir::{Class, Hir, HirKind};
 
 #[derive(Debug, Clone)]
 pub enum MatchOp {
@@ -52,8 +59,62 @@ impl Hash for MatchOp {
     }
 }
 
+impl MatchOp {
+    /// the operator without its regex.
+    pub fn kind(&self) -> MatchOpKind {
+        match self {
+            MatchOp::Equal => MatchOpKind::Equal,
+            MatchOp::NotEqual => MatchOpKind::NotEqual,
+            MatchOp::Re(_) => MatchOpKind::Re,
+            MatchOp::NotRe(_) => MatchOpKind::NotRe,
+        }
+    }
+}
+
+impl Display for MatchOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.kind())
+    }
+}
+
+/// the operator of a matcher without the regex compiled from its value, so it
+/// can be parsed from `=`, `!=`, `=~` or `!~` alone, see [`Matcher::try_from`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MatchOpKind {
+    Equal,
+    NotEqual,
+    Re,
+    NotRe,
+}
+
+impl Display for MatchOpKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MatchOpKind::Equal => write!(f, "="),
+            MatchOpKind::NotEqual => write!(f, "!="),
+            MatchOpKind::Re => write!(f, "=~"),
+            MatchOpKind::NotRe => write!(f, "!~"),
+        }
+    }
+}
+
+impl FromStr for MatchOpKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "=" => Ok(MatchOpKind::Equal),
+            "!=" => Ok(MatchOpKind::NotEqual),
+            "=~" => Ok(MatchOpKind::Re),
+            "!~" => Ok(MatchOpKind::NotRe),
+            _ => Err(format!("invalid match op {s}")),
+        }
+    }
+}
+
 // Matcher models the matching of a label.
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ser", derive(serde::Serialize, serde::Deserialize))]
 pub struct Matcher {
     pub op: MatchOp,
     pub name: String,
@@ -74,6 +135,36 @@ impl Matcher {
         }
     }
 
+    /// build a matcher like `name="value"`.
+    pub fn eq(name: impl Into<String>, value: impl Into<String>) -> Self {
+        Self::new(MatchOp::Equal, name.into(), value.into())
+    }
+
+    /// build a matcher like `name!="value"`.
+    pub fn ne(name: impl Into<String>, value: impl Into<String>) -> Self {
+        Self::new(MatchOp::NotEqual, name.into(), value.into())
+    }
+
+    /// build a matcher like `name=~"value"`, fails if the value is not a valid regex.
+    pub fn re(name: impl Into<String>, value: impl Into<String>) -> Result<Self, String> {
+        Self::new_matcher(T_EQL_REGEX, name.into(), value.into())
+    }
+
+    /// build a matcher like `name!~"value"`, fails if the value is not a valid regex.
+    pub fn not_re(name: impl Into<String>, value: impl Into<String>) -> Result<Self, String> {
+        Self::new_matcher(T_NEQ_REGEX, name.into(), value.into())
+    }
+
+    /// like [`Matcher::is_match`], but a regex must match the whole value, as in
+    /// Prometheus, like `a=~"b"` does not match `abc`.
+    pub fn is_full_match(&self, s: &str) -> bool {
+        match &self.op {
+            MatchOp::Re(_) => full_match(&self.value, s),
+            MatchOp::NotRe(_) => !full_match(&self.value, s),
+            MatchOp::Equal | MatchOp::NotEqual => self.is_match(s),
+        }
+    }
+
     /// matches returns whether the matcher matches the given string value.
     pub fn is_match(&self, s: &str) -> bool {
         match &self.op {
@@ -84,28 +175,164 @@ impl Matcher {
         }
     }
 
+    /// whether the matcher accepts every non-empty value, like `!=""` or `=~".+"`,
+    /// so it only filters on the presence of the label, if at all.
+    pub fn matches_any_value(&self) -> bool {
+        match &self.op {
+            MatchOp::NotEqual | MatchOp::NotRe(_) => self.value.is_empty(),
+            MatchOp::Re(_) => regex_syntax::parse(&self.value).is_ok_and(|hir| is_match_any(&hir)),
+            MatchOp::Equal => false,
+        }
+    }
+
     pub fn new_matcher(id: TokenId, name: String, value: String) -> Result<Matcher, String> {
+        Self::new_matcher_at(id, name, value, 0).map_err(|(err, _)| err)
+    }
+
+    /// same as [`Matcher::new_matcher`], `offset` is the position of the value
+    /// in the query, the error of an invalid regex has the span of its invalid
+    /// part in the query.
+    pub(crate) fn new_matcher_at(
+        id: TokenId,
+        name: String,
+        value: String,
+        offset: usize,
+    ) -> Result<Matcher, (String, Option<Range<usize>>)> {
+        let regex = |value: &str| new_regex(value, offset).map_err(|(err, span)| (err, Some(span)));
         match id {
             T_EQL => Ok(Matcher::new(MatchOp::Equal, name, value)),
             T_NEQ => Ok(Matcher::new(MatchOp::NotEqual, name, value)),
             T_EQL_REGEX => {
-                let re = Regex::new(&value).map_err(|_| format!("illegal regex for {}", &value))?;
+                let re = regex(&value)?;
                 Ok(Matcher::new(MatchOp::Re(re), name, value))
             }
             T_NEQ_REGEX => {
-                let re = Regex::new(&value).map_err(|_| format!("illegal regex for {}", &value))?;
+                let re = regex(&value)?;
                 Ok(Matcher::new(MatchOp::NotRe(re), name, value))
             }
-            _ => Err(format!("invalid match op {id}")),
+            _ => Err((format!("invalid match op {id}"), None)),
         }
     }
 }
 
+/// convert a `(name, op, value)` tuple, like `("job", "=~", "api.*")`, to a matcher.
+impl TryFrom<(&str, &str, &str)> for Matcher {
+    type Error = String;
+
+    fn try_from((name, op, value): (&str, &str, &str)) -> Result<Self, Self::Error> {
+        match op.parse()? {
+            MatchOpKind::Equal => Ok(Matcher::eq(name, value)),
+            MatchOpKind::NotEqual => Ok(Matcher::ne(name, value)),
+            MatchOpKind::Re => Matcher::re(name, value),
+            MatchOpKind::NotRe => Matcher::not_re(name, value),
+        }
+    }
+}
+
+/// the value is escaped and written in double quotes, so it parses back to the same matcher.
+/// So is the name, unless it is a legacy label name, like `"service.name"="api"`.
+impl Display for Matcher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match NameValidationScheme::Legacy.is_valid_label_name(&self.name) {
+            true => write!(f, "{}", self.name)?,
+            false => write!(f, "{}", quote(&self.name))?,
+        }
+        write!(f, "{}{}", self.op, quote(&self.value))
+    }
+}
+
+/// whether the regex is like `.*` or `.+`, ignoring anchors and groups.
+fn is_match_any(hir: &Hir) -> bool {
+    match hir.kind() {
+        HirKind::Capture(capture) => is_match_any(&capture.sub),
+        HirKind::Concat(hirs) => {
+            let mut hirs = hirs
+                .iter()
+                .filter(|h| !matches!(h.kind(), HirKind::Look(_)));
+            matches!((hirs.next(), hirs.next()), (Some(h), None) if is_match_any(h))
+        }
+        HirKind::Repetition(rep) if rep.min <= 1 && rep.max.is_none() => match rep.sub.kind() {
+            // any char, except a newline without the `s` flag
+            HirKind::Class(Class::Unicode(class)) => {
+                let ranges = class.ranges();
+                ranges.first().is_some_and(|r| r.start() == '\0')
+                    && ranges.last().is_some_and(|r| r.end() == char::MAX)
+                    && ranges.windows(2).all(|w| {
+                        w[0].end() as u32 + 2 == w[1].start() as u32
+                            && (w[0].end() as u32 + 1) == '\n' as u32
+                    })
+            }
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+lazy_static! {
+    /// the anchored regexes of [`Matcher::is_full_match`] by their pattern.
+    static ref ANCHORED_REGEXES: Mutex<HashMap<String, Regex>> = Mutex::new(HashMap::new());
+}
+
+/// the cache is cleared once it holds this many regexes.
+const ANCHORED_REGEXES_CAPACITY: usize = 1024;
+
+fn full_match(pattern: &str, s: &str) -> bool {
+    let re = {
+        let mut cache = ANCHORED_REGEXES
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        match cache.get(pattern) {
+            Some(re) => re.clone(),
+            None => {
+                let Ok(re) = Regex::new(&format!("^(?:{pattern})$")) else {
+                    return false;
+                };
+                if cache.len() >= ANCHORED_REGEXES_CAPACITY {
+                    cache.clear();
+                }
+                cache.insert(pattern.to_string(), re.clone());
+                re
+            }
+        }
+    };
+    re.is_match(s)
+}
+
+/// compile the regex, the error has the span of the offending part of the
+/// pattern, shifted by `offset`, which is also in its message.
+pub(crate) fn new_regex(re: &str, offset: usize) -> Result<Regex, (String, Range<usize>)> {
+    Regex::new(re).map_err(|err| {
+        let (span, reason) = match regex_syntax::Parser::new().parse(re) {
+            Err(regex_syntax::Error::Parse(e)) => (*e.span(), e.kind().to_string()),
+            Err(regex_syntax::Error::Translate(e)) => (*e.span(), e.kind().to_string()),
+            _ => {
+                let end = offset + re.len();
+                let message = format!("illegal regex for {re} at position {offset}..{end}: {err}");
+                return (message, offset..end);
+            }
+        };
+        let start = offset + span.start.offset;
+        let end = offset + span.end.offset;
+        let message = format!("illegal regex for {re} at position {start}..{end}: {reason}");
+        (message, start..end)
+    })
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "ser", derive(serde::Serialize, serde::Deserialize))]
 pub struct Matchers {
     pub matchers: HashSet<Matcher>,
 }
 
+/// matchers are sorted and joined by comma, like `a="b",c=~"d.*"`, so the output is stable.
+impl Display for Matchers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut matchers: Vec<String> = self.matchers.iter().map(|m| m.to_string()).collect();
+        matchers.sort();
+        write!(f, "{}", matchers.join(","))
+    }
+}
+
 impl Matchers {
     pub fn empty() -> Self {
         Self {
@@ -166,7 +393,91 @@ mod tests {
         assert_eq!(
             Matcher::new_matcher(token::T_ADD, "".into(), "".into()),
             Err(format!("invalid match op {}", token::T_ADD))
-        )
+        );
+        assert_eq!(
+            Matcher::new_matcher(token::T_EQL_REGEX, "".into(), "a(b".into()),
+            Err("illegal regex for a(b at position 1..2: unclosed group".into())
+        );
+        assert_eq!(
+            Matcher::new_matcher_at(token::T_NEQ_REGEX, "".into(), "a]|[b".into(), 10),
+            Err((
+                "illegal regex for a]|[b at position 13..14: unclosed character class".into(),
+                Some(13..14)
+            ))
+        );
+    }
+
+    #[test]
+    fn test_matcher_constructors() {
+        assert_eq!(
+            Matcher::eq("job", "api"),
+            Matcher::new(MatchOp::Equal, "job".into(), "api".into())
+        );
+        assert_eq!(
+            Matcher::ne("job", "api"),
+            Matcher::new(MatchOp::NotEqual, "job".into(), "api".into())
+        );
+        assert_eq!(
+            Matcher::re("job", "api.*"),
+            Ok(Matcher::new(
+                MatchOp::Re(Regex::new("api.*").unwrap()),
+                "job".into(),
+                "api.*".into()
+            ))
+        );
+        assert_eq!(
+            Matcher::not_re("job", "api.*"),
+            Ok(Matcher::new(
+                MatchOp::NotRe(Regex::new("api.*").unwrap()),
+                "job".into(),
+                "api.*".into()
+            ))
+        );
+        assert!(Matcher::re("job", "api(").is_err());
+
+        assert_eq!(
+            Matcher::try_from(("job", "=", "api")),
+            Ok(Matcher::eq("job", "api"))
+        );
+        assert_eq!(
+            Matcher::try_from(("job", "!=", "api")),
+            Ok(Matcher::ne("job", "api"))
+        );
+        assert_eq!(
+            Matcher::try_from(("job", "=~", "api.*")),
+            Matcher::re("job", "api.*")
+        );
+        assert_eq!(
+            Matcher::try_from(("job", "!~", "api.*")),
+            Matcher::not_re("job", "api.*")
+        );
+        assert_eq!(
+            Matcher::try_from(("job", "==", "api")),
+            Err("invalid match op ==".into())
+        );
+    }
+
+    #[test]
+    fn test_matchop_display_and_from_str() {
+        let re = Regex::new("a.*").unwrap();
+        let cases = vec![
+            (MatchOp::Equal, MatchOpKind::Equal, "="),
+            (MatchOp::NotEqual, MatchOpKind::NotEqual, "!="),
+            (MatchOp::Re(re.clone()), MatchOpKind::Re, "=~"),
+            (MatchOp::NotRe(re), MatchOpKind::NotRe, "!~"),
+        ];
+        for (op, kind, s) in cases {
+            assert_eq!(op.to_string(), s);
+            assert_eq!(op.kind(), kind);
+            assert_eq!(s.parse(), Ok(kind));
+            assert_eq!(kind.to_string(), s);
+        }
+
+        assert_eq!(
+            "==".parse::<MatchOpKind>(),
+            Err("invalid match op ==".into())
+        );
+        assert_eq!("".parse::<MatchOpKind>(), Err("invalid match op ".into()));
     }
 
     #[test]
@@ -311,6 +622,22 @@ mod tests {
         assert!(!matcher.is_match("api/v2"));
     }
 
+    #[test]
+    fn test_matcher_full_match() {
+        let re = Matcher::re("a", "b|c").unwrap();
+        assert!(re.is_match("abc"));
+        assert!(!re.is_full_match("abc"));
+        assert!(re.is_full_match("b"));
+        assert!(re.is_full_match("c"));
+
+        let not_re = Matcher::not_re("a", "b").unwrap();
+        assert!(not_re.is_full_match("abc"));
+        assert!(!not_re.is_full_match("b"));
+
+        assert!(Matcher::eq("a", "b").is_full_match("b"));
+        assert!(!Matcher::ne("a", "b").is_full_match("b"));
+    }
+
     #[test]
     fn test_eq_matcher_equality() {
         assert_eq!(
@@ -486,4 +813,64 @@ mod tests {
                 ))
         );
     }
+
+    #[test]
+    fn test_matchers_display() {
+        assert_eq!(Matcher::eq("job", "api").to_string(), r#"job="api""#);
+        assert_eq!(
+            Matcher::not_re("code", "5..").unwrap().to_string(),
+            r#"code!~"5..""#
+        );
+
+        let matchers = Matchers::empty()
+            .append(Matcher::ne("job", "api"))
+            .append(Matcher::re("code", "5..").unwrap())
+            .append(Matcher::eq("instance", r#"a"b"#));
+        assert_eq!(
+            matchers.to_string(),
+            r#"code=~"5..",instance="a\"b",job!="api""#
+        );
+        assert_eq!(Matchers::empty().to_string(), "");
+
+        // the quotes and backslashes in the value are escaped
+        assert_eq!(Matcher::eq("a", r#"x"y"#).to_string(), r#"a="x\"y""#);
+        assert_eq!(Matcher::eq("a", r"x\y").to_string(), r#"a="x\\y""#);
+        assert_eq!(
+            Matcher::re("a", r"eu\.west").unwrap().to_string(),
+            r#"a=~"eu\\.west""#
+        );
+
+        // names which are not legacy label names are quoted
+        assert_eq!(
+            Matcher::eq("service.name", "api").to_string(),
+            r#""service.name"="api""#
+        );
+    }
+
+    #[test]
+    fn test_matches_any_value() {
+        let any = [
+            Matcher::ne("a", ""),
+            Matcher::not_re("a", "").unwrap(),
+            Matcher::re("a", ".*").unwrap(),
+            Matcher::re("a", ".+").unwrap(),
+            Matcher::re("a", "^(.*)$").unwrap(),
+            Matcher::re("a", "(?s:.*)").unwrap(),
+        ];
+        for m in any {
+            assert!(m.matches_any_value(), "{m}");
+        }
+        let not_any = [
+            Matcher::eq("a", ""),
+            Matcher::ne("a", "b"),
+            Matcher::not_re("a", ".*").unwrap(),
+            Matcher::re("a", "b.*").unwrap(),
+            Matcher::re("a", ".").unwrap(),
+            Matcher::re("a", "[a-z]*").unwrap(),
+            Matcher::re("a", ".{2,}").unwrap(),
+        ];
+        for m in not_any {
+            assert!(!m.matches_any_value(), "{m}");
+        }
+    }
 }
@@ -16,7 +16,8 @@
 
 mod matcher;
 
-pub use matcher::{MatchOp, Matcher, Matchers};
+pub(crate) use matcher::new_regex;
+pub use matcher::{MatchOp, MatchOpKind, Matcher, Matchers};
 use std::collections::HashSet;
 
 /// "__name__"
@@ -28,6 +29,165 @@ pub const BUCKET_LABEL: &str = "le";
 /// "instance"
 pub const INSTANCE_NAME: &str = "instance";
 
+/// NameValidationScheme decides which characters are allowed in metric and label names.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NameValidationScheme {
+    /// metric names match `[a-zA-Z_:][a-zA-Z0-9_:]*` and label names
+    /// match `[a-zA-Z_][a-zA-Z0-9_]*`.
+    #[default]
+    Legacy,
+    /// any non-empty UTF-8 string is valid, as in Prometheus 3.0.
+    Utf8,
+}
+
+impl NameValidationScheme {
+    pub fn is_valid_metric_name(&self, name: &str) -> bool {
+        match self {
+            NameValidationScheme::Legacy => is_legacy_name(name, true),
+            NameValidationScheme::Utf8 => !name.is_empty(),
+        }
+    }
+
+    pub fn is_valid_label_name(&self, name: &str) -> bool {
+        match self {
+            NameValidationScheme::Legacy => is_legacy_name(name, false),
+            NameValidationScheme::Utf8 => !name.is_empty(),
+        }
+    }
+}
+
+fn is_legacy_name(name: &str, allow_colon: bool) -> bool {
+    let valid = |ch: char| ch.is_ascii_alphanumeric() || ch == '_' || (allow_colon && ch == ':');
+    match name.chars().next() {
+        Some(first) => !first.is_ascii_digit() && name.chars().all(valid),
+        None => false,
+    }
+}
+
 pub type Label = String;
 /// Unordered set for a group of labels.
 pub type Labels = HashSet<Label>;
+
+/// Build [`Matchers`] from `name op "value"` pairs, the op is one of
+/// `=`, `!=`, `=~` and `!~`.
+///
+/// # Panics
+///
+/// Panics if the value of a regex matcher is not a valid regex.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::label::{Matcher, Matchers};
+/// use promql_parser::matchers;
+///
+/// let matchers = matchers!(job = "api", status =~ "5..");
+/// let expected = Matchers::empty()
+///     .append(Matcher::eq("job", "api"))
+///     .append(Matcher::re("status", "5..").unwrap());
+/// assert_eq!(matchers, expected);
+/// ```
+#[macro_export]
+macro_rules! matchers {
+    (@append $m:expr;) => { $m };
+    (@append $m:expr; $name:ident =~ $value:expr $(, $($rest:tt)*)?) => {
+        $crate::matchers!(@append $m.append(
+            $crate::label::Matcher::re(stringify!($name), $value).expect("invalid regex in matchers!")
+        ); $($($rest)*)?)
+    };
+    (@append $m:expr; $name:ident !~ $value:expr $(, $($rest:tt)*)?) => {
+        $crate::matchers!(@append $m.append(
+            $crate::label::Matcher::not_re(stringify!($name), $value).expect("invalid regex in matchers!")
+        ); $($($rest)*)?)
+    };
+    (@append $m:expr; $name:ident != $value:expr $(, $($rest:tt)*)?) => {
+        $crate::matchers!(@append $m.append(
+            $crate::label::Matcher::ne(stringify!($name), $value)
+        ); $($($rest)*)?)
+    };
+    (@append $m:expr; $name:ident = $value:expr $(, $($rest:tt)*)?) => {
+        $crate::matchers!(@append $m.append(
+            $crate::label::Matcher::eq(stringify!($name), $value)
+        ); $($($rest)*)?)
+    };
+    () => { $crate::label::Matchers::empty() };
+    ($name:ident $($rest:tt)*) => {
+        $crate::matchers!(@append $crate::label::Matchers::empty(); $name $($rest)*)
+    };
+}
+
+/// Build [`Labels`] from label names.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::labels;
+/// use promql_parser::label::Labels;
+///
+/// let labels = labels!("job", "instance");
+/// assert_eq!(labels, Labels::from(["job".into(), "instance".into()]));
+/// ```
+#[macro_export]
+macro_rules! labels {
+    ($($label:expr),* $(,)?) => {
+        $crate::label::Labels::from([$(::std::string::String::from($label)),*])
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_name_validation_scheme() {
+        let legacy = NameValidationScheme::Legacy;
+        assert!(legacy.is_valid_metric_name("foo:bar_1"));
+        assert!(legacy.is_valid_metric_name(":foo"));
+        assert!(!legacy.is_valid_metric_name("1foo"));
+        assert!(!legacy.is_valid_metric_name("foo.bar"));
+        assert!(!legacy.is_valid_metric_name(""));
+        assert!(legacy.is_valid_label_name("_foo1"));
+        assert!(!legacy.is_valid_label_name("foo:bar"));
+        assert!(!legacy.is_valid_label_name("fö"));
+
+        let utf8 = NameValidationScheme::Utf8;
+        assert!(utf8.is_valid_metric_name("foo.bar"));
+        assert!(utf8.is_valid_label_name("fö:1"));
+        assert!(!utf8.is_valid_label_name(""));
+    }
+
+    #[test]
+    fn test_matchers_macro() {
+        assert_eq!(matchers!(), Matchers::empty());
+        assert_eq!(
+            matchers!(job = "api"),
+            Matchers::one(Matcher::eq("job", "api"))
+        );
+
+        let value = String::from("api");
+        let expected = Matchers::empty()
+            .append(Matcher::eq("job", "api"))
+            .append(Matcher::ne("env", "dev"))
+            .append(Matcher::re("status", "5..").unwrap())
+            .append(Matcher::not_re("path", "/health.*").unwrap());
+        assert_eq!(
+            matchers!(job = value, env != "dev", status =~ "5..", path !~ "/health.*",),
+            expected
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_matchers_macro_invalid_regex() {
+        matchers!(job =~ "api(");
+    }
+
+    #[test]
+    fn test_labels_macro() {
+        assert_eq!(labels!(), Labels::new());
+        assert_eq!(
+            labels!("job", String::from("instance"),),
+            Labels::from([String::from("job"), String::from("instance")])
+        );
+    }
+}
@@ -57,6 +57,9 @@
 #![allow(clippy::let_unit_value)]
 lrpar::lrpar_mod!("parser/promql.y");
 
+mod assertions;
 pub mod label;
 pub mod parser;
+#[cfg(feature = "testing")]
+pub mod testing;
 pub mod util;
@@ -12,12 +12,24 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::label::{Labels, Matcher, Matchers, METRIC_NAME};
+use crate::label::{
+    new_regex, Labels, MatchOp, Matcher, Matchers, NameValidationScheme, METRIC_NAME,
+};
+use crate::parser::function::is_label_arg;
+use crate::parser::quote;
 use crate::parser::token::{
-    self, token_display, T_BOTTOMK, T_COUNT_VALUES, T_END, T_QUANTILE, T_START, T_TOPK,
+    self, token_display, T_ADD, T_BOTTOMK, T_COUNT_VALUES, T_DIV, T_END, T_EQLC, T_GTE, T_GTR,
+    T_LSS, T_LTE, T_MUL, T_NEQ, T_QUANTILE, T_START, T_SUB, T_TOPK,
 };
 use crate::parser::{Function, FunctionArgs, Token, TokenId, TokenType, ValueType};
-use std::ops::Neg;
+use crate::util::diagnostic::{codes, Diagnostic};
+use crate::util::display_duration;
+use crate::util::rewrite::{fill_subquery_steps, resolve_at_modifiers};
+use crate::util::series::selector_to_string;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::{Add, Div, Mul, Neg, Range, Sub};
 use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 
@@ -31,12 +43,13 @@ use std::time::{Duration, SystemTime};
 /// # Vector Match Modifier
 ///
 /// - Exclude means `without` removes the listed labels from the result vector,
-/// while all other labels are preserved in the output.
+///   while all other labels are preserved in the output.
 /// - Include means `by` does the opposite and drops labels that are not listed in the by clause,
-/// even if their label values are identical between all elements of the vector.
+///   even if their label values are identical between all elements of the vector.
 ///
 /// if empty listed labels, meaning no grouping
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "ser", derive(serde::Serialize, serde::Deserialize))]
 pub enum LabelModifier {
     Include(Labels),
     Exclude(Labels),
@@ -59,6 +72,7 @@ impl LabelModifier {
 /// The label list provided with the group_left or group_right modifier contains
 /// additional labels from the "one"-side to be included in the result metrics.
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "ser", derive(serde::Serialize, serde::Deserialize))]
 pub enum VectorMatchCardinality {
     OneToOne,
     ManyToOne(Labels),
@@ -79,6 +93,7 @@ impl VectorMatchCardinality {
 
 /// Binary Expr Modifier
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "ser", derive(serde::Serialize, serde::Deserialize))]
 pub struct BinModifier {
     /// The matching behavior for the operation if both operands are Vectors.
     /// If they are not this field is None.
@@ -141,17 +156,134 @@ impl BinModifier {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "ser", derive(serde::Serialize, serde::Deserialize))]
 pub enum Offset {
     Pos(Duration),
     Neg(Duration),
 }
 
+impl Offset {
+    /// the sum of the offsets, None on overflow. A zero offset is positive.
+    ///
+    /// # Examples
+    ///
+    /// ``` rust
+    /// use promql_parser::parser::Offset;
+    /// use std::time::Duration;
+    ///
+    /// let offset = Offset::Pos(Duration::from_secs(60));
+    /// let shift = Offset::Neg(Duration::from_secs(90));
+    /// assert_eq!(offset.checked_add(&shift), Some(Offset::Neg(Duration::from_secs(30))));
+    /// assert_eq!(offset.checked_add(&-offset.clone()), Some(Offset::Pos(Duration::ZERO)));
+    /// assert_eq!(shift.as_millis(), -90_000);
+    /// assert_eq!(Offset::Pos(Duration::MAX).checked_add(&offset), None);
+    /// ```
+    pub fn checked_add(&self, other: &Offset) -> Option<Offset> {
+        match (self, other) {
+            (Offset::Pos(a), Offset::Pos(b)) => a.checked_add(*b).map(Offset::Pos),
+            (Offset::Neg(a), Offset::Neg(b)) => a.checked_add(*b).map(Offset::Neg),
+            (Offset::Pos(a), Offset::Neg(b)) | (Offset::Neg(b), Offset::Pos(a)) if a >= b => {
+                Some(Offset::Pos(*a - *b))
+            }
+            (Offset::Pos(a), Offset::Neg(b)) | (Offset::Neg(b), Offset::Pos(a)) => {
+                Some(Offset::Neg(*b - *a))
+            }
+        }
+    }
+
+    /// the difference of the offsets, None on overflow.
+    pub fn checked_sub(&self, other: &Offset) -> Option<Offset> {
+        self.checked_add(&-other.clone())
+    }
+
+    /// the offset in milliseconds, negative for [`Offset::Neg`].
+    pub fn as_millis(&self) -> i128 {
+        match self {
+            Offset::Pos(d) => d.as_millis() as i128,
+            Offset::Neg(d) => -(d.as_millis() as i128),
+        }
+    }
+}
+
+impl Neg for Offset {
+    type Output = Self;
+
+    fn neg(self) -> Self::Output {
+        match self {
+            Offset::Pos(d) if d.is_zero() => Offset::Pos(d),
+            Offset::Pos(d) => Offset::Neg(d),
+            Offset::Neg(d) => Offset::Pos(d),
+        }
+    }
+}
+
+/// the resolution step of a subquery, which keeps whether the step was written
+/// in the query, so the query is rendered as it was written.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::{parse, Expr, SubqueryStep};
+/// use promql_parser::util::rewrite::fill_subquery_steps;
+/// use std::time::Duration;
+///
+/// let Expr::Subquery(sq) = parse("foo[1h:]").unwrap() else { unreachable!() };
+/// assert_eq!(sq.step, SubqueryStep::Default);
+///
+/// let Expr::Subquery(sq) = parse("foo[1h:5m]").unwrap() else { unreachable!() };
+/// assert_eq!(sq.step, SubqueryStep::Explicit(Duration::from_secs(300)));
+///
+/// let expr = fill_subquery_steps(&parse("foo[1h:]").unwrap(), Duration::from_secs(30));
+/// let Expr::Subquery(sq) = expr else { unreachable!() };
+/// assert_eq!(sq.step, SubqueryStep::Resolved(Duration::from_secs(30)));
+/// assert_eq!(sq.to_string(), "foo[1h:30s]");
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ser", derive(serde::Serialize, serde::Deserialize))]
+pub enum SubqueryStep {
+    /// no step, like `foo[1h:]`, the global evaluation interval is used.
+    #[default]
+    Default,
+    /// the step written in the query, like `foo[1h:5m]`.
+    Explicit(Duration),
+    /// the evaluation interval filled in for a default step, see
+    /// [`fill_subquery_steps`](crate::util::rewrite::fill_subquery_steps).
+    /// It is rendered like an explicit step.
+    Resolved(Duration),
+}
+
+impl SubqueryStep {
+    /// the step, None if it is the default one which is not resolved yet.
+    pub fn duration(&self) -> Option<Duration> {
+        match self {
+            SubqueryStep::Default => None,
+            SubqueryStep::Explicit(d) | SubqueryStep::Resolved(d) => Some(*d),
+        }
+    }
+
+    /// whether the step is written in the query.
+    pub fn is_explicit(&self) -> bool {
+        matches!(self, SubqueryStep::Explicit(_))
+    }
+}
+
+/// the step as written after the colon of a subquery, empty for the default step.
+impl fmt::Display for SubqueryStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.duration() {
+            Some(step) => write!(f, "{}", display_duration(&step)),
+            None => Ok(()),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "ser", derive(serde::Serialize, serde::Deserialize))]
 pub enum AtModifier {
     Start,
     End,
     /// at can be earlier than UNIX_EPOCH
-    At(SystemTime),
+    At(#[cfg_attr(feature = "ser", serde(with = "crate::parser::ser::system_time"))] SystemTime),
 }
 
 impl TryFrom<TokenId> for AtModifier {
@@ -202,12 +334,14 @@ impl TryFrom<f64> for AtModifier {
     fn try_from(secs: f64) -> Result<Self, Self::Error> {
         let err_info = format!("timestamp out of bounds for @ modifier: {secs}");
 
-        if secs.is_nan() || secs.is_infinite() || secs >= f64::MAX || secs <= f64::MIN {
+        // the timestamp must be an i64 of milliseconds, as in Prometheus, NaN
+        // and the infinities are out of bounds as well.
+        let milli = (secs * 1000f64).round();
+        if !(milli > i64::MIN as f64 && milli < i64::MAX as f64) {
             return Err(err_info);
         }
-        let milli = (secs * 1000f64).round().abs() as u64;
 
-        let duration = Duration::from_millis(milli);
+        let duration = Duration::from_millis(milli.abs() as u64);
         let mut st = Some(SystemTime::UNIX_EPOCH);
         if secs.is_sign_positive() {
             st = SystemTime::UNIX_EPOCH.checked_add(duration);
@@ -220,6 +354,22 @@ impl TryFrom<f64> for AtModifier {
     }
 }
 
+fn set_at(slot: &mut Option<AtModifier>, at: AtModifier) -> Result<(), String> {
+    if slot.is_some() {
+        return Err("@ <timestamp> may not be set multiple times".into());
+    }
+    *slot = Some(at);
+    Ok(())
+}
+
+fn set_offset(slot: &mut Option<Offset>, offset: Offset) -> Result<(), String> {
+    if slot.is_some() {
+        return Err("offset may not be set multiple times".into());
+    }
+    *slot = Some(offset);
+    Ok(())
+}
+
 /// EvalStmt holds an expression and information on the range it should
 /// be evaluated on.
 #[allow(rustdoc::broken_intra_doc_links)]
@@ -238,6 +388,17 @@ pub struct EvalStmt {
     pub lookback_delta: Duration,
 }
 
+impl EvalStmt {
+    /// resolve `@ start()` and `@ end()` to the times of the statement, and set
+    /// the step of the subqueries without one to the default evaluation interval,
+    /// see [`resolve_at_modifiers`] and [`fill_subquery_steps`].
+    pub fn resolve(mut self, default_step: Duration) -> Self {
+        let expr = resolve_at_modifiers(&self);
+        self.expr = fill_subquery_steps(&expr, default_step);
+        self
+    }
+}
+
 /// Grammar:
 /// ``` norust
 /// <aggr-op> [without|by (<label list>)] ([parameter,] <vector expression>)
@@ -246,6 +407,7 @@ pub struct EvalStmt {
 ///
 /// parameter is only required for `count_values`, `quantile`, `topk` and `bottomk`.
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "ser", derive(serde::Serialize, serde::Deserialize))]
 pub struct AggregateExpr {
     /// The used aggregation operation.
     pub op: TokenType,
@@ -259,6 +421,7 @@ pub struct AggregateExpr {
 
 /// UnaryExpr will negate the expr
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "ser", derive(serde::Serialize, serde::Deserialize))]
 pub struct UnaryExpr {
     pub expr: Box<Expr>,
 }
@@ -271,6 +434,7 @@ pub struct UnaryExpr {
 /// <vector expr> <bin-op> on(<label list>) group_right(<label list>) <vector expr>
 /// ```
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "ser", derive(serde::Serialize, serde::Deserialize))]
 pub struct BinaryExpr {
     /// The operation of the expression.
     pub op: TokenType,
@@ -309,6 +473,7 @@ impl BinaryExpr {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "ser", derive(serde::Serialize, serde::Deserialize))]
 pub struct ParenExpr {
     pub expr: Box<Expr>,
 }
@@ -318,16 +483,44 @@ pub struct ParenExpr {
 /// <instant_query> '[' <range> ':' [<resolution>] ']' [ @ <float_literal> ] [ offset <duration> ]
 /// ```
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "ser", derive(serde::Serialize, serde::Deserialize))]
 pub struct SubqueryExpr {
     pub expr: Box<Expr>,
     pub offset: Option<Offset>,
     pub at: Option<AtModifier>,
     pub range: Duration,
     /// Default is the global evaluation interval.
-    pub step: Option<Duration>,
+    pub step: SubqueryStep,
+}
+
+impl SubqueryExpr {
+    /// set @ modifier for the subquery, but CAN ONLY be set once.
+    pub fn at_expr(mut self, at: AtModifier) -> Result<Self, String> {
+        set_at(&mut self.at, at)?;
+        Ok(self)
+    }
+
+    /// set offset for the subquery, but CAN ONLY be set once.
+    pub fn offset_expr(mut self, offset: Offset) -> Result<Self, String> {
+        set_offset(&mut self.offset, offset)?;
+        Ok(self)
+    }
+
+    /// set the resolution step, like `foo[1h:5m]`.
+    pub fn with_step(mut self, step: Duration) -> Self {
+        self.step = SubqueryStep::Explicit(step);
+        self
+    }
+
+    /// resolve the step of this subquery, falling back to the global evaluation
+    /// `interval` when no resolution is given, like `foo[1h:]`.
+    pub fn resolve_step(&self, interval: Duration) -> Duration {
+        self.step.duration().unwrap_or(interval)
+    }
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "ser", derive(serde::Serialize, serde::Deserialize))]
 pub struct NumberLiteral {
     pub val: f64,
 }
@@ -355,11 +548,27 @@ impl Neg for NumberLiteral {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "ser", derive(serde::Serialize, serde::Deserialize))]
 pub struct StringLiteral {
     pub val: String,
 }
 
+/// MetricMatch is whether a selector selects the series of a metric, see
+/// [`VectorSelector::matches_metric`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MetricMatch {
+    /// the selector never selects the metric.
+    No,
+    /// the `__name__` matchers accept the metric without naming it, like
+    /// `{__name__=~"http_.*"}` or `{job="api"}`, so the metric is selected
+    /// along with others if it has the other labels.
+    Maybe,
+    /// the selector names the metric, like `foo` or `{__name__="foo"}`.
+    Yes,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "ser", derive(serde::Serialize, serde::Deserialize))]
 pub struct VectorSelector {
     pub name: Option<String>,
     pub matchers: Matchers,
@@ -405,6 +614,174 @@ impl From<&str> for VectorSelector {
     }
 }
 
+impl VectorSelector {
+    /// create a builder for the vector selector with the given metric name.
+    ///
+    /// # Examples
+    ///
+    /// ``` rust
+    /// use promql_parser::label::{MatchOp, Matcher};
+    /// use promql_parser::parser::{AtModifier, Offset, VectorSelector};
+    /// use std::time::Duration;
+    ///
+    /// let vs = VectorSelector::builder("foo")
+    ///     .matcher(Matcher::new(MatchOp::Equal, "job".into(), "api".into()))
+    ///     .offset(Offset::Pos(Duration::from_secs(300)))
+    ///     .at(AtModifier::End)
+    ///     .build()
+    ///     .unwrap();
+    /// assert_eq!(vs.name, Some(String::from("foo")));
+    /// assert_eq!(vs.matchers.matchers.len(), 2);
+    ///
+    /// let err = VectorSelector::builder("foo")
+    ///     .at(AtModifier::Start)
+    ///     .at(AtModifier::End)
+    ///     .build();
+    /// assert!(err.is_err());
+    /// ```
+    pub fn builder(name: impl Into<String>) -> VectorSelectorBuilder {
+        VectorSelectorBuilder {
+            vs: VectorSelector::from(name.into()),
+            err: None,
+        }
+    }
+
+    /// set @ modifier for the vector selector, but CAN ONLY be set once.
+    pub fn at_expr(mut self, at: AtModifier) -> Result<Self, String> {
+        set_at(&mut self.at, at)?;
+        Ok(self)
+    }
+
+    /// set offset for the vector selector, but CAN ONLY be set once.
+    pub fn offset_expr(mut self, offset: Offset) -> Result<Self, String> {
+        set_offset(&mut self.offset, offset)?;
+        Ok(self)
+    }
+
+    /// the metric name, which is either the name or the value of an equal `__name__` matcher.
+    pub fn metric_name(&self) -> Option<&str> {
+        self.name.as_deref().or_else(|| {
+            self.matchers
+                .matchers
+                .iter()
+                .find(|m| m.op == MatchOp::Equal && m.name == METRIC_NAME)
+                .map(|m| m.value.as_str())
+        })
+    }
+
+    /// the matchers without the `__name__` matcher equal to the name, which
+    /// the parser adds for `foo{...}`. The matchers of `{__name__="foo"}` are kept.
+    ///
+    /// # Examples
+    ///
+    /// ``` rust
+    /// use promql_parser::label::Matcher;
+    /// use promql_parser::parser::{parse, Expr};
+    ///
+    /// let Expr::VectorSelector(vs) = parse(r#"foo{job="api"}"#).unwrap() else {
+    ///     unreachable!()
+    /// };
+    /// assert_eq!(vs.matchers.matchers.len(), 2);
+    /// assert_eq!(vs.label_matchers().matchers.len(), 1);
+    /// assert!(vs.label_matchers().matchers.contains(&Matcher::eq("job", "api")));
+    /// assert_eq!(vs.normalized_matchers(), vs.matchers);
+    /// ```
+    pub fn label_matchers(&self) -> Matchers {
+        let matchers = self
+            .matchers
+            .matchers
+            .iter()
+            .filter(|m| {
+                !(m.op == MatchOp::Equal
+                    && m.name == METRIC_NAME
+                    && self.name.as_ref() == Some(&m.value))
+            })
+            .cloned()
+            .collect();
+        Matchers::new(matchers)
+    }
+
+    /// all the matchers, with the name folded in as an equal `__name__` matcher,
+    /// so `foo{job="api"}` and `{__name__="foo", job="api"}` have the same matchers.
+    pub fn normalized_matchers(&self) -> Matchers {
+        let mut matchers = self.matchers.matchers.clone();
+        if let Some(name) = &self.name {
+            matchers.insert(Matcher::eq(METRIC_NAME, name.as_str()));
+        }
+        Matchers::new(matchers)
+    }
+
+    /// whether the selector selects the series of the metric.
+    ///
+    /// # Examples
+    ///
+    /// ``` rust
+    /// use promql_parser::parser::{parse, Expr, MetricMatch};
+    ///
+    /// let Expr::VectorSelector(vs) = parse(r#"{__name__=~"http_.+"}"#).unwrap() else {
+    ///     unreachable!()
+    /// };
+    /// assert_eq!(vs.matches_metric("http_requests_total"), MetricMatch::Maybe);
+    /// assert_eq!(vs.matches_metric("up"), MetricMatch::No);
+    /// ```
+    pub fn matches_metric(&self, metric: &str) -> MetricMatch {
+        let name_matches = self
+            .matchers
+            .matchers
+            .iter()
+            .filter(|m| m.name == METRIC_NAME)
+            .all(|m| m.is_full_match(metric));
+        if !name_matches || self.name.as_deref().is_some_and(|name| name != metric) {
+            MetricMatch::No
+        } else if self.metric_name() == Some(metric) {
+            MetricMatch::Yes
+        } else {
+            MetricMatch::Maybe
+        }
+    }
+}
+
+/// VectorSelectorBuilder builds a [`VectorSelector`], the offset and @ modifiers
+/// can only be set once, which is checked by [`VectorSelectorBuilder::build`].
+#[derive(Debug, Clone)]
+pub struct VectorSelectorBuilder {
+    vs: VectorSelector,
+    err: Option<String>,
+}
+
+impl VectorSelectorBuilder {
+    pub fn matcher(mut self, matcher: Matcher) -> Self {
+        self.vs.matchers = self.vs.matchers.append(matcher);
+        self
+    }
+
+    pub fn offset(mut self, offset: Offset) -> Self {
+        if self.vs.offset.replace(offset).is_some() {
+            self.set_err("offset may not be set multiple times");
+        }
+        self
+    }
+
+    pub fn at(mut self, at: AtModifier) -> Self {
+        if self.vs.at.replace(at).is_some() {
+            self.set_err("@ <timestamp> may not be set multiple times");
+        }
+        self
+    }
+
+    pub fn build(self) -> Result<VectorSelector, String> {
+        match self.err {
+            Some(err) => Err(err),
+            None => Ok(self.vs),
+        }
+    }
+
+    /// only the first error is kept.
+    fn set_err(&mut self, err: &str) {
+        self.err.get_or_insert_with(|| err.into());
+    }
+}
+
 impl Neg for VectorSelector {
     type Output = UnaryExpr;
 
@@ -414,12 +791,62 @@ impl Neg for VectorSelector {
     }
 }
 
+/// MatrixSelector selects the samples of the range, the name, matchers and
+/// modifiers are kept in the nested vector selector, and are also available
+/// from the accessors of the matrix selector.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::{parse, Expr, Offset};
+/// use std::time::Duration;
+///
+/// let Expr::MatrixSelector(ms) = parse(r#"foo{job="api"}[5m] offset 1h"#).unwrap() else {
+///     unreachable!()
+/// };
+/// assert_eq!(ms.name(), Some("foo"));
+/// assert_eq!(ms.matchers().matchers.len(), 2);
+/// assert_eq!(ms.offset(), Some(&Offset::Pos(Duration::from_secs(3600))));
+/// assert_eq!(ms.at(), None);
+/// assert_eq!(ms.range, Duration::from_secs(300));
+/// ```
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "ser", derive(serde::Serialize, serde::Deserialize))]
 pub struct MatrixSelector {
     pub vector_selector: VectorSelector,
     pub range: Duration,
 }
 
+impl MatrixSelector {
+    pub fn name(&self) -> Option<&str> {
+        self.vector_selector.name.as_deref()
+    }
+
+    pub fn matchers(&self) -> &Matchers {
+        &self.vector_selector.matchers
+    }
+
+    pub fn offset(&self) -> Option<&Offset> {
+        self.vector_selector.offset.as_ref()
+    }
+
+    pub fn at(&self) -> Option<&AtModifier> {
+        self.vector_selector.at.as_ref()
+    }
+
+    /// set @ modifier for the inner vector selector, but CAN ONLY be set once.
+    pub fn at_expr(mut self, at: AtModifier) -> Result<Self, String> {
+        self.vector_selector = self.vector_selector.at_expr(at)?;
+        Ok(self)
+    }
+
+    /// set offset for the inner vector selector, but CAN ONLY be set once.
+    pub fn offset_expr(mut self, offset: Offset) -> Result<Self, String> {
+        self.vector_selector = self.vector_selector.offset_expr(offset)?;
+        Ok(self)
+    }
+}
+
 /// Call represents Prometheus Function.
 /// Some functions have special cases:
 ///
@@ -460,11 +887,56 @@ pub struct MatrixSelector {
 ///  - tan()
 ///  - tanh()
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "ser", derive(serde::Serialize, serde::Deserialize))]
 pub struct Call {
     pub func: Function,
     pub args: FunctionArgs,
 }
 
+impl Call {
+    /// the labels of the series returned by `absent()` or `absent_over_time()`,
+    /// None for other functions. As in Prometheus, the labels are only derived
+    /// from the equality matchers of a selector argument, and a label with
+    /// several matchers is dropped.
+    ///
+    /// # Examples
+    ///
+    /// ``` rust
+    /// use promql_parser::parser::{parse, Expr};
+    /// use std::collections::BTreeMap;
+    ///
+    /// let Expr::Call(call) = parse(r#"absent(foo{job="a", env=~"b.*"})"#).unwrap() else {
+    ///     unreachable!()
+    /// };
+    /// let labels = BTreeMap::from([("job".to_string(), "a".to_string())]);
+    /// assert_eq!(call.absent_labels(), Some(labels));
+    /// ```
+    pub fn absent_labels(&self) -> Option<BTreeMap<String, String>> {
+        if self.func.name != "absent" && self.func.name != "absent_over_time" {
+            return None;
+        }
+        let vs = match self.args.args.first().map(|e| &**e) {
+            Some(Expr::VectorSelector(vs)) => vs,
+            Some(Expr::MatrixSelector(ms)) => &ms.vector_selector,
+            _ => return Some(BTreeMap::new()),
+        };
+
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for m in &vs.matchers.matchers {
+            *counts.entry(m.name.as_str()).or_default() += 1;
+        }
+        let labels = vs
+            .matchers
+            .matchers
+            .iter()
+            .filter(|m| m.name != METRIC_NAME && m.op == MatchOp::Equal)
+            .filter(|m| counts.get(m.name.as_str()) == Some(&1))
+            .map(|m| (m.name.clone(), m.value.clone()))
+            .collect();
+        Some(labels)
+    }
+}
+
 /// Node for extending the AST. [Extension] won't be generate by this parser itself.
 #[derive(Debug, Clone)]
 pub struct Extension {
@@ -480,17 +952,51 @@ pub trait ExtensionExpr: std::fmt::Debug + Send + Sync {
     fn value_type(&self) -> ValueType;
 
     fn children(&self) -> &[Expr];
+
+    /// whether the other extension is the same expr. The default compares the
+    /// names and the [`Debug`](std::fmt::Debug) output, which is slow and
+    /// wrong if the output is not deterministic, so implementors should compare
+    /// their fields, like after `other.as_any().downcast_ref::<Self>()`.
+    fn eq_dyn(&self, other: &dyn ExtensionExpr) -> bool {
+        self.name() == other.name() && format!("{self:?}") == format!("{other:?}")
+    }
+
+    /// check the extension itself, its children are checked before. Surrounding
+    /// nodes are checked against its [`value_type`](ExtensionExpr::value_type).
+    fn validate(&self) -> Result<(), Diagnostic> {
+        Ok(())
+    }
+
+    /// feed the extension into the hasher, equal extensions by
+    /// [`eq_dyn`](ExtensionExpr::eq_dyn) must hash the same. The default only
+    /// hashes the name.
+    fn hash_dyn(&self, mut state: &mut dyn Hasher) {
+        self.name().hash(&mut state);
+    }
+
+    /// the data of the extension besides its name and children, written as the
+    /// payload of its protobuf message. The default is empty.
+    fn payload(&self) -> Vec<u8> {
+        vec![]
+    }
 }
 
 impl PartialEq for Extension {
     fn eq(&self, other: &Self) -> bool {
-        format!("{:?}", self) == format!("{:?}", other)
+        Arc::ptr_eq(&self.expr, &other.expr) || self.expr.eq_dyn(other.expr.as_ref())
     }
 }
 
 impl Eq for Extension {}
 
+impl Hash for Extension {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.expr.hash_dyn(state);
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "ser", derive(serde::Serialize, serde::Deserialize))]
 pub enum Expr {
     /// Aggregate represents an aggregation operation on a Vector.
     Aggregate(AggregateExpr),
@@ -529,6 +1035,65 @@ pub enum Expr {
     Extension(Extension),
 }
 
+/// `as_*` borrows and `into_*` takes the node of the variant, None for the other variants.
+macro_rules! accessors {
+    ($(($variant:ident, $ty:ty, $as:ident, $into:ident)),*) => {
+        impl Expr {
+            $(
+                #[doc = concat!("the node of an `Expr::", stringify!($variant), "`.")]
+                pub fn $as(&self) -> Option<&$ty> {
+                    match self {
+                        Expr::$variant(ex) => Some(ex),
+                        _ => None,
+                    }
+                }
+
+                #[doc = concat!("take the node of an `Expr::", stringify!($variant), "`.")]
+                pub fn $into(self) -> Option<$ty> {
+                    match self {
+                        Expr::$variant(ex) => Some(ex),
+                        _ => None,
+                    }
+                }
+            )*
+        }
+    };
+}
+
+accessors!(
+    (Aggregate, AggregateExpr, as_aggregate, into_aggregate),
+    (Unary, UnaryExpr, as_unary, into_unary),
+    (Binary, BinaryExpr, as_binary, into_binary),
+    (Paren, ParenExpr, as_paren, into_paren),
+    (Subquery, SubqueryExpr, as_subquery, into_subquery),
+    (
+        NumberLiteral,
+        NumberLiteral,
+        as_number_literal,
+        into_number_literal
+    ),
+    (
+        StringLiteral,
+        StringLiteral,
+        as_string_literal,
+        into_string_literal
+    ),
+    (
+        VectorSelector,
+        VectorSelector,
+        as_vector_selector,
+        into_vector_selector
+    ),
+    (
+        MatrixSelector,
+        MatrixSelector,
+        as_matrix_selector,
+        into_matrix_selector
+    ),
+    (Call, Call, as_call, into_call),
+    (Extension, Extension, as_extension, into_extension)
+);
+
 impl Expr {
     pub fn new_vector_selector(name: Option<String>, matchers: Matchers) -> Result<Self, String> {
         let vs = VectorSelector {
@@ -558,7 +1123,7 @@ impl Expr {
             offset: None,
             at: None,
             range,
-            step,
+            step: step.map_or(SubqueryStep::Default, SubqueryStep::Explicit),
         });
         Ok(se)
     }
@@ -591,29 +1156,10 @@ impl Expr {
     }
 
     pub fn at_expr(self, at: AtModifier) -> Result<Self, String> {
-        let already_set_err = Err("@ <timestamp> may not be set multiple times".into());
         match self {
-            Expr::VectorSelector(mut vs) => match vs.at {
-                None => {
-                    vs.at = Some(at);
-                    Ok(Expr::VectorSelector(vs))
-                }
-                Some(_) => already_set_err,
-            },
-            Expr::MatrixSelector(mut ms) => match ms.vector_selector.at {
-                None => {
-                    ms.vector_selector.at = Some(at);
-                    Ok(Expr::MatrixSelector(ms))
-                }
-                Some(_) => already_set_err,
-            },
-            Expr::Subquery(mut s) => match s.at {
-                None => {
-                    s.at = Some(at);
-                    Ok(Expr::Subquery(s))
-                }
-                Some(_) => already_set_err,
-            },
+            Expr::VectorSelector(vs) => vs.at_expr(at).map(Expr::VectorSelector),
+            Expr::MatrixSelector(ms) => ms.at_expr(at).map(Expr::MatrixSelector),
+            Expr::Subquery(s) => s.at_expr(at).map(Expr::Subquery),
             _ => {
                 Err("@ modifier must be preceded by an vector selector or matrix selector or a subquery".into())
             }
@@ -622,29 +1168,10 @@ impl Expr {
 
     /// set offset field for specified Expr, but CAN ONLY be set once.
     pub fn offset_expr(self, offset: Offset) -> Result<Self, String> {
-        let already_set_err = Err("offset may not be set multiple times".into());
         match self {
-            Expr::VectorSelector(mut vs) => match vs.offset {
-                None => {
-                    vs.offset = Some(offset);
-                    Ok(Expr::VectorSelector(vs))
-                }
-                Some(_) => already_set_err,
-            },
-            Expr::MatrixSelector(mut ms) => match ms.vector_selector.offset {
-                None => {
-                    ms.vector_selector.offset = Some(offset);
-                    Ok(Expr::MatrixSelector(ms))
-                }
-                Some(_) => already_set_err,
-            },
-            Expr::Subquery(mut s) => match s.offset {
-                None => {
-                    s.offset = Some(offset);
-                    Ok(Expr::Subquery(s))
-                }
-                Some(_) => already_set_err,
-            },
+            Expr::VectorSelector(vs) => vs.offset_expr(offset).map(Expr::VectorSelector),
+            Expr::MatrixSelector(ms) => ms.offset_expr(offset).map(Expr::MatrixSelector),
+            Expr::Subquery(s) => s.offset_expr(offset).map(Expr::Subquery),
             _ => {
                 Err("offset modifier must be preceded by an vector selector or matrix selector or a subquery".into())
             }
@@ -734,6 +1261,46 @@ impl Expr {
         }
     }
 
+    /// the direct children of the expr, the aggregation param comes before the
+    /// aggregated expr, like in `topk(5, foo)`.
+    pub fn children(&self) -> Vec<&Expr> {
+        match self {
+            Expr::Aggregate(ex) => ex.param.iter().chain([&ex.expr]).map(|e| &**e).collect(),
+            Expr::Unary(ex) => vec![&ex.expr],
+            Expr::Binary(ex) => vec![&ex.lhs, &ex.rhs],
+            Expr::Paren(ex) => vec![&ex.expr],
+            Expr::Subquery(ex) => vec![&ex.expr],
+            Expr::Call(ex) => ex.args.args.iter().map(|e| &**e).collect(),
+            Expr::Extension(ex) => ex.expr.children().iter().collect(),
+            Expr::NumberLiteral(_)
+            | Expr::StringLiteral(_)
+            | Expr::VectorSelector(_)
+            | Expr::MatrixSelector(_) => vec![],
+        }
+    }
+
+    /// same as [`Expr::children`], except the children of extensions, which are immutable.
+    pub(crate) fn children_mut(&mut self) -> Vec<&mut Expr> {
+        match self {
+            Expr::Aggregate(ex) => ex
+                .param
+                .iter_mut()
+                .chain([&mut ex.expr])
+                .map(|e| &mut **e)
+                .collect(),
+            Expr::Unary(ex) => vec![&mut ex.expr],
+            Expr::Binary(ex) => vec![&mut ex.lhs, &mut ex.rhs],
+            Expr::Paren(ex) => vec![&mut ex.expr],
+            Expr::Subquery(ex) => vec![&mut ex.expr],
+            Expr::Call(ex) => ex.args.args.iter_mut().map(|e| &mut **e).collect(),
+            Expr::Extension(_)
+            | Expr::NumberLiteral(_)
+            | Expr::StringLiteral(_)
+            | Expr::VectorSelector(_)
+            | Expr::MatrixSelector(_) => vec![],
+        }
+    }
+
     /// only Some if expr is [Expr::NumberLiteral]
     pub fn scalar_value(&self) -> Option<f64> {
         match self {
@@ -741,6 +1308,36 @@ impl Expr {
             _ => None,
         }
     }
+
+    /// whether the expr can be evaluated by a range query, see [`Expr::check_range_query`].
+    pub fn is_valid_for_range_query(&self) -> bool {
+        self.check_range_query().is_ok()
+    }
+
+    /// a range query must return a scalar or an instant vector, the error is
+    /// the one of the Prometheus API.
+    ///
+    /// # Examples
+    ///
+    /// ``` rust
+    /// use promql_parser::parser::parse;
+    ///
+    /// assert!(parse("rate(foo[5m])").unwrap().is_valid_for_range_query());
+    /// assert_eq!(
+    ///     parse("foo[5m]").unwrap().check_range_query(),
+    ///     Err(r#"invalid expression type "range vector" for range query, must be Scalar or instant Vector"#.into())
+    /// );
+    /// ```
+    pub fn check_range_query(&self) -> Result<(), String> {
+        let documented = match self.value_type() {
+            ValueType::Scalar | ValueType::Vector => return Ok(()),
+            ValueType::Matrix => "range vector",
+            ValueType::String => "string",
+        };
+        Err(format!(
+            "invalid expression type \"{documented}\" for range query, must be Scalar or instant Vector"
+        ))
+    }
 }
 
 impl From<String> for Expr {
@@ -796,454 +1393,2016 @@ impl Neg for Expr {
     }
 }
 
-/// check_ast checks the validity of the provided AST. This includes type checking.
-/// Recursively check correct typing for child nodes and raise errors in case of bad typing.
-pub fn check_ast(expr: Expr) -> Result<Expr, String> {
-    match expr {
-        Expr::Binary(ex) => check_ast_for_binary_expr(ex),
-        Expr::Aggregate(ex) => check_ast_for_aggregate_expr(ex),
-        Expr::Call(ex) => check_ast_for_call(ex),
-        Expr::Unary(ex) => check_ast_for_unary(ex),
-        Expr::Subquery(ex) => check_ast_for_subquery(ex),
-        Expr::VectorSelector(ex) => check_ast_for_vector_selector(ex),
-        Expr::Paren(_) => Ok(expr),
-        Expr::NumberLiteral(_) => Ok(expr),
-        Expr::StringLiteral(_) => Ok(expr),
-        Expr::MatrixSelector(_) => Ok(expr),
-        Expr::Extension(_) => Ok(expr),
+/// build binary expressions with operators, like `lhs / rhs`. The result is not
+/// checked, use [`check_ast`] to validate it.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::{self, Expr, VectorSelector};
+///
+/// let lhs = Expr::from(VectorSelector::from("foo"));
+/// let rhs = Expr::from(VectorSelector::from("bar"));
+/// assert_eq!(lhs / rhs + Expr::from(1.0), parser::parse("foo / bar + 1").unwrap());
+/// ```
+impl Add for Expr {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        self.binary(T_ADD, rhs, false)
     }
 }
 
-fn expect_type(
-    expected: ValueType,
-    actual: Option<ValueType>,
-    context: &str,
-) -> Result<bool, String> {
-    match actual {
-        Some(actual) => {
-            if actual == expected {
-                Ok(true)
-            } else {
-                Err(format!(
-                    "expected type {expected} in {context}, got {actual}"
-                ))
-            }
-        }
-        None => Err(format!("expected type {expected} in {context}, got None")),
+impl Sub for Expr {
+    type Output = Self;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        self.binary(T_SUB, rhs, false)
     }
 }
 
-/// the original logic is redundant in prometheus, and the following coding blocks
-/// have been optimized for readability, but all logic SHOULD be covered.
-fn check_ast_for_binary_expr(mut ex: BinaryExpr) -> Result<Expr, String> {
-    let op_display = token_display(ex.op.id());
+impl Mul for Expr {
+    type Output = Self;
 
-    if !ex.op.is_operator() {
-        return Err(format!(
-            "binary expression does not support operator '{op_display}'"
-        ));
+    fn mul(self, rhs: Self) -> Self::Output {
+        self.binary(T_MUL, rhs, false)
     }
+}
 
-    if ex.return_bool() && !ex.op.is_comparison_operator() {
-        return Err("bool modifier can only be used on comparison operators".into());
+impl Div for Expr {
+    type Output = Self;
+
+    fn div(self, rhs: Self) -> Self::Output {
+        self.binary(T_DIV, rhs, false)
     }
+}
 
-    if ex.op.is_comparison_operator()
-        && ex.lhs.value_type() == ValueType::Scalar
-        && ex.rhs.value_type() == ValueType::Scalar
-        && !ex.return_bool()
-    {
-        return Err("comparisons between scalars must use BOOL modifier".into());
+/// comparisons, `return_bool` adds the `bool` modifier, like `lhs == bool rhs`.
+impl Expr {
+    pub fn eqlc(self, rhs: Expr, return_bool: bool) -> Expr {
+        self.binary(T_EQLC, rhs, return_bool)
     }
 
-    // For `on` matching, a label can only appear in one of the lists.
-    // Every time series of the result vector must be uniquely identifiable.
-    if ex.is_matching_on() && ex.is_labels_joint() {
-        if let Some(labels) = ex.intersect_labels() {
-            if let Some(label) = labels.first() {
-                return Err(format!(
-                    "label '{label}' must not occur in ON and GROUP clause at once"
-                ));
-            }
-        };
+    pub fn neq(self, rhs: Expr, return_bool: bool) -> Expr {
+        self.binary(T_NEQ, rhs, return_bool)
     }
 
-    if ex.op.is_set_operator() {
-        if ex.lhs.value_type() == ValueType::Scalar || ex.rhs.value_type() == ValueType::Scalar {
-            return Err(format!(
-                "set operator '{op_display}' not allowed in binary scalar expression"
-            ));
-        }
+    pub fn gtr(self, rhs: Expr, return_bool: bool) -> Expr {
+        self.binary(T_GTR, rhs, return_bool)
+    }
 
-        if ex.lhs.value_type() == ValueType::Vector && ex.rhs.value_type() == ValueType::Vector {
-            if let Some(ref modifier) = ex.modifier {
-                if matches!(modifier.card, VectorMatchCardinality::OneToMany(_))
-                    || matches!(modifier.card, VectorMatchCardinality::ManyToOne(_))
-                {
-                    return Err(format!("no grouping allowed for '{op_display}' operation"));
-                }
-            };
-        }
+    pub fn gte(self, rhs: Expr, return_bool: bool) -> Expr {
+        self.binary(T_GTE, rhs, return_bool)
+    }
 
-        match &mut ex.modifier {
-            Some(modifier) => {
-                if modifier.card == VectorMatchCardinality::OneToOne {
-                    modifier.card = VectorMatchCardinality::ManyToMany;
-                }
-            }
-            None => {
-                ex.modifier =
-                    Some(BinModifier::default().with_card(VectorMatchCardinality::ManyToMany));
-            }
-        }
+    pub fn lss(self, rhs: Expr, return_bool: bool) -> Expr {
+        self.binary(T_LSS, rhs, return_bool)
     }
 
-    if ex.lhs.value_type() != ValueType::Scalar && ex.lhs.value_type() != ValueType::Vector {
-        return Err("binary expression must contain only scalar and instant vector types".into());
+    pub fn lte(self, rhs: Expr, return_bool: bool) -> Expr {
+        self.binary(T_LTE, rhs, return_bool)
     }
-    if ex.rhs.value_type() != ValueType::Scalar && ex.rhs.value_type() != ValueType::Vector {
-        return Err("binary expression must contain only scalar and instant vector types".into());
+
+    fn binary(self, op: TokenId, rhs: Expr, return_bool: bool) -> Expr {
+        let modifier = return_bool.then(|| BinModifier::default().with_return_bool(true));
+        Expr::Binary(BinaryExpr {
+            op: TokenType::new(op),
+            lhs: Box::new(self),
+            rhs: Box::new(rhs),
+            modifier,
+        })
     }
+}
 
-    if (ex.lhs.value_type() != ValueType::Vector || ex.rhs.value_type() != ValueType::Vector)
-        && ex.is_matching_labels_not_empty()
+/// aggregations over this expr, the expr and the param are type checked up front.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::{parse, Expr, VectorSelector};
+///
+/// let foo = Expr::from(VectorSelector::from("foo"));
+/// let sum = foo.clone().sum().unwrap().by(["job"]);
+/// assert_eq!(Expr::from(sum), parse("sum by (job) (foo)").unwrap());
+///
+/// let topk = foo.topk(5.0).unwrap();
+/// assert_eq!(Expr::from(topk), parse("topk(5, foo)").unwrap());
+/// ```
+impl Expr {
+    pub fn sum(self) -> Result<AggregateExpr, String> {
+        self.aggregate(token::T_SUM, None)
+    }
+
+    pub fn avg(self) -> Result<AggregateExpr, String> {
+        self.aggregate(token::T_AVG, None)
+    }
+
+    pub fn count(self) -> Result<AggregateExpr, String> {
+        self.aggregate(token::T_COUNT, None)
+    }
+
+    pub fn min(self) -> Result<AggregateExpr, String> {
+        self.aggregate(token::T_MIN, None)
+    }
+
+    pub fn max(self) -> Result<AggregateExpr, String> {
+        self.aggregate(token::T_MAX, None)
+    }
+
+    pub fn group(self) -> Result<AggregateExpr, String> {
+        self.aggregate(token::T_GROUP, None)
+    }
+
+    pub fn stddev(self) -> Result<AggregateExpr, String> {
+        self.aggregate(token::T_STDDEV, None)
+    }
+
+    pub fn stdvar(self) -> Result<AggregateExpr, String> {
+        self.aggregate(token::T_STDVAR, None)
+    }
+
+    pub fn topk(self, k: impl Into<Expr>) -> Result<AggregateExpr, String> {
+        self.aggregate(T_TOPK, Some(k.into()))
+    }
+
+    pub fn bottomk(self, k: impl Into<Expr>) -> Result<AggregateExpr, String> {
+        self.aggregate(T_BOTTOMK, Some(k.into()))
+    }
+
+    pub fn quantile(self, q: impl Into<Expr>) -> Result<AggregateExpr, String> {
+        self.aggregate(T_QUANTILE, Some(q.into()))
+    }
+
+    pub fn count_values(self, label: impl Into<Expr>) -> Result<AggregateExpr, String> {
+        self.aggregate(T_COUNT_VALUES, Some(label.into()))
+    }
+
+    fn aggregate(self, op: TokenId, param: Option<Expr>) -> Result<AggregateExpr, String> {
+        let ex = AggregateExpr {
+            op: TokenType::new(op),
+            expr: Box::new(self),
+            param: param.map(Box::new),
+            modifier: None,
+        };
+        check_ast_for_aggregate_expr(&ex)?;
+        Ok(ex)
+    }
+}
+
+/// the typed parameters of the aggregations, None for other aggregations, or if
+/// the parameter is not a literal, like `topk(scalar(foo), bar)`.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::{parse, Expr};
+///
+/// let Expr::Aggregate(agg) = parse("topk(3, foo)").unwrap() else { unreachable!() };
+/// assert_eq!(agg.k(), Some(3));
+/// assert_eq!(agg.quantile(), None);
+///
+/// let Expr::Aggregate(agg) = parse(r#"count_values("version", build_info)"#).unwrap() else {
+///     unreachable!()
+/// };
+/// assert_eq!(agg.value_label(), Some("version"));
+/// ```
+impl AggregateExpr {
+    /// the number of series of `topk` and `bottomk`, truncated like Prometheus,
+    /// None if it does not fit in an i64.
+    pub fn k(&self) -> Option<i64> {
+        if !matches!(self.op.id(), T_TOPK | T_BOTTOMK) {
+            return None;
+        }
+        let k = self.param.as_ref()?.scalar_value()?.trunc();
+        // i64::MAX as f64 rounds up to 2^63, which is out of range
+        (k >= i64::MIN as f64 && k < i64::MAX as f64).then_some(k as i64)
+    }
+
+    /// the quantile of `quantile`, like 0.9 in `quantile(0.9, foo)`.
+    pub fn quantile(&self) -> Option<f64> {
+        match self.op.id() {
+            T_QUANTILE => self.param.as_ref()?.scalar_value(),
+            _ => None,
+        }
+    }
+
+    /// the label of `count_values` which is set to the counted values.
+    pub fn value_label(&self) -> Option<&str> {
+        match (self.op.id(), self.param.as_deref()) {
+            (T_COUNT_VALUES, Some(Expr::StringLiteral(label))) => Some(&label.val),
+            _ => None,
+        }
+    }
+
+    /// group the result by the given labels, like `sum by (job) (...)`
+    pub fn by<I, S>(mut self, labels: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
     {
-        return Err("vector matching only allowed between vectors".into());
+        let labels = labels.into_iter().map(Into::into).collect();
+        self.modifier = Some(LabelModifier::Include(labels));
+        self
     }
 
-    Ok(Expr::Binary(ex))
+    /// drop the given labels from the result, like `sum without (job) (...)`
+    pub fn without<I, S>(mut self, labels: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let labels = labels.into_iter().map(Into::into).collect();
+        self.modifier = Some(LabelModifier::Exclude(labels));
+        self
+    }
 }
 
-fn check_ast_for_aggregate_expr(ex: AggregateExpr) -> Result<Expr, String> {
-    if !ex.op.is_aggregator() {
-        let op_display = token_display(ex.op.id());
-        return Err(format!(
-            "aggregation operator expected in aggregation expression but got '{op_display}'"
-        ));
+impl From<AggregateExpr> for Expr {
+    fn from(ex: AggregateExpr) -> Self {
+        Expr::Aggregate(ex)
     }
+}
 
-    expect_type(
-        ValueType::Vector,
-        Some(ex.expr.value_type()),
-        "aggregation expression",
-    )?;
+/// subquery over this expr, which has to be an instant vector.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::{parse, Expr, VectorSelector};
+/// use std::time::Duration;
+///
+/// let foo = Expr::from(VectorSelector::from("foo"));
+/// let sq = foo.subquery(Duration::from_secs(3600)).unwrap();
+/// assert_eq!(sq.resolve_step(Duration::from_secs(60)), Duration::from_secs(60));
+///
+/// let sq = sq.with_step(Duration::from_secs(300));
+/// assert_eq!(Expr::from(sq), parse("foo[1h:5m]").unwrap());
+/// ```
+impl Expr {
+    pub fn subquery(self, range: Duration) -> Result<SubqueryExpr, String> {
+        let ex = SubqueryExpr {
+            expr: Box::new(self),
+            offset: None,
+            at: None,
+            range,
+            step: SubqueryStep::Default,
+        };
+        check_ast_for_subquery(&ex)?;
+        Ok(ex)
+    }
+}
 
-    if matches!(ex.op.id(), T_TOPK | T_BOTTOMK | T_QUANTILE) {
-        expect_type(
-            ValueType::Scalar,
-            ex.param.as_ref().map(|ex| ex.value_type()),
-            "aggregation expression",
-        )?;
+impl From<SubqueryExpr> for Expr {
+    fn from(ex: SubqueryExpr) -> Self {
+        Expr::Subquery(ex)
     }
+}
 
-    if ex.op.id() == T_COUNT_VALUES {
-        expect_type(
-            ValueType::String,
-            ex.param.as_ref().map(|ex| ex.value_type()),
-            "aggregation expression",
-        )?;
+impl fmt::Display for Offset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Offset::Pos(d) => write!(f, "offset {}", display_duration(d)),
+            Offset::Neg(d) => write!(f, "offset -{}", display_duration(d)),
+        }
     }
+}
 
-    Ok(Expr::Aggregate(ex))
+impl fmt::Display for AtModifier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AtModifier::Start => write!(f, "@ start()"),
+            AtModifier::End => write!(f, "@ end()"),
+            AtModifier::At(time) => match time.duration_since(SystemTime::UNIX_EPOCH) {
+                Ok(d) => write!(f, "@ {:.3}", d.as_secs_f64()),
+                Err(e) => write!(f, "@ -{:.3}", e.duration().as_secs_f64()),
+            },
+        }
+    }
 }
 
-fn check_ast_for_call(ex: Call) -> Result<Expr, String> {
-    let expected_args_len = ex.func.arg_types.len();
-    let name = ex.func.name;
-    let actual_args_len = ex.args.len();
+/// writes the ` @ <timestamp>` and ` offset <duration>` suffix of selectors and subqueries.
+fn write_modifiers(
+    f: &mut fmt::Formatter,
+    at: &Option<AtModifier>,
+    offset: &Option<Offset>,
+) -> fmt::Result {
+    if let Some(at) = at {
+        write!(f, " {at}")?;
+    }
+    if let Some(offset) = offset {
+        write!(f, " {offset}")?;
+    }
+    Ok(())
+}
 
-    if ex.func.variadic {
-        let expected_args_len_without_default = expected_args_len - 1;
-        if expected_args_len_without_default > actual_args_len {
-            return Err(format!(
-                "expected at least {expected_args_len_without_default} argument(s) in call to '{name}', got {actual_args_len}"
-            ));
+/// labels are sorted, so the output is stable. Labels which are not legacy
+/// names, like `service.name`, are quoted and escaped.
+fn join_labels(labels: &Labels) -> String {
+    let mut labels: Vec<&str> = labels.iter().map(String::as_str).collect();
+    labels.sort_unstable();
+    labels
+        .into_iter()
+        .map(|l| {
+            if NameValidationScheme::Legacy.is_valid_label_name(l) {
+                l.to_string()
+            } else {
+                quote(l)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl fmt::Display for LabelModifier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LabelModifier::Include(labels) => write!(f, "by ({})", join_labels(labels)),
+            LabelModifier::Exclude(labels) => write!(f, "without ({})", join_labels(labels)),
         }
+    }
+}
 
-        // `label_join` do not have a maximum arguments threshold.
-        // this hard code SHOULD be careful if new functions are supported by Prometheus.
-        if actual_args_len > expected_args_len && name.ne("label_join") {
-            return Err(format!(
-                "expected at most {expected_args_len} argument(s) in call to '{name}', got {actual_args_len}"
-            ));
+impl fmt::Display for AggregateExpr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.op)?;
+        if let Some(modifier) = &self.modifier {
+            write!(f, " {modifier} ")?;
+        }
+        write!(f, "(")?;
+        if let Some(param) = &self.param {
+            write!(f, "{param}, ")?;
         }
+        write!(f, "{})", self.expr)
     }
+}
 
-    if !ex.func.variadic && expected_args_len != actual_args_len {
-        return Err(format!(
-            "expected {expected_args_len} argument(s) in call to '{name}', got {actual_args_len}"
-        ));
+impl fmt::Display for UnaryExpr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match unary_needs_parens(&self.expr) {
+            true => write!(f, "-({})", self.expr),
+            false => write!(f, "-{}", self.expr),
+        }
     }
+}
 
-    // special cases from https://prometheus.io/docs/prometheus/latest/querying/functions
-    if name.eq_ignore_ascii_case("exp") {
-        if let Some(val) = ex.args.first().and_then(|ex| ex.scalar_value()) {
-            if val.is_nan() || val.is_infinite() {
-                return Ok(Expr::Call(ex));
+/// whether the operand of a binary operator has to be in parens to keep the
+/// grouping of the tree, like `a - b` as the lhs of `*`. Trees built by hand
+/// may lack the [`ParenExpr`] the parser would need.
+pub(crate) fn needs_parens(op: &TokenType, operand: &Expr, is_rhs: bool) -> bool {
+    match operand {
+        Expr::Binary(inner) => {
+            let (outer, inner) = (op.precedence(), inner.op.precedence());
+            inner < outer || (inner == outer && is_rhs != op.is_right_associative())
+        }
+        // the unary minus binds like `*`, so `-a ^ b` is `-(a ^ b)`
+        Expr::Unary(_) => !is_rhs && op.precedence() > UNARY_PRECEDENCE,
+        Expr::NumberLiteral(nl) => {
+            !is_rhs && nl.val.is_sign_negative() && op.precedence() > UNARY_PRECEDENCE
+        }
+        _ => false,
+    }
+}
+
+/// whether the operand of the unary minus has to be in parens, like `a + b` in `-(a + b)`.
+pub(crate) fn unary_needs_parens(operand: &Expr) -> bool {
+    matches!(operand, Expr::Binary(inner) if inner.op.precedence() <= UNARY_PRECEDENCE)
+}
+
+/// whether the operand of a subquery has to be in parens, like `a + b` in `(a + b)[5m:]`,
+/// since the range binds tighter than any operator.
+pub(crate) fn subquery_needs_parens(operand: &Expr) -> bool {
+    matches!(operand, Expr::Binary(_) | Expr::Unary(_))
+}
+
+/// the unary minus binds like `*`, `/` and `%`.
+const UNARY_PRECEDENCE: u8 = 5;
+
+/// `group_left (a)` or `group_right (a)`, and empty for the other cardinalities,
+/// which have no modifier in the query.
+impl fmt::Display for VectorMatchCardinality {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VectorMatchCardinality::ManyToOne(labels) => {
+                write!(f, "group_left ({})", join_labels(labels))
+            }
+            VectorMatchCardinality::OneToMany(labels) => {
+                write!(f, "group_right ({})", join_labels(labels))
+            }
+            VectorMatchCardinality::OneToOne | VectorMatchCardinality::ManyToMany => Ok(()),
+        }
+    }
+}
+
+/// the modifiers as written after the operator, like `bool on (a, b) group_left (c)`.
+/// The cardinality is only written with `on` or `ignoring`, as in the query.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::{parse, BinModifier, Expr};
+///
+/// let Expr::Binary(ex) = parse("foo * on (b, a) group_left (c) bar").unwrap() else {
+///     unreachable!()
+/// };
+/// assert_eq!(ex.modifier.unwrap().to_string(), "on (a, b) group_left (c)");
+/// assert_eq!(BinModifier::default().to_string(), "");
+/// ```
+impl fmt::Display for BinModifier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = vec![];
+        if self.return_bool {
+            parts.push("bool".to_string());
+        }
+        if let Some(matching) = &self.matching {
+            parts.push(match matching {
+                LabelModifier::Include(labels) => format!("on ({})", join_labels(labels)),
+                LabelModifier::Exclude(labels) => format!("ignoring ({})", join_labels(labels)),
+            });
+            let card = self.card.to_string();
+            if !card.is_empty() {
+                parts.push(card);
+            }
+        }
+        write!(f, "{}", parts.join(" "))
+    }
+}
+
+impl fmt::Display for BinaryExpr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match needs_parens(&self.op, &self.lhs, false) {
+            true => write!(f, "({}) {}", self.lhs, self.op)?,
+            false => write!(f, "{} {}", self.lhs, self.op)?,
+        }
+        if let Some(modifier) = &self.modifier {
+            let modifier = modifier.to_string();
+            if !modifier.is_empty() {
+                write!(f, " {modifier}")?;
+            }
+        }
+        match needs_parens(&self.op, &self.rhs, true) {
+            true => write!(f, " ({})", self.rhs),
+            false => write!(f, " {}", self.rhs),
+        }
+    }
+}
+
+impl fmt::Display for ParenExpr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "({})", self.expr)
+    }
+}
+
+impl fmt::Display for SubqueryExpr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match subquery_needs_parens(&self.expr) {
+            true => write!(f, "({})", self.expr)?,
+            false => write!(f, "{}", self.expr)?,
+        }
+        write!(f, "[{}:{}]", display_duration(&self.range), self.step)?;
+        write_modifiers(f, &self.at, &self.offset)
+    }
+}
+
+impl fmt::Display for NumberLiteral {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.val {
+            v if v.is_nan() => write!(f, "NaN"),
+            v if v.is_infinite() && v > 0.0 => write!(f, "+Inf"),
+            v if v.is_infinite() => write!(f, "-Inf"),
+            v => write!(f, "{v}"),
+        }
+    }
+}
+
+/// the value is escaped and written in double quotes, so it parses back to the same string.
+impl fmt::Display for StringLiteral {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", quote(&self.val))
+    }
+}
+
+impl fmt::Display for VectorSelector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let selector = selector_to_string(self.name.as_deref(), &self.matchers);
+        write!(f, "{selector}")?;
+        write_modifiers(f, &self.at, &self.offset)
+    }
+}
+
+impl fmt::Display for MatrixSelector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let vs = &self.vector_selector;
+        let selector = selector_to_string(vs.name.as_deref(), &vs.matchers);
+        write!(f, "{selector}[{}]", display_duration(&self.range))?;
+        write_modifiers(f, &vs.at, &vs.offset)
+    }
+}
+
+impl fmt::Display for Call {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let args: Vec<String> = self.args.args.iter().map(|e| e.to_string()).collect();
+        write!(f, "{}({})", self.func.name, args.join(", "))
+    }
+}
+
+/// extensions are written like a function call over their children.
+impl fmt::Display for Extension {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let args: Vec<String> = self.expr.children().iter().map(|e| e.to_string()).collect();
+        write!(f, "{}({})", self.expr.name(), args.join(", "))
+    }
+}
+
+/// writes the expr back to PromQL, in the same way as Prometheus does.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::parse;
+///
+/// let expr = parse(r#"sum by (job) (rate(foo{code=~"5.."}[5m] offset 1h))"#).unwrap();
+/// assert_eq!(
+///     expr.to_string(),
+///     r#"sum by (job) (rate(foo{code=~"5.."}[5m] offset 1h))"#
+/// );
+/// ```
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expr::Aggregate(ex) => write!(f, "{ex}"),
+            Expr::Unary(ex) => write!(f, "{ex}"),
+            Expr::Binary(ex) => write!(f, "{ex}"),
+            Expr::Paren(ex) => write!(f, "{ex}"),
+            Expr::Subquery(ex) => write!(f, "{ex}"),
+            Expr::NumberLiteral(ex) => write!(f, "{ex}"),
+            Expr::StringLiteral(ex) => write!(f, "{ex}"),
+            Expr::VectorSelector(ex) => write!(f, "{ex}"),
+            Expr::MatrixSelector(ex) => write!(f, "{ex}"),
+            Expr::Call(ex) => write!(f, "{ex}"),
+            Expr::Extension(ex) => write!(f, "{ex}"),
+        }
+    }
+}
+
+/// check_ast checks the validity of the provided AST. This includes type checking.
+/// Only the root is checked, as the parser checks each node when it is built, use
+/// [`check`] for the whole tree. The children of an extension are never built by
+/// the parser, so they are all checked, and then the extension by
+/// [`ExtensionExpr::validate`]. The set operators get many-to-many matching.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        name = "promql.check_ast",
+        level = "trace",
+        skip_all,
+        err(level = "trace")
+    )
+)]
+pub fn check_ast(expr: Expr) -> Result<Expr, String> {
+    if let Expr::Extension(ex) = &expr {
+        for child in ex.expr.children() {
+            check(child).map_err(|d| d.message)?;
+        }
+        ex.expr.validate().map_err(|d| d.message)?;
+    }
+    check_node(&expr)?;
+    Ok(normalize_binary_expr(expr))
+}
+
+/// check the expr and all its descendants like [`check_ast`], without consuming
+/// them, so the expr can be kept on error. The innermost error is reported, as
+/// by the parser.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::ast::check;
+/// use promql_parser::parser::{parse, Expr};
+///
+/// let expr = parse("sum(rate(foo[5m]))").unwrap();
+/// assert!(check(&expr).is_ok());
+///
+/// let Expr::Aggregate(mut agg) = expr else { unreachable!() };
+/// agg.expr = Box::new(parse("foo[5m]").unwrap());
+/// let expr = Expr::Aggregate(agg);
+/// let err = check(&expr).unwrap_err();
+/// assert_eq!(err.message, "expected type vector in aggregation expression, got matrix");
+/// assert_eq!(expr.to_string(), "sum(foo[5m])");
+/// ```
+pub fn check(expr: &Expr) -> Result<(), Diagnostic> {
+    for child in expr.children() {
+        check(child)?;
+    }
+    if let Expr::Extension(ex) = expr {
+        ex.expr.validate()?;
+    }
+    check_node(expr).map_err(|err| Diagnostic::error(codes::CHECK, err))
+}
+
+/// check_ast on all the nodes, from the leaves up as the parser does.
+pub(crate) fn check_tree(mut expr: Expr) -> Result<Expr, String> {
+    for child in expr.children_mut() {
+        let node = std::mem::replace(child, Expr::from(0.0));
+        *child = check_tree(node)?;
+    }
+    check_ast(expr)
+}
+
+fn check_node(expr: &Expr) -> Result<(), String> {
+    match expr {
+        Expr::Binary(ex) => check_ast_for_binary_expr(ex),
+        Expr::Aggregate(ex) => check_ast_for_aggregate_expr(ex),
+        Expr::Call(ex) => check_ast_for_call(ex),
+        Expr::Unary(ex) => check_ast_for_unary(ex),
+        Expr::Subquery(ex) => check_ast_for_subquery(ex),
+        Expr::VectorSelector(ex) => check_ast_for_vector_selector(ex),
+        Expr::Paren(_)
+        | Expr::NumberLiteral(_)
+        | Expr::StringLiteral(_)
+        | Expr::MatrixSelector(_)
+        | Expr::Extension(_) => Ok(()),
+    }
+}
+
+/// the set operators always match many-to-many, which is the cardinality
+/// of a checked set operation. Vector matching only applies between two
+/// vectors, so like prometheus it is dropped otherwise and only `bool` is kept.
+fn normalize_binary_expr(expr: Expr) -> Expr {
+    let Expr::Binary(mut ex) = expr else {
+        return expr;
+    };
+    if ex.op.is_set_operator() {
+        match &mut ex.modifier {
+            Some(modifier) => {
+                if modifier.card == VectorMatchCardinality::OneToOne {
+                    modifier.card = VectorMatchCardinality::ManyToMany;
+                }
+            }
+            None => {
+                ex.modifier =
+                    Some(BinModifier::default().with_card(VectorMatchCardinality::ManyToMany));
+            }
+        }
+    } else if ex.lhs.value_type() != ValueType::Vector || ex.rhs.value_type() != ValueType::Vector {
+        let return_bool = ex.return_bool();
+        ex.modifier = return_bool.then(|| BinModifier::default().with_return_bool(true));
+    }
+    Expr::Binary(ex)
+}
+
+fn expect_type(
+    expected: ValueType,
+    actual: Option<ValueType>,
+    context: &str,
+) -> Result<bool, String> {
+    match actual {
+        Some(actual) => {
+            if actual == expected {
+                Ok(true)
+            } else {
+                Err(format!(
+                    "expected type {expected} in {context}, got {actual}"
+                ))
+            }
+        }
+        None => Err(format!("expected type {expected} in {context}, got None")),
+    }
+}
+
+/// the original logic is redundant in prometheus, and the following coding blocks
+/// have been optimized for readability, but all logic SHOULD be covered.
+fn check_ast_for_binary_expr(ex: &BinaryExpr) -> Result<(), String> {
+    let op_display = token_display(ex.op.id());
+
+    if !ex.op.is_operator() {
+        return Err(format!(
+            "binary expression does not support operator '{op_display}'"
+        ));
+    }
+
+    if ex.return_bool() && !ex.op.is_comparison_operator() {
+        return Err("bool modifier can only be used on comparison operators".into());
+    }
+
+    if ex.op.is_comparison_operator()
+        && ex.lhs.value_type() == ValueType::Scalar
+        && ex.rhs.value_type() == ValueType::Scalar
+        && !ex.return_bool()
+    {
+        return Err(SCALAR_COMPARISON_WITHOUT_BOOL.into());
+    }
+
+    // For `on` matching, a label can only appear in one of the lists.
+    // Every time series of the result vector must be uniquely identifiable.
+    if ex.is_matching_on() && ex.is_labels_joint() {
+        if let Some(labels) = ex.intersect_labels() {
+            if let Some(label) = labels.first() {
+                return Err(format!(
+                    "label '{label}' must not occur in ON and GROUP clause at once"
+                ));
+            }
+        };
+    }
+
+    if ex.op.is_set_operator() {
+        if ex.lhs.value_type() == ValueType::Scalar || ex.rhs.value_type() == ValueType::Scalar {
+            return Err(format!(
+                "set operator '{op_display}' not allowed in binary scalar expression"
+            ));
+        }
+
+        if ex.lhs.value_type() == ValueType::Vector && ex.rhs.value_type() == ValueType::Vector {
+            if let Some(ref modifier) = ex.modifier {
+                if matches!(modifier.card, VectorMatchCardinality::OneToMany(_))
+                    || matches!(modifier.card, VectorMatchCardinality::ManyToOne(_))
+                {
+                    return Err(format!("no grouping allowed for '{op_display}' operation"));
+                }
+            };
+        }
+    }
+
+    if ex.lhs.value_type() != ValueType::Scalar && ex.lhs.value_type() != ValueType::Vector {
+        return Err("binary expression must contain only scalar and instant vector types".into());
+    }
+    if ex.rhs.value_type() != ValueType::Scalar && ex.rhs.value_type() != ValueType::Vector {
+        return Err("binary expression must contain only scalar and instant vector types".into());
+    }
+
+    if (ex.lhs.value_type() != ValueType::Vector || ex.rhs.value_type() != ValueType::Vector)
+        && ex.is_matching_labels_not_empty()
+    {
+        return Err("vector matching only allowed between vectors".into());
+    }
+
+    Ok(())
+}
+
+fn check_ast_for_aggregate_expr(ex: &AggregateExpr) -> Result<(), String> {
+    if !ex.op.is_aggregator() {
+        let op_display = token_display(ex.op.id());
+        return Err(format!(
+            "aggregation operator expected in aggregation expression but got '{op_display}'"
+        ));
+    }
+
+    expect_type(
+        ValueType::Vector,
+        Some(ex.expr.value_type()),
+        "aggregation expression",
+    )?;
+
+    if matches!(ex.op.id(), T_TOPK | T_BOTTOMK | T_QUANTILE) {
+        expect_type(
+            ValueType::Scalar,
+            ex.param.as_ref().map(|ex| ex.value_type()),
+            "aggregation expression",
+        )?;
+    }
+
+    if ex.op.id() == T_COUNT_VALUES {
+        expect_type(
+            ValueType::String,
+            ex.param.as_ref().map(|ex| ex.value_type()),
+            "aggregation expression",
+        )?;
+        // only empty names are rejected here, the scheme is checked by `parse_with_options`
+        if let Some(Expr::StringLiteral(StringLiteral { val })) = ex.param.as_deref() {
+            if !NameValidationScheme::Utf8.is_valid_label_name(val) {
+                return Err(format!("invalid label name {val:?}"));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn check_ast_for_call(ex: &Call) -> Result<(), String> {
+    let expected_args_len = ex.func.arg_types.len();
+    let name = ex.func.name;
+    let actual_args_len = ex.args.len();
+
+    if ex.func.variadic {
+        let expected_args_len_without_default = expected_args_len - 1;
+        if expected_args_len_without_default > actual_args_len {
+            return Err(format!(
+                "expected at least {expected_args_len_without_default} argument(s) in call to '{name}', got {actual_args_len}"
+            ));
+        }
+
+        // `label_join` and `sort_by_label` do not have a maximum arguments threshold.
+        // this hard code SHOULD be careful if new functions are supported by Prometheus.
+        let unbounded = matches!(name, "label_join" | "sort_by_label" | "sort_by_label_desc");
+        if actual_args_len > expected_args_len && !unbounded {
+            return Err(format!(
+                "expected at most {expected_args_len} argument(s) in call to '{name}', got {actual_args_len}"
+            ));
+        }
+    }
+
+    if !ex.func.variadic && expected_args_len != actual_args_len {
+        return Err(format!(
+            "expected {expected_args_len} argument(s) in call to '{name}', got {actual_args_len}"
+        ));
+    }
+
+    // special cases from https://prometheus.io/docs/prometheus/latest/querying/functions
+    if name.eq_ignore_ascii_case("exp") {
+        if let Some(val) = ex.args.first().and_then(|ex| ex.scalar_value()) {
+            if val.is_nan() || val.is_infinite() {
+                return Ok(());
+            }
+        }
+    } else if name.eq_ignore_ascii_case("ln")
+        || name.eq_ignore_ascii_case("log2")
+        || name.eq_ignore_ascii_case("log10")
+    {
+        if let Some(val) = ex.args.first().and_then(|ex| ex.scalar_value()) {
+            if val.is_nan() || val.is_infinite() || val <= 0.0 {
+                return Ok(());
+            }
+        }
+    }
+
+    for (mut idx, actual_arg) in ex.args.args.iter().enumerate() {
+        // this only happens when function args are variadic
+        if idx >= ex.func.arg_types.len() {
+            idx = ex.func.arg_types.len() - 1;
+        }
+
+        expect_type(
+            ex.func.arg_types[idx],
+            Some(actual_arg.value_type()),
+            &format!("call to function '{name}'"),
+        )?;
+    }
+
+    check_label_args(ex, &[]).map_err(|(err, _)| err)?;
+
+    Ok(())
+}
+
+/// check the static label names and regex of `label_replace`, `label_join` and
+/// `sort_by_label`, as Prometheus does when evaluating them. `offsets` are the positions of the
+/// args in the query, which locate the invalid part of a regex in the span of
+/// the error.
+///
+/// only empty names are rejected here, the names are checked against the
+/// [`NameValidationScheme`] by [`crate::parser::parse_with_options`].
+pub(crate) fn check_label_args(
+    ex: &Call,
+    offsets: &[usize],
+) -> Result<(), (String, Option<Range<usize>>)> {
+    let name = ex.func.name;
+    for (idx, arg) in ex.args.args.iter().enumerate() {
+        let Expr::StringLiteral(StringLiteral { val }) = &**arg else {
+            continue;
+        };
+        if is_label_arg(name, idx) && !NameValidationScheme::Utf8.is_valid_label_name(val) {
+            let kind = match (name, idx) {
+                ("label_replace" | "label_join", 1) => "destination ",
+                ("label_replace" | "label_join", _) => "source ",
+                _ => "",
+            };
+            let err = format!("invalid {kind}label name in {name}(): {val:?}");
+            return Err((err, None));
+        }
+        if name == "label_replace" && idx == 4 {
+            let offset = offsets.get(idx).copied().unwrap_or_default();
+            new_regex(val, offset).map_err(|(err, span)| {
+                let err = format!("invalid regular expression in {name}(): {err}");
+                (err, Some(span))
+            })?;
+        }
+    }
+    Ok(())
+}
+
+fn check_ast_for_unary(ex: &UnaryExpr) -> Result<(), String> {
+    let value_type = ex.expr.value_type();
+    if value_type != ValueType::Scalar && value_type != ValueType::Vector {
+        return Err(format!(
+            "unary expression only allowed on expressions of type scalar or vector, got: {value_type}"
+        ));
+    }
+
+    Ok(())
+}
+
+/// the error of a comparison between scalars without `bool`, which the parse
+/// error diagnostic suggests a fix for.
+pub(crate) const SCALAR_COMPARISON_WITHOUT_BOOL: &str =
+    "comparisons between scalars must use BOOL modifier";
+
+fn check_ast_for_subquery(ex: &SubqueryExpr) -> Result<(), String> {
+    let value_type = ex.expr.value_type();
+    if value_type != ValueType::Vector {
+        return Err(format!(
+            "subquery is only allowed on vector, got {value_type} instead"
+        ));
+    }
+
+    Ok(())
+}
+
+fn check_ast_for_vector_selector(ex: &VectorSelector) -> Result<(), String> {
+    // A Vector selector must contain at least one non-empty matcher to prevent
+    // implicit selection of all metrics (e.g. by a typo).
+    if ex.matchers.is_empty_matchers() {
+        return Err("vector selector must contain at least one non-empty matcher".into());
+    }
+
+    let mut du = ex.matchers.find_matchers(METRIC_NAME);
+    if du.len() >= 2 {
+        // this is to ensure that the err information can be predicted with fixed order
+        du.sort();
+        return Err(format!(
+            "metric name must not be set twice: '{}' or '{}'",
+            du[0], du[1]
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn test_valid_at_modifier() {
+        let cases = vec![
+            // tuple: (seconds, elapsed milliseconds before or after UNIX_EPOCH)
+            (0.0, 0),
+            (1000.3, 1000300),    // after UNIX_EPOCH
+            (1000.9, 1000900),    // after UNIX_EPOCH
+            (1000.9991, 1000999), // after UNIX_EPOCH
+            (1000.9999, 1001000), // after UNIX_EPOCH
+            (-1000.3, 1000300),   // before UNIX_EPOCH
+            (-1000.9, 1000900),   // before UNIX_EPOCH
+        ];
+
+        for (secs, elapsed) in cases {
+            match AtModifier::try_from(secs).unwrap() {
+                AtModifier::At(st) => {
+                    if secs.is_sign_positive() || secs == 0.0 {
+                        assert_eq!(
+                            elapsed,
+                            st.duration_since(SystemTime::UNIX_EPOCH)
+                                .unwrap()
+                                .as_millis()
+                        )
+                    } else if secs.is_sign_negative() {
+                        assert_eq!(
+                            elapsed,
+                            SystemTime::UNIX_EPOCH
+                                .duration_since(st)
+                                .unwrap()
+                                .as_millis()
+                        )
+                    }
+                }
+                _ => panic!(),
+            }
+        }
+
+        assert_eq!(
+            AtModifier::try_from(Expr::from(1.0)),
+            AtModifier::try_from(1.0),
+        );
+    }
+
+    #[test]
+    fn test_invalid_at_modifier() {
+        let cases = vec![
+            f64::MAX,
+            f64::MIN,
+            f64::NAN,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+        ];
+
+        for secs in cases {
+            assert!(AtModifier::try_from(secs).is_err())
+        }
+
+        assert_eq!(
+            AtModifier::try_from(token::T_ADD),
+            Err("invalid @ modifier preprocessor '+', START or END is valid.".into())
+        );
+
+        assert_eq!(
+            AtModifier::try_from(Expr::from("string literal")),
+            Err("invalid float value after @ modifier".into())
+        );
+    }
+
+    #[test]
+    fn test_binary_labels() {
+        assert_eq!(
+            LabelModifier::Include(HashSet::from([String::from("foo"), String::from("bar")]))
+                .labels(),
+            &HashSet::from([String::from("foo"), String::from("bar")])
+        );
+
+        assert_eq!(
+            LabelModifier::Exclude(HashSet::from([String::from("foo"), String::from("bar")]))
+                .labels(),
+            &HashSet::from([String::from("foo"), String::from("bar")])
+        );
+
+        assert_eq!(
+            VectorMatchCardinality::OneToMany(HashSet::from([
+                String::from("foo"),
+                String::from("bar")
+            ]))
+            .labels()
+            .unwrap(),
+            &HashSet::from([String::from("foo"), String::from("bar")])
+        );
+
+        assert_eq!(
+            VectorMatchCardinality::ManyToOne(HashSet::from([
+                String::from("foo"),
+                String::from("bar")
+            ]))
+            .labels()
+            .unwrap(),
+            &HashSet::from([String::from("foo"), String::from("bar")])
+        );
+
+        assert_eq!(VectorMatchCardinality::OneToOne.labels(), None);
+        assert_eq!(VectorMatchCardinality::ManyToMany.labels(), None);
+    }
+
+    #[test]
+    fn test_neg() {
+        assert_eq!(
+            -VectorSelector::from("foo"),
+            UnaryExpr {
+                expr: Box::new(Expr::from(VectorSelector::from("foo")))
+            }
+        )
+    }
+
+    #[test]
+    fn test_scalar_value() {
+        assert_eq!(Some(1.0), Expr::from(1.0).scalar_value());
+        assert_eq!(None, Expr::from("1.0").scalar_value());
+    }
+
+    #[test]
+    fn test_aggregate_params() {
+        let aggregate = |query: &str| match crate::parser::parse(query).unwrap() {
+            Expr::Aggregate(agg) => agg,
+            _ => unreachable!(),
+        };
+        assert_eq!(aggregate("topk(5, foo)").k(), Some(5));
+        assert_eq!(aggregate("bottomk(2.7, foo)").k(), Some(2));
+        assert_eq!(aggregate("topk(-2.5, foo)").k(), Some(-2));
+        assert_eq!(aggregate("topk(1e19, foo)").k(), None);
+        assert_eq!(aggregate("topk(NaN, foo)").k(), None);
+        assert_eq!(aggregate("topk(scalar(bar), foo)").k(), None);
+        assert_eq!(aggregate("quantile(0.5, foo)").k(), None);
+
+        assert_eq!(aggregate("quantile(0.99, foo)").quantile(), Some(0.99));
+        assert_eq!(aggregate("quantile(scalar(bar), foo)").quantile(), None);
+        assert_eq!(aggregate("topk(1, foo)").quantile(), None);
+
+        assert_eq!(
+            aggregate(r#"count_values("value", foo)"#).value_label(),
+            Some("value")
+        );
+        assert_eq!(aggregate("sum(foo)").value_label(), None);
+        assert_eq!(aggregate("sum(foo)").k(), None);
+    }
+
+    #[test]
+    fn test_at_expr() {
+        assert_eq!(
+            "@ <timestamp> may not be set multiple times",
+            Expr::from(VectorSelector::from("foo"))
+                .at_expr(AtModifier::try_from(1.0).unwrap())
+                .and_then(|ex| ex.at_expr(AtModifier::try_from(1.0).unwrap()))
+                .unwrap_err()
+        );
+
+        assert_eq!(
+            "@ <timestamp> may not be set multiple times",
+            Expr::new_matrix_selector(
+                Expr::from(VectorSelector::from("foo")),
+                Duration::from_secs(1),
+            )
+            .and_then(|ex| ex.at_expr(AtModifier::try_from(1.0).unwrap()))
+            .and_then(|ex| ex.at_expr(AtModifier::try_from(1.0).unwrap()))
+            .unwrap_err()
+        );
+
+        assert_eq!(
+            "@ <timestamp> may not be set multiple times",
+            Expr::new_subquery_expr(
+                Expr::from(VectorSelector::from("foo")),
+                Duration::from_secs(1),
+                None,
+            )
+            .and_then(|ex| ex.at_expr(AtModifier::try_from(1.0).unwrap()))
+            .and_then(|ex| ex.at_expr(AtModifier::try_from(1.0).unwrap()))
+            .unwrap_err()
+        )
+    }
+
+    #[test]
+    fn test_offset_expr() {
+        assert_eq!(
+            "offset may not be set multiple times",
+            Expr::from(VectorSelector::from("foo"))
+                .offset_expr(Offset::Pos(Duration::from_secs(1000)))
+                .and_then(|ex| ex.offset_expr(Offset::Pos(Duration::from_secs(1000))))
+                .unwrap_err()
+        );
+
+        assert_eq!(
+            "offset may not be set multiple times",
+            Expr::new_matrix_selector(
+                Expr::from(VectorSelector::from("foo")),
+                Duration::from_secs(1),
+            )
+            .and_then(|ex| ex.offset_expr(Offset::Pos(Duration::from_secs(1000))))
+            .and_then(|ex| ex.offset_expr(Offset::Pos(Duration::from_secs(1000))))
+            .unwrap_err()
+        );
+
+        assert_eq!(
+            "offset may not be set multiple times",
+            Expr::new_subquery_expr(
+                Expr::from(VectorSelector::from("foo")),
+                Duration::from_secs(1),
+                None,
+            )
+            .and_then(|ex| ex.offset_expr(Offset::Pos(Duration::from_secs(1000))))
+            .and_then(|ex| ex.offset_expr(Offset::Pos(Duration::from_secs(1000))))
+            .unwrap_err()
+        );
+    }
+
+    #[test]
+    fn test_range_query() {
+        let cases = [
+            ("1 + 2", None),
+            ("(foo)", None),
+            ("sum(rate(foo[5m]))", None),
+            ("scalar(foo)", None),
+            ("(foo[5m])", Some("range vector")),
+            ("rate(foo[5m])[1h:]", Some("range vector")),
+            (r#""foo""#, Some("string")),
+        ];
+        for (input, documented) in cases {
+            let expr = crate::parser::parse(input).unwrap();
+            assert_eq!(expr.is_valid_for_range_query(), documented.is_none());
+            let expected = documented.map(|d| {
+                format!(r#"invalid expression type "{d}" for range query, must be Scalar or instant Vector"#)
+            });
+            assert_eq!(expr.check_range_query().err(), expected, "{input}");
+        }
+    }
+
+    #[test]
+    fn test_offset_arithmetic() {
+        let pos = |secs| Offset::Pos(Duration::from_secs(secs));
+        let neg = |secs| Offset::Neg(Duration::from_secs(secs));
+        let cases = vec![
+            (pos(10), pos(5), Some(pos(15))),
+            (neg(10), neg(5), Some(neg(15))),
+            (pos(10), neg(5), Some(pos(5))),
+            (pos(5), neg(10), Some(neg(5))),
+            (neg(10), pos(5), Some(neg(5))),
+            (neg(5), pos(10), Some(pos(5))),
+            (neg(5), pos(5), Some(pos(0))),
+            (Offset::Neg(Duration::MAX), neg(1), None),
+            (
+                Offset::Neg(Duration::MAX),
+                pos(1),
+                Some(Offset::Neg(Duration::MAX - Duration::from_secs(1))),
+            ),
+        ];
+        for (a, b, expected) in cases {
+            assert_eq!(a.checked_add(&b), expected, "{a} + {b}");
+            assert_eq!(b.checked_add(&a), expected, "{b} + {a}");
+        }
+        assert_eq!(pos(10).checked_sub(&pos(15)), Some(neg(5)));
+        assert_eq!(Offset::Pos(Duration::MAX).checked_sub(&neg(1)), None);
+
+        assert_eq!(-pos(10), neg(10));
+        assert_eq!(-neg(10), pos(10));
+        assert_eq!(-pos(0), pos(0));
+
+        assert_eq!(pos(2).as_millis(), 2000);
+        assert_eq!(neg(2).as_millis(), -2000);
+        assert_eq!(Offset::Neg(Duration::from_micros(1500)).as_millis(), -1);
+        assert_eq!(
+            Offset::Neg(Duration::MAX).as_millis(),
+            -(Duration::MAX.as_millis() as i128)
+        );
+    }
+
+    #[test]
+    fn test_vector_selector_builder() {
+        use crate::label::MatchOp;
+
+        let matcher = Matcher::new(MatchOp::NotEqual, "job".into(), "api".into());
+        let vs = VectorSelector::builder("foo")
+            .matcher(matcher.clone())
+            .offset(Offset::Neg(Duration::from_secs(60)))
+            .at(AtModifier::End)
+            .build();
+        let expected = VectorSelector {
+            name: Some("foo".into()),
+            matchers: Matchers::one(Matcher::new_eq_metric_matcher("foo".into())).append(matcher),
+            offset: Some(Offset::Neg(Duration::from_secs(60))),
+            at: Some(AtModifier::End),
+        };
+        assert_eq!(vs, Ok(expected));
+
+        assert_eq!(
+            VectorSelector::builder("foo").build(),
+            Ok(VectorSelector::from("foo"))
+        );
+
+        assert_eq!(
+            VectorSelector::builder("foo")
+                .offset(Offset::Pos(Duration::from_secs(1)))
+                .offset(Offset::Pos(Duration::from_secs(2)))
+                .at(AtModifier::Start)
+                .at(AtModifier::End)
+                .build(),
+            Err("offset may not be set multiple times".into())
+        );
+
+        assert_eq!(
+            VectorSelector::builder("foo")
+                .at(AtModifier::Start)
+                .at(AtModifier::Start)
+                .build(),
+            Err("@ <timestamp> may not be set multiple times".into())
+        );
+    }
+
+    #[test]
+    fn test_binary_operators() {
+        use crate::parser::parse;
+
+        let foo = || Expr::from(VectorSelector::from("foo"));
+        let bar = || Expr::from(VectorSelector::from("bar"));
+
+        assert_eq!(foo() + bar(), parse("foo + bar").unwrap());
+        assert_eq!(foo() - bar(), parse("foo - bar").unwrap());
+        assert_eq!(foo() * bar(), parse("foo * bar").unwrap());
+        assert_eq!(foo() / bar(), parse("foo / bar").unwrap());
+        assert_eq!(foo() - bar() * foo(), parse("foo - bar * foo").unwrap());
+        assert_eq!(
+            (foo() - bar()) * foo(),
+            Expr::new_binary_expr(
+                Expr::new_binary_expr(foo(), T_SUB, None, bar()).unwrap(),
+                T_MUL,
+                None,
+                foo()
+            )
+            .unwrap()
+        );
+
+        assert_eq!(foo().eqlc(bar(), false), parse("foo == bar").unwrap());
+        assert_eq!(foo().neq(bar(), true), parse("foo != bool bar").unwrap());
+        assert_eq!(foo().gtr(bar(), false), parse("foo > bar").unwrap());
+        assert_eq!(foo().gte(bar(), true), parse("foo >= bool bar").unwrap());
+        assert_eq!(foo().lss(bar(), false), parse("foo < bar").unwrap());
+        assert_eq!(
+            Expr::from(1.0).lte(Expr::from(2.0), true),
+            parse("1 <= bool 2").unwrap()
+        );
+
+        // trees built without parens are displayed with the parens they need
+        let pow = |lhs: Expr, rhs: Expr| lhs.binary(token::T_POW, rhs, false);
+        let cases = vec![
+            ((foo() - bar()) * foo(), "(foo - bar) * foo"),
+            (foo() - (bar() - foo()), "foo - (bar - foo)"),
+            (foo() - bar() - foo(), "foo - bar - foo"),
+            (foo() - bar() * foo(), "foo - bar * foo"),
+            (-(foo() + bar()), "-(foo + bar)"),
+            (-(foo() * bar()), "-(foo * bar)"),
+            (-pow(foo(), bar()), "-foo ^ bar"),
+            (-foo() * bar(), "-foo * bar"),
+            (pow(-foo(), bar()), "(-foo) ^ bar"),
+            (pow(Expr::from(-2.0), Expr::from(2.0)), "(-2) ^ 2"),
+            (pow(pow(foo(), bar()), foo()), "(foo ^ bar) ^ foo"),
+            (pow(foo(), pow(bar(), foo())), "foo ^ bar ^ foo"),
+            (foo().gtr(bar(), false).gtr(foo(), false), "foo > bar > foo"),
+            (
+                foo().gtr(bar().gtr(foo(), false), false),
+                "foo > (bar > foo)",
+            ),
+            (
+                Expr::from((foo() + bar()).subquery(Duration::from_secs(3600)).unwrap()),
+                "(foo + bar)[1h:]",
+            ),
+            (
+                Expr::from((-foo()).subquery(Duration::from_secs(3600)).unwrap()),
+                "(-foo)[1h:]",
+            ),
+        ];
+        for (expr, expected) in cases {
+            assert_eq!(expr.to_string(), expected);
+            let reparsed = parse(expected).unwrap();
+            assert_eq!(reparsed.to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn test_aggregate_builders() {
+        use crate::parser::parse;
+
+        let foo = || Expr::from(VectorSelector::from("foo"));
+
+        let cases = vec![
+            (foo().sum().unwrap(), "sum(foo)"),
+            (foo().avg().unwrap().by(["job"]), "avg by (job) (foo)"),
+            (
+                foo().max().unwrap().without(["job", "instance"]),
+                "max without (instance, job) (foo)",
+            ),
+            (foo().topk(5.0).unwrap(), "topk(5, foo)"),
+            (
+                foo().bottomk(3.0).unwrap().by(["job"]),
+                "bottomk by (job) (3, foo)",
+            ),
+            (foo().quantile(0.9).unwrap(), "quantile(0.9, foo)"),
+            (
+                foo().count_values("code").unwrap(),
+                "count_values(\"code\", foo)",
+            ),
+            (
+                foo().count().unwrap().by(Vec::<String>::new()),
+                "count by () (foo)",
+            ),
+        ];
+        for (ex, query) in cases {
+            assert_eq!(Expr::from(ex), parse(query).unwrap());
+        }
+
+        let fail_cases = vec![
+            (
+                Expr::from(1.0).sum(),
+                "expected type vector in aggregation expression, got scalar",
+            ),
+            (
+                foo().topk("5"),
+                "expected type scalar in aggregation expression, got string",
+            ),
+            (
+                foo().count_values(1.0),
+                "expected type string in aggregation expression, got scalar",
+            ),
+        ];
+        for (res, err) in fail_cases {
+            assert_eq!(res.unwrap_err(), err);
+        }
+    }
+
+    #[test]
+    fn test_subquery_builder() {
+        use crate::parser::parse;
+
+        let foo = || Expr::from(VectorSelector::from("foo"));
+        let hour = Duration::from_secs(3600);
+        let minute = Duration::from_secs(60);
+
+        let sq = foo().subquery(hour).unwrap();
+        assert_eq!(Expr::from(sq.clone()), parse("foo[1h:]").unwrap());
+        assert_eq!(sq.resolve_step(minute), minute);
+
+        let sq = sq.with_step(5 * minute);
+        assert_eq!(Expr::from(sq.clone()), parse("foo[1h:5m]").unwrap());
+        assert_eq!(sq.resolve_step(minute), 5 * minute);
+
+        assert_eq!(
+            Expr::from(1.0).subquery(hour).unwrap_err(),
+            "subquery is only allowed on vector, got scalar instead"
+        );
+    }
+
+    #[test]
+    fn test_node_modifiers() {
+        use crate::parser::parse;
+
+        let five_min = Offset::Pos(Duration::from_secs(300));
+        let hour = Duration::from_secs(3600);
+
+        let vs = VectorSelector::from("foo")
+            .offset_expr(five_min.clone())
+            .and_then(|vs| vs.at_expr(AtModifier::End))
+            .unwrap();
+        assert_eq!(
+            Expr::from(vs.clone()),
+            parse("foo @ end() offset 5m").unwrap()
+        );
+        assert_eq!(
+            vs.clone().at_expr(AtModifier::Start).unwrap_err(),
+            "@ <timestamp> may not be set multiple times"
+        );
+
+        let ms = MatrixSelector {
+            vector_selector: VectorSelector::from("foo"),
+            range: hour,
+        };
+        let ms = ms.at_expr(AtModifier::Start).unwrap();
+        assert_eq!(
+            Expr::MatrixSelector(ms.clone()),
+            parse("foo[1h] @ start()").unwrap()
+        );
+        assert_eq!(
+            ms.at_expr(AtModifier::End).unwrap_err(),
+            "@ <timestamp> may not be set multiple times"
+        );
+
+        let sq = Expr::from(VectorSelector::from("foo"))
+            .subquery(hour)
+            .unwrap()
+            .offset_expr(five_min.clone())
+            .unwrap();
+        assert_eq!(Expr::from(sq.clone()), parse("foo[1h:] offset 5m").unwrap());
+        assert_eq!(
+            sq.offset_expr(five_min).unwrap_err(),
+            "offset may not be set multiple times"
+        );
+    }
+
+    #[test]
+    fn test_expr_display() {
+        use crate::parser::parse;
+
+        let cases = vec![
+            ("foo", "foo"),
+            ("-foo", "-foo"),
+            ("1e3", "1000"),
+            ("-Inf", "-Inf"),
+            ("NaN", "NaN"),
+            (r#""a\"b""#, r#""a\"b""#),
+            (r#"foo{a="b",__name__="foo"}"#, r#"foo{a="b"}"#),
+            (r#"{__name__="foo"}"#, r#"{__name__="foo"}"#),
+            ("foo @ 123 offset -5m", "foo @ 123.000 offset -5m"),
+            ("foo[90s] @ start()", "foo[1m30s] @ start()"),
+            ("foo[5m:] @ end()", "foo[5m:] @ end()"),
+            ("foo[5m:1m] offset 1w", "foo[5m:1m] offset 1w"),
+            ("sum without (b, a) (foo)", "sum without (a, b) (foo)"),
+            ("sum(foo) by ()", "sum by () (foo)"),
+            ("topk(3, foo)", "topk(3, foo)"),
+            ("a + on(x) group_left(y) b", "a + on (x) group_left (y) b"),
+            ("a == bool b", "a == bool b"),
+            ("a and ignoring(x) b", "a and ignoring (x) b"),
+            ("(a + b) * c", "(a + b) * c"),
+            (
+                r#"label_replace(up, "a", "$1", "b", "(.*)")"#,
+                r#"label_replace(up, "a", "$1", "b", "(.*)")"#,
+            ),
+        ];
+        for (input, expected) in cases {
+            let expr = parse(input).unwrap();
+            assert_eq!(expr.to_string(), expected);
+            assert_eq!(parse(expected).unwrap(), expr);
+        }
+
+        // the values are written in double quotes, whatever the quotes were
+        let cases = vec![
+            (r#"foo{a='x"y'}"#, r#"foo{a="x\"y"}"#),
+            (r#"foo{a!~'x\\"'}"#, r#"foo{a!~"x\\\""}"#),
+            (r#"'a"b'"#, r#""a\"b""#),
+            (r#""a\\b""#, r#""a\\b""#),
+            ("`a\"b`", r#""a\"b""#),
+            (
+                r#"label_join(foo, "a", '"', "b")"#,
+                r#"label_join(foo, "a", "\"", "b")"#,
+            ),
+            (r#"foo{a='a"b'}"#, r#"foo{a="a\"b"}"#),
+            (r#"foo{a="it's"}"#, r#"foo{a="it's"}"#),
+            ("foo{a=`a'b\"c`}", r#"foo{a="a'b\"c"}"#),
+            ("foo{a=~`eu\\.west`}", r#"foo{a=~"eu\\.west"}"#),
+            (r#"foo{a=~"eu\\.west"}"#, r#"foo{a=~"eu\\.west"}"#),
+            (r#"foo{a=~'\\d+\t'}"#, r#"foo{a=~"\\d+\t"}"#),
+            (
+                r#"label_replace(foo, "a", "$1", "b", `(.+)\.x`)"#,
+                r#"label_replace(foo, "a", "$1", "b", "(.+)\\.x")"#,
+            ),
+        ];
+        for (input, expected) in cases {
+            let expr = parse(input).unwrap();
+            assert_eq!(expr.to_string(), expected, "{input}");
+            // and parse back to the same expr
+            assert_eq!(parse(expected).unwrap(), expr, "{input}");
+        }
+
+        // the regex reads the value without the escapes of the query
+        let Expr::VectorSelector(vs) = parse(r#"foo{a=~"eu\\.west"}"#).unwrap() else {
+            unreachable!()
+        };
+        let matcher = vs.matchers.matchers.iter().find(|m| m.name == "a").unwrap();
+        assert!(matcher.is_match("eu.west"));
+        assert!(!matcher.is_match("euxwest"));
+    }
+
+    #[test]
+    fn test_accessors() {
+        use crate::parser::parse;
+
+        let expr = parse("rate(foo[5m])").unwrap();
+        assert_eq!(expr.as_call().map(|call| call.func.name), Some("rate"));
+        assert!(expr.as_vector_selector().is_none());
+        assert!(expr.as_aggregate().is_none());
+
+        let call = expr.into_call().unwrap();
+        let ms = call.args[0].as_matrix_selector().unwrap();
+        assert_eq!(ms.vector_selector.name.as_deref(), Some("foo"));
+
+        let expr = parse("sum(foo) + -(1)").unwrap();
+        let binary = expr.as_binary().unwrap();
+        assert!(binary.lhs.as_aggregate().is_some());
+        let unary = binary.rhs.as_unary().unwrap();
+        let number = unary.expr.as_paren().unwrap().expr.as_number_literal();
+        assert_eq!(number.map(|n| n.val), Some(1.0));
+        assert!(expr.clone().into_binary().is_some());
+        assert!(expr.into_string_literal().is_none());
+
+        let expr = parse(r#""a""#).unwrap();
+        assert_eq!(expr.as_string_literal().map(|s| s.val.as_str()), Some("a"));
+        assert!(parse("foo[5m:]").unwrap().into_subquery().is_some());
+        assert!(parse("foo").unwrap().into_vector_selector().is_some());
+        assert!(parse("foo").unwrap().as_extension().is_none());
+    }
+
+    #[test]
+    fn test_extension_eq() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        /// the reads are not part of the expr, but of its Debug output.
+        #[derive(Debug)]
+        struct Cached {
+            key: String,
+            reads: AtomicUsize,
+        }
+
+        impl ExtensionExpr for Cached {
+            fn as_any(&self) -> &dyn std::any::Any {
+                self
+            }
+
+            fn name(&self) -> &str {
+                "cached"
+            }
+
+            fn value_type(&self) -> ValueType {
+                ValueType::Vector
+            }
+
+            fn children(&self) -> &[Expr] {
+                &[]
             }
-        }
-    } else if name.eq_ignore_ascii_case("ln")
-        || name.eq_ignore_ascii_case("log2")
-        || name.eq_ignore_ascii_case("log10")
-    {
-        if let Some(val) = ex.args.first().and_then(|ex| ex.scalar_value()) {
-            if val.is_nan() || val.is_infinite() || val <= 0.0 {
-                return Ok(Expr::Call(ex));
+
+            fn eq_dyn(&self, other: &dyn ExtensionExpr) -> bool {
+                other
+                    .as_any()
+                    .downcast_ref::<Self>()
+                    .is_some_and(|other| self.key == other.key)
             }
-        }
-    }
 
-    for (mut idx, actual_arg) in ex.args.args.iter().enumerate() {
-        // this only happens when function args are variadic
-        if idx >= ex.func.arg_types.len() {
-            idx = ex.func.arg_types.len() - 1;
+            fn hash_dyn(&self, mut state: &mut dyn Hasher) {
+                self.key.hash(&mut state);
+            }
         }
 
-        expect_type(
-            ex.func.arg_types[idx],
-            Some(actual_arg.value_type()),
-            &format!("call to function '{name}'"),
-        )?;
-    }
+        // the field is only read by the Debug output
+        #[derive(Debug)]
+        #[allow(dead_code)]
+        struct Plain(u8);
 
-    Ok(Expr::Call(ex))
-}
+        impl ExtensionExpr for Plain {
+            fn as_any(&self) -> &dyn std::any::Any {
+                self
+            }
 
-fn check_ast_for_unary(ex: UnaryExpr) -> Result<Expr, String> {
-    let value_type = ex.expr.value_type();
-    if value_type != ValueType::Scalar && value_type != ValueType::Vector {
-        return Err(format!(
-            "unary expression only allowed on expressions of type scalar or vector, got {value_type}"
-        ));
-    }
+            fn name(&self) -> &str {
+                "plain"
+            }
 
-    Ok(Expr::Unary(ex))
-}
+            fn value_type(&self) -> ValueType {
+                ValueType::Scalar
+            }
 
-fn check_ast_for_subquery(ex: SubqueryExpr) -> Result<Expr, String> {
-    let value_type = ex.expr.value_type();
-    if value_type != ValueType::Vector {
-        return Err(format!(
-            "subquery is only allowed on vector, got {value_type} instead"
-        ));
-    }
+            fn children(&self) -> &[Expr] {
+                &[]
+            }
+        }
 
-    Ok(Expr::Subquery(ex))
-}
+        let ext = |expr: Arc<dyn ExtensionExpr>| Extension { expr };
+        let cached = |key: &str, reads| {
+            ext(Arc::new(Cached {
+                key: key.into(),
+                reads: AtomicUsize::new(reads),
+            }))
+        };
+        let hash = |ex: &Extension| {
+            let mut hasher = DefaultHasher::new();
+            ex.hash(&mut hasher);
+            hasher.finish()
+        };
 
-fn check_ast_for_vector_selector(ex: VectorSelector) -> Result<Expr, String> {
-    // A Vector selector must contain at least one non-empty matcher to prevent
-    // implicit selection of all metrics (e.g. by a typo).
-    if ex.matchers.is_empty_matchers() {
-        return Err("vector selector must contain at least one non-empty matcher".into());
+        let a = cached("a", 0);
+        let b = cached("a", 3);
+        assert_eq!(a, b);
+        assert_eq!(hash(&a), hash(&b));
+        a.expr
+            .as_any()
+            .downcast_ref::<Cached>()
+            .unwrap()
+            .reads
+            .fetch_add(1, Ordering::Relaxed);
+        assert_eq!(a, a.clone());
+        assert_ne!(a, cached("b", 0));
+        assert_ne!(a, ext(Arc::new(Plain(0))));
+
+        // the defaults compare the Debug output
+        assert_eq!(ext(Arc::new(Plain(1))), ext(Arc::new(Plain(1))));
+        assert_ne!(ext(Arc::new(Plain(1))), ext(Arc::new(Plain(2))));
+        assert_eq!(
+            hash(&ext(Arc::new(Plain(1)))),
+            hash(&ext(Arc::new(Plain(2))))
+        );
+        assert_eq!(Expr::Extension(a.clone()), Expr::Extension(b));
     }
 
-    let mut du = ex.matchers.find_matchers(METRIC_NAME);
-    if du.len() >= 2 {
-        // this is to ensure that the err information can be predicted with fixed order
-        du.sort();
-        return Err(format!(
-            "metric name must not be set twice: '{}' or '{}'",
-            du[0], du[1]
-        ));
-    }
+    #[test]
+    fn test_extension_children() {
+        use crate::parser::function::get_function;
+        use crate::parser::{parse, type_check};
+        use crate::util::{walk_expr, ExprVisitor};
 
-    Ok(Expr::VectorSelector(ex))
-}
+        #[derive(Debug)]
+        struct Wrap(Vec<Expr>);
 
-#[cfg(test)]
-mod tests {
-    use std::collections::HashSet;
+        impl ExtensionExpr for Wrap {
+            fn as_any(&self) -> &dyn std::any::Any {
+                self
+            }
 
-    use super::*;
+            fn name(&self) -> &str {
+                "wrap"
+            }
 
-    #[test]
-    fn test_valid_at_modifier() {
-        let cases = vec![
-            // tuple: (seconds, elapsed milliseconds before or after UNIX_EPOCH)
-            (0.0, 0),
-            (1000.3, 1000300),    // after UNIX_EPOCH
-            (1000.9, 1000900),    // after UNIX_EPOCH
-            (1000.9991, 1000999), // after UNIX_EPOCH
-            (1000.9999, 1001000), // after UNIX_EPOCH
-            (-1000.3, 1000300),   // before UNIX_EPOCH
-            (-1000.9, 1000900),   // before UNIX_EPOCH
-        ];
+            fn value_type(&self) -> ValueType {
+                ValueType::Vector
+            }
 
-        for (secs, elapsed) in cases {
-            match AtModifier::try_from(secs).unwrap() {
-                AtModifier::At(st) => {
-                    if secs.is_sign_positive() || secs == 0.0 {
-                        assert_eq!(
-                            elapsed,
-                            st.duration_since(SystemTime::UNIX_EPOCH)
-                                .unwrap()
-                                .as_millis()
-                        )
-                    } else if secs.is_sign_negative() {
-                        assert_eq!(
-                            elapsed,
-                            SystemTime::UNIX_EPOCH
-                                .duration_since(st)
-                                .unwrap()
-                                .as_millis()
-                        )
-                    }
-                }
-                _ => panic!(),
+            fn children(&self) -> &[Expr] {
+                &self.0
             }
         }
 
-        assert_eq!(
-            AtModifier::try_from(Expr::from(1.0)),
-            AtModifier::try_from(1.0),
-        );
+        let wrap = |children| {
+            Expr::Extension(Extension {
+                expr: Arc::new(Wrap(children)),
+            })
+        };
+        // rate of a vector, which the constructors and the parser reject
+        let invalid = Expr::Call(Call {
+            func: get_function("rate").unwrap(),
+            args: FunctionArgs::new_args(Expr::from(VectorSelector::from("foo"))),
+        });
+        let err = "expected type matrix in call to function 'rate', got vector";
+
+        let valid = wrap(vec![parse("rate(foo[5m])").unwrap()]);
+        assert!(check_ast(valid.clone()).is_ok());
+        assert!(check(&valid).is_ok());
+        assert_eq!(type_check(valid.clone()), Ok(valid.clone()));
+
+        let expr = wrap(vec![parse("foo").unwrap(), invalid.clone()]);
+        assert_eq!(check_ast(expr.clone()).unwrap_err(), err);
+        assert_eq!(check(&expr).unwrap_err().message, err);
+        assert_eq!(type_check(expr.clone()).unwrap_err(), err);
+        // also below other nodes and extensions
+        let nested = Expr::new_paren_expr(wrap(vec![wrap(vec![invalid])])).unwrap();
+        assert_eq!(type_check(nested.clone()).unwrap_err(), err);
+        assert_eq!(check(&nested).unwrap_err().message, err);
+
+        // the children are searchable and visited
+        assert_eq!(nested.find_calls("rate").len(), 1);
+        assert_eq!(nested.nodes().len(), 5);
+        struct Count(usize);
+        impl ExprVisitor for Count {
+            type Error = ();
+            fn pre_visit(&mut self, _: &Expr) -> Result<bool, ()> {
+                self.0 += 1;
+                Ok(true)
+            }
+        }
+        let mut count = Count(0);
+        walk_expr(&mut count, &nested).unwrap();
+        assert_eq!(count.0, 5);
     }
 
     #[test]
-    fn test_invalid_at_modifier() {
-        let cases = vec![
-            f64::MAX,
-            f64::MIN,
-            f64::NAN,
-            f64::INFINITY,
-            f64::NEG_INFINITY,
-        ];
+    fn test_extension_validate() {
+        use crate::parser::function::get_function;
+        use crate::parser::type_check;
+
+        /// a range of a remote source, which must not be empty.
+        #[derive(Debug)]
+        struct Remote {
+            source: String,
+        }
 
-        for secs in cases {
-            assert!(AtModifier::try_from(secs).is_err())
+        impl ExtensionExpr for Remote {
+            fn as_any(&self) -> &dyn std::any::Any {
+                self
+            }
+
+            fn name(&self) -> &str {
+                "remote"
+            }
+
+            fn value_type(&self) -> ValueType {
+                ValueType::Matrix
+            }
+
+            fn children(&self) -> &[Expr] {
+                &[]
+            }
+
+            fn validate(&self) -> Result<(), Diagnostic> {
+                match self.source.is_empty() {
+                    true => Err(Diagnostic::error(
+                        "remote",
+                        "remote source must not be empty",
+                    )),
+                    false => Ok(()),
+                }
+            }
         }
 
+        let remote = |source: &str| {
+            Expr::Extension(Extension {
+                expr: Arc::new(Remote {
+                    source: source.into(),
+                }),
+            })
+        };
+        let rate = |arg| {
+            Expr::Call(Call {
+                func: get_function("rate").unwrap(),
+                args: FunctionArgs::new_args(arg),
+            })
+        };
+
+        assert!(check_ast(remote("a")).is_ok());
+        let err = check_ast(remote("")).unwrap_err();
+        assert_eq!(err, "remote source must not be empty");
+        let err = check(&rate(remote(""))).unwrap_err();
+        assert_eq!(err.code, "remote");
+        assert_eq!(err.message, "remote source must not be empty");
         assert_eq!(
-            AtModifier::try_from(token::T_ADD),
-            Err("invalid @ modifier preprocessor '+', START or END is valid.".into())
+            type_check(rate(remote(""))).unwrap_err(),
+            "remote source must not be empty"
         );
 
+        // the value type is checked by the surrounding nodes
+        assert!(type_check(rate(remote("a"))).is_ok());
         assert_eq!(
-            AtModifier::try_from(Expr::from("string literal")),
-            Err("invalid float value after @ modifier".into())
+            type_check(Expr::new_paren_expr(remote("a")).unwrap() + Expr::from(1.0)).unwrap_err(),
+            "binary expression must contain only scalar and instant vector types"
         );
     }
 
     #[test]
-    fn test_binary_labels() {
-        assert_eq!(
-            LabelModifier::Include(HashSet::from([String::from("foo"), String::from("bar")]))
-                .labels(),
-            &HashSet::from([String::from("foo"), String::from("bar")])
-        );
+    fn test_selector_matchers() {
+        use crate::parser::parse;
+
+        let cases = vec![
+            (r#"foo{a="b"}"#, Some("foo"), vec![r#"a="b""#]),
+            (
+                r#"{__name__="foo", a="b"}"#,
+                Some("foo"),
+                vec![r#"__name__="foo""#, r#"a="b""#],
+            ),
+            (r#"{__name__=~"foo.*"}"#, None, vec![r#"__name__=~"foo.*""#]),
+            ("foo", Some("foo"), vec![]),
+        ];
+        for (query, metric, labels) in cases {
+            let Expr::VectorSelector(vs) = parse(query).unwrap() else {
+                unreachable!()
+            };
+            assert_eq!(vs.metric_name(), metric, "{query}");
+            let mut matchers: Vec<String> = vs
+                .label_matchers()
+                .matchers
+                .iter()
+                .map(|m| m.to_string())
+                .collect();
+            matchers.sort();
+            assert_eq!(matchers, labels, "{query}");
+        }
 
+        let folded = |q: &str| match parse(q).unwrap() {
+            Expr::VectorSelector(vs) => vs.normalized_matchers(),
+            _ => unreachable!(),
+        };
         assert_eq!(
-            LabelModifier::Exclude(HashSet::from([String::from("foo"), String::from("bar")]))
-                .labels(),
-            &HashSet::from([String::from("foo"), String::from("bar")])
+            folded(r#"foo{a="b"}"#),
+            folded(r#"{a="b", __name__="foo"}"#)
         );
+        let mut vs = VectorSelector::from("foo");
+        vs.matchers = Matchers::empty();
+        assert_eq!(vs.normalized_matchers(), folded("foo"));
+    }
 
+    #[test]
+    fn test_matches_metric() {
+        let matches = |q: &str, metric: &str| match crate::parser::parse(q).unwrap() {
+            Expr::VectorSelector(vs) => vs.matches_metric(metric),
+            _ => unreachable!(),
+        };
+        assert_eq!(matches("foo", "foo"), MetricMatch::Yes);
+        assert_eq!(matches(r#"{__name__="foo"}"#, "foo"), MetricMatch::Yes);
+        assert_eq!(matches(r#"{__name__=~"fo+"}"#, "foo"), MetricMatch::Maybe);
         assert_eq!(
-            VectorMatchCardinality::OneToMany(HashSet::from([
-                String::from("foo"),
-                String::from("bar")
-            ]))
-            .labels()
-            .unwrap(),
-            &HashSet::from([String::from("foo"), String::from("bar")])
+            matches(r#"{__name__!="bar", job="api"}"#, "foo"),
+            MetricMatch::Maybe
         );
-
+        assert_eq!(matches(r#"{job="api"}"#, "foo"), MetricMatch::Maybe);
+        assert_eq!(matches("foo", "foo_total"), MetricMatch::No);
+        assert_eq!(matches(r#"{__name__=~"fo"}"#, "foo"), MetricMatch::No);
         assert_eq!(
-            VectorMatchCardinality::ManyToOne(HashSet::from([
-                String::from("foo"),
-                String::from("bar")
-            ]))
-            .labels()
-            .unwrap(),
-            &HashSet::from([String::from("foo"), String::from("bar")])
+            matches(r#"{__name__!~"f.*", job="api"}"#, "foo"),
+            MetricMatch::No
         );
 
-        assert_eq!(VectorMatchCardinality::OneToOne.labels(), None);
-        assert_eq!(VectorMatchCardinality::ManyToMany.labels(), None);
+        // the parser rejects a name with `__name__` matchers, but they can be built
+        let vs = VectorSelector::builder("foo")
+            .matcher(Matcher::re(METRIC_NAME, "f.*").unwrap())
+            .build()
+            .unwrap();
+        assert_eq!(vs.matches_metric("foo"), MetricMatch::Yes);
+        let vs = VectorSelector::builder("foo")
+            .matcher(Matcher::ne(METRIC_NAME, "foo"))
+            .build()
+            .unwrap();
+        assert_eq!(vs.matches_metric("foo"), MetricMatch::No);
+
+        let expr = crate::parser::parse("sum(rate(foo[5m])) / count(foo offset 1h) + topk(1, bar)")
+            .unwrap();
+        assert_eq!(expr.selectors_for_metric("foo").len(), 2);
+        assert!(expr.references_metric("bar"));
+        assert!(!expr.references_metric("baz"));
+        let expr = crate::parser::parse(r#"{__name__=~"ba.*"}"#).unwrap();
+        assert_eq!(expr.selectors_for_metric("baz").len(), 1);
+        assert!(!expr.references_metric("baz"));
     }
 
     #[test]
-    fn test_neg() {
+    fn test_check() {
+        for query in [
+            "foo and bar",
+            "sum by (job) (rate(foo[5m])) > on (job) group_left count(bar)",
+            r#"label_replace(foo, "dst", "$1", "src", "(.*)")"#,
+        ] {
+            assert!(
+                check(&crate::parser::parse(query).unwrap()).is_ok(),
+                "{query}"
+            );
+        }
+
+        // exprs built without the parser are checked all the way down
+        let expr = Expr::new_unary_expr(
+            Expr::new_paren_expr(
+                Expr::new_call(
+                    Function::new("abs", vec![ValueType::Vector], false, ValueType::Vector),
+                    FunctionArgs::new_args(Expr::from(VectorSelector::from("foo")))
+                        .append_args(Expr::from(1.0)),
+                )
+                .unwrap(),
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        let err = check(&expr).unwrap_err();
+        assert_eq!(err.code, codes::CHECK);
         assert_eq!(
-            -VectorSelector::from("foo"),
-            UnaryExpr {
-                expr: Box::new(Expr::from(VectorSelector::from("foo")))
-            }
+            err.message,
+            "expected 1 argument(s) in call to 'abs', got 2"
+        );
+        assert_eq!(err.span, None);
+
+        // check does not give the set operators many-to-many matching
+        let expr = Expr::new_binary_expr(
+            Expr::from(VectorSelector::from("foo")),
+            token::T_LAND,
+            None,
+            Expr::from(VectorSelector::from("bar")),
         )
+        .unwrap();
+        assert!(check(&expr).is_ok());
+        let Expr::Binary(ex) = &expr else {
+            unreachable!()
+        };
+        assert_eq!(ex.modifier, None);
+        let Expr::Binary(ex) = check_ast(expr).unwrap() else {
+            unreachable!()
+        };
+        assert_eq!(
+            ex.modifier.unwrap().card,
+            VectorMatchCardinality::ManyToMany
+        );
     }
 
     #[test]
-    fn test_scalar_value() {
-        assert_eq!(Some(1.0), Expr::from(1.0).scalar_value());
-        assert_eq!(None, Expr::from("1.0").scalar_value());
-    }
+    fn test_modifier_display() {
+        let labels = |ls: &[&str]| ls.iter().map(|l| l.to_string()).collect::<Labels>();
 
-    #[test]
-    fn test_at_expr() {
         assert_eq!(
-            "@ <timestamp> may not be set multiple times",
-            Expr::from(VectorSelector::from("foo"))
-                .at_expr(AtModifier::try_from(1.0).unwrap())
-                .and_then(|ex| ex.at_expr(AtModifier::try_from(1.0).unwrap()))
-                .unwrap_err()
+            LabelModifier::Include(labels(&["b", "a"])).to_string(),
+            "by (a, b)"
         );
-
         assert_eq!(
-            "@ <timestamp> may not be set multiple times",
-            Expr::new_matrix_selector(
-                Expr::from(VectorSelector::from("foo")),
-                Duration::from_secs(1),
-            )
-            .and_then(|ex| ex.at_expr(AtModifier::try_from(1.0).unwrap()))
-            .and_then(|ex| ex.at_expr(AtModifier::try_from(1.0).unwrap()))
-            .unwrap_err()
+            LabelModifier::Exclude(labels(&[])).to_string(),
+            "without ()"
+        );
+        assert_eq!(
+            LabelModifier::Include(labels(&["service.name", "job"])).to_string(),
+            r#"by (job, "service.name")"#
         );
+        for query in [
+            r#"sum by ("a\"b", "c\\d") (foo)"#,
+            r#"foo / on ("a\"b") group_left ('c"d') bar"#,
+        ] {
+            let display = crate::parser::parse(query).unwrap().to_string();
+            let reparsed = crate::parser::parse(&display).unwrap();
+            assert_eq!(reparsed.to_string(), display, "{query}");
+        }
 
+        assert_eq!(VectorMatchCardinality::OneToOne.to_string(), "");
+        assert_eq!(VectorMatchCardinality::ManyToMany.to_string(), "");
         assert_eq!(
-            "@ <timestamp> may not be set multiple times",
-            Expr::new_subquery_expr(
-                Expr::from(VectorSelector::from("foo")),
-                Duration::from_secs(1),
-                None,
-            )
-            .and_then(|ex| ex.at_expr(AtModifier::try_from(1.0).unwrap()))
-            .and_then(|ex| ex.at_expr(AtModifier::try_from(1.0).unwrap()))
-            .unwrap_err()
-        )
+            VectorMatchCardinality::ManyToOne(labels(&["c"])).to_string(),
+            "group_left (c)"
+        );
+        assert_eq!(
+            VectorMatchCardinality::OneToMany(labels(&[])).to_string(),
+            "group_right ()"
+        );
+
+        let modifier = BinModifier::default()
+            .with_return_bool(true)
+            .with_matching(Some(LabelModifier::Exclude(labels(&["x"]))))
+            .with_card(VectorMatchCardinality::OneToMany(labels(&["y"])));
+        assert_eq!(modifier.to_string(), "bool ignoring (x) group_right (y)");
+        let modifier = modifier.with_matching(None).with_return_bool(false);
+        assert_eq!(modifier.to_string(), "");
     }
 
     #[test]
-    fn test_offset_expr() {
+    fn test_absent_labels() {
+        let labels = |q: &str| -> Option<Vec<(String, String)>> {
+            match crate::parser::parse(q).unwrap() {
+                Expr::Call(call) => call.absent_labels().map(|l| l.into_iter().collect()),
+                _ => unreachable!(),
+            }
+        };
+        let pairs = |v: &[(&str, &str)]| -> Option<Vec<(String, String)>> {
+            Some(
+                v.iter()
+                    .map(|(a, b)| (a.to_string(), b.to_string()))
+                    .collect(),
+            )
+        };
+
+        assert_eq!(labels("absent(foo)"), pairs(&[]));
         assert_eq!(
-            "offset may not be set multiple times",
-            Expr::from(VectorSelector::from("foo"))
-                .offset_expr(Offset::Pos(Duration::from_secs(1000)))
-                .and_then(|ex| ex.offset_expr(Offset::Pos(Duration::from_secs(1000))))
-                .unwrap_err()
+            labels(r#"absent(foo{job="a", instance=~".*", env="b"})"#),
+            pairs(&[("env", "b"), ("job", "a")])
         );
-
         assert_eq!(
-            "offset may not be set multiple times",
-            Expr::new_matrix_selector(
-                Expr::from(VectorSelector::from("foo")),
-                Duration::from_secs(1),
-            )
-            .and_then(|ex| ex.offset_expr(Offset::Pos(Duration::from_secs(1000))))
-            .and_then(|ex| ex.offset_expr(Offset::Pos(Duration::from_secs(1000))))
-            .unwrap_err()
+            labels(r#"absent(foo{job="a", job="b", env="c"})"#),
+            pairs(&[("env", "c")])
         );
-
+        assert_eq!(labels(r#"absent(foo{job="a", job!="b"})"#), pairs(&[]));
         assert_eq!(
-            "offset may not be set multiple times",
-            Expr::new_subquery_expr(
-                Expr::from(VectorSelector::from("foo")),
-                Duration::from_secs(1),
-                None,
-            )
-            .and_then(|ex| ex.offset_expr(Offset::Pos(Duration::from_secs(1000))))
-            .and_then(|ex| ex.offset_expr(Offset::Pos(Duration::from_secs(1000))))
-            .unwrap_err()
+            labels(r#"absent({__name__="foo", job="a"})"#),
+            pairs(&[("job", "a")])
+        );
+        assert_eq!(
+            labels(r#"absent_over_time(foo{job="a"}[5m])"#),
+            pairs(&[("job", "a")])
         );
+        assert_eq!(labels(r#"absent_over_time(foo{job="a"}[5m:])"#), pairs(&[]));
+        assert_eq!(labels(r#"absent(sum(foo{job="a"}))"#), pairs(&[]));
+        assert_eq!(labels(r#"abs(foo{job="a"})"#), None);
     }
 }
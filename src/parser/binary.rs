@@ -0,0 +1,160 @@
+// Copyright 2023 Greptime Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::parser::Expr;
+
+/// version of the binary format, bump it whenever the AST changes in a way
+/// that makes previously written bytes unreadable. Version 2 encodes the step
+/// of a subquery as a [`SubqueryStep`](crate::parser::SubqueryStep).
+pub const BINARY_FORMAT_VERSION: u8 = 2;
+
+/// the error of a value which can not be deserialized, postcard drops the
+/// messages of these errors.
+const INVALID_VALUE: &str =
+    "invalid value in binary format, like an unknown function or operator or an invalid regex";
+
+/// compact binary format of the AST, the first byte is [`BINARY_FORMAT_VERSION`],
+/// followed by the [postcard](https://docs.rs/postcard) encoded expr.
+///
+/// Functions are encoded by their name only, and looked up again when decoding,
+/// so bytes with a function this version does not know, like one added in a
+/// later release, fail to decode. Adding a function does not change the version.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::{parse, Expr};
+///
+/// let expr = parse(r#"sum by (job) (rate(foo{code=~"5.."}[5m]))"#).unwrap();
+/// let bytes = expr.to_bytes().unwrap();
+/// assert_eq!(Expr::from_bytes(&bytes).unwrap(), expr);
+/// ```
+impl Expr {
+    /// fails if the expr contains an [`Expr::Extension`].
+    pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
+        postcard::to_extend(self, vec![BINARY_FORMAT_VERSION]).map_err(|e| e.to_string())
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Expr, String> {
+        match bytes.split_first() {
+            Some((&BINARY_FORMAT_VERSION, rest)) => {
+                postcard::from_bytes(rest).map_err(|e| match e {
+                    postcard::Error::SerdeDeCustom => INVALID_VALUE.into(),
+                    e => e.to_string(),
+                })
+            }
+            Some((version, _)) => Err(format!(
+                "unsupported binary format version {version}, expected {BINARY_FORMAT_VERSION}"
+            )),
+            None => Err("empty bytes for binary format".into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::ast::ExtensionExpr;
+    use crate::parser::{parse, Extension, ValueType};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_binary_round_trip() {
+        let cases = vec![
+            "foo",
+            "-1.5",
+            r#""a string""#,
+            r#"foo{job="api",code!~"5.."} @ 1609746000 offset 5m"#,
+            "foo @ -10.5",
+            "rate(foo[5m] @ start())[1h:5m] offset -1w",
+            "topk by (job) (5, sum without (code) (foo))",
+            r#"count_values("code", foo)"#,
+            "a + on(x) group_left(y) b",
+            "a == bool ignoring(x) b",
+            "(a or b) unless c",
+            r#"label_replace(up, "a", "$1", "b", "(.*)")"#,
+        ];
+        for case in cases {
+            let expr = parse(case).unwrap();
+            let bytes = expr.to_bytes().unwrap();
+            assert_eq!(Expr::from_bytes(&bytes).unwrap(), expr, "{case}");
+        }
+
+        let expr = parse("max_over_time(foo[1h:])[1d:5m]").unwrap();
+        let expr =
+            crate::util::rewrite::fill_subquery_steps(&expr, std::time::Duration::from_secs(30));
+        assert_eq!(Expr::from_bytes(&expr.to_bytes().unwrap()).unwrap(), expr);
+    }
+
+    #[test]
+    fn test_binary_version() {
+        let mut bytes = parse("foo").unwrap().to_bytes().unwrap();
+        assert_eq!(bytes[0], BINARY_FORMAT_VERSION);
+
+        bytes[0] = BINARY_FORMAT_VERSION + 1;
+        assert_eq!(
+            Expr::from_bytes(&bytes).unwrap_err(),
+            format!(
+                "unsupported binary format version {}, expected {BINARY_FORMAT_VERSION}",
+                BINARY_FORMAT_VERSION + 1
+            )
+        );
+        // version 1 had no SubqueryStep, its bytes are not read as version 2
+        bytes[0] = 1;
+        assert_eq!(
+            Expr::from_bytes(&bytes).unwrap_err(),
+            format!("unsupported binary format version 1, expected {BINARY_FORMAT_VERSION}")
+        );
+        assert!(Expr::from_bytes(&[]).is_err());
+        assert!(Expr::from_bytes(&[BINARY_FORMAT_VERSION, 0xff]).is_err());
+    }
+
+    #[test]
+    fn test_binary_unknown_function() {
+        let bytes = parse("rate(foo[5m])").unwrap().to_bytes().unwrap();
+        let at = bytes.windows(4).position(|w| w == b"rate").unwrap();
+        let mut unknown = bytes.clone();
+        unknown[at..at + 4].copy_from_slice(b"xate");
+        assert_eq!(Expr::from_bytes(&unknown).unwrap_err(), INVALID_VALUE);
+    }
+
+    #[derive(Debug)]
+    struct DummyExtension;
+
+    impl ExtensionExpr for DummyExtension {
+        fn as_any(&self) -> &dyn std::any::Any {
+            self
+        }
+
+        fn name(&self) -> &str {
+            "dummy"
+        }
+
+        fn value_type(&self) -> ValueType {
+            ValueType::Vector
+        }
+
+        fn children(&self) -> &[Expr] {
+            &[]
+        }
+    }
+
+    #[test]
+    fn test_binary_extension() {
+        let expr = Expr::Extension(Extension {
+            expr: Arc::new(DummyExtension),
+        });
+        assert!(expr.to_bytes().is_err());
+    }
+}
@@ -0,0 +1,397 @@
+// Copyright 2023 Greptime Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::label::{MatchOp, Matcher, NameValidationScheme, METRIC_NAME};
+use crate::parser::ast::{needs_parens, unary_needs_parens};
+use crate::parser::token::token_display;
+use crate::parser::{
+    AtModifier, BinModifier, Expr, LabelModifier, Offset, VectorMatchCardinality, VectorSelector,
+};
+use std::fmt::Write;
+use std::time::{Duration, SystemTime};
+
+/// version of the canonical string format, bumped whenever the canonical
+/// string of any expr changes.
+pub const CANONICAL_FORMAT_VERSION: u8 = 1;
+
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::parse;
+///
+/// let a = parse(r#"sum by (job, code) (rate({__name__="foo", job='a"b'}[90s]))"#).unwrap();
+/// let b = parse(r#"sum by (code, job) (rate(foo{job="a\"b"}[1m30s]))"#).unwrap();
+/// assert_eq!(a.canonical_string(), r#"sum by(code,job)(rate(foo{job="a\"b"}[1m30s]))"#);
+/// assert_eq!(a.canonical_string(), b.canonical_string());
+/// ```
+impl Expr {
+    /// the canonical string of the expr, for cache keys and golden tests. Unlike
+    /// [`Display`](std::fmt::Display), it only changes with
+    /// [`CANONICAL_FORMAT_VERSION`], so keys should include the version.
+    ///
+    /// The format of version 1 is:
+    /// - the matchers are sorted by label, operator and value. The metric name
+    ///   is written before the braces if it is a valid legacy name, and the
+    ///   braces are left out if there are no other matchers.
+    /// - strings are double-quoted, with `\`, `"` and the control characters
+    ///   escaped, whatever quotes and escapes were used in the query.
+    /// - durations use the largest units first, from `y` down to `ms`, like `1m30s`.
+    /// - numbers are the shortest text parsing to the same value, or `NaN`,
+    ///   `Inf` and `-Inf`. `@` timestamps are seconds with three decimals.
+    /// - grouping labels are sorted and joined by `,`, an empty `by ()` is left out.
+    /// - spaces are only around binary operators and before keywords, like
+    ///   `sum by(job)(foo @ 10.000 offset 5m) > bool on(job) group_left(a) bar`.
+    /// - parentheses are kept as written.
+    /// - extensions are written by their [`Display`](std::fmt::Display).
+    pub fn canonical_string(&self) -> String {
+        let mut s = String::new();
+        write_expr(&mut s, self);
+        s
+    }
+}
+
+fn write_expr(s: &mut String, expr: &Expr) {
+    match expr {
+        Expr::Aggregate(ex) => {
+            s.push_str(token_display(ex.op.id()));
+            match &ex.modifier {
+                Some(LabelModifier::Include(labels)) if labels.is_empty() => {}
+                Some(LabelModifier::Include(labels)) => write_labels(s, " by", labels),
+                Some(LabelModifier::Exclude(labels)) => write_labels(s, " without", labels),
+                None => {}
+            }
+            s.push('(');
+            if let Some(param) = &ex.param {
+                write_expr(s, param);
+                s.push(',');
+            }
+            write_expr(s, &ex.expr);
+            s.push(')');
+        }
+        Expr::Unary(ex) => {
+            s.push('-');
+            write_operand(s, &ex.expr, unary_needs_parens(&ex.expr));
+        }
+        Expr::Binary(ex) => {
+            write_operand(s, &ex.lhs, needs_parens(&ex.op, &ex.lhs, false));
+            s.push(' ');
+            s.push_str(token_display(ex.op.id()));
+            if let Some(modifier) = &ex.modifier {
+                write_bin_modifier(s, modifier);
+            }
+            s.push(' ');
+            write_operand(s, &ex.rhs, needs_parens(&ex.op, &ex.rhs, true));
+        }
+        Expr::Paren(ex) => {
+            s.push('(');
+            write_expr(s, &ex.expr);
+            s.push(')');
+        }
+        Expr::Subquery(ex) => {
+            write_expr(s, &ex.expr);
+            s.push('[');
+            write_duration(s, ex.range);
+            s.push(':');
+            if let Some(step) = ex.step.duration() {
+                write_duration(s, step);
+            }
+            s.push(']');
+            write_modifiers(s, &ex.at, &ex.offset);
+        }
+        Expr::NumberLiteral(nl) => write_number(s, nl.val),
+        Expr::StringLiteral(sl) => write_string(s, &sl.val),
+        Expr::VectorSelector(vs) => {
+            write_selector(s, vs);
+            write_modifiers(s, &vs.at, &vs.offset);
+        }
+        Expr::MatrixSelector(ms) => {
+            write_selector(s, &ms.vector_selector);
+            s.push('[');
+            write_duration(s, ms.range);
+            s.push(']');
+            write_modifiers(s, &ms.vector_selector.at, &ms.vector_selector.offset);
+        }
+        Expr::Call(call) => {
+            s.push_str(call.func.name);
+            s.push('(');
+            for (i, arg) in call.args.iter().enumerate() {
+                if i > 0 {
+                    s.push(',');
+                }
+                write_expr(s, arg);
+            }
+            s.push(')');
+        }
+        Expr::Extension(_) => {
+            let _ = write!(s, "{expr}");
+        }
+    }
+}
+
+fn write_selector(s: &mut String, vs: &VectorSelector) {
+    let name = vs
+        .metric_name()
+        .filter(|name| NameValidationScheme::Legacy.is_valid_metric_name(name));
+    let mut matchers: Vec<&Matcher> = vs
+        .matchers
+        .matchers
+        .iter()
+        .filter(|m| {
+            !(m.op == MatchOp::Equal && m.name == METRIC_NAME && Some(m.value.as_str()) == name)
+        })
+        .collect();
+    matchers.sort_by(|a, b| {
+        (&a.name, op_order(&a.op), &a.value).cmp(&(&b.name, op_order(&b.op), &b.value))
+    });
+
+    if let Some(name) = name {
+        s.push_str(name);
+        if matchers.is_empty() {
+            return;
+        }
+    }
+    s.push('{');
+    for (i, m) in matchers.into_iter().enumerate() {
+        if i > 0 {
+            s.push(',');
+        }
+        write_label(s, &m.name);
+        s.push_str(&m.op.to_string());
+        write_string(s, &m.value);
+    }
+    s.push('}');
+}
+
+fn op_order(op: &MatchOp) -> u8 {
+    match op {
+        MatchOp::Equal => 0,
+        MatchOp::NotEqual => 1,
+        MatchOp::Re(_) => 2,
+        MatchOp::NotRe(_) => 3,
+    }
+}
+
+fn write_bin_modifier(s: &mut String, modifier: &BinModifier) {
+    if modifier.return_bool {
+        s.push_str(" bool");
+    }
+    match &modifier.matching {
+        Some(LabelModifier::Include(labels)) => write_labels(s, " on", labels),
+        Some(LabelModifier::Exclude(labels)) => write_labels(s, " ignoring", labels),
+        None => {}
+    }
+    match &modifier.card {
+        VectorMatchCardinality::ManyToOne(labels) => write_labels(s, " group_left", labels),
+        VectorMatchCardinality::OneToMany(labels) => write_labels(s, " group_right", labels),
+        VectorMatchCardinality::OneToOne | VectorMatchCardinality::ManyToMany => {}
+    }
+}
+
+/// the operand in parens if the tree has none where the grouping needs them, so
+/// `(a - b) * c` built without a paren differs from `a - b * c`.
+fn write_operand(s: &mut String, expr: &Expr, parens: bool) {
+    if parens {
+        s.push('(');
+    }
+    write_expr(s, expr);
+    if parens {
+        s.push(')');
+    }
+}
+
+fn write_labels<'a>(s: &mut String, keyword: &str, labels: impl IntoIterator<Item = &'a String>) {
+    let mut labels: Vec<&String> = labels.into_iter().collect();
+    labels.sort();
+    s.push_str(keyword);
+    s.push('(');
+    for (i, label) in labels.into_iter().enumerate() {
+        if i > 0 {
+            s.push(',');
+        }
+        write_label(s, label);
+    }
+    s.push(')');
+}
+
+fn write_label(s: &mut String, label: &str) {
+    if NameValidationScheme::Legacy.is_valid_label_name(label) {
+        s.push_str(label);
+    } else {
+        write_string(s, label);
+    }
+}
+
+fn write_modifiers(s: &mut String, at: &Option<AtModifier>, offset: &Option<Offset>) {
+    match at {
+        Some(AtModifier::Start) => s.push_str(" @ start()"),
+        Some(AtModifier::End) => s.push_str(" @ end()"),
+        Some(AtModifier::At(time)) => {
+            let (sign, d) = match time.duration_since(SystemTime::UNIX_EPOCH) {
+                Ok(d) => ("", d),
+                Err(e) => ("-", e.duration()),
+            };
+            let millis = d.as_millis();
+            let _ = write!(s, " @ {sign}{}.{:03}", millis / 1000, millis % 1000);
+        }
+        None => {}
+    }
+    match offset {
+        Some(Offset::Pos(d)) => {
+            s.push_str(" offset ");
+            write_duration(s, *d);
+        }
+        Some(Offset::Neg(d)) => {
+            s.push_str(" offset -");
+            write_duration(s, *d);
+        }
+        None => {}
+    }
+}
+
+fn write_duration(s: &mut String, d: Duration) {
+    const UNITS: [(&str, u128); 7] = [
+        ("y", 365 * 24 * 3600 * 1000),
+        ("w", 7 * 24 * 3600 * 1000),
+        ("d", 24 * 3600 * 1000),
+        ("h", 3600 * 1000),
+        ("m", 60 * 1000),
+        ("s", 1000),
+        ("ms", 1),
+    ];
+    let mut millis = d.as_millis();
+    if millis == 0 {
+        s.push_str("0s");
+        return;
+    }
+    for (unit, size) in UNITS {
+        if millis >= size {
+            let _ = write!(s, "{}{unit}", millis / size);
+            millis %= size;
+        }
+    }
+}
+
+fn write_number(s: &mut String, val: f64) {
+    if val.is_nan() {
+        s.push_str("NaN");
+    } else if val.is_infinite() {
+        s.push_str(if val > 0.0 { "Inf" } else { "-Inf" });
+    } else {
+        let _ = write!(s, "{val}");
+    }
+}
+
+fn write_string(s: &mut String, val: &str) {
+    s.push_str(&quote(val));
+}
+
+/// the value as a double quoted PromQL string, which parses back to the value.
+pub(crate) fn quote(val: &str) -> String {
+    let mut s = String::with_capacity(val.len() + 2);
+    s.push('"');
+    for ch in val.chars() {
+        match ch {
+            '\\' => s.push_str(r"\\"),
+            '"' => s.push_str("\\\""),
+            '\n' => s.push_str(r"\n"),
+            '\r' => s.push_str(r"\r"),
+            '\t' => s.push_str(r"\t"),
+            ch if ch.is_control() => {
+                let _ = write!(s, "\\u{:04x}", ch as u32);
+            }
+            ch => s.push(ch),
+        }
+    }
+    s.push('"');
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::label::NameValidationScheme;
+    use crate::parser::{parse, parse_with_options, Expr, ParseOptions, VectorSelector};
+
+    #[test]
+    fn test_canonical_string() {
+        let cases = vec![
+            ("1", "1"),
+            ("-1.5e3", "-1500"),
+            ("0x10 + Inf - NaN", "16 + Inf - NaN"),
+            (r#""a\tb""#, r#""a\tb""#),
+            (r#"'a"b'"#, r#""a\"b""#),
+            (r#"`a"b`"#, r#""a\"b""#),
+            (r#""\x41\u00e9\101""#, r#""AéA""#),
+            ("foo", "foo"),
+            (r#"{__name__="foo"}"#, "foo"),
+            (r#"{__name__="foo.bar"}"#, r#"{__name__="foo.bar"}"#),
+            (
+                r#"{a="c", __name__="foo.bar"}"#,
+                r#"{__name__="foo.bar",a="c"}"#,
+            ),
+            (
+                r#"foo{c!~"x", a="2", c=~"y", b="1", a="1"}"#,
+                r#"foo{a="1",a="2",b="1",c=~"y",c!~"x"}"#,
+            ),
+            ("foo[90s] offset -1h30m", "foo[1m30s] offset -1h30m"),
+            ("foo offset 192h @ 1.5", "foo @ 1.500 offset 1w1d"),
+            ("foo @ -10.25", "foo @ -10.250"),
+            ("foo[1y2w3d4h5m6s7ms]", "foo[1y2w3d4h5m6s7ms]"),
+            ("foo[400d]", "foo[1y5w]"),
+            ("rate(foo[5m])[1h:] @ end()", "rate(foo[5m])[1h:] @ end()"),
+            ("(foo)[1h:60s] offset 3600s", "(foo)[1h:1m] offset 1h"),
+            ("sum by () (foo)", "sum(foo)"),
+            ("sum without () (foo)", "sum without()(foo)"),
+            ("topk by (b, a) (5, foo)", "topk by(a,b)(5,foo)"),
+            (
+                r#"count_values without (a) ("v", foo)"#,
+                r#"count_values without(a)("v",foo)"#,
+            ),
+            (r#"sum by ("a.b", job) (foo)"#, r#"sum by("a.b",job)(foo)"#),
+            ("-(foo + 1)", "-(foo + 1)"),
+            (
+                "foo > bool on (b, a) group_left (d, c) bar",
+                "foo > bool on(a,b) group_left(c,d) bar",
+            ),
+            ("foo and ignoring (a) bar", "foo and ignoring(a) bar"),
+            ("foo or bar", "foo or bar"),
+            (
+                r#"label_replace(foo, "dst", "$1", "src", "(.*)")"#,
+                r#"label_replace(foo,"dst","$1","src","(.*)")"#,
+            ),
+        ];
+        let options =
+            ParseOptions::default().with_name_validation_scheme(NameValidationScheme::Utf8);
+        for (input, expected) in cases {
+            let expr = parse_with_options(input, &options).unwrap();
+            assert_eq!(expr.canonical_string(), expected, "{input}");
+        }
+
+        // trees built without parens keep their grouping
+        let (a, b, c) = (
+            || Expr::from(VectorSelector::from("a")),
+            || Expr::from(VectorSelector::from("b")),
+            || Expr::from(VectorSelector::from("c")),
+        );
+        let cases = vec![
+            ((a() - b()) * c(), "(a - b) * c", "a - b * c"),
+            (-(a() + b()), "-(a + b)", "-a + b"),
+            (a() - (b() - c()), "a - (b - c)", "a - b - c"),
+        ];
+        for (expr, expected, other) in cases {
+            assert_eq!(expr.canonical_string(), expected);
+            assert_eq!(parse(expected).unwrap().canonical_string(), expected);
+            assert_ne!(parse(other).unwrap().canonical_string(), expected);
+        }
+    }
+}
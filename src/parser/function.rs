@@ -13,6 +13,7 @@
 // limitations under the License.
 
 use std::collections::{HashMap, HashSet};
+use std::ops::Index;
 
 use lazy_static::lazy_static;
 
@@ -20,6 +21,7 @@ use crate::parser::{Expr, ValueType};
 
 /// called by func in Call
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "ser", derive(serde::Serialize, serde::Deserialize))]
 pub struct FunctionArgs {
     pub args: Vec<Box<Expr>>,
 }
@@ -35,11 +37,20 @@ impl FunctionArgs {
         }
     }
 
-    pub fn append_args(mut self: FunctionArgs, expr: Expr) -> Self {
+    pub fn append_args(self: FunctionArgs, expr: Expr) -> Self {
+        self.push(expr)
+    }
+
+    /// append the arg, like [`FunctionArgs::append_args`].
+    pub fn push(mut self, expr: Expr) -> Self {
         self.args.push(Box::new(expr));
         self
     }
 
+    pub fn iter(&self) -> impl Iterator<Item = &Expr> {
+        self.args.iter().map(|arg| &**arg)
+    }
+
     pub fn is_empty(&self) -> bool {
         self.args.is_empty()
     }
@@ -57,6 +68,46 @@ impl FunctionArgs {
     }
 }
 
+impl From<Vec<Expr>> for FunctionArgs {
+    fn from(args: Vec<Expr>) -> Self {
+        args.into_iter().collect()
+    }
+}
+
+impl FromIterator<Expr> for FunctionArgs {
+    fn from_iter<I: IntoIterator<Item = Expr>>(iter: I) -> Self {
+        Self {
+            args: iter.into_iter().map(Box::new).collect(),
+        }
+    }
+}
+
+impl IntoIterator for FunctionArgs {
+    type Item = Expr;
+    type IntoIter = std::iter::Map<std::vec::IntoIter<Box<Expr>>, fn(Box<Expr>) -> Expr>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.args.into_iter().map(|arg| *arg)
+    }
+}
+
+impl<'a> IntoIterator for &'a FunctionArgs {
+    type Item = &'a Expr;
+    type IntoIter = std::iter::Map<std::slice::Iter<'a, Box<Expr>>, fn(&Box<Expr>) -> &Expr>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.args.iter().map(|arg| &**arg)
+    }
+}
+
+impl Index<usize> for FunctionArgs {
+    type Output = Expr;
+
+    fn index(&self, index: usize) -> &Expr {
+        &self.args[index]
+    }
+}
+
 /// Functions is a list of all functions supported by PromQL, including their types.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Function {
@@ -64,9 +115,28 @@ pub struct Function {
     pub arg_types: Vec<ValueType>,
     pub variadic: bool,
     pub return_type: ValueType,
+    /// experimental functions are only accepted if enabled in the parse options.
+    pub experimental: bool,
+    /// the function replacing a deprecated one.
+    pub replacement: Option<&'static str>,
 }
 
 impl Function {
+    /// a function with the types of its args and result, like the predefined ones,
+    /// which may be used to build calls to functions of custom evaluators.
+    ///
+    /// # Examples
+    ///
+    /// ``` rust
+    /// use promql_parser::parser::{parse, Expr, Function, FunctionArgs, ValueType};
+    ///
+    /// let func = Function::new("my_func", vec![ValueType::Vector, ValueType::Scalar], false, ValueType::Vector);
+    /// let args = FunctionArgs::from(vec![parse("foo").unwrap(), parse("1").unwrap()]);
+    /// assert_eq!(args[1], parse("1").unwrap());
+    ///
+    /// let call = Expr::new_call(func, args).unwrap();
+    /// assert_eq!(call.to_string(), "my_func(foo, 1)");
+    /// ```
     pub fn new(
         name: &'static str,
         arg_types: Vec<ValueType>,
@@ -78,8 +148,24 @@ impl Function {
             arg_types,
             variadic,
             return_type,
+            experimental: false,
+            replacement: None,
         }
     }
+
+    pub fn with_experimental(mut self, experimental: bool) -> Self {
+        self.experimental = experimental;
+        self
+    }
+
+    pub fn with_replacement(mut self, replacement: Option<&'static str>) -> Self {
+        self.replacement = replacement;
+        self
+    }
+
+    pub fn is_deprecated(&self) -> bool {
+        self.replacement.is_some()
+    }
 }
 
 macro_rules! map {
@@ -89,7 +175,11 @@ macro_rules! map {
             let mut m: HashMap<&'static str, Function> = HashMap::new();
             $(
                 let variadic = FUNCTIONS_WITH_VARIADIC_ARGS.contains($name);
-                let func = Function::new($name, $arg, variadic, $ret);
+                let experimental = EXPERIMENTAL_FUNCTIONS.contains($name);
+                let replacement = DEPRECATED_FUNCTIONS.get($name).copied();
+                let func = Function::new($name, $arg, variadic, $ret)
+                    .with_experimental(experimental)
+                    .with_replacement(replacement);
                 m.insert($name, func);
             )*
             m
@@ -109,7 +199,23 @@ lazy_static! {
         "minute",
         "label_join",
         "round",
+        "sort_by_label",
+        "sort_by_label_desc",
+    ]);
+    static ref EXPERIMENTAL_FUNCTIONS: HashSet<&'static str> = HashSet::from([
+        "double_exponential_smoothing",
+        "first_over_time",
+        "mad_over_time",
+        "sort_by_label",
+        "sort_by_label_desc",
+        "ts_of_first_over_time",
+        "ts_of_last_over_time",
+        "ts_of_max_over_time",
+        "ts_of_min_over_time",
     ]);
+    // deprecated functions with their replacements
+    static ref DEPRECATED_FUNCTIONS: HashMap<&'static str, &'static str> =
+        HashMap::from([("holt_winters", "double_exponential_smoothing")]);
     static ref FUNCTIONS: HashMap<&'static str, Function> = map!(
         ("abs", vec![ValueType::Vector], ValueType::Vector),
         ("absent", vec![ValueType::Vector], ValueType::Vector),
@@ -156,7 +262,17 @@ lazy_static! {
         ("deg", vec![ValueType::Vector], ValueType::Vector),
         ("delta", vec![ValueType::Matrix], ValueType::Vector),
         ("deriv", vec![ValueType::Matrix], ValueType::Vector),
+        (
+            "double_exponential_smoothing",
+            vec![ValueType::Matrix, ValueType::Scalar, ValueType::Scalar],
+            ValueType::Vector
+        ),
         ("exp", vec![ValueType::Vector], ValueType::Vector),
+        (
+            "first_over_time",
+            vec![ValueType::Matrix],
+            ValueType::Vector
+        ),
         ("floor", vec![ValueType::Vector], ValueType::Vector),
         (
             "histogram_count",
@@ -208,6 +324,7 @@ lazy_static! {
         ("ln", vec![ValueType::Vector], ValueType::Vector),
         ("log10", vec![ValueType::Vector], ValueType::Vector),
         ("log2", vec![ValueType::Vector], ValueType::Vector),
+        ("mad_over_time", vec![ValueType::Matrix], ValueType::Vector),
         ("max_over_time", vec![ValueType::Matrix], ValueType::Vector),
         ("min_over_time", vec![ValueType::Matrix], ValueType::Vector),
         ("minute", vec![ValueType::Vector], ValueType::Vector),
@@ -241,6 +358,16 @@ lazy_static! {
         ("sin", vec![ValueType::Vector], ValueType::Vector),
         ("sinh", vec![ValueType::Vector], ValueType::Vector),
         ("sort", vec![ValueType::Vector], ValueType::Vector),
+        (
+            "sort_by_label",
+            vec![ValueType::Vector, ValueType::String],
+            ValueType::Vector
+        ),
+        (
+            "sort_by_label_desc",
+            vec![ValueType::Vector, ValueType::String],
+            ValueType::Vector
+        ),
         ("sort_desc", vec![ValueType::Vector], ValueType::Vector),
         ("sqrt", vec![ValueType::Vector], ValueType::Vector),
         (
@@ -258,16 +385,67 @@ lazy_static! {
         ("tanh", vec![ValueType::Vector], ValueType::Vector),
         ("time", vec![], ValueType::Scalar),
         ("timestamp", vec![ValueType::Vector], ValueType::Vector),
+        (
+            "ts_of_first_over_time",
+            vec![ValueType::Matrix],
+            ValueType::Vector
+        ),
+        (
+            "ts_of_last_over_time",
+            vec![ValueType::Matrix],
+            ValueType::Vector
+        ),
+        (
+            "ts_of_max_over_time",
+            vec![ValueType::Matrix],
+            ValueType::Vector
+        ),
+        (
+            "ts_of_min_over_time",
+            vec![ValueType::Matrix],
+            ValueType::Vector
+        ),
         ("vector", vec![ValueType::Scalar], ValueType::Vector),
         ("year", vec![ValueType::Vector], ValueType::Vector)
     );
 }
 
+/// all predefined functions sorted by name, which is the source of truth for
+/// listing the supported and experimental functions.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::function::functions;
+///
+/// let experimental: Vec<&str> = functions()
+///     .filter(|f| f.experimental)
+///     .map(|f| f.name)
+///     .collect();
+/// assert!(experimental.contains(&"first_over_time"));
+/// ```
+pub fn functions() -> impl Iterator<Item = &'static Function> {
+    let mut functions: Vec<&Function> = FUNCTIONS.values().collect();
+    functions.sort_unstable_by_key(|f| f.name);
+    functions.into_iter()
+}
+
 /// get_function returns a predefined Function object for the given name.
-pub(crate) fn get_function(name: &str) -> Option<Function> {
+pub fn get_function(name: &str) -> Option<Function> {
     FUNCTIONS.get(name).cloned()
 }
 
+/// whether the arg of the function is a label name, like the destination and
+/// source labels of `label_replace` and `label_join`, or the labels of `sort_by_label`.
+pub(crate) fn is_label_arg(func: &str, idx: usize) -> bool {
+    match func {
+        "label_replace" => idx == 1 || idx == 3,
+        "label_join" => idx == 1 || idx >= 3,
+        "sort_by_label" | "sort_by_label_desc" => idx >= 1,
+        _ => false,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -298,4 +476,46 @@ mod tests {
 
         assert_eq!(args1, args2);
     }
+
+    #[test]
+    fn test_experimental_functions() {
+        for name in EXPERIMENTAL_FUNCTIONS.iter() {
+            assert!(get_function(name).unwrap().experimental, "{name}");
+        }
+        assert!(!get_function("last_over_time").unwrap().experimental);
+        let names: Vec<&str> = functions().map(|f| f.name).collect();
+        assert_eq!(names.len(), FUNCTIONS.len());
+        assert!(names.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[test]
+    fn test_function_args() {
+        let (foo, bar) = (parse("foo").unwrap(), parse("bar").unwrap());
+        let args = FunctionArgs::empty_args()
+            .push(foo.clone())
+            .push(bar.clone());
+        assert_eq!(args, FunctionArgs::from(vec![foo.clone(), bar.clone()]));
+        assert_eq!(args, vec![foo.clone(), bar.clone()].into_iter().collect());
+        assert_eq!(
+            args,
+            FunctionArgs::new_args(foo.clone()).append_args(bar.clone())
+        );
+        assert_eq!((&args[0], &args[1]), (&foo, &bar));
+        assert_eq!(args.iter().collect::<Vec<_>>(), vec![&foo, &bar]);
+        assert_eq!((&args).into_iter().collect::<Vec<_>>(), vec![&foo, &bar]);
+        assert_eq!(args.into_iter().collect::<Vec<_>>(), vec![foo, bar]);
+    }
+
+    #[test]
+    fn test_deprecated_functions() {
+        for (name, replacement) in DEPRECATED_FUNCTIONS.iter() {
+            let func = get_function(name).unwrap();
+            let new = get_function(replacement).unwrap();
+            assert!(func.is_deprecated(), "{name}");
+            assert!(!new.is_deprecated(), "{replacement}");
+            assert_eq!(func.arg_types, new.arg_types, "{name}");
+            assert_eq!(func.return_type, new.return_type, "{name}");
+        }
+        assert!(!get_function("rate").unwrap().is_deprecated());
+    }
 }
@@ -16,22 +16,120 @@ use crate::parser::token::*;
 use lrlex::{DefaultLexeme, LRNonStreamingLexer};
 use lrpar::Lexeme;
 use std::fmt::Debug;
+use std::ops::Range;
 
 const ESCAPE_SYMBOLS: &str = r#"abfnrtv\01234567xuU"#;
 const STRING_SYMBOLS: &str = r#"'"`"#;
 
 pub type LexemeType = DefaultLexeme<TokenId>;
 
-pub fn lexer(s: &str) -> Result<LRNonStreamingLexer<LexemeType, TokenId>, String> {
-    let lexemes: Vec<Result<LexemeType, String>> = Lexer::new(s).collect();
+pub fn lexer(s: &str) -> Result<LRNonStreamingLexer<'_, '_, LexemeType, TokenId>, String> {
+    lexer_with_check(s, |_| Ok(())).map_err(|e| e.message)
+}
+
+/// the value of a string, `raw` is the text between its quotes. The escape
+/// sequences are resolved like Go's `strconv.Unquote` does, which Prometheus
+/// uses, raw strings in backticks are taken as they are. Go strings may hold
+/// any bytes, like `"\xff"`, the ones which are not UTF-8 are replaced by U+FFFD.
+pub(crate) fn unquote(raw: &str, quote: char) -> Result<String, String> {
+    if quote == '`' || !raw.contains('\\') {
+        return Ok(raw.to_string());
+    }
+    let invalid = || format!("error unquoting string {quote}{raw}{quote}: invalid syntax");
+    // \x and octal escapes are bytes, which may be part of a multi-byte char
+    let mut out: Vec<u8> = Vec::with_capacity(raw.len());
+    let mut chars = raw.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            out.extend_from_slice(ch.encode_utf8(&mut [0; 4]).as_bytes());
+            continue;
+        }
+        let next = chars.next().ok_or_else(invalid)?;
+        let simple = match next {
+            'a' => Some('\x07'),
+            'b' => Some('\x08'),
+            'f' => Some('\x0c'),
+            'n' => Some('\n'),
+            'r' => Some('\r'),
+            't' => Some('\t'),
+            'v' => Some('\x0b'),
+            '\\' => Some('\\'),
+            ch if ch == quote => Some(ch),
+            _ => None,
+        };
+        if let Some(ch) = simple {
+            out.extend_from_slice(ch.encode_utf8(&mut [0; 4]).as_bytes());
+            continue;
+        }
+        let (radix, len, first) = match next {
+            'x' => (16, 2, None),
+            'u' => (16, 4, None),
+            'U' => (16, 8, None),
+            '0'..='7' => (8, 2, Some(next)),
+            _ => return Err(invalid()),
+        };
+        let digits: String = first.into_iter().chain(chars.by_ref().take(len)).collect();
+        let expected = len + usize::from(first.is_some());
+        if digits.chars().count() != expected || !digits.chars().all(|c| c.is_digit(radix)) {
+            return Err(invalid());
+        }
+        let code = u32::from_str_radix(&digits, radix).map_err(|_| invalid())?;
+        match next {
+            'u' | 'U' => {
+                let ch = char::from_u32(code).ok_or_else(invalid)?;
+                out.extend_from_slice(ch.encode_utf8(&mut [0; 4]).as_bytes());
+            }
+            _ => out.push(u8::try_from(code).map_err(|_| invalid())?),
+        }
+    }
+    Ok(String::from_utf8_lossy(&out).into_owned())
+}
+
+/// an error of [`lexer_with_check`], the span is the text the lexer failed on,
+/// None for the errors of the check.
+#[derive(Debug)]
+pub(crate) struct LexError {
+    pub(crate) message: String,
+    pub(crate) span: Option<Range<usize>>,
+}
+
+/// same as [`lexer`], but `check` is called with the number of tokens lexed so far,
+/// excluding the end of input, and lexing is aborted on the first error it returns.
+pub(crate) fn lexer_with_check(
+    s: &str,
+    mut check: impl FnMut(usize) -> Result<(), String>,
+) -> Result<LRNonStreamingLexer<'_, '_, LexemeType, TokenId>, LexError> {
+    #[cfg(feature = "tracing")]
+    let span = tracing::debug_span!("promql.lex", tokens = tracing::field::Empty).entered();
+    let mut lexemes: Vec<Result<LexemeType, String>> = Vec::new();
+    let mut count = 0;
+    let mut lexer = Lexer::new(s);
+    for lexeme in lexer.by_ref() {
+        if matches!(&lexeme, Ok(l) if l.tok_id() != T_EOF) {
+            count += 1;
+            check(count).map_err(|message| LexError {
+                message,
+                span: None,
+            })?;
+        }
+        lexemes.push(lexeme);
+    }
+    #[cfg(feature = "tracing")]
+    span.record("tokens", count);
     match lexemes.last() {
-        Some(Err(info)) => Err(info.into()),
+        Some(Err(info)) => Err(LexError {
+            message: info.into(),
+            span: Some(lexer.error_span()),
+        }),
         Some(Ok(_)) => {
             // TODO: use better error mechanism, instead of filtering the err.
             let lexemes = lexemes.into_iter().filter_map(|l| l.ok()).map(Ok).collect();
             Ok(LRNonStreamingLexer::new(s, lexemes, Vec::new()))
         }
-        None => Err(format!("no expression found in input: '{s}'")),
+        None => Err(LexError {
+            message: format!("no expression found in input: '{s}'"),
+            span: None,
+        }),
     }
 }
 
@@ -60,11 +158,13 @@ struct Context {
     start: usize, // Start position of one Token, increment by char.len_utf8.
     pos: usize,   // Current position in the input, increment by char.len_utf8.
 
-    paren_depth: usize, // Nesting depth of ( ) exprs, 0 means no parens.
-    brace_open: bool,   // Whether a { is opened.
-    bracket_open: bool, // Whether a [ is opened.
-    got_colon: bool,    // Whether we got a ':' after [ was opened.
-    eof: bool,          // Whether we got end of file
+    parens: Vec<usize>,   // Positions of the unclosed ( exprs.
+    brace_open: bool,     // Whether a { is opened.
+    brace_start: usize,   // Position of the last opened {.
+    bracket_open: bool,   // Whether a [ is opened.
+    bracket_start: usize, // Position of the last opened [.
+    got_colon: bool,      // Whether we got a ':' after [ was opened.
+    eof: bool,            // Whether we got end of file
 }
 
 impl Context {
@@ -75,9 +175,11 @@ impl Context {
             start: 0,
             pos: 0,
 
-            paren_depth: 0,
+            parens: vec![],
             brace_open: false,
+            brace_start: 0,
             bracket_open: false,
+            bracket_start: 0,
             got_colon: false,
             eof: false,
         }
@@ -147,6 +249,8 @@ impl Context {
 struct Lexer {
     state: State,
     ctx: Context,
+    /// the position of the unclosed char of the last error, like `(`.
+    unclosed: Option<usize>,
 }
 
 /// block for context operations.
@@ -154,7 +258,11 @@ impl Lexer {
     fn new(input: &str) -> Self {
         let ctx = Context::new(input);
         let state = State::Start;
-        Self { state, ctx }
+        Self {
+            state,
+            ctx,
+            unclosed: None,
+        }
     }
 
     fn is_inside_braces(&self) -> bool {
@@ -167,6 +275,7 @@ impl Lexer {
 
     fn dive_into_braces(&mut self) {
         self.ctx.brace_open = true;
+        self.ctx.brace_start = self.ctx.start;
     }
 
     fn is_inside_brackets(&self) -> bool {
@@ -179,6 +288,7 @@ impl Lexer {
 
     fn dive_into_brackets(&mut self) {
         self.ctx.bracket_open = true;
+        self.ctx.bracket_start = self.ctx.start;
     }
 
     fn is_colon_scanned(&self) -> bool {
@@ -193,26 +303,38 @@ impl Lexer {
         self.ctx.got_colon = false;
     }
 
-    /// true only if paren depth less than MAX
-    fn inc_paren_depth(&mut self) -> bool {
-        if self.ctx.paren_depth < usize::MAX {
-            self.ctx.paren_depth += 1;
-            return true;
-        }
-        false
+    fn inc_paren_depth(&mut self) {
+        self.ctx.parens.push(self.ctx.start);
     }
 
-    /// true only if paren depth larger than 1
+    /// true only if there is an unclosed left parenthesis
     fn dec_paren_depth(&mut self) -> bool {
-        if self.ctx.paren_depth >= 1 {
-            self.ctx.paren_depth -= 1;
-            return true;
-        }
-        false
+        self.ctx.parens.pop().is_some()
     }
 
     fn is_paren_balanced(&self) -> bool {
-        self.ctx.paren_depth == 0
+        self.ctx.parens.is_empty()
+    }
+
+    /// position of the innermost unclosed left parenthesis
+    fn unclosed_paren(&self) -> Option<usize> {
+        self.ctx.parens.last().copied()
+    }
+
+    /// the error of the char at the position which is never closed.
+    fn unclosed(&mut self, pos: usize, message: String) -> State {
+        self.unclosed = Some(pos);
+        State::Err(message)
+    }
+
+    /// the span of the last error, which is the unclosed char, or the text
+    /// lexed when lexing failed, like the unexpected character.
+    fn error_span(&self) -> Range<usize> {
+        match self.unclosed {
+            // the unclosed chars are all ascii
+            Some(pos) => pos..pos + 1,
+            None => self.ctx.start..self.ctx.pos,
+        }
     }
 
     fn pop(&mut self) -> Option<char> {
@@ -285,8 +407,9 @@ impl Lexer {
 
         let c = match self.pop() {
             None => {
-                if !self.is_paren_balanced() {
-                    return State::Err("unclosed left parenthesis".into());
+                if let Some(pos) = self.unclosed_paren() {
+                    let message = format!("unclosed left parenthesis at position {pos}");
+                    return self.unclosed(pos, message);
                 }
 
                 if !self.is_eof() {
@@ -349,10 +472,8 @@ impl Lexer {
             ch if is_alpha(ch) || ch == ':' => State::KeywordOrIdentifier,
             ch if STRING_SYMBOLS.contains(ch) => State::String(ch),
             '(' => {
-                if self.inc_paren_depth() {
-                    return State::Lexeme(T_LEFT_PAREN);
-                }
-                State::Err("too many left parentheses".into())
+                self.inc_paren_depth();
+                State::Lexeme(T_LEFT_PAREN)
             }
             ')' => {
                 if self.is_paren_balanced() {
@@ -544,9 +665,10 @@ impl Lexer {
     }
 
     /// scans a quoted string. The initial quote has already been consumed.
+    /// Raw strings in backticks have no escape sequences.
     fn accept_string(&mut self, symbol: char) -> State {
         while let Some(ch) = self.pop() {
-            if ch == '\\' {
+            if ch == '\\' && symbol != '`' {
                 return State::Escape(symbol);
             }
 
@@ -588,7 +710,13 @@ impl Lexer {
                 State::Lexeme(T_RIGHT_BRACE)
             }
             Some(ch) => State::Err(format!("unexpected character inside braces: '{ch}'")),
-            None => State::Err("unexpected end of input inside braces".into()),
+            None => {
+                let pos = self.ctx.brace_start;
+                let message = format!(
+                    "unexpected end of input inside braces, unclosed left brace at position {pos}"
+                );
+                self.unclosed(pos, message)
+            }
         }
     }
 
@@ -639,7 +767,13 @@ impl Lexer {
             }
             Some('[') => State::Err("unexpected left brace '[' inside brackets".into()),
             Some(ch) => State::Err(format!("unexpected character inside brackets: '{ch}'")),
-            None => State::Err("unexpected end of input inside brackets".into()),
+            None => {
+                let pos = self.ctx.bracket_start;
+                let message = format!(
+                    "unexpected end of input inside brackets, unclosed left bracket at position {pos}"
+                );
+                self.unclosed(pos, message)
+            }
         }
     }
 
@@ -702,13 +836,10 @@ mod tests {
     /// - MatchTuple.1 is the expected generated Lexemes
     /// - MatchTuple.2 is the Err info if the input is invalid PromQL query
     type MatchTuple = (&'static str, Vec<LexemeTuple>, Option<&'static str>);
+    type LexemeResults = Vec<Result<LexemeType, String>>;
 
     fn assert_matches(v: Vec<MatchTuple>) {
-        let cases: Vec<(
-            &str,
-            Vec<Result<LexemeType, String>>,
-            Vec<Result<LexemeType, String>>,
-        )> = v
+        let cases: Vec<(&str, LexemeResults, LexemeResults)> = v
             .into_iter()
             .map(|(input, lexemes, err)| {
                 let mut expected: Vec<Result<LexemeType, String>> = lexemes
@@ -716,12 +847,11 @@ mod tests {
                     .map(|(token_id, start, len)| Ok(LexemeType::new(token_id, start, len)))
                     .collect();
 
-                if err.is_some() {
-                    expected.push(Err(err.unwrap().to_string()));
+                if let Some(err) = err {
+                    expected.push(Err(err.to_string()));
                 }
 
                 let actual: Vec<Result<LexemeType, String>> = Lexer::new(input)
-                    .into_iter()
                     // in lex test cases, we don't compare the EOF token
                     .filter(|r| !matches!(r, Ok(l) if l.tok_id() == T_EOF))
                     .collect();
@@ -822,6 +952,14 @@ mod tests {
                 None,
             ),
             ("0x123", vec![(T_NUMBER, 0, 5)], None),
+            ("0X1F", vec![(T_NUMBER, 0, 4)], None),
+            ("0755", vec![(T_NUMBER, 0, 4)], None),
+            ("1e+3", vec![(T_NUMBER, 0, 4)], None),
+            ("5e-3", vec![(T_NUMBER, 0, 4)], None),
+            (".5e3", vec![(T_NUMBER, 0, 4)], None),
+            ("5e", vec![(T_NUMBER, 0, 2)], None),
+            ("0b101", vec![], Some("bad number or duration syntax: 0b")),
+            ("0o17", vec![], Some("bad number or duration syntax: 0o")),
         ];
         assert_matches(cases);
     }
@@ -836,11 +974,8 @@ mod tests {
                 vec![],
                 Some("unknown escape sequence '.'"),
             ),
-            (
-                "`test\\.expression`",
-                vec![],
-                Some("unknown escape sequence '.'"),
-            ),
+            // raw strings have no escape sequences
+            ("`test\\.expression`", vec![(T_STRING, 1, 16)], None),
             (".٩", vec![], Some("unexpected character after '.': '٩'")),
             // TODO: accept_escape SHOULD support invalid escape character
             // "\xff"
@@ -1057,7 +1192,7 @@ mod tests {
             (
                 "(",
                 vec![(T_LEFT_PAREN, 0, 1)],
-                Some("unclosed left parenthesis"),
+                Some("unclosed left parenthesis at position 0"),
             ),
             (")", vec![], Some("unexpected right parenthesis ')'")),
             (
@@ -1072,12 +1207,12 @@ mod tests {
                     (T_LEFT_PAREN, 1, 1),
                     (T_RIGHT_PAREN, 2, 1),
                 ],
-                Some("unclosed left parenthesis"),
+                Some("unclosed left parenthesis at position 0"),
             ),
             (
                 "{",
                 vec![(T_LEFT_BRACE, 0, 1)],
-                Some("unexpected end of input inside braces"),
+                Some("unexpected end of input inside braces, unclosed left brace at position 0"),
             ),
             ("}", vec![], Some("unexpected right brace '}'")),
             (
@@ -1093,7 +1228,9 @@ mod tests {
             (
                 "[",
                 vec![(T_LEFT_BRACKET, 0, 1)],
-                Some("unexpected end of input inside brackets"),
+                Some(
+                    "unexpected end of input inside brackets, unclosed left bracket at position 0",
+                ),
             ),
             (
                 "[[",
@@ -21,24 +21,46 @@
 //! parameters like "start"/"end" time or "step" time etc, which is included in [`EvalStmt`].
 
 pub mod ast;
+#[cfg(feature = "binary")]
+mod binary;
+mod canonical;
 pub mod function;
 pub mod lex;
+mod node;
 pub mod parse;
 pub mod production;
+#[cfg(feature = "proto")]
+pub mod proto;
+#[cfg(feature = "ser")]
+mod ser;
+mod span;
+mod stats;
 pub mod token;
 pub mod value;
 
 pub use ast::{
     AggregateExpr, AtModifier, BinModifier, BinaryExpr, Call, EvalStmt, Expr, Extension,
-    LabelModifier, MatrixSelector, NumberLiteral, Offset, ParenExpr, StringLiteral, SubqueryExpr,
-    UnaryExpr, VectorMatchCardinality, VectorSelector,
+    LabelModifier, MatrixSelector, MetricMatch, NumberLiteral, Offset, ParenExpr, StringLiteral,
+    SubqueryExpr, SubqueryStep, UnaryExpr, VectorMatchCardinality, VectorSelector,
+    VectorSelectorBuilder,
 };
 
+#[cfg(feature = "binary")]
+pub use binary::BINARY_FORMAT_VERSION;
+pub(crate) use canonical::quote;
+pub use canonical::CANONICAL_FORMAT_VERSION;
 pub use function::{Function, FunctionArgs};
 pub use lex::{lexer, LexemeType};
-pub use parse::parse;
+pub use node::{FoundNode, NodeId};
+pub(crate) use parse::parse_with_spans_diagnostic;
+pub use parse::{
+    parse, parse_bytes, parse_stmt, parse_syntax, parse_with_diagnostic, parse_with_options,
+    parse_with_spans, parse_with_stats, type_check, CancellationToken, ParseOptions,
+};
+pub use span::Spans;
+pub use stats::ParseStats;
 pub use token::{Token, TokenId, TokenType};
 pub use value::{Value, ValueType};
 
-// FIXME: show more helpful error message to some invalid promql queries.
+/// fallback message when the parser gives no detail about a syntax error.
 pub const INVALID_QUERY_INFO: &str = "invalid promql query";
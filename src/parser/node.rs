@@ -0,0 +1,517 @@
+// Copyright 2023 Greptime Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::parser::{AtModifier, Expr, MetricMatch, Offset, VectorSelector};
+use std::fmt;
+
+/// NodeId identifies a node of an expr by its position in the pre-order
+/// traversal of the tree, the root is always 0. The id only depends on the
+/// structure of the tree, so it is the same in all clones of the tree, and
+/// can be used as the key of side tables annotating the nodes.
+///
+/// The ids are not kept across rewrites, which may change the structure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(usize);
+
+impl NodeId {
+    pub const ROOT: NodeId = NodeId(0);
+
+    /// the id of the node at the index in pre-order.
+    pub(crate) fn new(index: usize) -> Self {
+        NodeId(index)
+    }
+
+    pub fn index(&self) -> usize {
+        self.0
+    }
+}
+
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::{parse, NodeId};
+///
+/// let expr = parse("sum(rate(foo[5m]))").unwrap();
+/// let nodes = expr.nodes();
+/// assert_eq!(nodes.len(), 3);
+/// assert_eq!(nodes[2].1.to_string(), "foo[5m]");
+///
+/// // the ids are the same in the clones
+/// let id = nodes[1].0;
+/// let cloned = expr.clone();
+/// assert_eq!(cloned.get_node(id).unwrap().to_string(), "rate(foo[5m])");
+/// assert_eq!(cloned.node_id(cloned.get_node(id).unwrap()), Some(id));
+/// assert_eq!(cloned.node_id(nodes[1].1), None);
+/// assert_eq!(expr.get_node(NodeId::ROOT), Some(&expr));
+///
+/// assert_eq!(expr.node_count(), 3);
+/// assert_eq!(expr.depth(), 3);
+/// ```
+impl Expr {
+    /// all nodes with their ids, in pre-order.
+    pub fn nodes(&self) -> Vec<(NodeId, &Expr)> {
+        let mut nodes = vec![];
+        collect_nodes(self, &mut nodes);
+        nodes
+    }
+
+    /// the node with the id, None if the tree has fewer nodes.
+    pub fn get_node(&self, id: NodeId) -> Option<&Expr> {
+        find_node(self, &mut 0, &mut |i, _| i == id.0).map(|(_, node)| node)
+    }
+
+    /// the id of a node of this tree, the node is compared by reference, so
+    /// nodes of other trees, including the clones of this tree, are not found.
+    pub fn node_id(&self, node: &Expr) -> Option<NodeId> {
+        find_node(self, &mut 0, &mut |_, n| std::ptr::eq(n, node)).map(|(id, _)| id)
+    }
+
+    /// the number of nodes on the longest path from the root to a leaf, a
+    /// single selector has depth 1. The tree is walked without recursion, so
+    /// it is safe on trees of any depth.
+    pub fn depth(&self) -> usize {
+        let mut stack = vec![(self, 1)];
+        let mut depth = 0;
+        while let Some((expr, d)) = stack.pop() {
+            depth = depth.max(d);
+            stack.extend(expr.children().into_iter().map(|child| (child, d + 1)));
+        }
+        depth
+    }
+
+    /// the number of nodes, including the root, counted without recursion.
+    pub fn node_count(&self) -> usize {
+        let mut stack = vec![self];
+        let mut count = 0;
+        while let Some(expr) = stack.pop() {
+            count += 1;
+            stack.extend(expr.children());
+        }
+        count
+    }
+}
+
+/// a node found by [`Expr::find`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct FoundNode<'a> {
+    pub id: NodeId,
+    pub node: &'a Expr,
+    /// the path from the root to the parent of the node.
+    pub ancestors: Vec<&'a Expr>,
+}
+
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::{parse, Expr, MetricMatch};
+///
+/// let expr = parse("sum(rate(foo[5m])) / sum(rate(bar[5m])) > on () foo").unwrap();
+/// let found: Vec<String> = expr
+///     .find_calls("rate")
+///     .iter()
+///     .map(|f| f.node.to_string())
+///     .collect();
+/// assert_eq!(found, vec!["rate(foo[5m])", "rate(bar[5m])"]);
+///
+/// let found = expr.selectors_for_metric("foo");
+/// assert_eq!(found.len(), 2);
+/// assert_eq!(found[0].0.ancestors.len(), 4);
+/// assert_eq!(found[0].0.ancestors, expr.ancestors(found[0].0.id));
+///
+/// let found = expr.find(|e| matches!(e, Expr::Aggregate(_)));
+/// assert_eq!(found.len(), 2);
+///
+/// let expr = parse(r#"foo / on(job) {__name__=~"fo.*"} + bar + {job="api"}"#).unwrap();
+/// let found: Vec<(String, MetricMatch)> = expr
+///     .selectors_for_metric("foo")
+///     .into_iter()
+///     .map(|(f, m)| (f.node.to_string(), m))
+///     .collect();
+/// assert_eq!(
+///     found,
+///     [
+///         ("foo".to_string(), MetricMatch::Yes),
+///         (r#"{__name__=~"fo.*"}"#.to_string(), MetricMatch::Maybe),
+///         (r#"{job="api"}"#.to_string(), MetricMatch::Maybe),
+///     ]
+/// );
+/// assert!(expr.references_metric("foo"));
+/// assert!(!expr.references_metric("fox"));
+/// ```
+impl Expr {
+    /// all nodes matching the predicate, in pre-order.
+    pub fn find(&self, predicate: impl Fn(&Expr) -> bool) -> Vec<FoundNode<'_>> {
+        let mut found = vec![];
+        find_nodes(self, &predicate, &mut vec![], &mut 0, &mut found);
+        found
+    }
+
+    /// all calls of the function.
+    pub fn find_calls(&self, name: &str) -> Vec<FoundNode<'_>> {
+        self.find(|e| matches!(e, Expr::Call(call) if call.func.name == name))
+    }
+
+    /// the vector and matrix selectors which may select the series of the
+    /// metric, including the ones with a regex `__name__` matcher or without
+    /// a name, see [`VectorSelector::matches_metric`].
+    pub fn selectors_for_metric(&self, metric: &str) -> Vec<(FoundNode<'_>, MetricMatch)> {
+        self.find(|e| selector(e).is_some())
+            .into_iter()
+            .filter_map(|f| {
+                let matched = selector(f.node)?.matches_metric(metric);
+                (matched != MetricMatch::No).then_some((f, matched))
+            })
+            .collect()
+    }
+
+    /// whether a selector of the expr names the metric, unlike
+    /// [`Expr::selectors_for_metric`] the regex matchers are not considered.
+    pub fn references_metric(&self, metric: &str) -> bool {
+        self.selectors_for_metric(metric)
+            .iter()
+            .any(|(_, m)| *m == MetricMatch::Yes)
+    }
+
+    /// the path from the root to the parent of the node, empty for the root
+    /// or an unknown id.
+    pub fn ancestors(&self, id: NodeId) -> Vec<&Expr> {
+        let mut path = vec![];
+        if find_path(self, id, &mut 0, &mut path) {
+            path.pop();
+        }
+        path
+    }
+
+    /// the subtree of the node as a standalone query, None for an unknown id
+    /// or when the offsets overflow. The parentheses of the query are nodes
+    /// of their own, so the subtree needs no parentheses to parse the same.
+    ///
+    /// The nodes inside subqueries are evaluated at the steps of the
+    /// subqueries, which are shifted by their offset and `@` modifiers. These
+    /// are added to the selectors and subqueries of the subtree, so that the
+    /// query evaluates the subtree at the last step of the enclosing
+    /// subqueries.
+    ///
+    /// # Examples
+    ///
+    /// ``` rust
+    /// use promql_parser::parser::parse;
+    ///
+    /// let expr = parse("max_over_time((rate(foo[5m]) > 0)[1h:1m] offset 1d) / on () bar").unwrap();
+    /// let rate = &expr.find_calls("rate")[0];
+    /// assert_eq!(expr.subtree_to_query(rate.id).unwrap(), "rate(foo[5m] offset 1d)");
+    ///
+    /// let paren = expr.node_id(rate.ancestors[3]).unwrap();
+    /// assert_eq!(expr.subtree_to_query(paren).unwrap(), "(rate(foo[5m] offset 1d) > 0)");
+    /// ```
+    pub fn subtree_to_query(&self, id: NodeId) -> Option<String> {
+        let mut path = vec![];
+        if !find_path(self, id, &mut 0, &mut path) {
+            return None;
+        }
+        let node = path.pop()?;
+        // the offset and @ of the last step of the enclosing subqueries, an
+        // @ pins the time, so the offsets outside of it do not apply.
+        let mut offset: Option<Offset> = None;
+        let mut at = None;
+        for ancestor in path {
+            if let Expr::Subquery(sq) = ancestor {
+                if sq.at.is_some() {
+                    at.clone_from(&sq.at);
+                    offset.clone_from(&sq.offset);
+                } else {
+                    offset = add_offset(offset, &sq.offset)?;
+                }
+            }
+        }
+        let mut node = node.clone();
+        if offset.is_some() || at.is_some() {
+            shift(&mut node, &offset, &at)?;
+        }
+        Some(node.to_string())
+    }
+}
+
+fn add_offset(offset: Option<Offset>, other: &Option<Offset>) -> Option<Option<Offset>> {
+    match (offset, other) {
+        (Some(offset), Some(other)) => offset.checked_add(other).map(Some),
+        (offset, other) => Some(offset.or(other.clone())),
+    }
+}
+
+/// evaluate the selectors and subqueries of the expr at the time shifted by
+/// the offset and @, the exprs with an @ of their own are pinned already.
+/// The nodes inside the subqueries are relative to the subqueries.
+fn shift(expr: &mut Expr, offset: &Option<Offset>, at: &Option<AtModifier>) -> Option<()> {
+    let (own_offset, own_at) = match expr {
+        Expr::VectorSelector(vs) => (&mut vs.offset, &mut vs.at),
+        Expr::MatrixSelector(ms) => (&mut ms.vector_selector.offset, &mut ms.vector_selector.at),
+        Expr::Subquery(sq) => (&mut sq.offset, &mut sq.at),
+        _ => {
+            for child in expr.children_mut() {
+                shift(child, offset, at)?;
+            }
+            return Some(());
+        }
+    };
+    if own_at.is_none() {
+        *own_offset = add_offset(own_offset.take(), offset)?;
+        own_at.clone_from(at);
+    }
+    Some(())
+}
+
+fn selector(expr: &Expr) -> Option<&VectorSelector> {
+    match expr {
+        Expr::VectorSelector(vs) => Some(vs),
+        Expr::MatrixSelector(ms) => Some(&ms.vector_selector),
+        _ => None,
+    }
+}
+
+fn find_nodes<'a>(
+    expr: &'a Expr,
+    predicate: &impl Fn(&Expr) -> bool,
+    path: &mut Vec<&'a Expr>,
+    next: &mut usize,
+    found: &mut Vec<FoundNode<'a>>,
+) {
+    if predicate(expr) {
+        found.push(FoundNode {
+            id: NodeId(*next),
+            node: expr,
+            ancestors: path.clone(),
+        });
+    }
+    *next += 1;
+    path.push(expr);
+    for child in expr.children() {
+        find_nodes(child, predicate, path, next, found);
+    }
+    path.pop();
+}
+
+/// push the nodes from the root to the node with the id, `next` is the id of expr.
+fn find_path<'a>(expr: &'a Expr, id: NodeId, next: &mut usize, path: &mut Vec<&'a Expr>) -> bool {
+    path.push(expr);
+    if *next == id.0 {
+        return true;
+    }
+    *next += 1;
+    for child in expr.children() {
+        if find_path(child, id, next, path) {
+            return true;
+        }
+    }
+    path.pop();
+    false
+}
+
+/// the first node in pre-order for which the predicate holds, the nodes
+/// after it are not visited.
+fn find_node<'a>(
+    expr: &'a Expr,
+    next: &mut usize,
+    predicate: &mut impl FnMut(usize, &Expr) -> bool,
+) -> Option<(NodeId, &'a Expr)> {
+    let id = *next;
+    if predicate(id, expr) {
+        return Some((NodeId(id), expr));
+    }
+    *next += 1;
+    expr.children()
+        .into_iter()
+        .find_map(|child| find_node(child, next, predicate))
+}
+
+fn collect_nodes<'a>(expr: &'a Expr, nodes: &mut Vec<(NodeId, &'a Expr)>) {
+    nodes.push((NodeId(nodes.len()), expr));
+    for child in expr.children() {
+        collect_nodes(child, nodes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    #[test]
+    fn test_node_ids() {
+        let expr = parse("topk(3, foo) / on (job) (bar + 1)").unwrap();
+        let nodes: Vec<(usize, String)> = expr
+            .nodes()
+            .into_iter()
+            .map(|(id, node)| (id.index(), node.to_string()))
+            .collect();
+        let expected = vec![
+            (0, "topk(3, foo) / on (job) (bar + 1)"),
+            (1, "topk(3, foo)"),
+            (2, "3"),
+            (3, "foo"),
+            (4, "(bar + 1)"),
+            (5, "bar + 1"),
+            (6, "bar"),
+            (7, "1"),
+        ];
+        let expected: Vec<(usize, String)> = expected
+            .into_iter()
+            .map(|(i, s)| (i, s.to_string()))
+            .collect();
+        assert_eq!(nodes, expected);
+
+        assert_eq!(expr.get_node(NodeId(8)), None);
+        for (id, node) in expr.nodes() {
+            assert_eq!(expr.get_node(id), Some(node));
+            assert_eq!(expr.node_id(node), Some(id));
+        }
+
+        // the walk stops at the node found
+        let mut visited = vec![];
+        let found = find_node(&expr, &mut 0, &mut |i, _| {
+            visited.push(i);
+            i == 3
+        });
+        assert_eq!(found.unwrap().1.to_string(), "foo");
+        assert_eq!(visited, vec![0, 1, 2, 3]);
+        assert_eq!(expr.node_id(&parse("foo").unwrap()), None);
+        assert_eq!(NodeId(3).to_string(), "#3");
+    }
+
+    #[test]
+    fn test_depth_and_node_count() {
+        let cases = vec![
+            ("foo", 1, 1),
+            ("1 + 2 * 3", 3, 5),
+            ("topk(scalar(count(up)), rate(foo[5m]))", 4, 6),
+            ("max_over_time((-sum by (job) (foo))[1h:])", 6, 6),
+            ("absent(nonexistent{job=\"myjob\"})", 2, 2),
+        ];
+        for (query, depth, count) in cases {
+            let expr = parse(query).unwrap();
+            assert_eq!(expr.depth(), depth, "{query}");
+            assert_eq!(expr.node_count(), count, "{query}");
+            assert_eq!(expr.node_count(), expr.nodes().len(), "{query}");
+        }
+
+        let mut expr = parse("foo").unwrap();
+        for _ in 0..1000 {
+            expr = Expr::new_paren_expr(expr).unwrap();
+        }
+        assert_eq!(expr.depth(), 1001);
+        assert_eq!(expr.node_count(), 1001);
+    }
+
+    #[test]
+    fn test_find() {
+        let expr = parse(
+            r#"rate(foo[5m]) + on () {__name__="foo", job="a"} + count_values("x", rate(bar[1m]))"#,
+        )
+        .unwrap();
+
+        let ids: Vec<usize> = expr
+            .find_calls("rate")
+            .iter()
+            .map(|f| f.id.index())
+            .collect();
+        assert_eq!(ids, vec![2, 7]);
+        assert!(expr.find_calls("irate").is_empty());
+
+        let found: Vec<(String, MetricMatch)> = expr
+            .selectors_for_metric("foo")
+            .iter()
+            .map(|(f, m)| (f.node.to_string(), *m))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                ("foo[5m]".to_string(), MetricMatch::Yes),
+                (r#"{__name__="foo",job="a"}"#.to_string(), MetricMatch::Yes)
+            ]
+        );
+        assert!(expr.selectors_for_metric("baz").is_empty());
+
+        let ids: Vec<(usize, MetricMatch)> = expr
+            .selectors_for_metric("bar")
+            .iter()
+            .map(|(f, m)| (f.id.index(), *m))
+            .collect();
+        assert_eq!(ids, vec![(8, MetricMatch::Yes)]);
+        assert!(expr.references_metric("bar"));
+        let regex = parse(r#"sum(rate({__name__=~"ba.*"}[5m]))"#).unwrap();
+        assert_eq!(regex.selectors_for_metric("baz").len(), 1);
+        assert!(!regex.references_metric("baz"));
+
+        let literals = expr.find(|e| matches!(e, Expr::StringLiteral(_)));
+        assert_eq!(literals.len(), 1);
+        assert_eq!(expr.get_node(literals[0].id), Some(literals[0].node));
+    }
+
+    #[test]
+    fn test_ancestors() {
+        let expr = parse("sum(rate(foo[5m])) + bar").unwrap();
+        let ancestors = |id| -> Vec<String> {
+            expr.ancestors(NodeId(id))
+                .iter()
+                .map(|e| e.to_string())
+                .collect()
+        };
+        assert!(ancestors(0).is_empty());
+        assert_eq!(ancestors(4), vec!["sum(rate(foo[5m])) + bar"]);
+        assert_eq!(
+            ancestors(3),
+            vec![
+                "sum(rate(foo[5m])) + bar",
+                "sum(rate(foo[5m]))",
+                "rate(foo[5m])"
+            ]
+        );
+        assert!(ancestors(5).is_empty());
+    }
+
+    #[test]
+    fn test_subtree_to_query() {
+        let input =
+            r#"topk by (job) (5, -(sum(rate(foo{a="b"}[5m] offset 1m)) > bool 0)) or vector(1)"#;
+        let expr = parse(input).unwrap();
+        for (id, node) in expr.nodes() {
+            let query = expr.subtree_to_query(id).unwrap();
+            assert_eq!(&parse(&query).unwrap(), node, "{query}");
+        }
+        assert_eq!(expr.subtree_to_query(NodeId(0)).unwrap(), expr.to_string());
+        assert_eq!(expr.subtree_to_query(NodeId(100)), None);
+
+        let expr = parse(
+            "max_over_time((foo offset 1m + bar @ 10 + last_over_time(baz[5m:] offset -2m))[1h:] offset 1h)[1d:] @ 100",
+        )
+        .unwrap();
+        let query = |id| expr.subtree_to_query(NodeId(id)).unwrap();
+        assert_eq!(
+            query(1),
+            "max_over_time((foo offset 1m + bar @ 10.000 + last_over_time(baz[5m:] offset -2m))[1h:] @ 100.000 offset 1h)"
+        );
+        assert_eq!(
+            query(3),
+            "(foo @ 100.000 offset 1h1m + bar @ 10.000 + last_over_time(baz[5m:] @ 100.000 offset 58m))"
+        );
+        assert_eq!(query(7), "bar @ 10.000");
+        assert_eq!(query(10), "baz @ 100.000 offset 58m");
+    }
+}
@@ -12,16 +12,873 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::parser::{lex, Expr, INVALID_QUERY_INFO};
+use lrlex::LRNonStreamingLexer;
+use lrpar::{LexParseError, Lexeme, Lexer, NonStreamingLexer};
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::label::NameValidationScheme;
+use crate::parser::ast::{check_tree, SCALAR_COMPARISON_WITHOUT_BOOL};
+use crate::parser::function::is_label_arg;
+use crate::parser::production::ParseContext;
+use crate::parser::token::*;
+use crate::parser::{
+    lex, BinaryExpr, EvalStmt, Expr, LexemeType, Offset, ParseStats, Spans, StringLiteral,
+    INVALID_QUERY_INFO,
+};
+use crate::util::diagnostic::{codes, Diagnostic};
+use crate::util::lint::{lint, LintKind, LintOptions};
+use crate::util::step::MAX_POINTS;
+use crate::util::{display_duration, walk_expr, ExprVisitor};
+
+/// Options to control how a query is parsed, see [`parse_with_options`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ParseOptions {
+    /// which metric and label names are accepted.
+    pub name_validation_scheme: NameValidationScheme,
+    /// queries with more tokens are rejected before they are parsed.
+    pub max_tokens: Option<usize>,
+    /// the wall-clock time parsing may take, it is checked for each token
+    /// lexed and each node built, so parsing is aborted soon after it runs out.
+    pub timeout: Option<Duration>,
+    /// parsing is aborted once the token is cancelled.
+    pub cancellation_token: Option<CancellationToken>,
+    /// accept the experimental functions, like `first_over_time`.
+    pub experimental_functions: bool,
+    /// the bytes the nodes of the query may take, estimated while they are
+    /// built, so wide queries like thousands of `or` are aborted early.
+    pub max_size: Option<usize>,
+    /// reject the negative offsets, like `foo offset -5m`, for the engines
+    /// which do not support them.
+    pub reject_negative_offsets: bool,
+}
+
+impl ParseOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_name_validation_scheme(mut self, scheme: NameValidationScheme) -> Self {
+        self.name_validation_scheme = scheme;
+        self
+    }
+
+    pub fn with_max_tokens(mut self, max_tokens: usize) -> Self {
+        self.max_tokens = Some(max_tokens);
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_cancellation_token(mut self, token: CancellationToken) -> Self {
+        self.cancellation_token = Some(token);
+        self
+    }
+
+    pub fn with_experimental_functions(mut self, enabled: bool) -> Self {
+        self.experimental_functions = enabled;
+        self
+    }
+
+    pub fn with_reject_negative_offsets(mut self, reject: bool) -> Self {
+        self.reject_negative_offsets = reject;
+        self
+    }
+
+    pub fn with_max_size(mut self, max_size: usize) -> Self {
+        self.max_size = Some(max_size);
+        self
+    }
+}
+
+/// CancellationToken aborts the parsing of a query from another thread, clones share the state.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// tokens are equal if they share the same state.
+impl PartialEq for CancellationToken {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for CancellationToken {}
+
+/// the time budget and cancellation of a single parse, checked while lexing,
+/// while the nodes are built and between the steps of parsing.
+#[derive(Debug, Default)]
+pub(crate) struct Budget {
+    deadline: Option<(Instant, Duration)>,
+    cancellation_token: Option<CancellationToken>,
+}
+
+impl Budget {
+    pub(crate) fn new(options: &ParseOptions) -> Self {
+        Self {
+            deadline: options.timeout.map(|t| (Instant::now() + t, t)),
+            cancellation_token: options.cancellation_token.clone(),
+        }
+    }
+
+    pub(crate) fn check(&self) -> Result<(), String> {
+        if self
+            .cancellation_token
+            .as_ref()
+            .is_some_and(|t| t.is_cancelled())
+        {
+            return Err("parsing cancelled".into());
+        }
+        match self.deadline {
+            Some((deadline, timeout)) if Instant::now() >= deadline => Err(format!(
+                "parsing exceeded the timeout of {}",
+                display_duration(&timeout)
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Parse the given query literal to an AST with the given options.
+pub fn parse_with_options(input: &str, options: &ParseOptions) -> Result<Expr, String> {
+    parse_with_diagnostic(input, options).map_err(|d| d.message)
+}
+
+/// same as [`parse_with_options`], the error is a [`Diagnostic`], with the code
+/// of the step which failed and the span in the query, if it is known.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::{parse_with_diagnostic, ParseOptions};
+/// use promql_parser::util::diagnostic::codes;
+///
+/// let err = parse_with_diagnostic("foo + ", &ParseOptions::new()).unwrap_err();
+/// assert_eq!(err.code, codes::SYNTAX);
+/// assert_eq!(err.span, Some(6..6));
+///
+/// let err = parse_with_diagnostic("rate(foo)", &ParseOptions::new()).unwrap_err();
+/// assert_eq!(err.code, codes::CHECK);
+/// assert_eq!(err.message, "expected type matrix in call to function 'rate', got vector");
+/// ```
+pub fn parse_with_diagnostic(input: &str, options: &ParseOptions) -> Result<Expr, Diagnostic> {
+    #[cfg(feature = "tracing")]
+    let span = parse_span(input);
+    let result = parse_and_check(
+        input,
+        options,
+        &ParseContext::new(true).with_limits(options),
+    )
+    .map(|(expr, _)| expr);
+    #[cfg(feature = "tracing")]
+    record_result(&span, result.as_ref().map_err(|d| &d.message));
+    result
+}
+
+/// same as [`parse_with_diagnostic`], for queries given as bytes, like read
+/// from the network. Invalid UTF-8 is a syntax error at the first invalid byte,
+/// control characters, like NUL, are rejected by the lexer outside of strings.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::{parse_bytes, ParseOptions};
+///
+/// let expr = parse_bytes(b"sum(foo)", &ParseOptions::new()).unwrap();
+/// assert_eq!(expr.to_string(), "sum(foo)");
+///
+/// let err = parse_bytes(b"foo{a=\"\xff\"}", &ParseOptions::new()).unwrap_err();
+/// assert_eq!(err.message, "invalid UTF-8 in query at position 7");
+/// assert_eq!(err.span, Some(7..8));
+///
+/// let err = parse_bytes(b"foo\0", &ParseOptions::new()).unwrap_err();
+/// assert_eq!(err.message, "unexpected character: '\\0'");
+/// ```
+pub fn parse_bytes(input: &[u8], options: &ParseOptions) -> Result<Expr, Diagnostic> {
+    match std::str::from_utf8(input) {
+        Ok(input) => parse_with_diagnostic(input, options),
+        Err(e) => {
+            let start = e.valid_up_to();
+            let end = start + e.error_len().unwrap_or(input.len() - start);
+            let message = format!("invalid UTF-8 in query at position {start}");
+            Err(Diagnostic::error(codes::SYNTAX, message).with_span(Some(start..end)))
+        }
+    }
+}
+
+/// same as [`parse_with_options`], also returning the statistics of the query.
+/// The tokens are counted by the lexer, the rest is collected by a single
+/// traversal of the parsed tree, see [`ParseStats`].
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::{parse_with_stats, ParseOptions};
+///
+/// let (_, stats) = parse_with_stats("sum(rate(foo[5m])) / bar", &ParseOptions::new()).unwrap();
+/// assert_eq!(stats.tokens, 12);
+/// assert_eq!(stats.total_nodes(), 5);
+/// assert_eq!(stats.nodes["Call"], 1);
+/// assert_eq!(stats.max_depth, 4);
+/// assert_eq!(stats.selectors, 2);
+/// ```
+pub fn parse_with_stats(input: &str, options: &ParseOptions) -> Result<(Expr, ParseStats), String> {
+    #[cfg(feature = "tracing")]
+    let span = parse_span(input);
+    let result = parse_and_check(
+        input,
+        options,
+        &ParseContext::new(true).with_limits(options),
+    )
+    .map_err(|d| d.message);
+    #[cfg(feature = "tracing")]
+    record_result(&span, result.as_ref().map(|(expr, _)| expr));
+    result.map(|(expr, tokens)| {
+        let stats = ParseStats::new(tokens, &expr);
+        (expr, stats)
+    })
+}
 
 /// Parse the given query literal to an AST (which is [`Expr`] in this crate).
+/// Experimental functions are rejected, see [`ParseOptions::experimental_functions`].
 pub fn parse(input: &str) -> Result<Expr, String> {
-    match lex::lexer(input) {
-        Err(e) => Err(e),
-        Ok(lexer) => {
-            // NOTE: the errs is ignored so far.
-            let (res, _errs) = crate::promql_y::parse(&lexer);
-            res.ok_or_else(|| String::from(INVALID_QUERY_INFO))?
+    #[cfg(feature = "tracing")]
+    let span = parse_span(input);
+    let result = lex::lexer(input).and_then(|lexer| {
+        let ctx = ParseContext::new(true);
+        let expr = parse_lexer(input, &lexer, &ctx).map_err(|d| d.message)?;
+        walk_expr(&mut FunctionChecker::default(), &expr)?;
+        Ok(expr)
+    });
+    #[cfg(feature = "tracing")]
+    record_result(&span, result.as_ref());
+    result
+}
+
+/// same as [`parse_with_options`], also returning the spans of the nodes in
+/// the query.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::{parse_with_spans, ParseOptions};
+///
+/// let query = "sum(rate(foo[5m] offset 1m)) / 2";
+/// let (expr, spans) = parse_with_spans(query, &ParseOptions::new()).unwrap();
+/// let texts: Vec<&str> = expr
+///     .nodes()
+///     .into_iter()
+///     .map(|(id, _)| &query[spans.get(id).unwrap()])
+///     .collect();
+/// assert_eq!(
+///     texts,
+///     ["sum(rate(foo[5m] offset 1m)) / 2", "sum(rate(foo[5m] offset 1m))", "rate(foo[5m] offset 1m)", "foo[5m] offset 1m", "2"]
+/// );
+/// ```
+pub fn parse_with_spans(input: &str, options: &ParseOptions) -> Result<(Expr, Spans), String> {
+    parse_with_spans_diagnostic(input, options).map_err(|d| d.message)
+}
+
+/// same as [`parse_with_spans`], the error is a [`Diagnostic`] like the one of
+/// [`parse_with_diagnostic`].
+pub(crate) fn parse_with_spans_diagnostic(
+    input: &str,
+    options: &ParseOptions,
+) -> Result<(Expr, Spans), Diagnostic> {
+    let ctx = ParseContext::new(true).with_limits(options);
+    let (expr, _) = parse_and_check(input, options, &ctx)?;
+    let spans = Spans::from_post_order(&expr, ctx.into_spans())
+        .map_err(|e| Diagnostic::error(codes::CHECK, e))?;
+    Ok((expr, spans))
+}
+
+/// parse the syntax of the query, without the checks of [`type_check`], so
+/// tools like formatters can work on queries which are not valid, like
+/// `rate(foo)`. The static args of `label_replace` and `label_join` are still
+/// checked, since the positions of their errors are only known while parsing,
+/// and so is the operand of unary `+`, which is not kept in the tree.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::{parse, parse_syntax, type_check};
+///
+/// let expr = parse_syntax("sum(rate(foo)) + 1").unwrap();
+/// assert_eq!(expr.to_string(), "sum(rate(foo)) + 1");
+/// assert_eq!(
+///     type_check(expr).unwrap_err(),
+///     "expected type matrix in call to function 'rate', got vector"
+/// );
+///
+/// let expr = type_check(parse_syntax("foo and bar").unwrap()).unwrap();
+/// assert_eq!(expr, parse("foo and bar").unwrap());
+///
+/// assert!(parse_syntax("sum(foo").is_err());
+/// ```
+pub fn parse_syntax(input: &str) -> Result<Expr, String> {
+    #[cfg(feature = "tracing")]
+    let span = parse_span(input);
+    let result = lex::lexer(input).and_then(|lexer| {
+        let ctx = ParseContext::new(false);
+        parse_lexer(input, &lexer, &ctx).map_err(|d| d.message)
+    });
+    #[cfg(feature = "tracing")]
+    record_result(&span, result.as_ref());
+    result
+}
+
+/// check the types of an expr given by [`parse_syntax`], the result is the
+/// same as [`parse`] of the query.
+pub fn type_check(expr: Expr) -> Result<Expr, String> {
+    let expr = check_tree(expr)?;
+    walk_expr(&mut FunctionChecker::default(), &expr)?;
+    Ok(expr)
+}
+
+/// parse the query into the statement evaluating it from start to end, the
+/// query is an instant query if they are equal. The times and the type of the
+/// query are checked like the Prometheus API, see [`Expr::check_range_query`],
+/// and a range query may not return more than [`MAX_POINTS`] points per series.
+/// Use [`EvalStmt::resolve`] to also resolve `@ start()`, `@ end()` and the
+/// default steps of subqueries.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::{parse_stmt, ParseOptions};
+/// use std::time::{Duration, SystemTime};
+///
+/// let time = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+/// let (step, lookback) = (Duration::from_secs(60), Duration::from_secs(300));
+/// let options = ParseOptions::new();
+///
+/// let query = "max_over_time(foo[1h:] @ end())";
+/// let stmt = parse_stmt(query, time(1000), time(2000), step, lookback, &options)
+///     .unwrap()
+///     .resolve(Duration::from_secs(30));
+/// assert_eq!(stmt.expr.to_string(), "max_over_time(foo[1h:30s] @ 2000.000)");
+///
+/// assert_eq!(
+///     parse_stmt("foo[5m]", time(1000), time(2000), step, lookback, &options).unwrap_err(),
+///     r#"invalid expression type "range vector" for range query, must be Scalar or instant Vector"#
+/// );
+/// assert!(parse_stmt("foo[5m]", time(1000), time(1000), step, lookback, &options).is_ok());
+/// ```
+pub fn parse_stmt(
+    query: &str,
+    start: SystemTime,
+    end: SystemTime,
+    interval: Duration,
+    lookback_delta: Duration,
+    options: &ParseOptions,
+) -> Result<EvalStmt, String> {
+    if end < start {
+        return Err("end timestamp must not be before start time".into());
+    }
+    if start != end {
+        if interval.is_zero() {
+            return Err(
+                "zero or negative query resolution step widths are not accepted. Try a positive integer"
+                    .into(),
+            );
+        }
+        let duration = end.duration_since(start).unwrap_or_default();
+        if duration.as_millis() / interval.as_millis().max(1) > MAX_POINTS as u128 {
+            return Err("exceeded maximum resolution of 11,000 points per timeseries. Try decreasing the query resolution (?step=XX)".into());
+        }
+    }
+    let expr = parse_with_options(query, options)?;
+    if start != end {
+        expr.check_range_query()?;
+    }
+    Ok(EvalStmt {
+        expr,
+        start,
+        end,
+        interval,
+        lookback_delta,
+    })
+}
+
+/// parse and check the query, returning the number of tokens lexed.
+fn parse_and_check(
+    input: &str,
+    options: &ParseOptions,
+    ctx: &ParseContext,
+) -> Result<(Expr, usize), Diagnostic> {
+    let budget = ctx.budget();
+    let mut tokens = 0;
+    let mut limited = false;
+    let lexer = lex::lexer_with_check(input, |count| {
+        tokens = count;
+        let result = match options.max_tokens {
+            Some(max) if count > max => {
+                Err(format!("too many tokens in query, the limit is {max}"))
+            }
+            _ => budget.check(),
+        };
+        limited = result.is_err();
+        result
+    })
+    .map_err(|e| match limited {
+        true => Diagnostic::error(codes::LIMIT, e.message),
+        false => Diagnostic::error(codes::SYNTAX, e.message).with_span(e.span),
+    })?;
+    let expr = parse_lexer(input, &lexer, ctx)?;
+    budget
+        .check()
+        .map_err(|e| Diagnostic::error(codes::LIMIT, e))?;
+
+    #[cfg(feature = "tracing")]
+    let _span = tracing::debug_span!("promql.check").entered();
+    let mut checker = NameChecker {
+        scheme: options.name_validation_scheme,
+    };
+    walk_expr(&mut checker, &expr).map_err(|e| Diagnostic::error(codes::NAME, e))?;
+    let mut checker = FunctionChecker {
+        experimental: options.experimental_functions,
+    };
+    walk_expr(&mut checker, &expr).map_err(|e| Diagnostic::error(codes::FUNCTION, e))?;
+    if options.reject_negative_offsets {
+        walk_expr(&mut OffsetChecker, &expr).map_err(|e| Diagnostic::error(codes::CHECK, e))?;
+    }
+    Ok((expr, tokens))
+}
+
+/// the span of a parse, entered until it is dropped, the time taken is the
+/// duration of the span.
+#[cfg(feature = "tracing")]
+fn parse_span(input: &str) -> tracing::span::EnteredSpan {
+    use tracing::field::Empty;
+    tracing::debug_span!(
+        "promql.parse",
+        query_len = input.len(),
+        nodes = Empty,
+        error = Empty
+    )
+    .entered()
+}
+
+/// record the number of nodes or the error of a parse on its span.
+#[cfg(feature = "tracing")]
+fn record_result(span: &tracing::Span, result: Result<&Expr, &String>) {
+    if span.is_disabled() {
+        return;
+    }
+    match result {
+        Ok(expr) => span.record("nodes", expr.nodes().len()),
+        Err(e) => span.record("error", e.as_str()),
+    };
+}
+
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(name = "promql.grammar", level = "debug", skip_all)
+)]
+fn parse_lexer(
+    input: &str,
+    lexer: &LRNonStreamingLexer<'_, '_, LexemeType, TokenId>,
+    ctx: &ParseContext,
+) -> Result<Expr, Diagnostic> {
+    let (res, errs) = crate::promql_y::parse(lexer, ctx);
+    match res {
+        Some(res) => res.map_err(|e| match ctx.limit_exceeded() {
+            // the limit aborts the parse, even if a node failed a check before.
+            Some(err) => Diagnostic::error(codes::LIMIT, err),
+            None => {
+                let span = ctx.error_span(&e);
+                let diagnostic = Diagnostic::error(codes::CHECK, e).with_span(span);
+                match ctx.checked() {
+                    true => suggest_fix(input, diagnostic),
+                    false => diagnostic,
+                }
+            }
+        }),
+        None => Err(errs
+            .first()
+            .map(|err| syntax_error(input, lexer, err, ctx.budget()))
+            .unwrap_or_else(|| Diagnostic::error(codes::SYNTAX, INVALID_QUERY_INFO))),
+    }
+}
+
+/// add the fix of the lint for a comparison between scalars without `bool`,
+/// which the check rejects, so the lints never see it in a parsed query.
+fn suggest_fix(input: &str, diagnostic: Diagnostic) -> Diagnostic {
+    let span = match &diagnostic.span {
+        Some(span) if diagnostic.message == SCALAR_COMPARISON_WITHOUT_BOOL => span.clone(),
+        _ => return diagnostic,
+    };
+    let Ok(node) = parse_syntax(&input[span.clone()]) else {
+        return diagnostic;
+    };
+    let fix = lint(&node, &LintOptions::new())
+        .into_iter()
+        .find(|l| l.kind == LintKind::ScalarComparisonWithoutBool && l.id.index() == 0)
+        .and_then(|l| l.fix);
+    match fix {
+        Some(fix) => diagnostic.with_related(format!("replace {node} with {fix}"), Some(span)),
+        None => diagnostic,
+    }
+}
+
+/// build the error for the lexeme the parser can not accept, listing the
+/// tokens which would have been valid at its position.
+fn syntax_error(
+    input: &str,
+    lexer: &LRNonStreamingLexer<'_, '_, LexemeType, TokenId>,
+    err: &LexParseError<LexemeType, TokenId>,
+    budget: &Budget,
+) -> Diagnostic {
+    let lexeme = match err {
+        LexParseError::ParseError(err) => err.lexeme(),
+        LexParseError::LexError(_) => return Diagnostic::error(codes::SYNTAX, INVALID_QUERY_INFO),
+    };
+
+    let found = describe_lexeme(lexeme.tok_id(), lexer.span_str(lexeme.span()));
+    let pos = lexeme.span().start();
+    let prefix: Vec<LexemeType> = lexer
+        .iter()
+        .flatten()
+        .take_while(|l| l.span().start() < pos)
+        .collect();
+    if lexeme.tok_id() == T_BOOL {
+        if let Some(message) = misplaced_bool(&prefix) {
+            let span = lexeme.span();
+            return Diagnostic::error(codes::SYNTAX, message)
+                .with_span(Some(span.start()..span.end()));
+        }
+    }
+    let mut tokens = Vec::new();
+    for id in 0..T_STARTSYMBOLS_START {
+        // each candidate parses the query again, which is the costly part of parsing.
+        if let Err(e) = budget.check() {
+            return Diagnostic::error(codes::LIMIT, e);
+        }
+        if passes_checks(&prefix, id) && accepts(input, &prefix, id, pos) {
+            tokens.push(id);
+        }
+    }
+    let expected = expected_tokens(&tokens);
+
+    let message = match expected.split_last() {
+        None => format!("unexpected {found}"),
+        Some((last, [])) => format!("unexpected {found}, expected {last}"),
+        Some((last, init)) => format!("unexpected {found}, expected {} or {last}", init.join(", ")),
+    };
+    let span = lexeme.span();
+    Diagnostic::error(codes::SYNTAX, message).with_span(Some(span.start()..span.end()))
+}
+
+/// whether the parser can go on with the given token after the prefix.
+///
+/// parser states are shared between contexts in LALR tables, so the actions of
+/// the failed state are not precise enough. Instead the prefix is parsed again
+/// with each candidate token appended.
+fn accepts(input: &str, prefix: &[LexemeType], id: TokenId, pos: usize) -> bool {
+    let mut lexemes: Vec<_> = prefix.iter().copied().map(Ok).collect();
+    lexemes.push(Ok(LexemeType::new(id, pos, 0)));
+    let lexer = LRNonStreamingLexer::new(input, lexemes, Vec::new());
+    // only the syntax matters, the nodes need no checks
+    let (res, errs) = crate::promql_y::parse(&lexer, &ParseContext::new(false));
+    res.is_some()
+        || !errs.iter().any(|err| match err {
+            LexParseError::ParseError(err) => {
+                let l = err.lexeme();
+                !l.faulty() && l.tok_id() == id && l.span().start() == pos
+            }
+            LexParseError::LexError(_) => false,
+        })
+}
+
+/// whether the token after the prefix is not always rejected later, the grammar
+/// accepts an empty query, `bool` after any binary operator and `group_left` or
+/// `group_right` without `on` or `ignoring`, which the checks reject.
+fn passes_checks(prefix: &[LexemeType], id: TokenId) -> bool {
+    match id {
+        T_EOF => !prefix.is_empty(),
+        T_BOOL | T_GROUP_LEFT | T_GROUP_RIGHT => {
+            let Some(op) = prefix.iter().rposition(|l| is_binary_operator(l.tok_id())) else {
+                return true;
+            };
+            let modifiers = &prefix[op + 1..];
+            // otherwise the token is a label or metric name
+            if !only_modifiers(modifiers) {
+                return true;
+            }
+            let op = TokenType::new(prefix[op].tok_id());
+            match id {
+                T_BOOL => modifiers.is_empty() && op.is_comparison_operator(),
+                _ => !modifiers.is_empty() && !op.is_set_operator(),
+            }
+        }
+        _ => true,
+    }
+}
+
+fn describe_lexeme(id: TokenId, text: &str) -> String {
+    match id {
+        // the end of input lexeme added by the parser itself has no text.
+        _ if text.is_empty() && id != T_STRING => "end of input".into(),
+        T_EOF => "end of input".into(),
+        T_DURATION | T_IDENTIFIER | T_METRIC_IDENTIFIER | T_NUMBER | T_STRING => {
+            format!("{} \"{text}\"", describe_token(id))
+        }
+        _ => format!("'{text}'"),
+    }
+}
+
+fn describe_token(id: TokenId) -> String {
+    match id {
+        T_EOF => "end of input".into(),
+        T_DURATION => "duration".into(),
+        T_IDENTIFIER => "identifier".into(),
+        T_METRIC_IDENTIFIER => "metric identifier".into(),
+        T_NUMBER => "number".into(),
+        T_STRING => "string".into(),
+        _ => format!("'{}'", token_display(id)),
+    }
+}
+
+/// turn the accepted tokens into readable names.
+///
+/// keywords are also accepted as label or metric names, so they are folded into
+/// `identifier` when it is expected. Aggregators and binary operators are listed
+/// as a whole, since naming each of them makes the message unreadable.
+fn expected_tokens(tokens: &[TokenId]) -> Vec<String> {
+    let ident = tokens.contains(&T_IDENTIFIER);
+    // label names are expected where identifiers are valid but numbers are not.
+    let label = ident && !tokens.contains(&T_NUMBER);
+    let as_name = |id: TokenId| {
+        if label {
+            id == T_METRIC_IDENTIFIER || is_label_keyword(id)
+        } else {
+            ident && is_metric_keyword(id)
+        }
+    };
+    let binary_ops = tokens
+        .iter()
+        .filter(|&&id| is_binary_operator(id) && !as_name(id))
+        .count();
+
+    let mut names: Vec<String> = vec![];
+    for &id in tokens {
+        let name = if as_name(id) {
+            continue;
+        } else if TokenType::new(id).is_aggregator() {
+            "aggregation".into()
+        } else if binary_ops > 2 && is_binary_operator(id) {
+            "binary operator".into()
+        } else {
+            describe_token(id)
+        };
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    names
+}
+
+/// name the modifier a misplaced `bool` is found after, the same as Prometheus
+/// rejects it, but with a message saying where `bool` belongs.
+fn misplaced_bool(prefix: &[LexemeType]) -> Option<String> {
+    let op = prefix
+        .iter()
+        .rposition(|l| is_binary_operator(l.tok_id()))?;
+    let modifiers = &prefix[op + 1..];
+    if !only_modifiers(modifiers) {
+        return None;
+    }
+    if modifiers.first().map(|l| l.tok_id()) == Some(T_BOOL) && modifiers.len() == 1 {
+        return Some("unexpected 'bool', bool modifier must only be given once".into());
+    }
+    let modifier = modifiers.iter().rev().find_map(|l| match l.tok_id() {
+        T_ON => Some("on"),
+        T_IGNORING => Some("ignoring"),
+        T_GROUP_LEFT => Some("group_left"),
+        T_GROUP_RIGHT => Some("group_right"),
+        _ => None,
+    })?;
+    Some(format!(
+        "unexpected 'bool' after '{modifier}', bool modifier must directly follow the binary operator"
+    ))
+}
+
+/// whether the lexemes after a binary operator are only its modifiers, like
+/// `on (a, b) group_left`, so a `bool` after them is misplaced and does not
+/// follow the rhs, like in `foo == on (a) bar bool`.
+fn only_modifiers(lexemes: &[LexemeType]) -> bool {
+    let is_grouping = |id| matches!(id, T_ON | T_IGNORING | T_GROUP_LEFT | T_GROUP_RIGHT);
+    let mut in_labels = false;
+    let mut prev = None;
+    for id in lexemes.iter().map(|l| l.tok_id()) {
+        let valid = if in_labels {
+            matches!(id, T_RIGHT_PAREN | T_COMMA | T_IDENTIFIER | T_STRING) || is_label_keyword(id)
+        } else {
+            id == T_BOOL || is_grouping(id) || (id == T_LEFT_PAREN && prev.is_some_and(is_grouping))
+        };
+        if !valid {
+            return false;
+        }
+        in_labels = match id {
+            T_LEFT_PAREN => true,
+            T_RIGHT_PAREN => false,
+            _ => in_labels,
+        };
+        prev = Some(id);
+    }
+    !in_labels
+}
+
+fn is_binary_operator(id: TokenId) -> bool {
+    TokenType::new(id).is_operator() && !matches!(id, T_AT | T_EQL_REGEX | T_NEQ_REGEX)
+}
+
+/// keywords which are also valid metric names, see `metric_identifier`.
+fn is_metric_keyword(id: TokenId) -> bool {
+    matches!(
+        id,
+        T_LAND | T_LOR | T_LUNLESS | T_BY | T_OFFSET | T_WITHOUT | T_START | T_END
+    )
+}
+
+/// keywords which are also valid label names, see `maybe_label`.
+fn is_label_keyword(id: TokenId) -> bool {
+    TokenType::new(id).is_aggregator()
+        || matches!(id, T_LAND | T_LOR | T_LUNLESS | T_ATAN2 | T_START | T_END)
+        || (T_KEYWORDS_START < id && id < T_KEYWORDS_END && id != T_WITHOUT)
+}
+
+/// checks metric and label names against the validation scheme.
+struct NameChecker {
+    scheme: NameValidationScheme,
+}
+
+impl NameChecker {
+    fn check_label<'a>(&self, labels: impl IntoIterator<Item = &'a String>) -> Result<(), String> {
+        let mut labels: Vec<_> = labels.into_iter().collect();
+        labels.sort();
+        match labels
+            .into_iter()
+            .find(|l| !self.scheme.is_valid_label_name(l))
+        {
+            Some(l) => Err(format!("invalid label name {l:?}")),
+            None => Ok(()),
+        }
+    }
+
+    fn check_selector(&self, vs: &crate::parser::VectorSelector) -> Result<(), String> {
+        if let Some(name) = &vs.name {
+            if !self.scheme.is_valid_metric_name(name) {
+                return Err(format!("invalid metric name {name:?}"));
+            }
+        }
+        self.check_label(vs.matchers.matchers.iter().map(|m| &m.name))
+    }
+}
+
+impl ExprVisitor for NameChecker {
+    type Error = String;
+
+    fn pre_visit(&mut self, expr: &Expr) -> Result<bool, Self::Error> {
+        match expr {
+            Expr::VectorSelector(vs) => self.check_selector(vs)?,
+            Expr::MatrixSelector(ms) => self.check_selector(&ms.vector_selector)?,
+            Expr::Aggregate(agg) => {
+                if let Some(modifier) = &agg.modifier {
+                    self.check_label(modifier.labels())?;
+                }
+                if let Some(Expr::StringLiteral(StringLiteral { val })) = agg.param.as_deref() {
+                    if agg.op.id() == T_COUNT_VALUES {
+                        self.check_label([val])?;
+                    }
+                }
+            }
+            Expr::Binary(BinaryExpr {
+                modifier: Some(modifier),
+                ..
+            }) => {
+                if let Some(matching) = &modifier.matching {
+                    self.check_label(matching.labels())?;
+                }
+                if let Some(labels) = modifier.card.labels() {
+                    self.check_label(labels)?;
+                }
+            }
+            Expr::Call(call) => {
+                let labels =
+                    call.args
+                        .args
+                        .iter()
+                        .enumerate()
+                        .filter_map(|(idx, arg)| match &**arg {
+                            Expr::StringLiteral(StringLiteral { val })
+                                if is_label_arg(call.func.name, idx) =>
+                            {
+                                Some(val)
+                            }
+                            _ => None,
+                        });
+                self.check_label(labels)?;
+            }
+            _ => {}
+        }
+        Ok(true)
+    }
+}
+
+/// rejects the experimental functions, unless they are enabled.
+#[derive(Default)]
+struct FunctionChecker {
+    experimental: bool,
+}
+
+impl ExprVisitor for FunctionChecker {
+    type Error = String;
+
+    fn pre_visit(&mut self, expr: &Expr) -> Result<bool, Self::Error> {
+        match expr {
+            Expr::Call(call) if call.func.experimental && !self.experimental => {
+                Err(format!("function '{}' is not enabled", call.func.name))
+            }
+            _ => Ok(true),
+        }
+    }
+}
+
+/// rejects the negative offsets.
+struct OffsetChecker;
+
+impl ExprVisitor for OffsetChecker {
+    type Error = String;
+
+    fn pre_visit(&mut self, expr: &Expr) -> Result<bool, Self::Error> {
+        let offset = match expr {
+            Expr::VectorSelector(vs) => &vs.offset,
+            Expr::MatrixSelector(ms) => &ms.vector_selector.offset,
+            Expr::Subquery(sq) => &sq.offset,
+            _ => return Ok(true),
+        };
+        match offset {
+            Some(offset @ Offset::Neg(_)) => Err(format!("negative {offset} is not enabled")),
+            _ => Ok(true),
         }
     }
 }
@@ -39,8 +896,8 @@ mod tests {
     use crate::label::{MatchOp, Matcher, Matchers};
     use crate::parser::function::get_function;
     use crate::parser::{
-        token, AtModifier as At, BinModifier, Expr, FunctionArgs, LabelModifier, Offset,
-        VectorMatchCardinality, VectorSelector, INVALID_QUERY_INFO,
+        token, AtModifier as At, BinModifier, Expr, FunctionArgs, LabelModifier, Offset, TokenType,
+        VectorMatchCardinality, VectorSelector,
     };
     use crate::util::duration;
     use std::collections::HashSet;
@@ -108,15 +965,38 @@ mod tests {
             ("08", Expr::from(8.0)),
             ("+5.5e-3", Expr::from(0.0055)),
             ("-0755", Expr::from(-493.0)),
+            ("0X1F", Expr::from(31.0)),
+            ("0x1e", Expr::from(30.0)),
+            ("-0xc", Expr::from(-12.0)),
+            ("0755.5", Expr::from(755.5)),
+            ("0755e1", Expr::from(7550.0)),
+            ("089", Expr::from(89.0)),
+            (".5e1", Expr::from(5.0)),
+            ("1e+3", Expr::from(1000.0)),
+            ("inf", Expr::from(f64::INFINITY)),
+            ("iNf", Expr::from(f64::INFINITY)),
+            ("- -1", Expr::from(1.0)),
+            ("+-1", Expr::from(-1.0)),
 
             // for abnormal input
             ("NaN", Expr::from(f64::NAN)),
+            ("nan", Expr::from(f64::NAN)),
+            ("-NaN", Expr::from(f64::NAN)),
             (
                 "999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999999",
                 Expr::from(f64::INFINITY)
             ),
         ];
         assert_cases(Case::new_expr_cases(cases));
+
+        let fail_cases = vec![
+            ("5e", "ParseFloatError. 5e can't be parsed into f64"),
+            ("0x", "ParseFloatError. 0x can't be parsed into i64"),
+            ("0x1.8", "ParseFloatError. 0x1.8 can't be parsed into i64"),
+            ("0b101", "bad number or duration syntax: 0b"),
+            ("1.2.3", "bad number or duration syntax: 1.2."),
+        ];
+        assert_cases(Case::new_fail_cases(fail_cases));
     }
 
     #[test]
@@ -124,16 +1004,16 @@ mod tests {
         let cases = vec![
             (
                 "\"double-quoted string \\\" with escaped quote\"",
-                Expr::from("double-quoted string \\\" with escaped quote"),
+                Expr::from("double-quoted string \" with escaped quote"),
             ),
             (
                 // this case is the same with the previous upper one
                 r#""double-quoted string \" with escaped quote""#,
-                Expr::from(r#"double-quoted string \" with escaped quote"#),
+                Expr::from(r#"double-quoted string " with escaped quote"#),
             ),
             (
                 r#"'single-quoted string \' with escaped quote'"#,
-                Expr::from(r#"single-quoted string \' with escaped quote"#),
+                Expr::from(r#"single-quoted string ' with escaped quote"#),
             ),
             (
                 "`backtick-quoted string`",
@@ -141,16 +1021,22 @@ mod tests {
             ),
             (
                 r#""\a\b\f\n\r\t\v\\\" - \xFF\377\u1234\U00010111\U0001011111☺""#,
-                Expr::from(r#"\a\b\f\n\r\t\v\\\" - \xFF\377\u1234\U00010111\U0001011111☺"#),
+                Expr::from(
+                    "\x07\x08\x0c\n\r\t\x0b\\\" - \u{fffd}\u{fffd}\u{1234}\u{10111}\u{10111}11☺",
+                ),
             ),
             (
                 r#"'\a\b\f\n\r\t\v\\\' - \xFF\377\u1234\U00010111\U0001011111☺'"#,
-                Expr::from(r#"\a\b\f\n\r\t\v\\\' - \xFF\377\u1234\U00010111\U0001011111☺"#),
+                Expr::from(
+                    "\x07\x08\x0c\n\r\t\x0b\\' - \u{fffd}\u{fffd}\u{1234}\u{10111}\u{10111}11☺",
+                ),
             ),
             (
-                r#"`\a\b\f\n\r\t\v\\\` - \xFF\377\u1234\U00010111\U0001011111☺`"#,
-                Expr::from(r#"\a\b\f\n\r\t\v\\\` - \xFF\377\u1234\U00010111\U0001011111☺"#),
+                // raw strings have no escape sequences
+                r#"`\a\b\f\n\r\t\v\\\\\\" - \xFF\377\u1234\U00010111\U0001011111☺`"#,
+                Expr::from(r#"\a\b\f\n\r\t\v\\\\\\" - \xFF\377\u1234\U00010111\U0001011111☺"#),
             ),
+            (r#""\xc3\xa9\303\251""#, Expr::from("éé")),
         ];
         assert_cases(Case::new_expr_cases(cases));
 
@@ -158,7 +1044,22 @@ mod tests {
             (r#"`\\``"#, "unterminated quoted string `"),
             (r#""\"#, "escape sequence not terminated"),
             (r#""\c""#, "unknown escape sequence 'c'"),
-            // (r#""\x.""#, ""),
+            (
+                r#""\x.""#,
+                r#"error unquoting string "\x.": invalid syntax"#,
+            ),
+            (
+                r#""\u12""#,
+                r#"error unquoting string "\u12": invalid syntax"#,
+            ),
+            (
+                r#""\400""#,
+                r#"error unquoting string "\400": invalid syntax"#,
+            ),
+            (
+                r#""\uD800""#,
+                r#"error unquoting string "\uD800": invalid syntax"#,
+            ),
         ];
         assert_cases(Case::new_fail_cases(fail_cases));
     }
@@ -390,7 +1291,7 @@ mod tests {
                 )
                 .and_then(|ex| {
                     Expr::new_binary_expr(
-                        Expr::from(ex),
+                        ex,
                         token::T_LUNLESS,
                         Some(BinModifier::default().with_card(VectorMatchCardinality::ManyToMany)),
                         Expr::from(VectorSelector::from("baz")),
@@ -398,7 +1299,7 @@ mod tests {
                 })
                 .and_then(|ex| {
                     Expr::new_binary_expr(
-                        Expr::from(ex),
+                        ex,
                         token::T_LOR,
                         Some(BinModifier::default().with_card(VectorMatchCardinality::ManyToMany)),
                         Expr::from(VectorSelector::from("qux")),
@@ -559,6 +1460,42 @@ mod tests {
                     Expr::from(VectorSelector::from("bar")),
                 ),
             ),
+            (
+                "foo / on(test,test,blub) group_left(bar,bar) bar",
+                Expr::new_binary_expr(
+                    Expr::from(VectorSelector::from("foo")),
+                    token::T_DIV,
+                    Some(
+                        BinModifier::default()
+                            .with_matching(Some(LabelModifier::Include(HashSet::from([
+                                String::from("test"),
+                                String::from("blub"),
+                            ]))))
+                            .with_card(VectorMatchCardinality::ManyToOne(HashSet::from([
+                                String::from("bar"),
+                            ]))),
+                    ),
+                    Expr::from(VectorSelector::from("bar")),
+                ),
+            ),
+            (
+                "foo / on(test,blub,) group_left(bar,) bar",
+                Expr::new_binary_expr(
+                    Expr::from(VectorSelector::from("foo")),
+                    token::T_DIV,
+                    Some(
+                        BinModifier::default()
+                            .with_matching(Some(LabelModifier::Include(HashSet::from([
+                                String::from("test"),
+                                String::from("blub"),
+                            ]))))
+                            .with_card(VectorMatchCardinality::ManyToOne(HashSet::from([
+                                String::from("bar"),
+                            ]))),
+                    ),
+                    Expr::from(VectorSelector::from("bar")),
+                ),
+            ),
             (
                 "foo / ignoring(test,blub) group_left(blub) bar",
                 Expr::new_binary_expr(
@@ -679,17 +1616,108 @@ mod tests {
                     Expr::from(VectorSelector::from("method:http_requests:rate5m")),
                 ),
             ),
-        ];
-        assert_cases(Case::new_result_cases(cases));
-
-        let fail_cases = vec![
-            (
-                "foo and 1",
-                "set operator 'and' not allowed in binary scalar expression",
-            ),
+            // NOTE: a parenthesized rhs right after an empty group label list
             (
-                "1 and foo",
-                "set operator 'and' not allowed in binary scalar expression",
+                "foo * on(test) group_left() (bar)",
+                Expr::new_paren_expr(Expr::from(VectorSelector::from("bar"))).and_then(|rhs| {
+                    Expr::new_binary_expr(
+                        Expr::from(VectorSelector::from("foo")),
+                        token::T_MUL,
+                        Some(
+                            BinModifier::default()
+                                .with_matching(Some(LabelModifier::Include(HashSet::from([
+                                    String::from("test"),
+                                ]))))
+                                .with_card(VectorMatchCardinality::ManyToOne(HashSet::new())),
+                        ),
+                        rhs,
+                    )
+                }),
+            ),
+            (
+                "foo / ignoring(test) group_right (baz) (bar)",
+                Expr::new_paren_expr(Expr::from(VectorSelector::from("bar"))).and_then(|rhs| {
+                    Expr::new_binary_expr(
+                        Expr::from(VectorSelector::from("foo")),
+                        token::T_DIV,
+                        Some(
+                            BinModifier::default()
+                                .with_matching(Some(LabelModifier::Exclude(HashSet::from([
+                                    String::from("test"),
+                                ]))))
+                                .with_card(VectorMatchCardinality::OneToMany(HashSet::from([
+                                    String::from("baz"),
+                                ]))),
+                        ),
+                        rhs,
+                    )
+                }),
+            ),
+            (
+                "foo > bool on(test) group_left() (bar)",
+                Expr::new_paren_expr(Expr::from(VectorSelector::from("bar"))).and_then(|rhs| {
+                    Expr::new_binary_expr(
+                        Expr::from(VectorSelector::from("foo")),
+                        token::T_GTR,
+                        Some(
+                            BinModifier::default()
+                                .with_matching(Some(LabelModifier::Include(HashSet::from([
+                                    String::from("test"),
+                                ]))))
+                                .with_card(VectorMatchCardinality::ManyToOne(HashSet::new()))
+                                .with_return_bool(true),
+                        ),
+                        rhs,
+                    )
+                }),
+            ),
+            (
+                "foo != bool ignoring(test) bar",
+                Expr::new_binary_expr(
+                    Expr::from(VectorSelector::from("foo")),
+                    token::T_NEQ,
+                    Some(
+                        BinModifier::default()
+                            .with_matching(Some(LabelModifier::Exclude(HashSet::from([
+                                String::from("test"),
+                            ]))))
+                            .with_return_bool(true),
+                    ),
+                    Expr::from(VectorSelector::from("bar")),
+                ),
+            ),
+            // NOTE: vector matching is dropped when a side is a scalar, keeping bool
+            (
+                "foo * ignoring() group_left() 10",
+                Expr::new_binary_expr(
+                    Expr::from(VectorSelector::from("foo")),
+                    token::T_MUL,
+                    None,
+                    Expr::from(10.0),
+                ),
+            ),
+            (
+                "foo == bool on() (10)",
+                Expr::new_paren_expr(Expr::from(10.0)).and_then(|rhs| {
+                    Expr::new_binary_expr(
+                        Expr::from(VectorSelector::from("foo")),
+                        token::T_EQLC,
+                        Some(BinModifier::default().with_return_bool(true)),
+                        rhs,
+                    )
+                }),
+            ),
+        ];
+        assert_cases(Case::new_result_cases(cases));
+
+        let fail_cases = vec![
+            (
+                "foo and 1",
+                "set operator 'and' not allowed in binary scalar expression",
+            ),
+            (
+                "1 and foo",
+                "set operator 'and' not allowed in binary scalar expression",
             ),
             (
                 "foo or 1",
@@ -757,6 +1785,56 @@ mod tests {
                 "foo and bool 10",
                 "bool modifier can only be used on comparison operators",
             ),
+            (
+                "foo or bool on(bar) baz",
+                "bool modifier can only be used on comparison operators",
+            ),
+            (
+                "foo unless bool ignoring() group_left() (baz)",
+                "bool modifier can only be used on comparison operators",
+            ),
+            (
+                "foo == on(bar) bool baz",
+                "unexpected 'bool' after 'on', bool modifier must directly follow the binary operator",
+            ),
+            (
+                "foo == ignoring(bar) bool baz",
+                "unexpected 'bool' after 'ignoring', bool modifier must directly follow the binary operator",
+            ),
+            (
+                "foo > on(bar) group_left bool baz",
+                "unexpected 'bool' after 'group_left', bool modifier must directly follow the binary operator",
+            ),
+            (
+                "foo < ignoring(bar) group_right(x) bool baz",
+                "unexpected 'bool' after 'group_right', bool modifier must directly follow the binary operator",
+            ),
+            (
+                "foo == bool bool baz",
+                "unexpected 'bool', bool modifier must only be given once",
+            ),
+            // the bool follows the rhs, not the modifiers
+            (
+                "foo == on(bar) baz bool",
+                "unexpected 'bool', expected end of input, '{', '[', '(', binary operator, '@' or 'offset'",
+            ),
+            (
+                r#"foo == on(bar) group_left (a, "b") (baz) bool"#,
+                "unexpected 'bool', expected end of input, '[', binary operator, '@' or 'offset'",
+            ),
+            (
+                r#"foo == on(bar) group_left (a, "b") bool baz"#,
+                "unexpected 'bool' after 'group_left', bool modifier must directly follow the binary operator",
+            ),
+            (
+                "foo bool == baz",
+                "unexpected 'bool', expected end of input, '{', '[', '(', binary operator, '@' or 'offset'",
+            ),
+            // NOTE: a parenthesized group label list is never taken as the rhs
+            (
+                "foo * on(test) group_left (bar)",
+                "unexpected end of input, expected identifier, '{', '(', metric identifier, number, string, '+', '-' or aggregation",
+            ),
             (
                 "1 and 1",
                 "set operator 'and' not allowed in binary scalar expression",
@@ -777,6 +1855,100 @@ mod tests {
         assert_cases(Case::new_fail_cases(fail_cases));
     }
 
+    /// the expr with the grouping of the binary and unary operators made explicit.
+    fn grouping(expr: &Expr) -> String {
+        match expr {
+            Expr::Binary(ex) => {
+                let op = match &ex.modifier {
+                    Some(modifier) if modifier.return_bool => format!("{} bool", ex.op),
+                    _ => ex.op.to_string(),
+                };
+                format!("({} {op} {})", grouping(&ex.lhs), grouping(&ex.rhs))
+            }
+            Expr::Unary(ex) => format!("(-{})", grouping(&ex.expr)),
+            Expr::Paren(ex) => grouping(&ex.expr),
+            _ => expr.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_operator_precedence() {
+        // the groupings of the Prometheus parser
+        let cases = [
+            ("2 ^ 3 ^ 2", "(2 ^ (3 ^ 2))"),
+            ("a - b - c", "((a - b) - c)"),
+            ("a / b * c", "((a / b) * c)"),
+            ("a atan2 b * c", "((a atan2 b) * c)"),
+            ("a % b ^ c", "(a % (b ^ c))"),
+            ("a + b * c ^ d", "(a + (b * (c ^ d)))"),
+            ("a == b + c", "(a == (b + c))"),
+            ("a > b > c", "((a > b) > c)"),
+            ("1 > bool 2 > bool 3", "((1 > bool 2) > bool 3)"),
+            ("a or b and c unless d", "(a or ((b and c) unless d))"),
+            ("a unless b and c", "((a unless b) and c)"),
+            ("a and b or c", "((a and b) or c)"),
+            // unary minus binds like multiplication
+            ("-a ^ 2", "(-(a ^ 2))"),
+            ("-2 ^ 2", "(-(2 ^ 2))"),
+            ("-(a) ^ 2", "(-(a ^ 2))"),
+            ("-a * b", "((-a) * b)"),
+            ("-a - -b", "((-a) - (-b))"),
+            ("a * -b ^ c", "(a * (-(b ^ c)))"),
+            ("2 ^ -1 ^ 2", "(2 ^ (-(1 ^ 2)))"),
+            ("a ^ -b", "(a ^ (-b))"),
+            ("a - -2", "(a - -2)"),
+        ];
+        for (query, expected) in cases {
+            assert_eq!(
+                grouping(&crate::parser::parse(query).unwrap()),
+                expected,
+                "{query}"
+            );
+        }
+
+        // chained comparisons of scalars need bool on each
+        for query in ["1 < 2 < 3", "1 < bool 2 < 3", "1 < 2 < bool 3"] {
+            assert_eq!(
+                crate::parser::parse(query).unwrap_err(),
+                "comparisons between scalars must use BOOL modifier",
+                "{query}"
+            );
+        }
+
+        // the grammar agrees with the exposed precedence of each pair of operators
+        let ops = [
+            token::T_ADD,
+            token::T_SUB,
+            token::T_MUL,
+            token::T_DIV,
+            token::T_MOD,
+            token::T_POW,
+            token::T_ATAN2,
+            token::T_EQLC,
+            token::T_NEQ,
+            token::T_LTE,
+            token::T_LSS,
+            token::T_GTE,
+            token::T_GTR,
+            token::T_LAND,
+            token::T_LOR,
+            token::T_LUNLESS,
+        ];
+        for first in ops.map(TokenType::new) {
+            for second in ops.map(TokenType::new) {
+                let query = format!("a {first} b {second} c");
+                let groups_left = first.precedence() > second.precedence()
+                    || (first.precedence() == second.precedence()
+                        && !second.is_right_associative());
+                let expected = match groups_left {
+                    true => format!("((a {first} b) {second} c)"),
+                    false => format!("(a {first} (b {second} c))"),
+                };
+                assert_eq!(grouping(&crate::parser::parse(&query).unwrap()), expected);
+            }
+        }
+    }
+
     #[test]
     fn test_unary_expr() {
         let cases = vec![
@@ -799,6 +1971,9 @@ mod tests {
             (r#"-"string""#, "unary expression only allowed on expressions of type scalar or vector, got: string"),
             ("-test[5m]", "unary expression only allowed on expressions of type scalar or vector, got: matrix"),
             (r#"-"foo""#, "unary expression only allowed on expressions of type scalar or vector, got: string"),
+            (r#"+"foo""#, "unary expression only allowed on expressions of type scalar or vector, got: string"),
+            ("+test[5m]", "unary expression only allowed on expressions of type scalar or vector, got: matrix"),
+            ("+test[5m:]", "unary expression only allowed on expressions of type scalar or vector, got: matrix"),
         ];
         assert_cases(Case::new_fail_cases(cases));
     }
@@ -947,9 +2122,15 @@ mod tests {
             ("foo @ +Inf", "timestamp out of bounds for @ modifier: inf"),
             ("foo @ -Inf", "timestamp out of bounds for @ modifier: -inf"),
             ("foo @ NaN", "timestamp out of bounds for @ modifier: NaN"),
-            ("{", "unexpected end of input inside braces"),
+            (
+                "{",
+                "unexpected end of input inside braces, unclosed left brace at position 0",
+            ),
             ("}", "unexpected right brace '}'"),
-            ("some{", "unexpected end of input inside braces"),
+            (
+                "some{",
+                "unexpected end of input inside braces, unclosed left brace at position 4",
+            ),
             ("some}", "unexpected right brace '}'"),
             (
                 "some_metric{a=b}",
@@ -1002,6 +2183,14 @@ mod tests {
                 "foo{,}",
                 r#"unexpected ',' in label matching, expected identifier or right_brace"#,
             ),
+            (
+                r#"foo{a="b",,}"#,
+                "unexpected ',', expected identifier, '}' or string",
+            ),
+            (
+                r#"foo{"bar"}"#,
+                "metric name must not be set twice: 'bar' or 'foo'",
+            ),
             (
                 r#"foo{__name__ == "bar"}"#,
                 "unexpected '=' in label matching, expected string",
@@ -1009,7 +2198,15 @@ mod tests {
             (
                 r#"foo{__name__="bar" lol}"#,
                 // "invalid label matcher, expected label matching operator after 'lol'",
-                INVALID_QUERY_INFO,
+                r#"unexpected identifier "lol", expected ',' or '}'"#,
+            ),
+            (
+                r#"foo{a=~"(ab"}"#,
+                "illegal regex for (ab at position 8..9: unclosed group",
+            ),
+            (
+                r#"{a="b", c!~"x{2,1}"}"#,
+                "illegal regex for x{2,1} at position 13..18: invalid repetition count range, the start must be <= the end",
             ),
         ];
         assert_cases(Case::new_fail_cases(fail_cases));
@@ -1029,6 +2226,22 @@ mod tests {
             },
         ];
         assert_cases(fail_cases);
+
+        // timestamps are an i64 of milliseconds, as in Prometheus
+        let fail_cases = vec![
+            (
+                "foo @ 1e16",
+                "timestamp out of bounds for @ modifier: 10000000000000000",
+            ),
+            (
+                "foo[5m:] @ -9223372036854776",
+                "timestamp out of bounds for @ modifier: -9223372036854776",
+            ),
+        ];
+        assert_cases(Case::new_fail_cases(fail_cases));
+        for query in ["foo @ 9e15 offset 292y", "foo[5m] @ -9e15 offset -292y"] {
+            assert!(super::parse(query).is_ok(), "{query}");
+        }
     }
 
     #[test]
@@ -1115,6 +2328,18 @@ mod tests {
             ("foo[5m1h]", "not a valid duration string: 5m1h"),
             ("foo[5m1m]", "not a valid duration string: 5m1m"),
             ("foo[0m]", "duration must be greater than 0"),
+            ("foo[0s]", "duration must be greater than 0"),
+            ("foo[-5m]", "unexpected character inside brackets: '-'"),
+            // durations are an i64 of nanoseconds, as in Prometheus
+            ("foo[293y]", "duration out of range"),
+            ("foo[9999999999999999999y]", "duration out of range"),
+            ("foo[106751d23h47m16s855ms]", "duration out of range"),
+            ("foo offset 293y", "duration out of range"),
+            ("foo[5m] offset -293y", "duration out of range"),
+            (
+                "foo[1y99999999999999999999d]",
+                "not a valid duration string: 1y99999999999999999999d",
+            ),
             (
                 r#"foo["5m"]"#,
                 r#"unexpected character inside brackets: '"'"#,
@@ -1245,6 +2470,24 @@ mod tests {
                     )
                 },
             ),
+            ("sum by (foo, bar,) (some_metric)", {
+                let modifier = LabelModifier::Include(HashSet::from([
+                    String::from("foo"),
+                    String::from("bar"),
+                ]));
+                let ex = Expr::from(VectorSelector::from("some_metric"));
+                Expr::new_aggregate_expr(token::T_SUM, Some(modifier), FunctionArgs::new_args(ex))
+            }),
+            ("sum by (foo, foo) (some_metric)", {
+                let modifier = LabelModifier::Include(HashSet::from([String::from("foo")]));
+                let ex = Expr::from(VectorSelector::from("some_metric"));
+                Expr::new_aggregate_expr(token::T_SUM, Some(modifier), FunctionArgs::new_args(ex))
+            }),
+            ("sum (some_metric) without (foo,)", {
+                let modifier = LabelModifier::Exclude(HashSet::from([String::from("foo")]));
+                let ex = Expr::from(VectorSelector::from("some_metric"));
+                Expr::new_aggregate_expr(token::T_SUM, Some(modifier), FunctionArgs::new_args(ex))
+            }),
             ("sum(sum)", {
                 let ex = Expr::from(VectorSelector::from("sum"));
                 Expr::new_aggregate_expr(token::T_SUM, None, FunctionArgs::new_args(ex))
@@ -1253,19 +2496,21 @@ mod tests {
         assert_cases(Case::new_result_cases(cases));
 
         let fail_cases = vec![
-            ("sum without(==)(some_metric)", INVALID_QUERY_INFO),
-            ("sum without(,)(some_metric)", INVALID_QUERY_INFO),
-            ("sum without(foo,,)(some_metric)", INVALID_QUERY_INFO),
-            ("sum some_metric by (test)", INVALID_QUERY_INFO),
-            ("sum (some_metric) by test", INVALID_QUERY_INFO),
+            (r#"count_values("", some_metric)"#, r#"invalid label name """#),
+            ("sum without(==)(some_metric)", "unexpected '==', expected identifier, ')' or string"),
+            ("sum without(,)(some_metric)", "unexpected ',', expected identifier, ')' or string"),
+            ("sum without(foo,,)(some_metric)", "unexpected ',', expected identifier, ')' or string"),
+            ("foo + on(foo,,) bar", "unexpected ',', expected identifier, ')' or string"),
+            ("sum some_metric by (test)", r#"unexpected identifier "some_metric", expected end of input, '{', '[', '(', binary operator, '@', 'by', 'offset' or 'without'"#),
+            ("sum (some_metric) by test", r#"unexpected identifier "test", expected '('"#),
             (
                 "sum () by (test)",
                 "no arguments for aggregate expression 'sum' provided",
             ),
-            ("MIN keep_common (some_metric)", INVALID_QUERY_INFO),
-            ("MIN (some_metric) keep_common", INVALID_QUERY_INFO),
-            ("sum (some_metric) without (test) by (test)", INVALID_QUERY_INFO),
-            ("sum without (test) (some_metric) by (test)", INVALID_QUERY_INFO),
+            ("MIN keep_common (some_metric)", r#"unexpected identifier "keep_common", expected end of input, '{', '[', '(', binary operator, '@', 'by', 'offset' or 'without'"#),
+            ("MIN (some_metric) keep_common", r#"unexpected identifier "keep_common", expected end of input, '[', binary operator, '@', 'by', 'offset' or 'without'"#),
+            ("sum (some_metric) without (test) by (test)", "unexpected 'by', expected end of input, '[', binary operator, '@' or 'offset'"),
+            ("sum without (test) (some_metric) by (test)", "unexpected 'by', expected end of input, '[', binary operator, '@' or 'offset'"),
             (
                 "topk(some_metric)",
                 "wrong number of arguments for aggregate expression provided, expected 2, got 1",
@@ -1753,7 +2998,51 @@ mod tests {
                 "label_join()",
                 "expected at least 3 argument(s) in call to 'label_join', got 0",
             ),
+            (
+                "rate(some_metric[5m],)",
+                "trailing commas not allowed in function call args",
+            ),
+            (
+                r#"label_replace(a, "b", "c", "d", ".*",)"#,
+                "trailing commas not allowed in function call args",
+            ),
+            (
+                "absent(foo[5m])",
+                "expected type vector in call to function 'absent', got matrix",
+            ),
+            (
+                "absent(1)",
+                "expected type vector in call to function 'absent', got scalar",
+            ),
+            (
+                "absent()",
+                "expected 1 argument(s) in call to 'absent', got 0",
+            ),
+            (
+                "absent(foo, bar)",
+                "expected 1 argument(s) in call to 'absent', got 2",
+            ),
+            (
+                "absent_over_time(foo)",
+                "expected type matrix in call to function 'absent_over_time', got vector",
+            ),
             // (r#"label_replace(a, `b`, `c\xff`, `d`, `.*`)"#, ""),
+            (
+                r#"label_replace(a, "b", "c", "d", "(.*")"#,
+                "invalid regular expression in label_replace(): illegal regex for (.* at position 33..34: unclosed group",
+            ),
+            (
+                r#"label_replace(a, "", "c", "d", ".*")"#,
+                r#"invalid destination label name in label_replace(): """#,
+            ),
+            (
+                r#"label_replace(a, "b", "c", '', ".*")"#,
+                r#"invalid source label name in label_replace(): """#,
+            ),
+            (
+                r#"label_join(a, "b", ",", "c", "")"#,
+                r#"invalid source label name in label_join(): """#,
+            ),
         ];
         assert_cases(Case::new_fail_cases(fail_cases));
     }
@@ -2044,7 +3333,7 @@ mod tests {
                     None,
                     Expr::new_vector_selector(Some(name), matchers).unwrap(),
                 )
-                .and_then(|ex| Expr::new_paren_expr(ex))
+                .and_then(Expr::new_paren_expr)
                 .and_then(|ex| Expr::new_subquery_expr(ex, duration::MINUTE_DURATION * 5, None))
             }),
             (r#"(foo + bar{nm="val"})[5m:] offset 10m"#, {
@@ -2060,7 +3349,7 @@ mod tests {
                     None,
                     Expr::new_vector_selector(Some(name), matchers).unwrap(),
                 )
-                .and_then(|ex| Expr::new_paren_expr(ex))
+                .and_then(Expr::new_paren_expr)
                 .and_then(|ex| Expr::new_subquery_expr(ex, duration::MINUTE_DURATION * 5, None))
                 .and_then(|ex| ex.offset_expr(Offset::Pos(duration::MINUTE_DURATION * 10)))
             }),
@@ -2080,7 +3369,7 @@ mod tests {
                     None,
                     rhs,
                 )
-                .and_then(|ex| Expr::new_paren_expr(ex))
+                .and_then(Expr::new_paren_expr)
                 .and_then(|ex| Expr::new_subquery_expr(ex, duration::MINUTE_DURATION * 5, None))
                 .and_then(|ex| ex.at_expr(At::try_from(1603775019_f64).unwrap()))
             }),
@@ -2088,6 +3377,15 @@ mod tests {
         assert_cases(Case::new_result_cases(cases));
 
         let fail_cases = vec![
+            ("foo[5m:0s]", "duration must be greater than 0"),
+            ("foo[0s:1m]", "duration must be greater than 0"),
+            ("foo[5m:-1m]", "unexpected character inside brackets: '-'"),
+            ("foo[5m:293y]", "duration out of range"),
+            ("foo[293y:5m]", "duration out of range"),
+            (
+                "foo[5m:] offset 9999999999999999999y",
+                "duration out of range",
+            ),
             (
                 "test[5d] OFFSET 10s [10m:5s]",
                 "subquery is only allowed on vector, got matrix instead",
@@ -2144,6 +3442,34 @@ mod tests {
                 )
                 .and_then(|ex| ex.at_expr(At::Start)),
             ),
+            // the modifiers inside and outside of a subquery are independent
+            (
+                "(foo @ end())[1h:5m] @ start()",
+                Expr::from(VectorSelector::from("foo"))
+                    .at_expr(At::End)
+                    .and_then(Expr::new_paren_expr)
+                    .and_then(|ex| {
+                        Expr::new_subquery_expr(
+                            ex,
+                            duration::HOUR_DURATION,
+                            Some(duration::MINUTE_DURATION * 5),
+                        )
+                    })
+                    .and_then(|ex| ex.at_expr(At::Start)),
+            ),
+            (
+                "foo @ end()[1h:5m] @ start()",
+                Expr::from(VectorSelector::from("foo"))
+                    .at_expr(At::End)
+                    .and_then(|ex| {
+                        Expr::new_subquery_expr(
+                            ex,
+                            duration::HOUR_DURATION,
+                            Some(duration::MINUTE_DURATION * 5),
+                        )
+                    })
+                    .and_then(|ex| ex.at_expr(At::Start)),
+            ),
             // Check that start and end functions do not mask metrics.
             ("start", Ok(Expr::from(VectorSelector::from("start")))),
             ("end", Ok(Expr::from(VectorSelector::from("end")))),
@@ -2193,12 +3519,575 @@ mod tests {
         assert_cases(Case::new_result_cases(cases));
 
         let cases = vec![
-            ("start()", INVALID_QUERY_INFO),
-            ("end()", INVALID_QUERY_INFO),
+            (
+                "start()",
+                "unexpected '(', expected end of input, '{', '[', binary operator, '@' or 'offset'",
+            ),
+            (
+                "end()",
+                "unexpected '(', expected end of input, '{', '[', binary operator, '@' or 'offset'",
+            ),
+            (
+                "(foo @ end())[1h:5m] @ start() @ end()",
+                "@ <timestamp> may not be set multiple times",
+            ),
+            (
+                "(foo[1h:5m] @ start())[2h:] @ end()",
+                "subquery is only allowed on vector, got matrix instead",
+            ),
         ];
         assert_cases(Case::new_fail_cases(cases));
     }
 
+    #[test]
+    fn test_keywords_as_names() {
+        // keywords that are also allowed as metric names by the metric_identifier rule
+        let names = vec![
+            "avg",
+            "bottomk",
+            "by",
+            "count",
+            "count_values",
+            "group",
+            "and",
+            "or",
+            "unless",
+            "max",
+            "min",
+            "offset",
+            "quantile",
+            "stddev",
+            "stdvar",
+            "sum",
+            "topk",
+            "without",
+            "start",
+            "end",
+            "SUM",
+            "Offset",
+        ];
+        for name in names {
+            let cases = vec![
+                (name.to_string(), Ok(Expr::from(VectorSelector::from(name)))),
+                (
+                    format!(r#"{name}{{job="api"}}"#),
+                    Expr::new_vector_selector(
+                        Some(String::from(name)),
+                        Matchers::new(HashSet::from([
+                            Matcher::new_eq_metric_matcher(String::from(name)),
+                            Matcher::new(MatchOp::Equal, String::from("job"), String::from("api")),
+                        ])),
+                    ),
+                ),
+                (
+                    format!("{name}[5m] offset 1m"),
+                    Expr::new_matrix_selector(
+                        Expr::from(VectorSelector::from(name)),
+                        duration::MINUTE_DURATION * 5,
+                    )
+                    .and_then(|ex| ex.offset_expr(Offset::Pos(duration::MINUTE_DURATION))),
+                ),
+                (
+                    format!("foo + {name}"),
+                    Expr::new_binary_expr(
+                        Expr::from(VectorSelector::from("foo")),
+                        token::T_ADD,
+                        None,
+                        Expr::from(VectorSelector::from(name)),
+                    ),
+                ),
+            ];
+            let cases = cases
+                .into_iter()
+                .map(|(input, expected)| Case { input, expected })
+                .collect();
+            assert_cases(cases);
+        }
+
+        let cases = vec![
+            ("sum(sum) by (sum)", {
+                let modifier = LabelModifier::Include(HashSet::from([String::from("sum")]));
+                let ex = Expr::from(VectorSelector::from("sum"));
+                Expr::new_aggregate_expr(token::T_SUM, Some(modifier), FunctionArgs::new_args(ex))
+            }),
+            ("sum without(a)(without)", {
+                let modifier = LabelModifier::Exclude(HashSet::from([String::from("a")]));
+                let ex = Expr::from(VectorSelector::from("without"));
+                Expr::new_aggregate_expr(token::T_SUM, Some(modifier), FunctionArgs::new_args(ex))
+            }),
+            ("start @ end()", {
+                Expr::from(VectorSelector::from("start")).at_expr(At::End)
+            }),
+            (r#"foo{on="a", bool="b", by="c", offset="d", group_left="e"}"#, {
+                let matchers = Matchers::new(HashSet::from([
+                    Matcher::new_eq_metric_matcher(String::from("foo")),
+                    Matcher::new(MatchOp::Equal, String::from("on"), String::from("a")),
+                    Matcher::new(MatchOp::Equal, String::from("bool"), String::from("b")),
+                    Matcher::new(MatchOp::Equal, String::from("by"), String::from("c")),
+                    Matcher::new(MatchOp::Equal, String::from("offset"), String::from("d")),
+                    Matcher::new(MatchOp::Equal, String::from("group_left"), String::from("e")),
+                ]));
+                Expr::new_vector_selector(Some(String::from("foo")), matchers)
+            }),
+            ("sum by (and, or, unless, on, bool, ignoring, group_left, group_right, offset, start, end, atan2, avg) (foo)", {
+                let labels = [
+                    "and", "or", "unless", "on", "bool", "ignoring", "group_left",
+                    "group_right", "offset", "start", "end", "atan2", "avg",
+                ];
+                let modifier = LabelModifier::Include(labels.into_iter().map(String::from).collect());
+                let ex = Expr::from(VectorSelector::from("foo"));
+                Expr::new_aggregate_expr(token::T_SUM, Some(modifier), FunctionArgs::new_args(ex))
+            }),
+            ("foo * on(bool, on) group_left(and, by) bar", {
+                let modifier = BinModifier::default()
+                    .with_matching(Some(LabelModifier::Include(HashSet::from([
+                        String::from("bool"),
+                        String::from("on"),
+                    ]))))
+                    .with_card(VectorMatchCardinality::ManyToOne(HashSet::from([
+                        String::from("and"),
+                        String::from("by"),
+                    ])));
+                Expr::new_binary_expr(
+                    Expr::from(VectorSelector::from("foo")),
+                    token::T_MUL,
+                    Some(modifier),
+                    Expr::from(VectorSelector::from("bar")),
+                )
+            }),
+        ];
+        assert_cases(Case::new_result_cases(cases));
+
+        // keywords that are NOT allowed as metric names by upstream, since they
+        // are ambiguous with the binary modifiers. Use {__name__="on"} instead.
+        let fail_cases = vec![
+            ("on", "unexpected 'on', expected identifier, '{', '(', metric identifier, number, string, '+', '-' or aggregation"),
+            ("bool", "unexpected 'bool', expected identifier, '{', '(', metric identifier, number, string, '+', '-' or aggregation"),
+            ("ignoring", "unexpected 'ignoring', expected identifier, '{', '(', metric identifier, number, string, '+', '-' or aggregation"),
+            ("group_left", "unexpected 'group_left', expected identifier, '{', '(', metric identifier, number, string, '+', '-' or aggregation"),
+            ("group_right", "unexpected 'group_right', expected identifier, '{', '(', metric identifier, number, string, '+', '-' or aggregation"),
+            ("atan2", "unexpected 'atan2', expected identifier, '{', '(', metric identifier, number, string, '+', '-' or aggregation"),
+            ("sum without(without)(foo)", "unexpected 'without', expected identifier, ')' or string"),
+            (
+                "sum by (foo:bar) (foo)",
+                "foo:bar is not valid label in grouping opts",
+            ),
+        ];
+        assert_cases(Case::new_fail_cases(fail_cases));
+    }
+
+    #[test]
+    fn test_expected_tokens() {
+        let fail_cases = vec![
+            (
+                "foo[5m 1m]",
+                r#"unexpected duration "1m", expected ':' or ']'"#,
+            ),
+            (
+                r#"foo{a="b" c}"#,
+                r#"unexpected identifier "c", expected ',' or '}'"#,
+            ),
+            ("sum(foo) by", "unexpected end of input, expected '('"),
+            (
+                "sum without(a) by (b) (foo)",
+                "unexpected 'by', expected '('",
+            ),
+            (
+                "rate(foo[5m] x)",
+                r#"unexpected identifier "x", expected ',', '[', ')', binary operator, '@' or 'offset'"#,
+            ),
+            (
+                "foo bar",
+                r#"unexpected identifier "bar", expected end of input, '{', '[', '(', binary operator, '@' or 'offset'"#,
+            ),
+            (
+                "foo >",
+                "unexpected end of input, expected identifier, '{', '(', metric identifier, number, string, '+', '-', aggregation, 'bool', 'ignoring' or 'on'",
+            ),
+            (
+                "foo and on (a)",
+                "unexpected end of input, expected identifier, '{', '(', metric identifier, number, string, '+', '-' or aggregation",
+            ),
+            (
+                r#"foo{"a" b}"#,
+                r#"unexpected identifier "b", expected '=', ',', '}', '=~', '!=' or '!~'"#,
+            ),
+        ];
+        assert_cases(Case::new_fail_cases(fail_cases));
+    }
+
+    #[test]
+    fn test_parse_budget() {
+        use super::{parse_with_options, CancellationToken, ParseOptions};
+        use crate::util::diagnostic::codes;
+        use std::time::Duration;
+
+        let query = "sum(rate(foo[5m]))";
+        let options = ParseOptions::new().with_max_tokens(10);
+        assert_eq!(parse_with_options(query, &options), super::parse(query));
+        let options = ParseOptions::new().with_max_tokens(9);
+        assert_eq!(
+            parse_with_options(query, &options),
+            Err("too many tokens in query, the limit is 9".into())
+        );
+
+        let options = ParseOptions::new().with_timeout(Duration::from_secs(60));
+        assert_eq!(parse_with_options(query, &options), super::parse(query));
+        let options = ParseOptions::new().with_timeout(Duration::ZERO);
+        assert_eq!(
+            parse_with_options(query, &options),
+            Err("parsing exceeded the timeout of 0s".into())
+        );
+        assert_eq!(
+            parse_with_options("foo +", &options),
+            Err("parsing exceeded the timeout of 0s".into())
+        );
+
+        // the budget is checked while the nodes are built, not only after lexing
+        let lexer = crate::parser::lex::lexer(query).unwrap();
+        let ctx = super::ParseContext::new(true).with_limits(&options);
+        let err = super::parse_lexer(query, &lexer, &ctx).unwrap_err();
+        assert_eq!(err.code, codes::LIMIT);
+        assert_eq!(err.message, "parsing exceeded the timeout of 0s");
+
+        let token = CancellationToken::new();
+        let options = ParseOptions::new().with_cancellation_token(token.clone());
+        assert_eq!(parse_with_options(query, &options), super::parse(query));
+        token.cancel();
+        assert_eq!(
+            parse_with_options(query, &options),
+            Err("parsing cancelled".into())
+        );
+
+        let options = ParseOptions::new().with_max_size(1 << 20);
+        assert_eq!(parse_with_options(query, &options), super::parse(query));
+        let wide = vec!["foo"; 10_000].join(" or ");
+        let err = super::parse_with_diagnostic(&wide, &options).unwrap_err();
+        assert_eq!(err.code, codes::LIMIT);
+        assert_eq!(err.message, "query exceeds the size limit of 1048576 bytes");
+        // strings count with their length
+        let long = format!(
+            "label_replace(foo, \"a\", \"{}\", \"b\", \"\")",
+            "x".repeat(1 << 20)
+        );
+        assert_eq!(
+            parse_with_options(&long, &options),
+            Err("query exceeds the size limit of 1048576 bytes".into())
+        );
+        // the limit is also reported when a node failed a check before
+        let wide = format!("rate(foo) or {wide}");
+        let err = super::parse_with_diagnostic(&wide, &options).unwrap_err();
+        assert_eq!(err.code, codes::LIMIT);
+    }
+
+    #[test]
+    fn test_parse_with_options() {
+        use super::{parse_with_options, NameChecker, ParseOptions};
+        use crate::label::NameValidationScheme;
+        use crate::util::walk_expr;
+
+        let legacy = ParseOptions::new();
+        let utf8 = ParseOptions::new().with_name_validation_scheme(NameValidationScheme::Utf8);
+        let query = r#"sum by (job) (rate(foo:bar{a="b"}[5m])) / on(job) group_left(le) baz"#;
+        assert_eq!(parse_with_options(query, &legacy), super::parse(query));
+        assert_eq!(parse_with_options(query, &utf8), super::parse(query));
+
+        let mut legacy = NameChecker {
+            scheme: NameValidationScheme::Legacy,
+        };
+        let mut utf8 = NameChecker {
+            scheme: NameValidationScheme::Utf8,
+        };
+
+        let name = Some(String::from("foo.bar"));
+        let expr = Expr::new_vector_selector(name, Matchers::empty()).unwrap();
+        assert_eq!(
+            walk_expr(&mut legacy, &expr),
+            Err(r#"invalid metric name "foo.bar""#.into())
+        );
+        assert_eq!(walk_expr(&mut utf8, &expr), Ok(true));
+
+        let matcher = Matcher::new(MatchOp::Equal, "a.b".into(), "c".into());
+        let rhs = Expr::new_vector_selector(None, Matchers::one(matcher)).unwrap();
+        let expr =
+            Expr::new_binary_expr(super::parse("foo").unwrap(), token::T_ADD, None, rhs).unwrap();
+        assert_eq!(
+            walk_expr(&mut legacy, &expr),
+            Err(r#"invalid label name "a.b""#.into())
+        );
+        assert_eq!(walk_expr(&mut utf8, &expr), Ok(true));
+
+        let expr = Expr::new_aggregate_expr(
+            token::T_SUM,
+            Some(LabelModifier::Include(HashSet::from(["a-b".into()]))),
+            FunctionArgs::new_args(super::parse("foo").unwrap()),
+        )
+        .unwrap();
+        assert_eq!(
+            walk_expr(&mut legacy, &expr),
+            Err(r#"invalid label name "a-b""#.into())
+        );
+
+        let expr = super::parse(r#"count_values("not a valid label!", foo)"#).unwrap();
+        assert_eq!(
+            walk_expr(&mut legacy, &expr),
+            Err(r#"invalid label name "not a valid label!""#.into())
+        );
+        assert_eq!(walk_expr(&mut utf8, &expr), Ok(true));
+
+        let expr = super::parse(r#"label_join(foo, "a", ",", "b", "c.d")"#).unwrap();
+        assert_eq!(
+            walk_expr(&mut legacy, &expr),
+            Err(r#"invalid label name "c.d""#.into())
+        );
+        assert_eq!(walk_expr(&mut utf8, &expr), Ok(true));
+        // the regex is not a label name
+        let expr = super::parse(r#"label_replace(foo, "a", "$1", "b", "c.d")"#).unwrap();
+        assert_eq!(walk_expr(&mut legacy, &expr), Ok(true));
+    }
+
+    #[test]
+    fn test_experimental_functions() {
+        use super::{parse_with_options, ParseOptions};
+
+        let enabled = ParseOptions::new().with_experimental_functions(true);
+        for query in [
+            "first_over_time(foo[5m])",
+            "mad_over_time(foo[5m])",
+            r#"sort_by_label(foo, "a", "b", "c")"#,
+            r#"sort_by_label_desc(foo, "a")"#,
+            "ts_of_first_over_time(foo[5m])",
+            "ts_of_last_over_time(foo[5m])",
+            "ts_of_max_over_time(foo[5m])",
+            "ts_of_min_over_time(foo[5m])",
+        ] {
+            let expr = parse_with_options(query, &enabled).unwrap();
+            assert_eq!(expr.to_string(), query);
+        }
+
+        let fail_cases = vec![
+            (
+                "first_over_time(foo[5m])",
+                "function 'first_over_time' is not enabled",
+            ),
+            (
+                "sum(mad_over_time(foo[5m]))",
+                "function 'mad_over_time' is not enabled",
+            ),
+            (
+                "topk(scalar(ts_of_max_over_time(foo[5m])), bar)",
+                "function 'ts_of_max_over_time' is not enabled",
+            ),
+        ];
+        for (query, err) in &fail_cases {
+            assert_eq!(
+                parse_with_options(query, &ParseOptions::new()),
+                Err(err.to_string())
+            );
+        }
+        assert_cases(Case::new_fail_cases(fail_cases));
+        assert_eq!(
+            parse_with_options("first_over_time(foo)", &enabled),
+            Err("expected type matrix in call to function 'first_over_time', got vector".into())
+        );
+        assert_eq!(
+            parse_with_options(r#"sort_by_label(foo, "a", "")"#, &enabled),
+            Err(r#"invalid label name in sort_by_label(): """#.into())
+        );
+    }
+
+    #[test]
+    fn test_negative_offsets() {
+        use super::{parse_with_options, ParseOptions};
+        use crate::util::format::{prettify, FormatConfig};
+
+        let neg = |secs| Some(Offset::Neg(Duration::from_secs(secs)));
+        let cases = vec![
+            ("foo offset -5m", "foo offset -5m", neg(300)),
+            ("foo offset - 90s", "foo offset -1m30s", neg(90)),
+            ("foo[5m] offset -1h30m", "foo[5m] offset -1h30m", neg(5400)),
+            (
+                "rate(foo[5m])[1h:1m] offset -1w",
+                "rate(foo[5m])[1h:1m] offset -1w",
+                neg(604800),
+            ),
+            ("foo @ 10 offset -1d", "foo @ 10.000 offset -1d", neg(86400)),
+            (
+                "foo offset +5m",
+                "foo offset 5m",
+                Some(Offset::Pos(Duration::from_secs(300))),
+            ),
+        ];
+        for (query, rendered, offset) in cases {
+            let expr = crate::parser::parse(query).unwrap();
+            let actual = match &expr {
+                Expr::VectorSelector(vs) => &vs.offset,
+                Expr::MatrixSelector(ms) => &ms.vector_selector.offset,
+                Expr::Subquery(sq) => &sq.offset,
+                _ => unreachable!(),
+            };
+            assert_eq!(actual, &offset, "{query}");
+            assert_eq!(expr.to_string(), rendered, "{query}");
+            assert_eq!(
+                prettify(&expr, &FormatConfig::default()),
+                rendered,
+                "{query}"
+            );
+            assert_eq!(crate::parser::parse(rendered).unwrap(), expr, "{query}");
+        }
+
+        // nested in the function args and aggregation params
+        let query = "topk(scalar(foo offset -1m), rate(bar[5m] offset -1m))";
+        let expr = crate::parser::parse(query).unwrap();
+        assert_eq!(expr.to_string(), query);
+
+        let disabled = ParseOptions::new().with_reject_negative_offsets(true);
+        for (query, err) in [
+            ("foo offset -5m", "negative offset -5m is not enabled"),
+            (
+                "sum(rate(foo[5m] offset -1h))",
+                "negative offset -1h is not enabled",
+            ),
+            ("foo[1h:] offset -1d", "negative offset -1d is not enabled"),
+            (query, "negative offset -1m is not enabled"),
+        ] {
+            assert_eq!(
+                parse_with_options(query, &disabled),
+                Err(err.to_string()),
+                "{query}"
+            );
+        }
+        assert!(parse_with_options("foo offset 5m", &disabled).is_ok());
+        let enabled = disabled.with_reject_negative_offsets(false);
+        assert!(parse_with_options(query, &enabled).is_ok());
+    }
+
+    #[test]
+    fn test_offset_and_at_order() {
+        let cases = vec![
+            ("foo offset 5m @ 1609746000", "foo @ 1609746000 offset 5m"),
+            ("foo offset -5m @ start()", "foo @ start() offset -5m"),
+            (
+                r#"foo{a="b"}[5m] offset 1h @ end()"#,
+                r#"foo{a="b"}[5m] @ end() offset 1h"#,
+            ),
+            ("foo[5m:1m] offset 5m @ 100", "foo[5m:1m] @ 100 offset 5m"),
+            (
+                "sum(foo)[5m:] offset 1w @ -100",
+                "sum(foo)[5m:] @ -100 offset 1w",
+            ),
+            (
+                "rate(foo[5m] offset 1m @ 100)[1h:] offset 1h @ 200",
+                "rate(foo[5m] @ 100 offset 1m)[1h:] @ 200 offset 1h",
+            ),
+            (
+                "foo offset 5m @ 100 + bar @ 100 offset 5m",
+                "foo @ 100 offset 5m + bar offset 5m @ 100",
+            ),
+        ];
+        for (query, swapped) in cases {
+            let expr = crate::parser::parse(query).unwrap();
+            assert_eq!(crate::parser::parse(swapped), Ok(expr.clone()), "{query}");
+            // the modifiers are always written in the same order
+            assert_eq!(crate::parser::parse(&expr.to_string()), Ok(expr), "{query}");
+        }
+
+        let fail_cases = vec![
+            (
+                "foo offset 1m @ 2 offset 3m",
+                "offset may not be set multiple times",
+            ),
+            (
+                "foo @ 1 offset 2m @ 3",
+                "@ <timestamp> may not be set multiple times",
+            ),
+            (
+                "foo[5m] @ 1 offset 2m @ 3",
+                "@ <timestamp> may not be set multiple times",
+            ),
+            (
+                "foo[5m:] offset 1m @ 2 offset 3m",
+                "offset may not be set multiple times",
+            ),
+        ];
+        for (query, err) in fail_cases {
+            assert_eq!(crate::parser::parse(query), Err(err.into()), "{query}");
+        }
+    }
+
+    #[test]
+    fn test_quoted_label_names() {
+        use super::{parse_with_options, ParseOptions};
+        use crate::label::{Labels, NameValidationScheme};
+        use crate::parser::BinaryExpr;
+
+        let labels = |ls: &[&str]| ls.iter().map(|l| l.to_string()).collect::<Labels>();
+        let cases = vec![
+            (
+                r#"sum by ("my.label", job) (foo)"#,
+                r#"sum by (job, "my.label") (foo)"#,
+            ),
+            (r#"sum without ('a') (foo)"#, "sum without (a) (foo)"),
+            (
+                r#"foo + on("service.name") group_left("x.y") bar"#,
+                r#"foo + on ("service.name") group_left ("x.y") bar"#,
+            ),
+            (r#"count_values("a.b", foo)"#, r#"count_values("a.b", foo)"#),
+            (
+                r#"x{"a.b"="c", 'job'!~"api"}"#,
+                r#"x{"a.b"="c",job!~"api"}"#,
+            ),
+            (
+                r#"{"foo.bar", "a.b"=~"c"}"#,
+                r#"{"a.b"=~"c",__name__="foo.bar"}"#,
+            ),
+        ];
+        for (query, display) in cases {
+            let expr = crate::parser::parse(query).unwrap();
+            assert_eq!(expr.to_string(), display, "{query}");
+            assert_eq!(crate::parser::parse(display), Ok(expr), "{query}");
+        }
+
+        let expr = crate::parser::parse(r#"foo + on("service.name") group_left("x.y") bar"#);
+        let Ok(Expr::Binary(BinaryExpr {
+            modifier: Some(modifier),
+            ..
+        })) = expr
+        else {
+            unreachable!()
+        };
+        assert_eq!(
+            modifier.matching,
+            Some(LabelModifier::Include(labels(&["service.name"])))
+        );
+        assert_eq!(modifier.card.labels(), Some(&labels(&["x.y"])));
+
+        // the names are checked against the validation scheme
+        let query = r#"sum by ("my.label") (foo)"#;
+        let utf8 = ParseOptions::new().with_name_validation_scheme(NameValidationScheme::Utf8);
+        assert!(parse_with_options(query, &utf8).is_ok());
+        assert_eq!(
+            parse_with_options(query, &ParseOptions::new()),
+            Err(r#"invalid label name "my.label""#.into())
+        );
+
+        let query = r#"x{"a.b"="c"}"#;
+        assert!(parse_with_options(query, &utf8).is_ok());
+        assert_eq!(
+            parse_with_options(query, &ParseOptions::new()),
+            Err(r#"invalid label name "a.b""#.into())
+        );
+        assert_eq!(
+            parse_with_options(r#"x{""="c"}"#, &utf8),
+            Err(r#"invalid label name """#.into())
+        );
+
+        assert_eq!(
+            crate::parser::parse(r#"sum by ("") (foo)"#),
+            Err("label name must not be empty in grouping opts".into())
+        );
+    }
+
     #[test]
     fn test_corner_fail_cases() {
         let fail_cases = vec![
@@ -2207,19 +4096,29 @@ mod tests {
                 "# just a comment\n\n",
                 "no expression found in input",
             ),
-            ("1+", INVALID_QUERY_INFO),
+            ("1+", "unexpected end of input, expected identifier, '{', '(', metric identifier, number, string, '+', '-', aggregation, 'ignoring' or 'on'"),
             (".", "unexpected character: '.'"),
             ("2.5.", "bad number or duration syntax: 2.5."),
             ("100..4", "bad number or duration syntax: 100.."),
             ("0deadbeef", "bad number or duration syntax: 0de"),
-            ("1 /", INVALID_QUERY_INFO),
-            ("*1", INVALID_QUERY_INFO),
+            ("1 /", "unexpected end of input, expected identifier, '{', '(', metric identifier, number, string, '+', '-', aggregation, 'ignoring' or 'on'"),
+            ("*1", "unexpected '*', expected identifier, '{', '(', metric identifier, number, string, '+', '-' or aggregation"),
             ("(1))", "unexpected right parenthesis ')'"),
-            ("((1)", "unclosed left parenthesis"),
-            ("(", "unclosed left parenthesis"),
+            ("((1)", "unclosed left parenthesis at position 0"),
+            ("(", "unclosed left parenthesis at position 0"),
+            ("(1 + (2)", "unclosed left parenthesis at position 0"),
+            ("sum(rate(foo[5m])", "unclosed left parenthesis at position 3"),
+            (
+                "rate(foo[5m ",
+                "unexpected end of input inside brackets, unclosed left bracket at position 8",
+            ),
+            (
+                r#"foo{a="b", c="d""#,
+                "unexpected end of input inside braces, unclosed left brace at position 3",
+            ),
             ("1 !~ 1", "unexpected character after '!': '~'"),
             ("1 =~ 1", "unexpected character after '=': '~'"),
-            ("*test", INVALID_QUERY_INFO),
+            ("*test", "unexpected '*', expected identifier, '{', '(', metric identifier, number, string, '+', '-' or aggregation"),
             (
                 "1 offset 1d",
                 "offset modifier must be preceded by an vector selector or matrix selector or a subquery"
@@ -2228,12 +4127,12 @@ mod tests {
                 "foo offset 1s offset 2s",
                 "offset may not be set multiple times"
             ),
-            ("a - on(b) ignoring(c) d", INVALID_QUERY_INFO),
+            ("a - on(b) ignoring(c) d", "unexpected 'ignoring', expected identifier, '{', '(', metric identifier, number, string, '+', '-', aggregation, 'group_left' or 'group_right'"),
 
             // Fuzzing regression tests.
-            ("-=", INVALID_QUERY_INFO),
-            ("++-++-+-+-<", INVALID_QUERY_INFO),
-            ("e-+=/(0)", INVALID_QUERY_INFO),
+            ("-=", "unexpected '=', expected identifier, '{', '(', metric identifier, number, string, '+', '-' or aggregation"),
+            ("++-++-+-+-<", "unexpected '<', expected identifier, '{', '(', metric identifier, number, string, '+', '-' or aggregation"),
+            ("e-+=/(0)", "unexpected '=', expected identifier, '{', '(', metric identifier, number, string, '+', '-' or aggregation"),
             ("a>b()", "unknown function with name 'b'"),
             (
                 "rate(avg)",
@@ -2255,4 +4154,302 @@ mod tests {
         ];
         assert_cases(fail_cases);
     }
+
+    #[test]
+    fn test_parse_bytes() {
+        use super::{parse_bytes, ParseOptions};
+        let options = ParseOptions::new();
+
+        let cases = vec![
+            (
+                &b"\xff"[..],
+                "invalid UTF-8 in query at position 0",
+                Some(0..1),
+            ),
+            (
+                b"foo + \xc3(",
+                "invalid UTF-8 in query at position 6",
+                Some(6..7),
+            ),
+            // a truncated sequence at the end runs to the end of the input
+            (
+                b"foo\xe5\x8c",
+                "invalid UTF-8 in query at position 3",
+                Some(3..5),
+            ),
+            (b"\0", "unexpected character: '\\0'", Some(0..1)),
+            (
+                b"foo{a=\"b\"}\x1b",
+                "unexpected character: '\\u{1b}'",
+                Some(10..11),
+            ),
+        ];
+        for (input, message, span) in cases {
+            let err = parse_bytes(input, &options).unwrap_err();
+            assert_eq!(err.message, message, "{input:?}");
+            assert_eq!(err.span, span, "{input:?}");
+        }
+        assert!(parse_bytes(b"foo{a=\"\0\"}", &options).is_ok());
+
+        // control characters at every position give a result, never a panic.
+        let query = r#"sum by(a) (rate(foo{b=~"c"}[5m] offset 1m)) > bool 0.5 # c"#;
+        for pos in 0..=query.len() {
+            for c in [0x00, 0x01, 0x0b, 0x1b, 0x7f] {
+                let mut input = query.as_bytes().to_vec();
+                input.insert(pos, c);
+                let _ = parse_bytes(&input, &options);
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_stmt() {
+        use super::{parse_stmt, ParseOptions};
+        use std::time::SystemTime;
+
+        let time = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        let step = Duration::from_secs(15);
+        let lookback = Duration::from_secs(300);
+        let options = ParseOptions::new();
+
+        let query = "sum(rate(foo[5m] @ start())) + max_over_time(bar[1h:] offset 1m)";
+        let stmt = parse_stmt(query, time(100), time(200), step, lookback, &options).unwrap();
+        assert_eq!(stmt.expr, crate::parser::parse(query).unwrap());
+        assert_eq!((stmt.start, stmt.end), (time(100), time(200)));
+        assert_eq!((stmt.interval, stmt.lookback_delta), (step, lookback));
+        assert_eq!(
+            stmt.resolve(Duration::from_secs(60)).expr.to_string(),
+            "sum(rate(foo[5m] @ 100.000)) + max_over_time(bar[1h:1m] offset 1m)"
+        );
+
+        // an instant query may return any type and needs no step
+        for query in ["foo[5m]", r#""a""#, "1"] {
+            let stmt = parse_stmt(
+                query,
+                time(100),
+                time(100),
+                Duration::ZERO,
+                lookback,
+                &options,
+            );
+            assert!(stmt.is_ok());
+        }
+
+        // 11000 points per series at most
+        let second = Duration::from_secs(1);
+        assert!(parse_stmt("foo", time(0), time(11000), second, lookback, &options).is_ok());
+        assert!(parse_stmt("foo", time(0), time(11001), second, lookback, &options).is_err());
+
+        let options = ParseOptions::new().with_reject_negative_offsets(true);
+        assert_eq!(
+            parse_stmt(
+                "foo offset -1m",
+                time(100),
+                time(200),
+                step,
+                lookback,
+                &options
+            )
+            .unwrap_err(),
+            crate::parser::parse_with_options("foo offset -1m", &options).unwrap_err()
+        );
+        let options = ParseOptions::new();
+
+        let cases = [
+            (
+                "foo",
+                time(200),
+                time(100),
+                step,
+                "end timestamp must not be before start time",
+            ),
+            (
+                "foo",
+                time(100),
+                time(200),
+                Duration::ZERO,
+                "zero or negative query resolution step widths are not accepted. Try a positive integer",
+            ),
+            (
+                r#""a""#,
+                time(100),
+                time(200),
+                step,
+                r#"invalid expression type "string" for range query, must be Scalar or instant Vector"#,
+            ),
+            (
+                "rate(foo)",
+                time(100),
+                time(200),
+                step,
+                "expected type matrix in call to function 'rate', got vector",
+            ),
+            (
+                "foo",
+                time(0),
+                time(86400),
+                Duration::from_secs(7),
+                "exceeded maximum resolution of 11,000 points per timeseries. Try decreasing the query resolution (?step=XX)",
+            ),
+        ];
+        for (query, start, end, step, err) in cases {
+            assert_eq!(
+                parse_stmt(query, start, end, step, lookback, &options).unwrap_err(),
+                err,
+                "{query}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_with_stats() {
+        use super::{parse_with_stats, ParseOptions};
+
+        let options = ParseOptions::new();
+        let (expr, stats) =
+            parse_with_stats("topk(scalar(foo), max_over_time((-bar)[1h:5m]))", &options).unwrap();
+        assert_eq!(
+            expr,
+            super::parse("topk(scalar(foo), max_over_time((-bar)[1h:5m]))").unwrap()
+        );
+        assert_eq!(stats.tokens, 20);
+        assert_eq!(
+            stats.nodes.into_iter().collect::<Vec<_>>(),
+            vec![
+                ("Aggregate", 1),
+                ("Call", 2),
+                ("Paren", 1),
+                ("Subquery", 1),
+                ("Unary", 1),
+                ("VectorSelector", 2)
+            ]
+        );
+        assert_eq!(stats.max_depth, 6);
+        assert_eq!(stats.selectors, 2);
+
+        let options = options.with_max_tokens(3);
+        assert_eq!(parse_with_stats("foo + bar", &options).unwrap().1.tokens, 3);
+        assert!(parse_with_stats("foo + bar + baz", &options).is_err());
+    }
+
+    #[test]
+    fn test_parse_syntax() {
+        use super::{parse_syntax, type_check};
+
+        // valid syntax, but not valid queries
+        for query in [
+            "rate(foo)",
+            "sum(foo[5m])",
+            r#"foo + "bar""#,
+            "1 > 2",
+            "foo and 1",
+            r#"-"foo""#,
+            "-foo[5m]",
+            r#"count_values("", foo)"#,
+            "topk(foo, bar)",
+            "abs(foo, bar)",
+            "sum(rate(foo)) by (job)",
+            r#"{__name__="foo", __name__="bar"}"#,
+            r#"{job=~".*"}"#,
+            "double_exponential_smoothing(foo, 0.5, 0.5)",
+        ] {
+            let expr = parse_syntax(query).unwrap();
+            assert_eq!(type_check(expr.clone()), super::parse(query), "{query}");
+            assert!(super::parse(&expr.to_string()).is_err(), "{query}");
+        }
+
+        for query in [
+            "foo",
+            "foo or on (job) bar unless baz",
+            "sum by (job) (rate(foo[5m] @ end())) / on (job) group_left () count(bar)",
+            "topk(3, foo)[1h:] offset 1m",
+            r#"label_replace(foo, "a", "$1", "b", "(.*)")"#,
+        ] {
+            let expr = parse_syntax(query).unwrap();
+            assert_eq!(
+                type_check(expr).unwrap(),
+                super::parse(query).unwrap(),
+                "{query}"
+            );
+        }
+
+        // syntax errors, and the errors only known while parsing, are the same
+        for query in [
+            "sum(foo",
+            "foo offset 1m offset 1m",
+            "unknown(foo)",
+            r#"+"foo""#,
+            r#"label_replace(foo, "a", "$1", "b", "(")"#,
+        ] {
+            assert_eq!(parse_syntax(query), super::parse(query), "{query}");
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_tracing() {
+        use std::sync::{Arc, Mutex};
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record};
+        use tracing::{Event, Metadata};
+
+        /// records the names of the spans and the values recorded on them.
+        #[derive(Default)]
+        struct Recorder {
+            recorded: Arc<Mutex<Vec<String>>>,
+            spans: Mutex<u64>,
+        }
+
+        impl Visit for &Recorder {
+            fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+                let value = format!("{field}={value:?}");
+                self.recorded.lock().unwrap().push(value);
+            }
+        }
+
+        impl tracing::Subscriber for Recorder {
+            fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+                metadata.level() <= &tracing::Level::DEBUG
+            }
+            fn new_span(&self, span: &Attributes<'_>) -> Id {
+                let name = span.metadata().name().to_string();
+                self.recorded.lock().unwrap().push(name);
+                let mut spans = self.spans.lock().unwrap();
+                *spans += 1;
+                Id::from_u64(*spans)
+            }
+            fn record(&self, _: &Id, values: &Record<'_>) {
+                values.record(&mut &*self);
+            }
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+            fn event(&self, _: &Event<'_>) {}
+            fn enter(&self, _: &Id) {}
+            fn exit(&self, _: &Id) {}
+        }
+
+        let recorded = Arc::new(Mutex::new(vec![]));
+        let subscriber = Recorder {
+            recorded: recorded.clone(),
+            ..Default::default()
+        };
+        tracing::subscriber::with_default(subscriber, || {
+            super::parse("sum(rate(foo[5m]))").unwrap();
+            super::parse_with_options("rate(foo)", &super::ParseOptions::new()).unwrap_err();
+        });
+        assert_eq!(
+            *recorded.lock().unwrap(),
+            vec![
+                "promql.parse",
+                "promql.lex",
+                "tokens=10",
+                "promql.grammar",
+                "nodes=3",
+                "promql.parse",
+                "promql.lex",
+                "tokens=4",
+                "promql.grammar",
+                r#"error="expected type matrix in call to function 'rate', got vector""#,
+            ]
+        );
+    }
 }
@@ -12,8 +12,175 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::parser::{LexemeType, Token, TokenId};
+use crate::label::{Labels, Matchers};
+use crate::parser::ast::check_ast;
+use crate::parser::lex::unquote;
+use crate::parser::parse::Budget;
+use crate::parser::{
+    Expr, LabelModifier, LexemeType, ParseOptions, Token, TokenId, VectorSelector,
+};
 use lrpar::{Lexeme, NonStreamingLexer, Span};
+use std::cell::{Cell, RefCell};
+use std::mem::{size_of, size_of_val};
+use std::ops::Range;
+
+/// the state of a parse, shared by the actions of the grammar.
+#[derive(Debug, Default)]
+pub(crate) struct ParseContext {
+    /// whether the nodes are checked, see [`crate::parser::parse_syntax`].
+    checked: bool,
+    /// the spans of the nodes built so far, in post-order.
+    spans: RefCell<Vec<Range<usize>>>,
+    /// the size in bytes the nodes may take, see [`crate::parser::ParseOptions::max_size`].
+    max_size: Option<usize>,
+    /// the estimated size of the nodes built so far.
+    size: Cell<usize>,
+    /// the timeout and cancellation, see [`crate::parser::ParseOptions::timeout`].
+    budget: Budget,
+    /// the error of the limit which aborted the parse.
+    exceeded: RefCell<Option<String>>,
+    /// the errors with a span, like the ones of durations or regexes.
+    errors: RefCell<Vec<(String, Range<usize>)>>,
+}
+
+impl ParseContext {
+    pub(crate) fn new(checked: bool) -> Self {
+        Self {
+            checked,
+            spans: RefCell::default(),
+            max_size: None,
+            size: Cell::default(),
+            budget: Budget::default(),
+            exceeded: RefCell::default(),
+            errors: RefCell::default(),
+        }
+    }
+
+    /// the max size and the budget of the options, the budget starts now.
+    pub(crate) fn with_limits(mut self, options: &ParseOptions) -> Self {
+        self.max_size = options.max_size;
+        self.budget = Budget::new(options);
+        self
+    }
+
+    pub(crate) fn budget(&self) -> &Budget {
+        &self.budget
+    }
+
+    /// the error once the nodes took more than the max size or the budget ran
+    /// out, the parse is then aborted.
+    pub(crate) fn limit_exceeded(&self) -> Option<String> {
+        self.exceeded.borrow().clone()
+    }
+
+    fn check_limits(&self, expr: &Expr) -> Result<(), String> {
+        self.size.set(self.size.get() + node_size(expr));
+        let result = match self.max_size {
+            Some(max) if self.size.get() > max => {
+                Err(format!("query exceeds the size limit of {max} bytes"))
+            }
+            _ => self.budget.check(),
+        };
+        if let Err(err) = &result {
+            self.exceeded.replace(Some(err.clone()));
+        }
+        result
+    }
+
+    pub(crate) fn checked(&self) -> bool {
+        self.checked
+    }
+
+    /// a new node, whose children are the last nodes built.
+    pub(crate) fn node(&self, expr: Expr, span: Span) -> Result<Expr, String> {
+        self.check_limits(&expr)?;
+        // the node is where the check failed, unless the error has a span already
+        let expr = self.token(self.check(expr), span)?;
+        self.spans.borrow_mut().push(span.start()..span.end());
+        Ok(expr)
+    }
+
+    /// the last node built, which now covers more of the query, like `foo`
+    /// in `foo offset 1m`.
+    pub(crate) fn modified(&self, expr: Expr, span: Span) -> Result<Expr, String> {
+        let expr = self.check(expr)?;
+        if let Some(last) = self.spans.borrow_mut().last_mut() {
+            *last = span.start()..span.end();
+        }
+        Ok(expr)
+    }
+
+    fn check(&self, expr: Expr) -> Result<Expr, String> {
+        match self.checked {
+            true => check_ast(expr),
+            false => Ok(expr),
+        }
+    }
+
+    /// keep the span of the token or node if its value is an error, see [`ParseContext::error_span`].
+    pub(crate) fn token<T>(&self, result: Result<T, String>, span: Span) -> Result<T, String> {
+        if let Err(err) = &result {
+            let span = span.start()..span.end();
+            self.errors.borrow_mut().push((err.clone(), span));
+        }
+        result
+    }
+
+    /// keep the span of the error, if it has one, see [`ParseContext::error_span`].
+    pub(crate) fn spanned<T>(
+        &self,
+        result: Result<T, (String, Option<Range<usize>>)>,
+    ) -> Result<T, String> {
+        result.map_err(|(err, span)| {
+            if let Some(span) = span {
+                self.errors.borrow_mut().push((err.clone(), span));
+            }
+            err
+        })
+    }
+
+    /// the span of the error, if it is one of a token or has a span of its own.
+    pub(crate) fn error_span(&self, err: &str) -> Option<Range<usize>> {
+        let errors = self.errors.borrow();
+        errors
+            .iter()
+            .find(|(e, _)| e == err)
+            .map(|(_, span)| span.clone())
+    }
+
+    /// the spans of the nodes in post-order.
+    pub(crate) fn into_spans(self) -> Vec<Range<usize>> {
+        self.spans.into_inner()
+    }
+}
+
+/// the estimated bytes of a node without its children, which are counted when
+/// they are built.
+fn node_size(expr: &Expr) -> usize {
+    let labels = |labels: Option<&Labels>| {
+        labels.map_or(0, |l| l.iter().map(|l| size_of::<String>() + l.len()).sum())
+    };
+    let matchers = |m: &Matchers| -> usize {
+        m.matchers
+            .iter()
+            .map(|m| size_of_val(m) + m.name.len() + m.value.len())
+            .sum()
+    };
+    let selector =
+        |vs: &VectorSelector| vs.name.as_ref().map_or(0, String::len) + matchers(&vs.matchers);
+    let own = match expr {
+        Expr::Aggregate(ex) => labels(ex.modifier.as_ref().map(LabelModifier::labels)),
+        Expr::Binary(ex) => ex.modifier.as_ref().map_or(0, |m| {
+            labels(m.matching.as_ref().map(LabelModifier::labels)) + labels(m.card.labels())
+        }),
+        Expr::Call(ex) => ex.args.args.len() * size_of::<Box<Expr>>(),
+        Expr::StringLiteral(ex) => ex.val.len(),
+        Expr::VectorSelector(vs) => selector(vs),
+        Expr::MatrixSelector(ms) => selector(&ms.vector_selector),
+        _ => 0,
+    };
+    size_of::<Expr>() + own
+}
 
 /// caller MUST pay attention to the index out of bounds issue
 pub(crate) fn span_to_string(
@@ -32,6 +199,21 @@ pub(crate) fn lexeme_to_string(
         .map_err(|_| "ParseError".into())
 }
 
+/// the value of a string lexeme, with its escape sequences resolved.
+pub(crate) fn lexeme_to_unquoted(
+    lexer: &dyn NonStreamingLexer<LexemeType, TokenId>,
+    lexeme: &Result<LexemeType, LexemeType>,
+) -> Result<String, String> {
+    let lexeme = lexeme.map_err(|_| "ParseError")?;
+    let span = lexeme.span();
+    if lexeme.faulty() || span.start() == 0 {
+        return Ok(span_to_string(lexer, span));
+    }
+    // the lexeme is the text between the quotes
+    let quote = lexer.span_str(Span::new(span.start() - 1, span.start()));
+    unquote(lexer.span_str(span), quote.chars().next().unwrap_or('"'))
+}
+
 pub(crate) fn lexeme_to_token(
     lexer: &dyn NonStreamingLexer<LexemeType, TokenId>,
     lexeme: Result<LexemeType, LexemeType>,
@@ -106,6 +106,7 @@ START_METRIC_SELECTOR
 %token STARTSYMBOLS_END
 
 %start start
+%parse-param ctx: &ParseContext
 
 // Operators are listed with increasing precedence.
 %left LOR
@@ -134,19 +135,24 @@ start -> Result<Expr, String>:
 ;
 
 expr -> Result<Expr, String>:
-/* check_ast from bottom to up for nested exprs */
-                aggregate_expr { check_ast($1?) }
-        |       at_expr { check_ast($1?) }
-        |       binary_expr { check_ast($1?) }
-        |       function_call { check_ast($1?) }
-        |       matrix_selector { check_ast($1?) }
-        |       number_literal { check_ast($1?) }
-        |       offset_expr { check_ast($1?) }
-        |       paren_expr { check_ast($1?) }
-        |       string_literal { check_ast($1?) }
-        |       subquery_expr { check_ast($1?) }
-        |       unary_expr  { check_ast($1?) }
-        |       vector_selector  { check_ast($1?) }
+/* check_ast from bottom to up for nested exprs, the span of an expr wrapping
+   the last node, like `foo offset 1m`, replaces the span of the node */
+                aggregate_expr { ctx.node($1?, $span) }
+        |       at_expr { ctx.modified($1?, $span) }
+        |       binary_expr { ctx.node($1?, $span) }
+        |       function_call { ctx.node($1?, $span) }
+        |       matrix_selector { ctx.modified($1?, $span) }
+        |       number_literal { ctx.node($1?, $span) }
+        |       offset_expr { ctx.modified($1?, $span) }
+        |       paren_expr { ctx.node($1?, $span) }
+        |       string_literal
+                {
+                        // the span of a string excludes its quotes
+                        ctx.node($1?, Span::new($span.start().saturating_sub(1), $span.end() + 1))
+                }
+        |       subquery_expr { ctx.node($1?, $span) }
+        |       unary_expr  { ctx.modified($1?, $span) }
+        |       vector_selector  { ctx.node($1?, $span) }
 ;
 
 /*
@@ -155,15 +161,15 @@ expr -> Result<Expr, String>:
 aggregate_expr -> Result<Expr, String>:
                 aggregate_op aggregate_modifier function_call_body
                 {
-                        Expr::new_aggregate_expr($1?.id(), Some($2?), $3?)
+                        Expr::new_aggregate_expr($1?.id(), Some($2?), $3?.0)
                 }
         |       aggregate_op function_call_body aggregate_modifier
                 {
-                        Expr::new_aggregate_expr($1?.id(), Some($3?), $2?)
+                        Expr::new_aggregate_expr($1?.id(), Some($3?), $2?.0)
                 }
         |       aggregate_op function_call_body
                 {
-                        Expr::new_aggregate_expr($1?.id(), None, $2?)
+                        Expr::new_aggregate_expr($1?.id(), None, $2?.0)
                 }
 ;
 
@@ -271,6 +277,16 @@ grouping_label -> Result<Token, String>:
                             Err(format!("{label} is not valid label in grouping opts"))
                         }
                 }
+        |       STRING
+                {
+                        let mut token = lexeme_to_token($lexer, $1)?;
+                        token.val = lexeme_to_unquoted($lexer, &$1)?;
+                        if token.val.is_empty() {
+                            Err("label name must not be empty in grouping opts".into())
+                        } else {
+                            Ok(token)
+                        }
+                }
 ;
 
 /*
@@ -280,24 +296,44 @@ function_call -> Result<Expr, String>:
                 IDENTIFIER function_call_body
                 {
                         let name = lexeme_to_string($lexer, &$1)?;
+                        let (args, offsets) = $2?;
                         match get_function(&name) {
                             None => Err(format!("unknown function with name '{name}'")),
-                            Some(func) => Expr::new_call(func, $2?)
+                            Some(func) => {
+                                let call = Call { func, args };
+                                ctx.spanned(check_label_args(&call, &offsets))?;
+                                Ok(Expr::Call(call))
+                            }
                         }
                 }
 ;
 
-function_call_body -> Result<FunctionArgs, String>:
+/* the args, with their positions in the query */
+function_call_body -> Result<(FunctionArgs, Vec<usize>), String>:
                 LEFT_PAREN function_call_args RIGHT_PAREN { $2 }
-        |       LEFT_PAREN RIGHT_PAREN { Ok(FunctionArgs::empty_args()) }
+        |       LEFT_PAREN RIGHT_PAREN { Ok((FunctionArgs::empty_args(), vec![])) }
 ;
 
-function_call_args -> Result<FunctionArgs, String>:
-                function_call_args COMMA expr { Ok($1?.append_args($3?)) }
-        |       expr { Ok(FunctionArgs::new_args($1?)) }
+function_call_args -> Result<(FunctionArgs, Vec<usize>), String>:
+                function_call_args COMMA function_call_arg
+                {
+                        let (args, mut offsets) = $1?;
+                        let (arg, offset) = $3?;
+                        offsets.push(offset);
+                        Ok((args.append_args(arg), offsets))
+                }
+        |       function_call_arg
+                {
+                        let (arg, offset) = $1?;
+                        Ok((FunctionArgs::new_args(arg), vec![offset]))
+                }
         |       function_call_args COMMA { Err("trailing commas not allowed in function call args".into()) }
 ;
 
+function_call_arg -> Result<(Expr, usize), String>:
+                expr { Ok(($1?, $span.start())) }
+;
+
 /*
  * Expressions inside parentheses.
  */
@@ -379,8 +415,25 @@ subquery_expr -> Result<Expr, String>:
  * Unary expressions.
  */
 unary_expr -> Result<Expr, String>:
-                ADD expr %prec MUL { $2 }
-        |       SUB expr %prec MUL { Expr::new_unary_expr($2?) }
+                ADD expr %prec MUL
+                {
+                        let ex = $2?;
+                        let value_type = ex.value_type();
+                        if value_type != ValueType::Scalar && value_type != ValueType::Vector {
+                            return Err(format!("unary expression only allowed on expressions of type scalar or vector, got: {value_type}"));
+                        }
+                        ctx.modified(ex, $span)
+                }
+        |       SUB expr %prec MUL
+                {
+                        let ex = $2?;
+                        match ex {
+                            // negative numbers are folded into the literal
+                            Expr::NumberLiteral(_) => ctx.modified(-ex, $span),
+                            _ if ctx.checked() => ctx.node(Expr::new_unary_expr(ex)?, $span),
+                            _ => ctx.node(-ex, $span),
+                        }
+                }
 ;
 
 /*
@@ -421,9 +474,18 @@ label_matcher -> Result<Matcher, String>:
                 IDENTIFIER match_op STRING
                 {
                         let name = lexeme_to_string($lexer, &$1)?;
-                        let value = lexeme_to_string($lexer, &$3)?;
-                        Matcher::new_matcher($2?.id(), name, value)
+                        let value = lexeme_to_unquoted($lexer, &$3)?;
+                        let offset = $3.map_err(|_| "ParseError")?.span().start();
+                        ctx.spanned(Matcher::new_matcher_at($2?.id(), name, value, offset))
+                }
+        |       STRING match_op STRING
+                {
+                        let name = lexeme_to_unquoted($lexer, &$1)?;
+                        let value = lexeme_to_unquoted($lexer, &$3)?;
+                        let offset = $3.map_err(|_| "ParseError")?.span().start();
+                        ctx.spanned(Matcher::new_matcher_at($2?.id(), name, value, offset))
                 }
+        |       STRING { Ok(Matcher::new_eq_metric_matcher(lexeme_to_unquoted($lexer, &$1)?)) }
         |       IDENTIFIER match_op match_op
                 {
                         let op = $3?.val;
@@ -457,6 +519,9 @@ label_matcher -> Result<Matcher, String>:
 /*
  * Metric descriptions.
  */
+// keywords that could also be a metric name. BOOL, ON, IGNORING, GROUP_LEFT, GROUP_RIGHT
+// and ATAN2 are left out on purpose, as upstream does, since they are ambiguous with
+// binary expressions. Such metrics can still be selected by {__name__="on"}.
 metric_identifier -> Result<Token, String>:
                 AVG { lexeme_to_token($lexer, $1) }
         |       BOTTOMK { lexeme_to_token($lexer, $1) }
@@ -556,11 +621,11 @@ number_literal -> Result<Expr, String>:
 ;
 
 string_literal -> Result<Expr, String>:
-                STRING { Ok(Expr::from(span_to_string($lexer, $span))) }
+                STRING { Ok(Expr::from(lexeme_to_unquoted($lexer, &$1)?)) }
 ;
 
 duration -> Result<Duration, String>:
-                DURATION { parse_duration($lexer.span_str($span)) }
+                DURATION { ctx.token(parse_duration($lexer.span_str($span)), $span) }
 ;
 
 /*
@@ -575,25 +640,23 @@ maybe_duration -> Result<Option<Duration>, String>:
 
 use std::collections::HashSet;
 use std::time::Duration;
+use lrpar::Span;
 use crate::label::{Labels, Matcher, Matchers};
 use crate::parser::{
-    AtModifier, BinModifier, Expr, FunctionArgs, LabelModifier,
-    Offset, Token, VectorMatchCardinality,
+    AtModifier, BinModifier, Call, Expr, FunctionArgs, LabelModifier,
+    Offset, Token, ValueType, VectorMatchCardinality,
 };
 use crate::parser::function::get_function;
-use crate::parser::ast::check_ast;
+use crate::parser::ast::check_label_args;
 use crate::parser::lex::is_label;
-use crate::parser::production::{lexeme_to_string, lexeme_to_token, span_to_string};
+use crate::parser::production::{lexeme_to_string, lexeme_to_token, lexeme_to_unquoted, ParseContext};
 use crate::util::{parse_duration, parse_str_radix};
 
 fn update_optional_matching(
     modifier: Option<BinModifier>,
     matching: Option<LabelModifier>,
 ) -> Option<BinModifier> {
-    let modifier = match modifier {
-        Some(modifier) => modifier,
-        None => Default::default(),
-    };
+    let modifier = modifier.unwrap_or_default();
     Some(modifier.with_matching(matching))
 }
 
@@ -601,9 +664,6 @@ fn update_optional_card(
     modifier: Option<BinModifier>,
     card: VectorMatchCardinality,
 ) -> Option<BinModifier> {
-    let modifier = match modifier {
-        Some(modifier) => modifier,
-        None => Default::default(),
-    };
+    let modifier = modifier.unwrap_or_default();
     Some(modifier.with_card(card))
 }
@@ -0,0 +1,1040 @@
+// Copyright 2023 Greptime Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! protobuf messages of the AST, see `proto/promql.proto` in this repo,
+//! and the conversions from and to [`Expr`].
+//!
+//! # Examples
+//!
+//! ``` rust
+//! use prost::Message;
+//! use promql_parser::parser::{parse, proto, Expr};
+//!
+//! let expr = parse(r#"sum by (job) (rate(foo{code=~"5.."}[5m]))"#).unwrap();
+//! let bytes = proto::Expr::from(&expr).encode_to_vec();
+//!
+//! let decoded = proto::Expr::decode(bytes.as_slice()).unwrap();
+//! assert_eq!(Expr::try_from(decoded).unwrap(), expr);
+//! ```
+
+use crate::label::{self, Labels, MatchOp, Matchers};
+use crate::parser::ast::check_tree;
+use crate::parser::function::get_function;
+use crate::parser::token::{token_from_display, T_EQL, T_EQL_REGEX, T_NEQ, T_NEQ_REGEX};
+use crate::parser::{ast, FunctionArgs, TokenType};
+use std::time::{Duration, SystemTime};
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Expr {
+    #[prost(oneof = "expr::Node", tags = "1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11")]
+    pub node: Option<expr::Node>,
+}
+
+pub mod expr {
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Node {
+        #[prost(message, tag = "1")]
+        Aggregate(Box<super::AggregateExpr>),
+        #[prost(message, tag = "2")]
+        Unary(Box<super::UnaryExpr>),
+        #[prost(message, tag = "3")]
+        Binary(Box<super::BinaryExpr>),
+        #[prost(message, tag = "4")]
+        Paren(Box<super::ParenExpr>),
+        #[prost(message, tag = "5")]
+        Subquery(Box<super::SubqueryExpr>),
+        #[prost(message, tag = "6")]
+        NumberLiteral(super::NumberLiteral),
+        #[prost(message, tag = "7")]
+        StringLiteral(super::StringLiteral),
+        #[prost(message, tag = "8")]
+        VectorSelector(super::VectorSelector),
+        #[prost(message, tag = "9")]
+        MatrixSelector(super::MatrixSelector),
+        #[prost(message, tag = "10")]
+        Call(super::Call),
+        #[prost(message, tag = "11")]
+        Extension(super::Extension),
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct LabelModifier {
+    #[prost(enumeration = "label_modifier::Kind", tag = "1")]
+    pub kind: i32,
+    #[prost(string, repeated, tag = "2")]
+    pub labels: Vec<String>,
+}
+
+pub mod label_modifier {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+    #[repr(i32)]
+    pub enum Kind {
+        Include = 0,
+        Exclude = 1,
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct VectorMatchCardinality {
+    #[prost(enumeration = "vector_match_cardinality::Kind", tag = "1")]
+    pub kind: i32,
+    #[prost(string, repeated, tag = "2")]
+    pub labels: Vec<String>,
+}
+
+pub mod vector_match_cardinality {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+    #[repr(i32)]
+    pub enum Kind {
+        OneToOne = 0,
+        ManyToOne = 1,
+        OneToMany = 2,
+        ManyToMany = 3,
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct BinModifier {
+    #[prost(message, optional, tag = "1")]
+    pub card: Option<VectorMatchCardinality>,
+    #[prost(message, optional, tag = "2")]
+    pub matching: Option<LabelModifier>,
+    #[prost(bool, tag = "3")]
+    pub return_bool: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Offset {
+    #[prost(int64, tag = "1")]
+    pub millis: i64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct AtModifier {
+    #[prost(enumeration = "at_modifier::Kind", tag = "1")]
+    pub kind: i32,
+    #[prost(int64, tag = "2")]
+    pub timestamp_ms: i64,
+}
+
+pub mod at_modifier {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+    #[repr(i32)]
+    pub enum Kind {
+        At = 0,
+        Start = 1,
+        End = 2,
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct AggregateExpr {
+    #[prost(string, tag = "1")]
+    pub op: String,
+    #[prost(message, optional, boxed, tag = "2")]
+    pub expr: Option<Box<Expr>>,
+    #[prost(message, optional, boxed, tag = "3")]
+    pub param: Option<Box<Expr>>,
+    #[prost(message, optional, tag = "4")]
+    pub modifier: Option<LabelModifier>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct UnaryExpr {
+    #[prost(message, optional, boxed, tag = "1")]
+    pub expr: Option<Box<Expr>>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct BinaryExpr {
+    #[prost(string, tag = "1")]
+    pub op: String,
+    #[prost(message, optional, boxed, tag = "2")]
+    pub lhs: Option<Box<Expr>>,
+    #[prost(message, optional, boxed, tag = "3")]
+    pub rhs: Option<Box<Expr>>,
+    #[prost(message, optional, tag = "4")]
+    pub modifier: Option<BinModifier>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ParenExpr {
+    #[prost(message, optional, boxed, tag = "1")]
+    pub expr: Option<Box<Expr>>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SubqueryExpr {
+    #[prost(message, optional, boxed, tag = "1")]
+    pub expr: Option<Box<Expr>>,
+    #[prost(message, optional, tag = "2")]
+    pub offset: Option<Offset>,
+    #[prost(message, optional, tag = "3")]
+    pub at: Option<AtModifier>,
+    #[prost(uint64, tag = "4")]
+    pub range_ms: u64,
+    #[prost(uint64, optional, tag = "5")]
+    pub step_ms: Option<u64>,
+    /// the step is the resolved default step, see [`ast::SubqueryStep::Resolved`].
+    #[prost(bool, tag = "6")]
+    pub step_resolved: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct NumberLiteral {
+    #[prost(double, tag = "1")]
+    pub val: f64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct StringLiteral {
+    #[prost(string, tag = "1")]
+    pub val: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Matcher {
+    #[prost(enumeration = "matcher::Op", tag = "1")]
+    pub op: i32,
+    #[prost(string, tag = "2")]
+    pub name: String,
+    #[prost(string, tag = "3")]
+    pub value: String,
+}
+
+pub mod matcher {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+    #[repr(i32)]
+    pub enum Op {
+        Equal = 0,
+        NotEqual = 1,
+        Re = 2,
+        NotRe = 3,
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct VectorSelector {
+    #[prost(string, optional, tag = "1")]
+    pub name: Option<String>,
+    #[prost(message, repeated, tag = "2")]
+    pub matchers: Vec<Matcher>,
+    #[prost(message, optional, tag = "3")]
+    pub offset: Option<Offset>,
+    #[prost(message, optional, tag = "4")]
+    pub at: Option<AtModifier>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct MatrixSelector {
+    #[prost(message, optional, tag = "1")]
+    pub vector_selector: Option<VectorSelector>,
+    #[prost(uint64, tag = "2")]
+    pub range_ms: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Call {
+    #[prost(string, tag = "1")]
+    pub func: String,
+    #[prost(message, repeated, tag = "2")]
+    pub args: Vec<Expr>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Extension {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(bytes = "vec", tag = "2")]
+    pub payload: Vec<u8>,
+    #[prost(message, repeated, tag = "3")]
+    pub children: Vec<Expr>,
+}
+
+fn millis(d: &Duration) -> u64 {
+    d.as_millis() as u64
+}
+
+fn sorted(labels: &Labels) -> Vec<String> {
+    let mut labels: Vec<String> = labels.iter().cloned().collect();
+    labels.sort();
+    labels
+}
+
+impl From<&ast::LabelModifier> for LabelModifier {
+    fn from(modifier: &ast::LabelModifier) -> Self {
+        let kind = match modifier {
+            ast::LabelModifier::Include(_) => label_modifier::Kind::Include,
+            ast::LabelModifier::Exclude(_) => label_modifier::Kind::Exclude,
+        };
+        Self {
+            kind: kind as i32,
+            labels: sorted(modifier.labels()),
+        }
+    }
+}
+
+impl From<&ast::BinModifier> for BinModifier {
+    fn from(modifier: &ast::BinModifier) -> Self {
+        use vector_match_cardinality::Kind;
+
+        let (kind, labels) = match &modifier.card {
+            ast::VectorMatchCardinality::OneToOne => (Kind::OneToOne, vec![]),
+            ast::VectorMatchCardinality::ManyToOne(l) => (Kind::ManyToOne, sorted(l)),
+            ast::VectorMatchCardinality::OneToMany(l) => (Kind::OneToMany, sorted(l)),
+            ast::VectorMatchCardinality::ManyToMany => (Kind::ManyToMany, vec![]),
+        };
+        Self {
+            card: Some(VectorMatchCardinality {
+                kind: kind as i32,
+                labels,
+            }),
+            matching: modifier.matching.as_ref().map(Into::into),
+            return_bool: modifier.return_bool,
+        }
+    }
+}
+
+impl From<&ast::Offset> for Offset {
+    fn from(offset: &ast::Offset) -> Self {
+        Self {
+            millis: offset.as_millis() as i64,
+        }
+    }
+}
+
+impl From<&ast::AtModifier> for AtModifier {
+    fn from(at: &ast::AtModifier) -> Self {
+        let (kind, timestamp_ms) = match at {
+            ast::AtModifier::Start => (at_modifier::Kind::Start, 0),
+            ast::AtModifier::End => (at_modifier::Kind::End, 0),
+            ast::AtModifier::At(time) => {
+                let ms = match time.duration_since(SystemTime::UNIX_EPOCH) {
+                    Ok(d) => d.as_millis() as i64,
+                    Err(e) => -(e.duration().as_millis() as i64),
+                };
+                (at_modifier::Kind::At, ms)
+            }
+        };
+        Self {
+            kind: kind as i32,
+            timestamp_ms,
+        }
+    }
+}
+
+impl From<&label::Matcher> for Matcher {
+    fn from(m: &label::Matcher) -> Self {
+        let op = match m.op {
+            MatchOp::Equal => matcher::Op::Equal,
+            MatchOp::NotEqual => matcher::Op::NotEqual,
+            MatchOp::Re(_) => matcher::Op::Re,
+            MatchOp::NotRe(_) => matcher::Op::NotRe,
+        };
+        Self {
+            op: op as i32,
+            name: m.name.clone(),
+            value: m.value.clone(),
+        }
+    }
+}
+
+impl From<&ast::VectorSelector> for VectorSelector {
+    fn from(vs: &ast::VectorSelector) -> Self {
+        let mut matchers: Vec<Matcher> = vs.matchers.matchers.iter().map(Into::into).collect();
+        matchers.sort_by(|a, b| (&a.name, a.op, &a.value).cmp(&(&b.name, b.op, &b.value)));
+        Self {
+            name: vs.name.clone(),
+            matchers,
+            offset: vs.offset.as_ref().map(Into::into),
+            at: vs.at.as_ref().map(Into::into),
+        }
+    }
+}
+
+impl From<&ast::Expr> for Expr {
+    fn from(ex: &ast::Expr) -> Self {
+        let boxed = |ex: &ast::Expr| Some(Box::new(Expr::from(ex)));
+        let node = match ex {
+            ast::Expr::Aggregate(ex) => expr::Node::Aggregate(Box::new(AggregateExpr {
+                op: ex.op.to_string(),
+                expr: boxed(&ex.expr),
+                param: ex.param.as_deref().and_then(boxed),
+                modifier: ex.modifier.as_ref().map(Into::into),
+            })),
+            ast::Expr::Unary(ex) => expr::Node::Unary(Box::new(UnaryExpr {
+                expr: boxed(&ex.expr),
+            })),
+            ast::Expr::Binary(ex) => expr::Node::Binary(Box::new(BinaryExpr {
+                op: ex.op.to_string(),
+                lhs: boxed(&ex.lhs),
+                rhs: boxed(&ex.rhs),
+                modifier: ex.modifier.as_ref().map(Into::into),
+            })),
+            ast::Expr::Paren(ex) => expr::Node::Paren(Box::new(ParenExpr {
+                expr: boxed(&ex.expr),
+            })),
+            ast::Expr::Subquery(ex) => expr::Node::Subquery(Box::new(SubqueryExpr {
+                expr: boxed(&ex.expr),
+                offset: ex.offset.as_ref().map(Into::into),
+                at: ex.at.as_ref().map(Into::into),
+                range_ms: millis(&ex.range),
+                step_ms: ex.step.duration().as_ref().map(millis),
+                step_resolved: matches!(ex.step, ast::SubqueryStep::Resolved(_)),
+            })),
+            ast::Expr::NumberLiteral(ex) => {
+                expr::Node::NumberLiteral(NumberLiteral { val: ex.val })
+            }
+            ast::Expr::StringLiteral(ex) => expr::Node::StringLiteral(StringLiteral {
+                val: ex.val.clone(),
+            }),
+            ast::Expr::VectorSelector(vs) => expr::Node::VectorSelector(vs.into()),
+            ast::Expr::MatrixSelector(ms) => expr::Node::MatrixSelector(MatrixSelector {
+                vector_selector: Some((&ms.vector_selector).into()),
+                range_ms: millis(&ms.range),
+            }),
+            ast::Expr::Call(call) => expr::Node::Call(Call {
+                func: call.func.name.to_string(),
+                args: call
+                    .args
+                    .args
+                    .iter()
+                    .map(|ex| Expr::from(ex.as_ref()))
+                    .collect(),
+            }),
+            ast::Expr::Extension(ext) => expr::Node::Extension(Extension {
+                name: ext.expr.name().to_string(),
+                payload: ext.expr.payload(),
+                children: ext.expr.children().iter().map(Expr::from).collect(),
+            }),
+        };
+        Self { node: Some(node) }
+    }
+}
+
+fn child(ex: Option<Box<Expr>>, node: &str) -> Result<Box<ast::Expr>, String> {
+    match ex {
+        Some(ex) => Ok(Box::new(decode(*ex)?)),
+        None => Err(format!("missing expr in {node}")),
+    }
+}
+
+fn token(op: &str) -> Result<TokenType, String> {
+    token_from_display(op)
+        .map(TokenType::new)
+        .filter(|t| t.is_operator() || t.is_aggregator())
+        .ok_or_else(|| format!("unknown operator {op}"))
+}
+
+fn labels(labels: Vec<String>) -> Labels {
+    labels.into_iter().collect()
+}
+
+impl TryFrom<LabelModifier> for ast::LabelModifier {
+    type Error = String;
+
+    fn try_from(modifier: LabelModifier) -> Result<Self, Self::Error> {
+        match label_modifier::Kind::try_from(modifier.kind) {
+            Ok(label_modifier::Kind::Include) => Ok(Self::Include(labels(modifier.labels))),
+            Ok(label_modifier::Kind::Exclude) => Ok(Self::Exclude(labels(modifier.labels))),
+            Err(_) => Err(format!("invalid label modifier kind {}", modifier.kind)),
+        }
+    }
+}
+
+impl TryFrom<BinModifier> for ast::BinModifier {
+    type Error = String;
+
+    fn try_from(modifier: BinModifier) -> Result<Self, Self::Error> {
+        use vector_match_cardinality::Kind;
+
+        let card = modifier.card.unwrap_or_default();
+        let card = match Kind::try_from(card.kind) {
+            Ok(Kind::OneToOne) => ast::VectorMatchCardinality::OneToOne,
+            Ok(Kind::ManyToOne) => ast::VectorMatchCardinality::ManyToOne(labels(card.labels)),
+            Ok(Kind::OneToMany) => ast::VectorMatchCardinality::OneToMany(labels(card.labels)),
+            Ok(Kind::ManyToMany) => ast::VectorMatchCardinality::ManyToMany,
+            Err(_) => return Err(format!("invalid vector match cardinality {}", card.kind)),
+        };
+        let matching = modifier.matching.map(TryInto::try_into).transpose()?;
+        Ok(ast::BinModifier::default()
+            .with_card(card)
+            .with_matching(matching)
+            .with_return_bool(modifier.return_bool))
+    }
+}
+
+impl From<Offset> for ast::Offset {
+    fn from(offset: Offset) -> Self {
+        let d = Duration::from_millis(offset.millis.unsigned_abs());
+        if offset.millis < 0 {
+            ast::Offset::Neg(d)
+        } else {
+            ast::Offset::Pos(d)
+        }
+    }
+}
+
+impl TryFrom<AtModifier> for ast::AtModifier {
+    type Error = String;
+
+    fn try_from(at: AtModifier) -> Result<Self, Self::Error> {
+        match at_modifier::Kind::try_from(at.kind) {
+            Ok(at_modifier::Kind::Start) => Ok(Self::Start),
+            Ok(at_modifier::Kind::End) => Ok(Self::End),
+            Ok(at_modifier::Kind::At) => {
+                let d = Duration::from_millis(at.timestamp_ms.unsigned_abs());
+                let time = if at.timestamp_ms < 0 {
+                    SystemTime::UNIX_EPOCH.checked_sub(d)
+                } else {
+                    SystemTime::UNIX_EPOCH.checked_add(d)
+                };
+                time.map(Self::At).ok_or_else(|| {
+                    format!(
+                        "timestamp out of bounds for @ modifier: {}",
+                        at.timestamp_ms
+                    )
+                })
+            }
+            Err(_) => Err(format!("invalid @ modifier kind {}", at.kind)),
+        }
+    }
+}
+
+impl TryFrom<Matcher> for label::Matcher {
+    type Error = String;
+
+    fn try_from(m: Matcher) -> Result<Self, Self::Error> {
+        let id = match matcher::Op::try_from(m.op) {
+            Ok(matcher::Op::Equal) => T_EQL,
+            Ok(matcher::Op::NotEqual) => T_NEQ,
+            Ok(matcher::Op::Re) => T_EQL_REGEX,
+            Ok(matcher::Op::NotRe) => T_NEQ_REGEX,
+            Err(_) => return Err(format!("invalid match op {}", m.op)),
+        };
+        label::Matcher::new_matcher(id, m.name, m.value)
+    }
+}
+
+impl TryFrom<VectorSelector> for ast::VectorSelector {
+    type Error = String;
+
+    fn try_from(vs: VectorSelector) -> Result<Self, Self::Error> {
+        let matchers = vs
+            .matchers
+            .into_iter()
+            .map(label::Matcher::try_from)
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            name: vs.name,
+            matchers: Matchers::new(matchers),
+            offset: vs.offset.map(Into::into),
+            at: vs.at.map(TryInto::try_into).transpose()?,
+        })
+    }
+}
+
+/// fails on invalid messages, and on [`Extension`], which can not be converted back.
+/// The decoded expr is checked like a parsed one, so it fails on type errors too.
+impl TryFrom<Expr> for ast::Expr {
+    type Error = String;
+
+    fn try_from(ex: Expr) -> Result<Self, Self::Error> {
+        check_tree(decode(ex)?)
+    }
+}
+
+fn decode(ex: Expr) -> Result<ast::Expr, String> {
+    let node = ex.node.ok_or("missing expr node")?;
+    let ex = match node {
+        expr::Node::Aggregate(ex) => ast::Expr::Aggregate(ast::AggregateExpr {
+            op: token(&ex.op)?,
+            expr: child(ex.expr, "aggregate expr")?,
+            param: ex
+                .param
+                .map(|p| child(Some(p), "aggregate expr"))
+                .transpose()?,
+            modifier: ex.modifier.map(TryInto::try_into).transpose()?,
+        }),
+        expr::Node::Unary(ex) => ast::Expr::Unary(ast::UnaryExpr {
+            expr: child(ex.expr, "unary expr")?,
+        }),
+        expr::Node::Binary(ex) => ast::Expr::Binary(ast::BinaryExpr {
+            op: token(&ex.op)?,
+            lhs: child(ex.lhs, "binary expr")?,
+            rhs: child(ex.rhs, "binary expr")?,
+            modifier: ex.modifier.map(TryInto::try_into).transpose()?,
+        }),
+        expr::Node::Paren(ex) => ast::Expr::Paren(ast::ParenExpr {
+            expr: child(ex.expr, "paren expr")?,
+        }),
+        expr::Node::Subquery(ex) => ast::Expr::Subquery(ast::SubqueryExpr {
+            expr: child(ex.expr, "subquery expr")?,
+            offset: ex.offset.map(Into::into),
+            at: ex.at.map(TryInto::try_into).transpose()?,
+            range: duration(ex.range_ms, "subquery range")?,
+            step: match (
+                ex.step_ms
+                    .map(|ms| duration(ms, "subquery step"))
+                    .transpose()?,
+                ex.step_resolved,
+            ) {
+                (Some(step), true) => ast::SubqueryStep::Resolved(step),
+                (Some(step), false) => ast::SubqueryStep::Explicit(step),
+                (None, _) => ast::SubqueryStep::Default,
+            },
+        }),
+        expr::Node::NumberLiteral(ex) => ast::Expr::NumberLiteral(ast::NumberLiteral::new(ex.val)),
+        expr::Node::StringLiteral(ex) => {
+            ast::Expr::StringLiteral(ast::StringLiteral { val: ex.val })
+        }
+        expr::Node::VectorSelector(vs) => ast::Expr::VectorSelector(vs.try_into()?),
+        expr::Node::MatrixSelector(ms) => ast::Expr::MatrixSelector(ast::MatrixSelector {
+            vector_selector: ms
+                .vector_selector
+                .ok_or("missing vector selector in matrix selector")?
+                .try_into()?,
+            range: duration(ms.range_ms, "matrix selector range")?,
+        }),
+        expr::Node::Call(call) => {
+            let func = get_function(&call.func)
+                .ok_or_else(|| format!("unknown function {}", call.func))?;
+            let args = call
+                .args
+                .into_iter()
+                .map(|ex| decode(ex).map(Box::new))
+                .collect::<Result<_, _>>()?;
+            ast::Expr::Call(ast::Call {
+                func,
+                args: FunctionArgs { args },
+            })
+        }
+        expr::Node::Extension(ext) => {
+            return Err(format!(
+                "extension expr {} can not be converted from proto",
+                ext.name
+            ))
+        }
+    };
+    Ok(ex)
+}
+
+/// the duration of the millis, which like a parsed one has to be greater than 0
+/// and fit in an i64 of nanoseconds.
+fn duration(millis: u64, name: &str) -> Result<Duration, String> {
+    match millis {
+        0 => Err(format!("{name} must be greater than 0")),
+        ms if ms > (i64::MAX / 1_000_000) as u64 => Err(format!("{name} is out of range")),
+        ms => Ok(Duration::from_millis(ms)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+    use prost::Message;
+
+    #[test]
+    fn test_proto_round_trip() {
+        let cases = vec![
+            "foo",
+            "-1.5",
+            r#""a string""#,
+            r#"foo{job="api",code!~"5.."} @ 1609746000 offset 5m"#,
+            "foo @ -10.5",
+            "rate(foo[5m] @ start())[1h:5m] offset -1w",
+            "max_over_time(foo[1h:] @ end())",
+            "topk by (job) (5, sum without (code) (foo))",
+            r#"count_values("code", foo)"#,
+            "a + on(x) group_left(y) b",
+            "a == bool ignoring(x) b",
+            "(a or b) unless c",
+            r#"label_replace(up, "a", "$1", "b", "(.*)")"#,
+        ];
+        for case in cases {
+            let expr = parse(case).unwrap();
+            let bytes = Expr::from(&expr).encode_to_vec();
+            let decoded = Expr::decode(bytes.as_slice()).unwrap();
+            assert_eq!(ast::Expr::try_from(decoded).unwrap(), expr, "{case}");
+        }
+
+        let expr = parse("max_over_time(foo[1h:])").unwrap();
+        let expr =
+            crate::util::rewrite::fill_subquery_steps(&expr, std::time::Duration::from_secs(30));
+        let decoded = Expr::decode(Expr::from(&expr).encode_to_vec().as_slice()).unwrap();
+        assert_eq!(ast::Expr::try_from(decoded).unwrap(), expr);
+    }
+
+    #[test]
+    fn test_proto_invalid() {
+        let number = |val| Expr {
+            node: Some(expr::Node::NumberLiteral(NumberLiteral { val })),
+        };
+        let binary = |op: &str, lhs: Option<Box<Expr>>| Expr {
+            node: Some(expr::Node::Binary(Box::new(BinaryExpr {
+                op: op.into(),
+                lhs,
+                rhs: Some(Box::new(number(1.0))),
+                modifier: None,
+            }))),
+        };
+        let call = |func: &str| Expr {
+            node: Some(expr::Node::Call(Call {
+                func: func.into(),
+                args: vec![],
+            })),
+        };
+        let selector = |op: i32, value: &str| Expr {
+            node: Some(expr::Node::VectorSelector(VectorSelector {
+                name: None,
+                matchers: vec![Matcher {
+                    op,
+                    name: "job".into(),
+                    value: value.into(),
+                }],
+                offset: None,
+                at: None,
+            })),
+        };
+        let string = Expr {
+            node: Some(expr::Node::StringLiteral(StringLiteral { val: "a".into() })),
+        };
+        let subquery = |range_ms, step_ms| Expr {
+            node: Some(expr::Node::Subquery(Box::new(SubqueryExpr {
+                expr: Some(Box::new(selector(matcher::Op::Equal as i32, "foo"))),
+                offset: None,
+                at: None,
+                range_ms,
+                step_ms,
+                step_resolved: false,
+            }))),
+        };
+        let matrix = |range_ms| Expr {
+            node: Some(expr::Node::MatrixSelector(MatrixSelector {
+                vector_selector: Some(VectorSelector {
+                    name: Some("foo".into()),
+                    matchers: vec![],
+                    offset: None,
+                    at: None,
+                }),
+                range_ms,
+            })),
+        };
+        let too_long = (i64::MAX / 1_000_000) as u64 + 1;
+        let extension = Expr {
+            node: Some(expr::Node::Extension(Extension {
+                name: "dummy".into(),
+                payload: vec![],
+                children: vec![],
+            })),
+        };
+
+        let cases = vec![
+            (Expr { node: None }, "missing expr node"),
+            (binary("+", None), "missing expr in binary expr"),
+            (
+                binary("plus", Some(Box::new(number(1.0)))),
+                "unknown operator plus",
+            ),
+            (
+                binary("by", Some(Box::new(number(1.0)))),
+                "unknown operator by",
+            ),
+            (
+                binary("+", Some(Box::new(string))),
+                "binary expression must contain only scalar and instant vector types",
+            ),
+            (call("foo"), "unknown function foo"),
+            (
+                call("rate"),
+                "expected 1 argument(s) in call to 'rate', got 0",
+            ),
+            (selector(7, "api"), "invalid match op 7"),
+            (subquery(0, None), "subquery range must be greater than 0"),
+            (subquery(too_long, None), "subquery range is out of range"),
+            (
+                subquery(1000, Some(0)),
+                "subquery step must be greater than 0",
+            ),
+            (
+                subquery(1000, Some(too_long)),
+                "subquery step is out of range",
+            ),
+            (matrix(0), "matrix selector range must be greater than 0"),
+            (matrix(too_long), "matrix selector range is out of range"),
+            (
+                extension,
+                "extension expr dummy can not be converted from proto",
+            ),
+        ];
+        for (ex, err) in cases {
+            assert_eq!(ast::Expr::try_from(ex).unwrap_err(), err);
+        }
+
+        let invalid_regex = selector(matcher::Op::Re as i32, "(");
+        assert!(ast::Expr::try_from(invalid_regex).is_err());
+    }
+
+    #[derive(Debug)]
+    struct DummyExtension(Vec<ast::Expr>);
+
+    impl ast::ExtensionExpr for DummyExtension {
+        fn as_any(&self) -> &dyn std::any::Any {
+            self
+        }
+
+        fn name(&self) -> &str {
+            "dummy"
+        }
+
+        fn value_type(&self) -> crate::parser::ValueType {
+            crate::parser::ValueType::Vector
+        }
+
+        fn children(&self) -> &[ast::Expr] {
+            &self.0
+        }
+
+        fn payload(&self) -> Vec<u8> {
+            vec![1, 2, 3]
+        }
+    }
+
+    #[test]
+    fn test_proto_extension() {
+        let expr = ast::Expr::Extension(ast::Extension {
+            expr: std::sync::Arc::new(DummyExtension(vec![parse("foo").unwrap()])),
+        });
+        let Some(expr::Node::Extension(ext)) = Expr::from(&expr).node else {
+            unreachable!()
+        };
+        assert_eq!(ext.name, "dummy");
+        assert_eq!(ext.payload, vec![1, 2, 3]);
+        assert_eq!(ext.children, vec![Expr::from(&parse("foo").unwrap())]);
+    }
+
+    /// the tags and wire types of the fields of an encoded message.
+    fn wire_fields(mut bytes: &[u8]) -> Vec<(u32, u32)> {
+        fn varint(bytes: &mut &[u8]) -> u64 {
+            let mut value = 0;
+            for shift in (0..64).step_by(7) {
+                let (byte, rest) = bytes.split_first().unwrap();
+                *bytes = rest;
+                value |= u64::from(byte & 0x7f) << shift;
+                if byte & 0x80 == 0 {
+                    break;
+                }
+            }
+            value
+        }
+
+        let mut fields = vec![];
+        while !bytes.is_empty() {
+            let key = varint(&mut bytes);
+            let (tag, wire_type) = ((key >> 3) as u32, (key & 7) as u32);
+            match wire_type {
+                0 => drop(varint(&mut bytes)),
+                1 => bytes = &bytes[8..],
+                2 => {
+                    let len = varint(&mut bytes) as usize;
+                    bytes = &bytes[len..];
+                }
+                5 => bytes = &bytes[4..],
+                _ => panic!("unexpected wire type {wire_type}"),
+            }
+            if !fields.contains(&(tag, wire_type)) {
+                fields.push((tag, wire_type));
+            }
+        }
+        fields.sort();
+        fields
+    }
+
+    /// the hand written messages must match `proto/promql.proto`: every field of
+    /// a message is set, so its encoding has the tags and wire types of the
+    /// definition, and the enums have the values of the definition.
+    #[test]
+    fn test_proto_matches_definition() {
+        let definition = include_str!("../../proto/promql.proto");
+        let mut messages: Vec<(String, Vec<(u32, u32)>)> = vec![];
+        let mut enums: Vec<(String, String, i32)> = vec![];
+        let mut scope: Vec<String> = vec![];
+        for line in definition.lines().map(str::trim) {
+            let line = line.split("//").next().unwrap().trim();
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.as_slice() {
+                ["message", name, "{"] => {
+                    scope.push(name.to_string());
+                    messages.push((name.to_string(), vec![]));
+                }
+                ["enum", name, "{"] => scope.push(format!("{}.{name}", scope[0])),
+                ["oneof", _, "{"] => scope.push(scope[0].clone()),
+                ["}"] => drop(scope.pop()),
+                [name, "=", value] if !scope.is_empty() => {
+                    let value = value.trim_end_matches(';').parse().unwrap();
+                    enums.push((scope.last().unwrap().clone(), name.to_string(), value));
+                }
+                [.., ty, _, "=", tag] => {
+                    let wire_type = match *ty {
+                        "int64" | "uint64" | "bool" | "Kind" | "Op" => 0,
+                        "double" => 1,
+                        _ => 2,
+                    };
+                    let tag = tag.trim_end_matches(';').parse().unwrap();
+                    messages.last_mut().unwrap().1.push((tag, wire_type));
+                }
+                _ => {}
+            }
+        }
+
+        let number = Expr::from(&parse("1").unwrap());
+        let boxed = || Some(Box::new(number.clone()));
+        let label_modifier = LabelModifier {
+            kind: 1,
+            labels: vec!["a".into()],
+        };
+        let card = VectorMatchCardinality {
+            kind: 1,
+            labels: vec!["a".into()],
+        };
+        let offset = Offset { millis: 1 };
+        let at = AtModifier {
+            kind: 1,
+            timestamp_ms: 1,
+        };
+        let matcher = Matcher {
+            op: 1,
+            name: "a".into(),
+            value: "b".into(),
+        };
+        let vector_selector = VectorSelector {
+            name: Some("foo".into()),
+            matchers: vec![matcher.clone()],
+            offset: Some(offset.clone()),
+            at: Some(at.clone()),
+        };
+        let nodes = vec![
+            expr::Node::Aggregate(Box::new(AggregateExpr {
+                op: "sum".into(),
+                expr: boxed(),
+                param: boxed(),
+                modifier: Some(label_modifier.clone()),
+            })),
+            expr::Node::Unary(Box::new(UnaryExpr { expr: boxed() })),
+            expr::Node::Binary(Box::new(BinaryExpr {
+                op: "+".into(),
+                lhs: boxed(),
+                rhs: boxed(),
+                modifier: Some(BinModifier {
+                    card: Some(card.clone()),
+                    matching: Some(label_modifier.clone()),
+                    return_bool: true,
+                }),
+            })),
+            expr::Node::Paren(Box::new(ParenExpr { expr: boxed() })),
+            expr::Node::Subquery(Box::new(SubqueryExpr {
+                expr: boxed(),
+                offset: Some(offset.clone()),
+                at: Some(at.clone()),
+                range_ms: 1,
+                step_ms: Some(1),
+                step_resolved: true,
+            })),
+            expr::Node::NumberLiteral(NumberLiteral { val: 1.0 }),
+            expr::Node::StringLiteral(StringLiteral { val: "a".into() }),
+            expr::Node::VectorSelector(vector_selector.clone()),
+            expr::Node::MatrixSelector(MatrixSelector {
+                vector_selector: Some(vector_selector),
+                range_ms: 1,
+            }),
+            expr::Node::Call(Call {
+                func: "rate".into(),
+                args: vec![number.clone()],
+            }),
+            expr::Node::Extension(Extension {
+                name: "a".into(),
+                payload: vec![1],
+                children: vec![number],
+            }),
+        ];
+
+        let mut encoded: Vec<(&str, Vec<u8>)> = vec![(
+            "Expr",
+            nodes
+                .iter()
+                .flat_map(|node| {
+                    Expr {
+                        node: Some(node.clone()),
+                    }
+                    .encode_to_vec()
+                })
+                .collect(),
+        )];
+        for node in nodes {
+            let (name, bytes) = match node {
+                expr::Node::Aggregate(ex) => ("AggregateExpr", ex.encode_to_vec()),
+                expr::Node::Unary(ex) => ("UnaryExpr", ex.encode_to_vec()),
+                expr::Node::Binary(ex) => {
+                    let modifier = ex.modifier.clone().unwrap();
+                    encoded.push(("BinModifier", modifier.encode_to_vec()));
+                    ("BinaryExpr", ex.encode_to_vec())
+                }
+                expr::Node::Paren(ex) => ("ParenExpr", ex.encode_to_vec()),
+                expr::Node::Subquery(ex) => ("SubqueryExpr", ex.encode_to_vec()),
+                expr::Node::NumberLiteral(ex) => ("NumberLiteral", ex.encode_to_vec()),
+                expr::Node::StringLiteral(ex) => ("StringLiteral", ex.encode_to_vec()),
+                expr::Node::VectorSelector(vs) => ("VectorSelector", vs.encode_to_vec()),
+                expr::Node::MatrixSelector(ms) => ("MatrixSelector", ms.encode_to_vec()),
+                expr::Node::Call(call) => ("Call", call.encode_to_vec()),
+                expr::Node::Extension(ext) => ("Extension", ext.encode_to_vec()),
+            };
+            encoded.push((name, bytes));
+        }
+        encoded.extend([
+            ("LabelModifier", label_modifier.encode_to_vec()),
+            ("VectorMatchCardinality", card.encode_to_vec()),
+            ("Offset", offset.encode_to_vec()),
+            ("AtModifier", at.encode_to_vec()),
+            ("Matcher", matcher.encode_to_vec()),
+        ]);
+
+        assert_eq!(encoded.len(), messages.len());
+        for (name, mut fields) in messages {
+            let (_, bytes) = encoded.iter().find(|(n, _)| *n == name).unwrap();
+            fields.sort();
+            assert_eq!(wire_fields(bytes), fields, "{name}");
+        }
+
+        for (name, value, number) in enums {
+            let actual = match name.as_str() {
+                "LabelModifier.Kind" => {
+                    label_modifier::Kind::try_from(number).map(|k| format!("{k:?}"))
+                }
+                "VectorMatchCardinality.Kind" => {
+                    vector_match_cardinality::Kind::try_from(number).map(|k| format!("{k:?}"))
+                }
+                "AtModifier.Kind" => at_modifier::Kind::try_from(number).map(|k| format!("{k:?}")),
+                "Matcher.Op" => matcher::Op::try_from(number).map(|k| format!("{k:?}")),
+                _ => panic!("unknown enum {name}"),
+            };
+            let expected: String = value
+                .split('_')
+                .map(|w| w[..1].to_string() + &w[1..].to_lowercase())
+                .collect();
+            assert_eq!(actual, Ok(expected), "{name}.{value}");
+        }
+    }
+}
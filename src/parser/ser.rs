@@ -0,0 +1,163 @@
+// Copyright 2023 Greptime Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! serde support for the AST nodes which can not simply derive it.
+
+use crate::label::MatchOp;
+use crate::parser::function::get_function;
+use crate::parser::token::{token_display, token_from_display};
+use crate::parser::{Extension, Function, TokenType};
+use regex::Regex;
+use serde::de::Error as _;
+use serde::ser::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// tokens are written as their text, like `+` or `sum`, which does not
+/// depend on the token ids of the generated parser.
+impl Serialize for TokenType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(token_display(self.id()))
+    }
+}
+
+impl<'de> Deserialize<'de> for TokenType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        token_from_display(&s)
+            .map(TokenType::new)
+            .ok_or_else(|| D::Error::custom(format!("unknown token {s}")))
+    }
+}
+
+/// functions are written as their name, like `rate`.
+impl Serialize for Function {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name)
+    }
+}
+
+impl<'de> Deserialize<'de> for Function {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        get_function(&name).ok_or_else(|| D::Error::custom(format!("unknown function {name}")))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+enum MatchOpRepr {
+    Equal,
+    NotEqual,
+    Re(String),
+    NotRe(String),
+}
+
+/// regex ops are written with their pattern, which is compiled again when read.
+impl Serialize for MatchOp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let repr = match self {
+            MatchOp::Equal => MatchOpRepr::Equal,
+            MatchOp::NotEqual => MatchOpRepr::NotEqual,
+            MatchOp::Re(re) => MatchOpRepr::Re(re.as_str().into()),
+            MatchOp::NotRe(re) => MatchOpRepr::NotRe(re.as_str().into()),
+        };
+        repr.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for MatchOp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let new_regex = |re: String| Regex::new(&re).map_err(D::Error::custom);
+        match MatchOpRepr::deserialize(deserializer)? {
+            MatchOpRepr::Equal => Ok(MatchOp::Equal),
+            MatchOpRepr::NotEqual => Ok(MatchOp::NotEqual),
+            MatchOpRepr::Re(re) => new_regex(re).map(MatchOp::Re),
+            MatchOpRepr::NotRe(re) => new_regex(re).map(MatchOp::NotRe),
+        }
+    }
+}
+
+/// extensions are defined outside of this crate, so they can not be serialized.
+impl Serialize for Extension {
+    fn serialize<S: Serializer>(&self, _: S) -> Result<S::Ok, S::Error> {
+        Err(S::Error::custom(format!(
+            "extension expr {} can not be serialized",
+            self.expr.name()
+        )))
+    }
+}
+
+impl<'de> Deserialize<'de> for Extension {
+    fn deserialize<D: Deserializer<'de>>(_: D) -> Result<Self, D::Error> {
+        Err(D::Error::custom("extension expr can not be deserialized"))
+    }
+}
+
+/// timestamps are written as milliseconds since UNIX_EPOCH, which can be negative.
+pub(crate) mod system_time {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::time::{Duration, SystemTime};
+
+    pub fn serialize<S: Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+        let millis = match time.duration_since(SystemTime::UNIX_EPOCH) {
+            Ok(d) => d.as_millis() as i64,
+            Err(e) => -(e.duration().as_millis() as i64),
+        };
+        millis.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SystemTime, D::Error> {
+        let millis = i64::deserialize(deserializer)?;
+        let d = Duration::from_millis(millis.unsigned_abs());
+        if millis >= 0 {
+            Ok(SystemTime::UNIX_EPOCH + d)
+        } else {
+            Ok(SystemTime::UNIX_EPOCH - d)
+        }
+    }
+}
+
+#[cfg(all(test, feature = "binary"))]
+mod tests {
+    use crate::parser::{parse, BinModifier, Expr, LabelModifier, VectorMatchCardinality};
+    use serde::de::DeserializeOwned;
+    use serde::Serialize;
+    use std::fmt::Debug;
+
+    fn round_trip<T: Serialize + DeserializeOwned + PartialEq + Debug>(value: T) {
+        let bytes = postcard::to_allocvec(&value).unwrap();
+        assert_eq!(postcard::from_bytes::<T>(&bytes).unwrap(), value);
+    }
+
+    #[test]
+    fn test_modifier_round_trip() {
+        let queries = [
+            "a * on (x, y) group_left (z) b",
+            "a > bool ignoring (x) group_right () b",
+            "a or b",
+            "a - b",
+        ];
+        for query in queries {
+            let Expr::Binary(ex) = parse(query).unwrap() else {
+                unreachable!()
+            };
+            let modifier = ex.modifier.unwrap_or_default();
+            round_trip(modifier.card.clone());
+            round_trip(modifier.matching.clone());
+            round_trip(modifier);
+        }
+        round_trip(LabelModifier::Include(["a".to_string()].into()));
+        round_trip(VectorMatchCardinality::ManyToMany);
+        round_trip(BinModifier::default());
+    }
+}
@@ -0,0 +1,189 @@
+// Copyright 2023 Greptime Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::parser::{Expr, NodeId};
+use std::ops::Range;
+
+/// Spans are the byte ranges of the nodes of an expr in the query it is parsed
+/// from, keyed by [`NodeId`], see [`crate::parser::parse_with_spans`].
+///
+/// The span of a node covers its modifiers, like `foo[5m] offset 1m`, and the
+/// parentheses of a paren expr. A unary `+` is part of the span of its operand,
+/// since it is not kept in the tree, and so is the `-` of a negative number.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Spans(Vec<Range<usize>>);
+
+impl Spans {
+    /// the spans of the nodes of the expr, which are given in post-order,
+    /// the order the parser builds the nodes in. Fails if there is not a span
+    /// for each node.
+    pub(crate) fn from_post_order(expr: &Expr, spans: Vec<Range<usize>>) -> Result<Self, String> {
+        let mut ids = Vec::with_capacity(spans.len());
+        post_order_ids(expr, &mut 0, &mut ids);
+        if ids.len() != spans.len() {
+            return Err(format!(
+                "{} spans for the {} nodes of {expr}",
+                spans.len(),
+                ids.len()
+            ));
+        }
+        let mut by_id = vec![0..0; ids.len()];
+        for (id, span) in ids.into_iter().zip(spans) {
+            by_id[id] = span;
+        }
+        Ok(Self(by_id))
+    }
+
+    /// the span of the node, None for an unknown id.
+    pub fn get(&self, id: NodeId) -> Option<Range<usize>> {
+        self.0.get(id.index()).cloned()
+    }
+
+    /// the text of the node in the query.
+    ///
+    /// # Examples
+    ///
+    /// ``` rust
+    /// use promql_parser::parser::{parse_with_spans, ParseOptions};
+    ///
+    /// let query = "sum by (job) (rate(foo[5m] offset 1m))  > 0.5";
+    /// let (expr, spans) = parse_with_spans(query, &ParseOptions::new()).unwrap();
+    /// let nodes = expr.nodes();
+    /// assert_eq!(spans.text(nodes[1].0, query), Some("sum by (job) (rate(foo[5m] offset 1m))"));
+    /// assert_eq!(spans.text(nodes[2].0, query), Some("rate(foo[5m] offset 1m)"));
+    /// assert_eq!(spans.text(nodes[4].0, query), Some("0.5"));
+    /// assert_eq!(spans.text(nodes[1].0, "foo"), None);
+    /// ```
+    pub fn text<'a>(&self, id: NodeId, input: &'a str) -> Option<&'a str> {
+        self.get(id).and_then(|span| input.get(span))
+    }
+
+    /// number of nodes with a span.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// push the pre-order ids of the nodes in post-order, `next` is the id of expr.
+fn post_order_ids(expr: &Expr, next: &mut usize, ids: &mut Vec<usize>) {
+    let id = *next;
+    *next += 1;
+    for child in expr.children() {
+        post_order_ids(child, next, ids);
+    }
+    ids.push(id);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::{parse, parse_with_spans, ParseOptions};
+
+    fn texts(query: &str) -> Vec<&str> {
+        let (expr, spans) = parse_with_spans(query, &ParseOptions::new()).unwrap();
+        assert_eq!(expr, parse(query).unwrap());
+        assert_eq!(spans.len(), expr.node_count());
+        expr.nodes()
+            .into_iter()
+            .map(|(id, _)| &query[spans.get(id).unwrap()])
+            .collect()
+    }
+
+    #[test]
+    fn test_spans_count() {
+        let expr = parse("foo + bar").unwrap();
+        assert_eq!(
+            super::Spans::from_post_order(&expr, vec![0..3, 6..9]),
+            Err("2 spans for the 3 nodes of foo + bar".into())
+        );
+        let spans = super::Spans::from_post_order(&expr, vec![0..3, 6..9, 0..9]).unwrap();
+        assert_eq!(spans.get(super::NodeId::ROOT), Some(0..9));
+    }
+
+    #[test]
+    fn test_spans() {
+        assert_eq!(texts("foo"), ["foo"]);
+        assert_eq!(texts(" foo  # comment"), ["foo"]);
+        assert_eq!(
+            texts("foo + bar * 2"),
+            ["foo + bar * 2", "foo", "bar * 2", "bar", "2"]
+        );
+        assert_eq!(texts("-foo"), ["-foo", "foo"]);
+        assert_eq!(texts("- -1"), ["- -1"]);
+        assert_eq!(texts("+foo @ 10"), ["+foo @ 10"]);
+        assert_eq!(texts("- - foo"), ["- - foo", "- foo", "foo"]);
+        assert_eq!(texts("(foo)"), ["(foo)", "foo"]);
+        assert_eq!(
+            texts("foo[5m] @ start() offset 1m"),
+            ["foo[5m] @ start() offset 1m"]
+        );
+        assert_eq!(
+            texts("max_over_time((-foo)[1h:5m] offset 1m)"),
+            [
+                "max_over_time((-foo)[1h:5m] offset 1m)",
+                "(-foo)[1h:5m] offset 1m",
+                "(-foo)",
+                "-foo",
+                "foo"
+            ]
+        );
+        assert_eq!(
+            texts(r#"count_values("x", foo) by (job)"#),
+            [r#"count_values("x", foo) by (job)"#, r#""x""#, "foo"]
+        );
+        assert_eq!(
+            texts("sum without (a) (topk by (b) (3, foo))"),
+            [
+                "sum without (a) (topk by (b) (3, foo))",
+                "topk by (b) (3, foo)",
+                "3",
+                "foo"
+            ]
+        );
+        assert_eq!(
+            texts(r#"label_join(foo{a="b"}, "c", ",", "a") > bool on (c) group_left () bar"#),
+            [
+                r#"label_join(foo{a="b"}, "c", ",", "a") > bool on (c) group_left () bar"#,
+                r#"label_join(foo{a="b"}, "c", ",", "a")"#,
+                r#"foo{a="b"}"#,
+                r#""c""#,
+                r#"",""#,
+                r#""a""#,
+                "bar"
+            ]
+        );
+        assert_eq!(texts("time()"), ["time()"]);
+    }
+
+    #[test]
+    fn test_text() {
+        let query = r#"foo + on (a) foo{a="b"} / -foo"#;
+        let (expr, spans) = parse_with_spans(query, &ParseOptions::new()).unwrap();
+        let nodes = expr.nodes();
+        assert_eq!(spans.text(nodes[4].0, query), Some("-foo"));
+        assert_eq!(spans.text(nodes[5].0, query), Some("foo"));
+        assert_eq!(spans.text(nodes[2].0, "foo"), None);
+
+        let query = "first_over_time(foo[5m]) + 1";
+        let options = ParseOptions::new().with_experimental_functions(true);
+        let (expr, spans) = parse_with_spans(query, &options).unwrap();
+        assert_eq!(
+            spans.text(expr.nodes()[1].0, query),
+            Some("first_over_time(foo[5m])")
+        );
+    }
+}
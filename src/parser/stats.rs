@@ -0,0 +1,75 @@
+// Copyright 2023 Greptime Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeMap;
+
+use crate::parser::Expr;
+
+/// statistics about a parsed query, see [`parse_with_stats`](crate::parser::parse_with_stats).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ParseStats {
+    /// the number of tokens, excluding the end of input.
+    pub tokens: usize,
+    /// the number of nodes per variant of [`Expr`], like `Call`.
+    pub nodes: BTreeMap<&'static str, usize>,
+    /// the depth of the tree, a single selector has depth 1.
+    pub max_depth: usize,
+    /// the number of vector and matrix selectors.
+    pub selectors: usize,
+}
+
+impl ParseStats {
+    /// collect the statistics of the expr in a single traversal,
+    /// including the aggregation params.
+    pub(crate) fn new(tokens: usize, expr: &Expr) -> Self {
+        let mut stats = Self {
+            tokens,
+            ..Self::default()
+        };
+        stats.collect(expr, 1);
+        stats
+    }
+
+    /// the number of nodes of all variants.
+    pub fn total_nodes(&self) -> usize {
+        self.nodes.values().sum()
+    }
+
+    fn collect(&mut self, expr: &Expr, depth: usize) {
+        *self.nodes.entry(variant_name(expr)).or_default() += 1;
+        self.max_depth = self.max_depth.max(depth);
+        if matches!(expr, Expr::VectorSelector(_) | Expr::MatrixSelector(_)) {
+            self.selectors += 1;
+        }
+        for child in expr.children() {
+            self.collect(child, depth + 1);
+        }
+    }
+}
+
+fn variant_name(expr: &Expr) -> &'static str {
+    match expr {
+        Expr::Aggregate(_) => "Aggregate",
+        Expr::Unary(_) => "Unary",
+        Expr::Binary(_) => "Binary",
+        Expr::Paren(_) => "Paren",
+        Expr::Subquery(_) => "Subquery",
+        Expr::NumberLiteral(_) => "NumberLiteral",
+        Expr::StringLiteral(_) => "StringLiteral",
+        Expr::VectorSelector(_) => "VectorSelector",
+        Expr::MatrixSelector(_) => "MatrixSelector",
+        Expr::Call(_) => "Call",
+        Expr::Extension(_) => "Extension",
+    }
+}
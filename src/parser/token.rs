@@ -14,6 +14,7 @@
 
 use lazy_static::lazy_static;
 use std::collections::HashMap;
+use std::fmt;
 
 lrlex::lrlex_mod!("token_map");
 pub use token_map::*;
@@ -67,7 +68,6 @@ lazy_static! {
 }
 
 /// this is for debug so far, maybe pretty feature in the future.
-#[allow(dead_code)]
 pub(crate) fn token_display(id: TokenId) -> &'static str {
     match id {
         // Token.
@@ -161,6 +161,12 @@ pub(crate) fn token_display(id: TokenId) -> &'static str {
     }
 }
 
+/// the reverse of [`token_display`], like `+` to `T_ADD`.
+#[cfg(any(feature = "ser", feature = "proto"))]
+pub(crate) fn token_from_display(s: &str) -> Option<TokenId> {
+    (0..=TokenId::MAX).find(|id| token_display(*id) == s)
+}
+
 /// This is a list of all keywords in PromQL.
 /// When changing this list, make sure to also change
 /// the maybe_label grammar rule in the generated parser
@@ -216,6 +222,32 @@ impl TokenType {
     pub fn is_operator(&self) -> bool {
         self.0 > T_OPERATORS_START && self.0 < T_OPERATORS_END
     }
+
+    /// the precedence of the binary operator, the same as in the Prometheus parser:
+    /// from 1 for `or` to 6 for `^`, and 0 for other tokens.
+    pub fn precedence(&self) -> u8 {
+        match self.0 {
+            T_LOR => 1,
+            T_LAND | T_LUNLESS => 2,
+            T_EQLC | T_NEQ | T_LTE | T_LSS | T_GTE | T_GTR => 3,
+            T_ADD | T_SUB => 4,
+            T_MUL | T_DIV | T_MOD | T_ATAN2 => 5,
+            T_POW => 6,
+            _ => 0,
+        }
+    }
+
+    /// whether the binary operator groups from the right, only `^` does, so
+    /// `2 ^ 3 ^ 2` is `2 ^ (3 ^ 2)`.
+    pub fn is_right_associative(&self) -> bool {
+        self.0 == T_POW
+    }
+}
+
+impl fmt::Display for TokenType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", token_display(self.0))
+    }
 }
 
 #[cfg(test)]
@@ -346,8 +378,8 @@ mod tests {
         assert!(matches!(get_keyword_token("nan"), Some(T_NUMBER)));
 
         // not keywords
-        assert!(matches!(get_keyword_token("at"), None));
-        assert!(matches!(get_keyword_token("unknown"), None));
+        assert!(get_keyword_token("at").is_none());
+        assert!(get_keyword_token("unknown").is_none());
     }
 
     #[test]
@@ -0,0 +1,243 @@
+// Copyright 2023 Greptime Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! helpers to assert the shape of parsed queries in tests, enabled by the
+//! `testing` feature.
+//!
+//! [`render`] writes an expr as a compact s-expression, which makes the
+//! structure explicit, like `(+ foo (* bar 2))`. Labels and matchers are
+//! sorted, so the rendering is stable, and it does not depend on the
+//! representation of the AST, like the `Debug` output does.
+
+use crate::parser::{parse, Expr};
+use crate::util::display_duration;
+
+/// render the expr as a s-expression, nodes with children are written as
+/// `(<node> <children>...)`:
+///
+/// - aggregations as `(sum by (job) <param> <expr>)`
+/// - calls as `(rate <args>...)`
+/// - binary exprs as `(<op> <modifiers> <lhs> <rhs>)`, like `(/ on (job) a b)`
+/// - unary exprs as `(- <expr>)`, and parens as `(paren <expr>)`
+/// - subqueries as `(subquery 5m:1m <modifiers> <expr>)`
+///
+/// selectors and literals are written as in PromQL.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::parse;
+/// use promql_parser::testing::render;
+///
+/// let expr = parse("sum by (job) (rate(foo[5m])) / on (job) group_left bar").unwrap();
+/// assert_eq!(render(&expr), "(/ on (job) group_left () (sum by (job) (rate foo[5m])) bar)");
+/// ```
+pub fn render(expr: &Expr) -> String {
+    match expr {
+        Expr::Aggregate(ex) => {
+            let mut head = ex.op.to_string();
+            if let Some(modifier) = &ex.modifier {
+                head.push_str(&format!(" {modifier}"));
+            }
+            let children: Vec<&Expr> = ex.param.iter().chain([&ex.expr]).map(|e| &**e).collect();
+            node(&head, &children)
+        }
+        Expr::Unary(ex) => node("-", &[&ex.expr]),
+        Expr::Binary(ex) => {
+            let mut head = ex.op.to_string();
+            if let Some(modifier) = &ex.modifier {
+                let modifier = modifier.to_string();
+                if !modifier.is_empty() {
+                    head.push_str(&format!(" {modifier}"));
+                }
+            }
+            node(&head, &[&ex.lhs, &ex.rhs])
+        }
+        Expr::Paren(ex) => node("paren", &[&ex.expr]),
+        Expr::Subquery(ex) => {
+            let mut head = format!("subquery {}:{}", display_duration(&ex.range), ex.step);
+            if let Some(at) = &ex.at {
+                head.push_str(&format!(" {at}"));
+            }
+            if let Some(offset) = &ex.offset {
+                head.push_str(&format!(" {offset}"));
+            }
+            node(&head, &[&ex.expr])
+        }
+        Expr::Call(call) => {
+            let args: Vec<&Expr> = call.args.iter().collect();
+            node(call.func.name, &args)
+        }
+        Expr::Extension(ex) => {
+            let children: Vec<&Expr> = ex.expr.children().iter().collect();
+            node(ex.expr.name(), &children)
+        }
+        Expr::NumberLiteral(_)
+        | Expr::StringLiteral(_)
+        | Expr::VectorSelector(_)
+        | Expr::MatrixSelector(_) => expr.to_string(),
+    }
+}
+
+fn node(head: &str, children: &[&Expr]) -> String {
+    let mut s = format!("({head}");
+    for child in children {
+        s.push(' ');
+        s.push_str(&render(child));
+    }
+    s.push(')');
+    s
+}
+
+/// values which can be rendered by the assertion macros, which are exprs
+/// and queries, the queries are parsed first.
+pub trait RenderAst {
+    fn render_ast(&self) -> String;
+}
+
+impl RenderAst for Expr {
+    fn render_ast(&self) -> String {
+        render(self)
+    }
+}
+
+impl RenderAst for str {
+    fn render_ast(&self) -> String {
+        match parse(self) {
+            Ok(expr) => render(&expr),
+            Err(e) => panic!("failed to parse {self:?}: {e}"),
+        }
+    }
+}
+
+impl RenderAst for String {
+    fn render_ast(&self) -> String {
+        self.as_str().render_ast()
+    }
+}
+
+impl<T: RenderAst + ?Sized> RenderAst for &T {
+    fn render_ast(&self) -> String {
+        (**self).render_ast()
+    }
+}
+
+/// assert the rendering of an expr or a query, see [`render`](crate::testing::render).
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::assert_ast;
+/// use promql_parser::parser::parse;
+///
+/// assert_ast!("1 + 2 * 3", "(+ 1 (* 2 3))");
+/// assert_ast!(parse("-foo offset 5m").unwrap(), "(- foo offset 5m)");
+/// ```
+#[macro_export]
+macro_rules! assert_ast {
+    ($actual:expr, $expected:expr $(,)?) => {
+        match &$actual {
+            actual => assert_eq!(
+                $crate::testing::RenderAst::render_ast(actual),
+                $expected,
+                "the AST of {:?} does not match",
+                actual
+            ),
+        }
+    };
+}
+
+/// assert two exprs or queries have the same AST.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::assert_ast_eq;
+///
+/// assert_ast_eq!("sum(foo) by (job)", "sum by (job) (foo)");
+/// ```
+#[macro_export]
+macro_rules! assert_ast_eq {
+    ($left:expr, $right:expr $(,)?) => {
+        assert_eq!(
+            $crate::testing::RenderAst::render_ast(&$left),
+            $crate::testing::RenderAst::render_ast(&$right)
+        )
+    };
+}
+
+/// assert the query fails to parse with the error.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::assert_parse_error;
+///
+/// assert_parse_error!("rate(foo)", "expected type matrix in call to function 'rate', got vector");
+/// ```
+#[macro_export]
+macro_rules! assert_parse_error {
+    ($query:expr, $expected:expr $(,)?) => {
+        match $crate::parser::parse($query) {
+            Ok(expr) => panic!(
+                "expected {:?} to fail to parse, got {}",
+                $query,
+                $crate::testing::render(&expr)
+            ),
+            Err(e) => assert_eq!(e, $expected, "the error of {:?} does not match", $query),
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_render() {
+        let cases = vec![
+            ("foo", "foo"),
+            (r#"foo{b="2", a=~"1"}"#, r#"foo{a=~"1",b="2"}"#),
+            ("foo[5m] @ 100 offset 1m", "foo[5m] @ 100.000 offset 1m"),
+            ("-a + 2 ^ 3 ^ 4", "(+ (- a) (^ 2 (^ 3 4)))"),
+            ("a > bool b", "(> bool a b)"),
+            (
+                "a * ignoring (y, x) group_right (z) b",
+                "(* ignoring (x, y) group_right (z) a b)",
+            ),
+            ("a and on () b", "(and on () a b)"),
+            ("topk(3, foo) without (b, a)", "(topk without (a, b) 3 foo)"),
+            ("(foo)", "(paren foo)"),
+            (
+                "max_over_time(rate(foo[5m])[1h:] offset 5m)",
+                "(max_over_time (subquery 1h: offset 5m (rate foo[5m])))",
+            ),
+            (
+                r#"label_join(up, "a", ",", "b")"#,
+                r#"(label_join up "a" "," "b")"#,
+            ),
+            ("time()", "(time)"),
+        ];
+        for (query, expected) in cases {
+            assert_ast!(query, expected);
+        }
+        assert_ast!(String::from("foo"), "foo");
+        assert_ast_eq!("foo + on(a) bar", "foo + on (a) bar");
+        assert_parse_error!("foo +", "unexpected end of input, expected identifier, '{', '(', metric identifier, number, string, '+', '-', aggregation, 'ignoring' or 'on'");
+    }
+
+    #[test]
+    #[should_panic(expected = "the AST of")]
+    fn test_assert_ast_mismatch() {
+        assert_ast!("foo + bar", "(+ bar foo)");
+    }
+}
@@ -0,0 +1,167 @@
+// Copyright 2023 Greptime Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! typed side tables of data attached to the nodes of an expr.
+
+use crate::parser::NodeId;
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Annotations keeps data of any type for the nodes of an expr, keyed by
+/// [`NodeId`], so passes can attach their results without changing the AST.
+/// A node has at most one value of each type.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::parse;
+/// use promql_parser::util::annotations::Annotations;
+///
+/// #[derive(Debug, PartialEq)]
+/// struct Cardinality(u64);
+///
+/// let expr = parse("sum(rate(foo[5m]))").unwrap();
+/// let mut annotations = Annotations::new();
+/// for (id, _) in expr.nodes() {
+///     annotations.insert(id, Cardinality(100));
+/// }
+///
+/// let (id, _) = expr.nodes()[1];
+/// assert_eq!(annotations.get::<Cardinality>(id), Some(&Cardinality(100)));
+/// assert_eq!(annotations.get::<String>(id), None);
+/// ```
+#[derive(Default)]
+pub struct Annotations {
+    values: HashMap<(NodeId, TypeId), Box<dyn Any + Send + Sync>>,
+}
+
+impl Annotations {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// attach the value to the node, returning the previous value of the same type.
+    pub fn insert<T: Any + Send + Sync>(&mut self, id: NodeId, value: T) -> Option<T> {
+        self.values
+            .insert((id, TypeId::of::<T>()), Box::new(value))
+            .and_then(|old| old.downcast().ok())
+            .map(|old| *old)
+    }
+
+    pub fn get<T: Any + Send + Sync>(&self, id: NodeId) -> Option<&T> {
+        self.values
+            .get(&(id, TypeId::of::<T>()))
+            .and_then(|v| v.downcast_ref())
+    }
+
+    pub fn get_mut<T: Any + Send + Sync>(&mut self, id: NodeId) -> Option<&mut T> {
+        self.values
+            .get_mut(&(id, TypeId::of::<T>()))
+            .and_then(|v| v.downcast_mut())
+    }
+
+    pub fn remove<T: Any + Send + Sync>(&mut self, id: NodeId) -> Option<T> {
+        self.values
+            .remove(&(id, TypeId::of::<T>()))
+            .and_then(|v| v.downcast().ok())
+            .map(|v| *v)
+    }
+
+    pub fn contains<T: Any + Send + Sync>(&self, id: NodeId) -> bool {
+        self.values.contains_key(&(id, TypeId::of::<T>()))
+    }
+
+    /// all values of the type, sorted by node id.
+    pub fn iter<T: Any + Send + Sync>(&self) -> Vec<(NodeId, &T)> {
+        let mut values: Vec<(NodeId, &T)> = self
+            .values
+            .iter()
+            .filter(|((_, type_id), _)| *type_id == TypeId::of::<T>())
+            .filter_map(|((id, _), v)| v.downcast_ref().map(|v| (*id, v)))
+            .collect();
+        values.sort_unstable_by_key(|(id, _)| *id);
+        values
+    }
+
+    /// number of values of all types.
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
+impl fmt::Debug for Annotations {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Annotations")
+            .field("len", &self.values.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    #[derive(Debug, PartialEq)]
+    struct Cardinality(u64);
+
+    #[derive(Debug, PartialEq)]
+    struct Cost(f64);
+
+    #[test]
+    fn test_annotations() {
+        let expr = parse("foo + bar").unwrap();
+        let ids: Vec<NodeId> = expr.nodes().into_iter().map(|(id, _)| id).collect();
+
+        let mut annotations = Annotations::new();
+        assert!(annotations.is_empty());
+        assert_eq!(annotations.insert(ids[1], Cardinality(10)), None);
+        assert_eq!(annotations.insert(ids[2], Cardinality(20)), None);
+        assert_eq!(annotations.insert(ids[1], Cost(1.5)), None);
+        assert_eq!(annotations.len(), 3);
+
+        assert_eq!(
+            annotations.insert(ids[1], Cardinality(11)),
+            Some(Cardinality(10))
+        );
+        assert_eq!(
+            annotations.get::<Cardinality>(ids[1]),
+            Some(&Cardinality(11))
+        );
+        assert_eq!(annotations.get::<Cost>(ids[1]), Some(&Cost(1.5)));
+        assert_eq!(annotations.get::<Cost>(ids[2]), None);
+        assert!(!annotations.contains::<Cardinality>(ids[0]));
+
+        annotations.get_mut::<Cardinality>(ids[2]).unwrap().0 += 1;
+        assert_eq!(
+            annotations.iter::<Cardinality>(),
+            vec![(ids[1], &Cardinality(11)), (ids[2], &Cardinality(21))]
+        );
+
+        // the ids are the same in a clone of the expr
+        let cloned = expr.clone();
+        let (id, node) = cloned.nodes()[2];
+        assert_eq!(node.to_string(), "bar");
+        assert_eq!(annotations.get::<Cardinality>(id), Some(&Cardinality(21)));
+
+        assert_eq!(annotations.remove::<Cost>(ids[1]), Some(Cost(1.5)));
+        assert_eq!(annotations.remove::<Cost>(ids[1]), None);
+        assert_eq!(annotations.len(), 2);
+    }
+}
@@ -0,0 +1,168 @@
+// Copyright 2023 Greptime Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! the engine capabilities a query requires, to route it to a backend supporting them.
+
+use crate::parser::{Expr, Offset};
+use crate::util::{walk_expr, ExprVisitor};
+use std::collections::BTreeSet;
+use std::convert::Infallible;
+use std::fmt;
+
+/// functions which only work on native histograms.
+const NATIVE_HISTOGRAM_FUNCTIONS: [&str; 3] =
+    ["histogram_count", "histogram_sum", "histogram_fraction"];
+
+/// Capability is a feature of PromQL which not all engines support.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Capability {
+    /// subqueries, like `max_over_time(rate(foo[5m])[1h:])`.
+    Subquery,
+    /// the `@` modifier, like `foo @ 1609746000` or `foo @ end()`.
+    AtModifier,
+    /// negative offsets, like `foo offset -5m`.
+    NegativeOffset,
+    /// the functions which have to be enabled, see [`crate::parser::Function::experimental`].
+    ExperimentalFunctions,
+    /// the set operators `and`, `or` and `unless`.
+    SetOperators,
+    /// the functions over native histograms, like `histogram_count`.
+    NativeHistograms,
+}
+
+impl Capability {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Capability::Subquery => "subquery",
+            Capability::AtModifier => "at_modifier",
+            Capability::NegativeOffset => "negative_offset",
+            Capability::ExperimentalFunctions => "experimental_functions",
+            Capability::SetOperators => "set_operators",
+            Capability::NativeHistograms => "native_histograms",
+        }
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// the capabilities required to evaluate the expr, including the ones in
+/// aggregation params. An empty set means any engine can evaluate it.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::parse;
+/// use promql_parser::util::capabilities::{capabilities, Capability};
+///
+/// let expr = parse("max_over_time(rate(foo[5m])[1h:] offset -1m) and bar @ end()").unwrap();
+/// assert_eq!(
+///     capabilities(&expr).into_iter().collect::<Vec<_>>(),
+///     vec![
+///         Capability::Subquery,
+///         Capability::AtModifier,
+///         Capability::NegativeOffset,
+///         Capability::SetOperators,
+///     ]
+/// );
+/// ```
+pub fn capabilities(expr: &Expr) -> BTreeSet<Capability> {
+    let mut collector = Collector::default();
+    let _ = walk_expr(&mut collector, expr);
+    collector.capabilities
+}
+
+#[derive(Default)]
+struct Collector {
+    capabilities: BTreeSet<Capability>,
+}
+
+impl Collector {
+    fn modifiers(&mut self, offset: &Option<Offset>, has_at: bool) {
+        if let Some(Offset::Neg(_)) = offset {
+            self.capabilities.insert(Capability::NegativeOffset);
+        }
+        if has_at {
+            self.capabilities.insert(Capability::AtModifier);
+        }
+    }
+}
+
+impl ExprVisitor for Collector {
+    type Error = Infallible;
+
+    fn pre_visit(&mut self, expr: &Expr) -> Result<bool, Self::Error> {
+        match expr {
+            Expr::VectorSelector(vs) => self.modifiers(&vs.offset, vs.at.is_some()),
+            Expr::MatrixSelector(ms) => {
+                let vs = &ms.vector_selector;
+                self.modifiers(&vs.offset, vs.at.is_some());
+            }
+            Expr::Subquery(sq) => {
+                self.capabilities.insert(Capability::Subquery);
+                self.modifiers(&sq.offset, sq.at.is_some());
+            }
+            Expr::Binary(ex) if ex.op.is_set_operator() => {
+                self.capabilities.insert(Capability::SetOperators);
+            }
+            Expr::Call(call) => {
+                if call.func.experimental {
+                    self.capabilities.insert(Capability::ExperimentalFunctions);
+                }
+                if NATIVE_HISTOGRAM_FUNCTIONS.contains(&call.func.name) {
+                    self.capabilities.insert(Capability::NativeHistograms);
+                }
+            }
+            _ => {}
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{parse_with_options, ParseOptions};
+
+    #[test]
+    fn test_capabilities() {
+        use Capability::*;
+
+        let cases = vec![
+            ("foo", vec![]),
+            ("sum by (job) (rate(foo[5m] offset 1h)) / bar", vec![]),
+            ("foo offset -5m", vec![NegativeOffset]),
+            ("rate(foo[5m] @ start())", vec![AtModifier]),
+            ("foo[5m:] offset -1m", vec![Subquery, NegativeOffset]),
+            ("foo unless bar", vec![SetOperators]),
+            ("topk(scalar(foo @ 100), bar)", vec![AtModifier]),
+            (
+                "histogram_count(rate(foo[5m])) / histogram_quantile(0.9, rate(bar[5m]))",
+                vec![NativeHistograms],
+            ),
+            ("mad_over_time(foo[5m])", vec![ExperimentalFunctions]),
+        ];
+        let options = ParseOptions::new().with_experimental_functions(true);
+        for (query, expected) in cases {
+            let expr = parse_with_options(query, &options).unwrap();
+            let actual: Vec<_> = capabilities(&expr).into_iter().collect();
+            assert_eq!(actual, expected, "{query}");
+        }
+
+        assert_eq!(Capability::NegativeOffset.to_string(), "negative_offset");
+    }
+}
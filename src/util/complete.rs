@@ -0,0 +1,311 @@
+// Copyright 2023 Greptime Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! context for completing the query being edited, which is usually not a valid query yet.
+
+use crate::label::{Matcher, Matchers, METRIC_NAME};
+use crate::util::series::selector_to_string;
+
+/// LabelValueContext is what is needed to complete the label value under the
+/// cursor, like with `/api/v1/label/<label>/values?match[]=<selector>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LabelValueContext {
+    /// the metric name of the selector, if any.
+    pub metric: Option<String>,
+    /// the label of the value being edited.
+    pub label: String,
+    /// the value typed before the cursor.
+    pub prefix: String,
+    /// the other complete matchers of the selector, without the metric name.
+    pub matchers: Matchers,
+}
+
+impl LabelValueContext {
+    /// the selector of the series the values come from, None if neither the
+    /// metric name nor any matcher narrows them down.
+    pub fn selector(&self) -> Option<String> {
+        if self.metric.is_none() && self.matchers.matchers.is_empty() {
+            return None;
+        }
+        Some(selector_to_string(self.metric.as_deref(), &self.matchers))
+    }
+}
+
+/// the context of the label value under the cursor, which is the byte offset
+/// in the query. None if the cursor is not inside the value of a matcher.
+///
+/// the query may be incomplete, matchers which can not be read are ignored.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::util::complete::label_value_context;
+///
+/// let query = r#"sum(rate(http_requests_total{job="api", code="5"#;
+/// let context = label_value_context(query, query.len()).unwrap();
+/// assert_eq!(context.metric.as_deref(), Some("http_requests_total"));
+/// assert_eq!(context.label, "code");
+/// assert_eq!(context.prefix, "5");
+/// assert_eq!(context.selector().unwrap(), r#"http_requests_total{job="api"}"#);
+///
+/// assert_eq!(label_value_context(query, 4), None);
+/// ```
+pub fn label_value_context(query: &str, cursor: usize) -> Option<LabelValueContext> {
+    if !query.is_char_boundary(cursor) {
+        return None;
+    }
+    let (brace, string) = scan(&query[..cursor]);
+    let (brace, string) = (brace?, string?);
+
+    // the matchers are between the braces, up to the closing brace or the end
+    let rest = &query[brace + 1..];
+    let end = brace + 1 + closing_brace(rest).unwrap_or(rest.len());
+    let segments = split_matchers(&query[brace + 1..end]);
+
+    let mut metric = metric_before(&query[..brace]);
+    let mut matchers = Matchers::empty();
+    let mut current = None;
+    for (start, segment) in segments {
+        let (start, stop) = (brace + 1 + start, brace + 1 + start + segment.len());
+        if (start..=stop).contains(&cursor) {
+            current = Some((start, segment));
+            continue;
+        }
+        match read_matcher(segment) {
+            Some(Segment::Name(name)) => metric = Some(name),
+            Some(Segment::Matcher(m)) if m.name == METRIC_NAME && m.op.to_string() == "=" => {
+                metric = Some(m.value)
+            }
+            Some(Segment::Matcher(m)) => matchers = matchers.append(m),
+            None => {}
+        }
+    }
+
+    // the text before the quote of the value is like `job=`
+    let (start, segment) = current?;
+    let head = segment[..string - start].trim_end();
+    let label = ["=~", "!~", "!=", "="]
+        .iter()
+        .find_map(|op| head.strip_suffix(op))?
+        .trim();
+    Some(LabelValueContext {
+        metric,
+        label: unquote(label)?.to_string(),
+        prefix: query[string + 1..cursor].to_string(),
+        matchers,
+    })
+}
+
+/// the position of the unclosed `{` and the opening quote of the unclosed
+/// string at the end of the text, if any.
+fn scan(text: &str) -> (Option<usize>, Option<usize>) {
+    let (mut brace, mut string) = (None, None);
+    let mut quote = None;
+    let mut chars = text.char_indices();
+    while let Some((i, ch)) = chars.next() {
+        match (quote, ch) {
+            (Some(q), '\\') if q != '`' => {
+                chars.next();
+            }
+            (Some(q), ch) if ch == q => {
+                quote = None;
+                string = None;
+            }
+            (Some(_), _) => {}
+            (None, '"' | '\'' | '`') => {
+                quote = Some(ch);
+                string = Some(i);
+            }
+            (None, '{') => brace = Some(i),
+            (None, '}') => brace = None,
+            (None, '#') => {
+                // a comment runs to the end of the line
+                for (_, ch) in chars.by_ref() {
+                    if ch == '\n' {
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    (brace, string)
+}
+
+/// the position of the first `}` outside of strings.
+fn closing_brace(text: &str) -> Option<usize> {
+    let mut quote = None;
+    let mut chars = text.char_indices();
+    while let Some((i, ch)) = chars.next() {
+        match (quote, ch) {
+            (Some(q), '\\') if q != '`' => {
+                chars.next();
+            }
+            (Some(q), ch) if ch == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'' | '`') => quote = Some(ch),
+            (None, '}') => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// split the text between the braces at the commas outside of strings, with
+/// the offset of each part.
+fn split_matchers(text: &str) -> Vec<(usize, &str)> {
+    let mut segments = vec![];
+    let (mut start, mut quote) = (0, None);
+    let mut chars = text.char_indices();
+    while let Some((i, ch)) = chars.next() {
+        match (quote, ch) {
+            (Some(q), '\\') if q != '`' => {
+                chars.next();
+            }
+            (Some(q), ch) if ch == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'' | '`') => quote = Some(ch),
+            (None, ',') => {
+                segments.push((start, &text[start..i]));
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    segments.push((start, &text[start..]));
+    segments
+}
+
+/// the identifier right before the `{`, like `foo` in `rate(foo{`.
+fn metric_before(text: &str) -> Option<String> {
+    let text = text.trim_end();
+    let start = text
+        .char_indices()
+        .rev()
+        .take_while(|(_, ch)| ch.is_ascii_alphanumeric() || *ch == '_' || *ch == ':')
+        .last()
+        .map(|(i, _)| i)?;
+    let name = &text[start..];
+    match name.chars().next() {
+        Some(ch) if !ch.is_ascii_digit() => Some(name.to_string()),
+        _ => None,
+    }
+}
+
+enum Segment {
+    /// a quoted metric name, like `{"foo.bar"}`.
+    Name(String),
+    Matcher(Matcher),
+}
+
+/// read a complete matcher, like `job="api"`.
+fn read_matcher(segment: &str) -> Option<Segment> {
+    let segment = segment.trim();
+    if quoted_len(segment) == Some(segment.len()) {
+        return unquote(segment).map(|name| Segment::Name(name.to_string()));
+    }
+    let value_start = match quoted_len(segment) {
+        // a quoted label name, like `"foo.bar"="baz"`
+        Some(len) => len + segment[len..].find(['"', '\'', '`'])?,
+        None => segment.find(['"', '\'', '`'])?,
+    };
+    let value = unquote(&segment[value_start..])?;
+    let head = segment[..value_start].trim_end();
+    let (name, op) = ["=~", "!~", "!=", "="]
+        .iter()
+        .find_map(|op| Some((head.strip_suffix(op)?, *op)))?;
+    let name = unquote(name.trim())?;
+    if name.is_empty() {
+        return None;
+    }
+    Matcher::try_from((name, op, value))
+        .ok()
+        .map(Segment::Matcher)
+}
+
+/// the length of the string the text starts with, quotes included.
+fn quoted_len(text: &str) -> Option<usize> {
+    let quote = text.chars().next().filter(|ch| "\"'`".contains(*ch))?;
+    let mut chars = text.char_indices().skip(1);
+    while let Some((i, ch)) = chars.next() {
+        if ch == '\\' && quote != '`' {
+            chars.next();
+        } else if ch == quote {
+            return Some(i + 1);
+        }
+    }
+    None
+}
+
+/// the text without its quotes, or the text itself if it is not quoted.
+/// None if the text is not a single string.
+fn unquote(text: &str) -> Option<&str> {
+    match quoted_len(text) {
+        Some(len) if len == text.len() => Some(&text[1..len - 1]),
+        Some(_) => None,
+        None if text.starts_with(['"', '\'', '`']) => None,
+        None => Some(text),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context(query: &str) -> Option<LabelValueContext> {
+        // the cursor is marked with `|`
+        let cursor = query.find('|').unwrap();
+        label_value_context(&query.replacen('|', "", 1), cursor)
+    }
+
+    #[test]
+    fn test_label_value_context() {
+        let c = context(r#"up{job="ap|"#).unwrap();
+        assert_eq!(c.metric.as_deref(), Some("up"));
+        assert_eq!(c.label, "job");
+        assert_eq!(c.prefix, "ap");
+        assert_eq!(c.selector().unwrap(), "up");
+
+        let c = context(r#"rate(foo{a!~"x", b="|"}[5m]) + bar{c="y"}"#).unwrap();
+        assert_eq!(c.metric.as_deref(), Some("foo"));
+        assert_eq!(c.label, "b");
+        assert_eq!(c.prefix, "");
+        assert_eq!(c.selector().unwrap(), r#"foo{a!~"x"}"#);
+
+        // the matchers after the cursor count too
+        let c = context(r#"{job="a|pi", __name__="up", "foo"='x'}"#).unwrap();
+        assert_eq!(c.metric.as_deref(), Some("up"));
+        assert_eq!(c.prefix, "a");
+        assert_eq!(c.selector().unwrap(), r#"up{foo="x"}"#);
+
+        let c = context(r#"{"foo.bar", "a.b"=~"x\"y, |"#).unwrap();
+        assert_eq!(c.metric.as_deref(), Some("foo.bar"));
+        assert_eq!(c.label, "a.b");
+        assert_eq!(c.prefix, r#"x\"y, "#);
+        assert!(c.matchers.matchers.is_empty());
+
+        // incomplete matchers are ignored
+        let c = context(r#"{job=, code="|"}"#).unwrap();
+        assert_eq!(c.metric, None);
+        assert_eq!(c.selector(), None);
+
+        // not inside a label value
+        assert_eq!(context(r#"up{jo|b="api"}"#), None);
+        assert_eq!(context(r#"up{job="api"|}"#), None);
+        assert_eq!(context(r#"up{job="api"} + fo|o"#), None);
+        assert_eq!(context(r#"label_replace(up, "d|st""#), None);
+        assert_eq!(context(r#"up{"foo.b|"#), None);
+        assert_eq!(context("# up{job=\"\n{job=\"|"), context(r#"{job="|"#));
+    }
+}
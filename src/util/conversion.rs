@@ -0,0 +1,153 @@
+// Copyright 2023 Greptime Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! the conversions between vectors and scalars by `scalar()` and `vector()`.
+
+use crate::parser::{Expr, NodeId, ValueType};
+
+/// the type a [`Conversion`] converts its argument to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConversionKind {
+    /// `scalar(v)`, which is NaN unless the vector has exactly one sample.
+    ToScalar,
+    /// `vector(s)`, a vector of one sample without labels.
+    ToVector,
+}
+
+/// a `scalar()` or `vector()` call of the expr.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Conversion<'a> {
+    /// the id of the call in the expr.
+    pub id: NodeId,
+    /// whether the call is `scalar()` or `vector()`.
+    pub kind: ConversionKind,
+    /// the call itself.
+    pub node: &'a Expr,
+}
+
+/// all conversions of the expr, in pre-order.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::parse;
+/// use promql_parser::util::conversion::{conversions, scalar_conversions, ConversionKind};
+///
+/// let expr = parse("vector(scalar(foo) * 2) + on () bar").unwrap();
+/// let found: Vec<ConversionKind> = conversions(&expr).iter().map(|c| c.kind).collect();
+/// assert_eq!(found, [ConversionKind::ToVector, ConversionKind::ToScalar]);
+///
+/// // the argument of vector() is a scalar because of scalar()
+/// let arg = &parse("scalar(foo) * 2").unwrap();
+/// assert_eq!(scalar_conversions(arg)[0].node.to_string(), "scalar(foo)");
+/// assert!(scalar_conversions(&expr).is_empty());
+/// ```
+pub fn conversions(expr: &Expr) -> Vec<Conversion<'_>> {
+    expr.nodes()
+        .into_iter()
+        .filter_map(|(id, node)| {
+            let kind = match node {
+                Expr::Call(call) if call.func.name == "scalar" => ConversionKind::ToScalar,
+                Expr::Call(call) if call.func.name == "vector" => ConversionKind::ToVector,
+                _ => return None,
+            };
+            Some(Conversion { id, kind, node })
+        })
+        .collect()
+}
+
+/// the `scalar()` calls the expr is a scalar because of, like the one of
+/// `scalar(foo) * 2`. Empty if the expr is not a scalar, or is one on its own,
+/// like `time() - 1`.
+pub fn scalar_conversions(expr: &Expr) -> Vec<Conversion<'_>> {
+    let mut sources = vec![];
+    collect_scalar_sources(expr, &mut sources);
+    conversions(expr)
+        .into_iter()
+        .filter(|c| sources.iter().any(|s| std::ptr::eq(*s, c.node)))
+        .collect()
+}
+
+/// whether the expr is a scalar because of `scalar()`, so it is silently NaN
+/// when the vector does not have exactly one sample.
+pub fn is_scalar_by_conversion(expr: &Expr) -> bool {
+    !scalar_conversions(expr).is_empty()
+}
+
+/// the scalar operations only keep the type of their operands, so the
+/// `scalar()` calls reached through them make the expr a scalar.
+fn collect_scalar_sources<'a>(expr: &'a Expr, sources: &mut Vec<&'a Expr>) {
+    if expr.value_type() != ValueType::Scalar {
+        return;
+    }
+    match expr {
+        Expr::Call(call) if call.func.name == "scalar" => sources.push(expr),
+        Expr::Binary(ex) => {
+            collect_scalar_sources(&ex.lhs, sources);
+            collect_scalar_sources(&ex.rhs, sources);
+        }
+        Expr::Paren(ex) => collect_scalar_sources(&ex.expr, sources),
+        Expr::Unary(ex) => collect_scalar_sources(&ex.expr, sources),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    #[test]
+    fn test_scalar_conversions() {
+        let cases = [
+            ("scalar(foo)", vec!["scalar(foo)"]),
+            ("-(scalar(foo) + time())", vec!["scalar(foo)"]),
+            (
+                "scalar(foo) / scalar(bar) > bool 1",
+                vec!["scalar(foo)", "scalar(bar)"],
+            ),
+            ("time() - 1", vec![]),
+            ("scalar(foo) * bar", vec![]),
+            ("vector(scalar(foo))", vec![]),
+            ("foo offset 5m", vec![]),
+            (
+                "scalar(vector(scalar(foo)))",
+                vec!["scalar(vector(scalar(foo)))"],
+            ),
+        ];
+        for (input, expected) in cases {
+            let expr = parse(input).unwrap();
+            let found: Vec<String> = scalar_conversions(&expr)
+                .iter()
+                .map(|c| c.node.to_string())
+                .collect();
+            assert_eq!(found, expected, "{input}");
+            assert_eq!(is_scalar_by_conversion(&expr), !expected.is_empty());
+        }
+
+        let expr = parse("scalar(foo) * 2 + scalar(vector(1))").unwrap();
+        let found: Vec<(usize, ConversionKind)> = conversions(&expr)
+            .iter()
+            .map(|c| (c.id.index(), c.kind))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (2, ConversionKind::ToScalar),
+                (5, ConversionKind::ToScalar),
+                (6, ConversionKind::ToVector)
+            ]
+        );
+    }
+}
@@ -0,0 +1,377 @@
+// Copyright 2023 Greptime Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! diagnostics of a query in one shape, whether they come from the parser,
+//! the checks of the AST or the lints.
+
+use crate::parser::{parse_with_spans_diagnostic, ParseOptions};
+use crate::util::lint::{lint_parsed, Lint, LintOptions};
+use std::fmt;
+use std::ops::Range;
+
+/// the codes of the parser errors, the lints use their own codes, see
+/// [`crate::util::lint::LintKind::code`].
+pub mod codes {
+    /// the query is not valid PromQL syntax.
+    pub const SYNTAX: &str = "syntax";
+    /// the query is valid syntax, but fails a check, like a type mismatch.
+    pub const CHECK: &str = "check";
+    /// a metric or label name is not valid in the name validation scheme.
+    pub const NAME: &str = "name";
+    /// a function is not enabled, like an experimental function.
+    pub const FUNCTION: &str = "function";
+    /// parsing was aborted by a limit, like the timeout or the max tokens.
+    pub const LIMIT: &str = "limit";
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    /// the query can not be evaluated.
+    Error,
+    /// the query can be evaluated, but is likely not what the author meant.
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// Related is more information of a diagnostic, like a suggested fix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Related {
+    pub message: String,
+    pub span: Option<Range<usize>>,
+}
+
+/// Diagnostic is an error or a finding in a query. `span` is the byte range in
+/// the query, if it is known.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub code: String,
+    pub severity: Severity,
+    pub message: String,
+    pub span: Option<Range<usize>>,
+    pub related: Vec<Related>,
+}
+
+impl Diagnostic {
+    pub fn new(code: impl Into<String>, severity: Severity, message: impl Into<String>) -> Self {
+        Self {
+            code: code.into(),
+            severity,
+            message: message.into(),
+            span: None,
+            related: vec![],
+        }
+    }
+
+    pub fn error(code: impl Into<String>, message: impl Into<String>) -> Self {
+        Self::new(code, Severity::Error, message)
+    }
+
+    pub fn warning(code: impl Into<String>, message: impl Into<String>) -> Self {
+        Self::new(code, Severity::Warning, message)
+    }
+
+    pub fn with_span(mut self, span: Option<Range<usize>>) -> Self {
+        self.span = span;
+        self
+    }
+
+    pub fn with_related(mut self, message: impl Into<String>, span: Option<Range<usize>>) -> Self {
+        self.related.push(Related {
+            message: message.into(),
+            span,
+        });
+        self
+    }
+}
+
+/// like `error[syntax] at 3..4: unclosed left parenthesis at position 3`.
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}[{}]", self.severity, self.code)?;
+        if let Some(span) = &self.span {
+            write!(f, " at {}..{}", span.start, span.end)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+/// a lint is a warning, its fix is a related message. The lint has no span,
+/// [`diagnose`] adds the span of its node in the query.
+impl From<Lint> for Diagnostic {
+    fn from(lint: Lint) -> Self {
+        let diagnostic = Diagnostic::warning(lint.kind.code(), lint.message);
+        match lint.fix {
+            Some(fix) => diagnostic.with_related(format!("replace {} with {fix}", lint.node), None),
+            None => diagnostic,
+        }
+    }
+}
+
+/// Diagnostics is a collection of diagnostics, in the order they are found.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Diagnostics(Vec<Diagnostic>);
+
+impl Diagnostics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, diagnostic: Diagnostic) {
+        self.0.push(diagnostic);
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Diagnostic> {
+        self.0.iter()
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.0.iter().any(|d| d.severity == Severity::Error)
+    }
+
+    pub fn errors(&self) -> impl Iterator<Item = &Diagnostic> {
+        self.0.iter().filter(|d| d.severity == Severity::Error)
+    }
+
+    pub fn warnings(&self) -> impl Iterator<Item = &Diagnostic> {
+        self.0.iter().filter(|d| d.severity == Severity::Warning)
+    }
+
+    /// sort by severity, and then by position, the diagnostics without a span last.
+    pub fn sort(&mut self) {
+        self.0.sort_by_key(|d| {
+            let span = d.span.as_ref().map(|s| (s.start, s.end));
+            (d.severity, span.is_none(), span)
+        });
+    }
+
+    pub fn into_vec(self) -> Vec<Diagnostic> {
+        self.0
+    }
+}
+
+impl Extend<Diagnostic> for Diagnostics {
+    fn extend<I: IntoIterator<Item = Diagnostic>>(&mut self, iter: I) {
+        self.0.extend(iter);
+    }
+}
+
+impl FromIterator<Diagnostic> for Diagnostics {
+    fn from_iter<I: IntoIterator<Item = Diagnostic>>(iter: I) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
+impl IntoIterator for Diagnostics {
+    type Item = Diagnostic;
+    type IntoIter = std::vec::IntoIter<Diagnostic>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a Diagnostics {
+    type Item = &'a Diagnostic;
+    type IntoIter = std::slice::Iter<'a, Diagnostic>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
+/// one diagnostic per line.
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, diagnostic) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{diagnostic}")?;
+        }
+        Ok(())
+    }
+}
+
+/// parse, check and lint the query, collecting all the findings. The lints
+/// are only run if the query parses, their spans are the ones of their nodes.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::ParseOptions;
+/// use promql_parser::util::diagnostic::{diagnose, Severity};
+/// use promql_parser::util::lint::LintOptions;
+///
+/// let diagnostics = diagnose("sum(rate(foo[15s]", &ParseOptions::new(), &LintOptions::new());
+/// assert!(diagnostics.has_errors());
+/// assert_eq!(
+///     diagnostics.to_string(),
+///     "error[syntax] at 8..9: unclosed left parenthesis at position 8"
+/// );
+///
+/// let diagnostics = diagnose("sum(rate(foo[15s]))", &ParseOptions::new(), &LintOptions::new());
+/// assert!(!diagnostics.has_errors());
+/// assert_eq!(diagnostics.warnings().count(), 1);
+/// assert_eq!(diagnostics.iter().next().unwrap().code, "short-range");
+/// assert_eq!(diagnostics.iter().next().unwrap().span, Some(9..17));
+/// ```
+pub fn diagnose(input: &str, options: &ParseOptions, lint_options: &LintOptions) -> Diagnostics {
+    match parse_with_spans_diagnostic(input, options) {
+        Err(diagnostic) => Diagnostics(vec![diagnostic]),
+        Ok((expr, spans)) => match lint_parsed(input, &expr, lint_options) {
+            Ok(lints) => lints
+                .into_iter()
+                .map(|lint| {
+                    let span = spans.get(lint.id);
+                    Diagnostic::from(lint).with_span(span)
+                })
+                .collect(),
+            Err(e) => Diagnostics(vec![Diagnostic::error(codes::SYNTAX, e)]),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::label::NameValidationScheme;
+    use crate::parser::parse_with_diagnostic;
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse_error_codes() {
+        let code = |query: &str, options: &ParseOptions| {
+            let err = parse_with_diagnostic(query, options).unwrap_err();
+            (err.code, err.span)
+        };
+        let options = ParseOptions::new();
+        assert_eq!(code("foo{", &options), (codes::SYNTAX.into(), Some(3..4)));
+        assert_eq!(
+            code("foo bar", &options),
+            (codes::SYNTAX.into(), Some(4..7))
+        );
+        assert_eq!(
+            code(r#"foo{a=~"(b"}"#, &options),
+            (codes::CHECK.into(), Some(8..9))
+        );
+        assert_eq!(code("1 > 2", &options), (codes::CHECK.into(), Some(0..5)));
+        assert_eq!(
+            code(r#"sum by ("a.b") (foo)"#, &options),
+            (codes::NAME.into(), None)
+        );
+        assert_eq!(
+            code("mad_over_time(foo[5m])", &options),
+            (codes::FUNCTION.into(), None)
+        );
+        // the spans of the durations and of the characters the lexer rejects
+        let cases = [
+            ("foo[0s]", codes::CHECK, 4..6),
+            ("foo[5m:0s]", codes::CHECK, 7..9),
+            ("sum(foo[5m:0ms] offset 1m)", codes::CHECK, 11..14),
+            ("foo offset 0s", codes::CHECK, 11..13),
+            ("rate(foo[293y])", codes::CHECK, 9..13),
+            ("foo[5m:-1m]", codes::SYNTAX, 7..8),
+            ("foo[-5m]", codes::SYNTAX, 4..5),
+            ("foo $", codes::SYNTAX, 4..5),
+            ("(foo", codes::SYNTAX, 0..1),
+            ("foo[5m", codes::SYNTAX, 3..4),
+            (
+                r#"label_replace(foo, "a", "$1", "b", "(.*")"#,
+                codes::CHECK,
+                36..37,
+            ),
+        ];
+        for (query, expected, span) in cases {
+            assert_eq!(
+                code(query, &options),
+                (expected.into(), Some(span)),
+                "{query}"
+            );
+        }
+        let limited = ParseOptions::new().with_max_tokens(2);
+        assert_eq!(code("foo + bar", &limited), (codes::LIMIT.into(), None));
+        let utf8 = options.with_name_validation_scheme(NameValidationScheme::Utf8);
+        assert!(parse_with_diagnostic(r#"sum by ("a.b") (foo)"#, &utf8).is_ok());
+    }
+
+    #[test]
+    fn test_parse_error_fix() {
+        let options = ParseOptions::new();
+        let err = parse_with_diagnostic("foo + scalar(bar) * (1 > 2)", &options).unwrap_err();
+        assert_eq!(
+            err.message,
+            "comparisons between scalars must use BOOL modifier"
+        );
+        assert_eq!(err.span, Some(21..26));
+        assert_eq!(
+            err.related,
+            vec![Related {
+                message: "replace 1 > 2 with 1 > bool 2".into(),
+                span: Some(21..26),
+            }]
+        );
+
+        let err = parse_with_diagnostic("rate(foo)", &options).unwrap_err();
+        assert!(err.related.is_empty());
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let options = LintOptions::new().with_scrape_interval(Duration::from_secs(30));
+        let mut diagnostics = diagnose(
+            "sum(foo_total) + rate(bar[1m])",
+            &ParseOptions::new(),
+            &options,
+        );
+        assert!(!diagnostics.has_errors());
+        assert_eq!(diagnostics.warnings().count(), 2);
+        assert_eq!(
+            diagnostics.to_string(),
+            "warning[raw-counter] at 4..13: counter foo_total is aggregated directly, use rate() or increase() first\n\
+             warning[short-range] at 22..29: range 1m of rate is shorter than 2m, which may not contain enough samples"
+        );
+        assert_eq!(
+            diagnostics.iter().next().unwrap().related[0].message,
+            "replace foo_total with rate(foo_total[2m])"
+        );
+        // the lints have the spans of their nodes
+        let spans: Vec<_> = diagnostics.iter().map(|d| d.span.clone()).collect();
+        assert_eq!(spans, vec![Some(4..13), Some(22..29)]);
+
+        diagnostics.push(Diagnostic::error("custom", "b").with_span(Some(5..6)));
+        diagnostics.push(Diagnostic::error("custom", "a").with_span(Some(1..2)));
+        diagnostics.sort();
+        let messages: Vec<&str> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(messages[..2], ["a", "b"]);
+        assert_eq!(diagnostics.errors().count(), 2);
+        assert_eq!(diagnostics.len(), 4);
+        assert_eq!(diagnostics.into_iter().last().unwrap().code, "short-range");
+    }
+}
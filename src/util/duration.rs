@@ -78,28 +78,63 @@ pub fn parse_duration(ds: &str) -> Result<Duration, String> {
         return Err(format!("not a valid duration string: {ds}"));
     }
 
+    // like Prometheus, the total must fit in an i64 of nanoseconds, which is
+    // about 292 years, durations are whole milliseconds.
+    let max_millis = (i64::MAX / 1_000_000) as u128;
     let caps = DURATION_RE.captures(ds).unwrap();
-    let dur = ALL_CAPS
-        .into_iter()
-        // map captured string to Option<Duration> iterator
-        // FIXME: None is ignored in closure. It is better to tell users which part is wrong.
-        .map(|(title, duration)| {
-            caps.name(title)
-                .and_then(|cap| cap.as_str().parse::<u32>().ok())
-                .and_then(|v| duration.checked_mul(v))
-        })
-        .fold(Ok(Duration::ZERO), |acc, x| {
-            acc.and_then(|d| {
-                d.checked_add(x.unwrap_or(Duration::ZERO))
-                    .ok_or_else(|| "duration overflowed".into())
-            })
-        });
-
-    if matches!(dur, Ok(d) if d == Duration::ZERO) {
-        Err("duration must be greater than 0".into())
-    } else {
-        dur
+    let mut millis: u128 = 0;
+    for (title, unit) in ALL_CAPS {
+        let Some(cap) = caps.name(title) else {
+            continue;
+        };
+        let v: u64 = cap
+            .as_str()
+            .parse()
+            .map_err(|_| format!("not a valid duration string: {ds}"))?;
+        millis += v as u128 * unit.as_millis();
+        if millis > max_millis {
+            return Err("duration out of range".into());
+        }
+    }
+
+    if millis == 0 {
+        return Err("duration must be greater than 0".into());
+    }
+    Ok(Duration::from_millis(millis as u64))
+}
+
+/// displays a Duration the way Prometheus does, years and weeks are only
+/// used when the duration is a whole multiple of them.
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+/// use promql_parser::util;
+///
+/// assert_eq!(util::display_duration(&Duration::from_secs(5400)), "1h30m");
+/// assert_eq!(util::display_duration(&Duration::from_secs(3600 * 24 * 14)), "2w");
+/// assert_eq!(util::display_duration(&Duration::from_secs(3600 * 24 * 15)), "15d");
+/// ```
+pub fn display_duration(d: &Duration) -> String {
+    let mut ms = d.as_millis();
+    if ms == 0 {
+        return "0s".into();
+    }
+
+    let mut s = String::new();
+    for (title, duration) in ALL_CAPS {
+        let unit = duration.as_millis();
+        if matches!(title, "y" | "w") && !ms.is_multiple_of(unit) {
+            continue;
+        }
+        let v = ms / unit;
+        if v > 0 {
+            s.push_str(&format!("{v}{title}"));
+            ms -= v * unit;
+        }
     }
+    s
 }
 
 #[cfg(test)]
@@ -145,21 +180,39 @@ mod tests {
         }
     }
 
-    // valid here but invalid in PromQL Go Version
+    // the same bounds as in PromQL Go Version, an i64 of nanoseconds
     #[test]
-    fn test_diff_with_promql() {
+    fn test_duration_bounds() {
+        assert_eq!(parse_duration("292y"), Ok(YEAR_DURATION * 292));
+        let max = "106751d23h47m16s854ms";
+        assert_eq!(
+            parse_duration(max).unwrap().as_nanos(),
+            (i64::MAX / 1_000_000 * 1_000_000) as u128
+        );
+
         let ds = vec![
-            ("294y", YEAR_DURATION * 294),
-            ("200y10400w", YEAR_DURATION * 200 + WEEK_DURATION * 10400),
-            ("107675d", DAY_DURATION * 107675),
-            ("2584200h", HOUR_DURATION * 2584200),
+            "106751d23h47m16s855ms",
+            "293y",
+            "294y",
+            "200y10400w",
+            "107675d",
+            "2584200h",
+            "9999999999999999999y",
+            "18446744073709551615ms",
         ];
-
-        for (s, expect) in ds {
-            let d = parse_duration(s);
-            assert!(d.is_ok());
-            assert_eq!(expect, d.unwrap(), "{} and {:?} not matched", s, expect);
+        for d in ds {
+            assert_eq!(
+                parse_duration(d),
+                Err("duration out of range".into()),
+                "{d}"
+            );
         }
+
+        // the number of a unit does not fit in u64
+        assert_eq!(
+            parse_duration("1y99999999999999999999d"),
+            Err("not a valid duration string: 1y99999999999999999999d".into())
+        );
     }
 
     #[test]
@@ -169,4 +222,25 @@ mod tests {
             assert!(parse_duration(d).is_err(), "{} is invalid duration!", d);
         }
     }
+
+    #[test]
+    fn test_display_duration() {
+        let ds = vec![
+            (Duration::ZERO, "0s"),
+            (MILLI_DURATION * 500, "500ms"),
+            (SECOND_DURATION * 90, "1m30s"),
+            (HOUR_DURATION * 49, "2d1h"),
+            (WEEK_DURATION * 3, "3w"),
+            (WEEK_DURATION * 3 + HOUR_DURATION, "21d1h"),
+            (YEAR_DURATION * 10, "10y"),
+            (DAY_DURATION + MILLI_DURATION, "1d1ms"),
+        ];
+
+        for (d, expect) in ds {
+            assert_eq!(display_duration(&d), expect);
+            if d != Duration::ZERO {
+                assert_eq!(parse_duration(expect).unwrap(), d);
+            }
+        }
+    }
 }
@@ -0,0 +1,399 @@
+// Copyright 2023 Greptime Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! escaping of UTF-8 metric and label names, mapping them onto the legacy
+//! charset `[a-zA-Z_:][a-zA-Z0-9_:]*`, the same as the escaping schemes of Prometheus.
+
+use crate::label::{Labels, MatchOp, METRIC_NAME};
+use crate::parser::function::is_label_arg;
+use crate::parser::token::T_COUNT_VALUES;
+use crate::parser::{Expr, LabelModifier, VectorMatchCardinality, VectorSelector};
+
+/// the prefix of the names escaped by [`EscapingScheme::Values`].
+const VALUES_PREFIX: &str = "U__";
+
+/// EscapingScheme decides how the characters outside of the legacy charset are replaced.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EscapingScheme {
+    /// each invalid character is replaced by an underscore, which can not be unescaped.
+    Underscores,
+    /// dots are replaced by `_dot_`, underscores by `__` and other invalid
+    /// characters by `__`. Only the dots and underscores can be unescaped.
+    Dots,
+    /// the name is prefixed with `U__`, underscores are replaced by `__` and
+    /// other invalid characters by their code point in hex, like `_2e_` for a dot.
+    /// The escaping can be reversed.
+    #[default]
+    Values,
+}
+
+fn is_legacy_char(ch: char, i: usize) -> bool {
+    ch.is_ascii_alphabetic() || ch == '_' || ch == ':' || (ch.is_ascii_digit() && i > 0)
+}
+
+fn is_legacy_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .enumerate()
+            .all(|(i, ch)| is_legacy_char(ch, i))
+}
+
+/// escape the name. Valid legacy names are returned as is, except by
+/// [`EscapingScheme::Dots`], which doubles their underscores as Prometheus does.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::util::escape::{escape_name, EscapingScheme};
+///
+/// assert_eq!(escape_name("http.status", EscapingScheme::Values), "U__http_2e_status");
+/// assert_eq!(escape_name("http.status", EscapingScheme::Dots), "http_dot_status");
+/// assert_eq!(escape_name("http.status", EscapingScheme::Underscores), "http_status");
+/// assert_eq!(escape_name("http_status", EscapingScheme::Values), "http_status");
+/// assert_eq!(escape_name("http_status", EscapingScheme::Dots), "http__status");
+/// ```
+pub fn escape_name(name: &str, scheme: EscapingScheme) -> String {
+    if name.is_empty() {
+        return String::new();
+    }
+    let mut escaped = String::with_capacity(name.len());
+    match scheme {
+        EscapingScheme::Underscores => {
+            for (i, ch) in name.chars().enumerate() {
+                escaped.push(if is_legacy_char(ch, i) { ch } else { '_' });
+            }
+        }
+        EscapingScheme::Dots => {
+            for (i, ch) in name.chars().enumerate() {
+                match ch {
+                    '_' => escaped.push_str("__"),
+                    '.' => escaped.push_str("_dot_"),
+                    ch if is_legacy_char(ch, i) => escaped.push(ch),
+                    _ => escaped.push_str("__"),
+                }
+            }
+        }
+        EscapingScheme::Values => {
+            if is_legacy_name(name) {
+                return name.to_string();
+            }
+            escaped.push_str(VALUES_PREFIX);
+            for (i, ch) in name.chars().enumerate() {
+                match ch {
+                    '_' => escaped.push_str("__"),
+                    ch if is_legacy_char(ch, i) => escaped.push(ch),
+                    ch => escaped.push_str(&format!("_{:x}_", ch as u32)),
+                }
+            }
+        }
+    }
+    escaped
+}
+
+/// reverse [`escape_name`] as far as the scheme allows. Names which are not
+/// validly escaped are returned as is.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::util::escape::{unescape_name, EscapingScheme};
+///
+/// assert_eq!(unescape_name("U__http_2e_status", EscapingScheme::Values), "http.status");
+/// assert_eq!(unescape_name("http_dot_status", EscapingScheme::Dots), "http.status");
+/// assert_eq!(unescape_name("U__bad_zz_", EscapingScheme::Values), "U__bad_zz_");
+/// ```
+pub fn unescape_name(name: &str, scheme: EscapingScheme) -> String {
+    match scheme {
+        EscapingScheme::Underscores => name.to_string(),
+        EscapingScheme::Dots => unescape_dots(name),
+        EscapingScheme::Values => unescape_values(name).unwrap_or_else(|| name.to_string()),
+    }
+}
+
+fn unescape_dots(name: &str) -> String {
+    let mut unescaped = String::with_capacity(name.len());
+    let mut rest = name;
+    while let Some(ch) = rest.chars().next() {
+        if let Some(tail) = rest.strip_prefix("_dot_") {
+            unescaped.push('.');
+            rest = tail;
+        } else if let Some(tail) = rest.strip_prefix("__") {
+            unescaped.push('_');
+            rest = tail;
+        } else {
+            unescaped.push(ch);
+            rest = &rest[ch.len_utf8()..];
+        }
+    }
+    unescaped
+}
+
+/// None if the name is not prefixed, or has an invalid escape sequence.
+fn unescape_values(name: &str) -> Option<String> {
+    let escaped = name.strip_prefix(VALUES_PREFIX)?;
+    let mut unescaped = String::with_capacity(escaped.len());
+    let mut chars = escaped.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '_' {
+            unescaped.push(ch);
+            continue;
+        }
+        match chars.next()? {
+            '_' => unescaped.push('_'),
+            first => {
+                let mut code = first.to_digit(16)?;
+                let mut digits = 1;
+                // at most 6 hex digits, as the max code point is 10FFFF,
+                // followed by the closing underscore
+                loop {
+                    match chars.next()? {
+                        '_' => break,
+                        _ if digits == 6 => return None,
+                        ch => code = code * 16 + ch.to_digit(16)?,
+                    }
+                    digits += 1;
+                }
+                unescaped.push(char::from_u32(code)?);
+            }
+        }
+    }
+    Some(unescaped)
+}
+
+/// escape all the metric and label names of the expr, in the selectors,
+/// grouping clauses, `count_values` and the label arguments of `label_replace`,
+/// `label_join` and `sort_by_label`.
+///
+/// the regex values of `__name__` matchers are left untouched, as the names
+/// they match can not be known.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::parse;
+/// use promql_parser::util::escape::{escape_names, EscapingScheme};
+///
+/// let expr = parse(r#"sum by ("service.name") (rate({__name__="http.requests"}[5m]))"#).unwrap();
+/// assert_eq!(
+///     escape_names(&expr, EscapingScheme::Values).to_string(),
+///     r#"sum by (U__service_2e_name) (rate({__name__="U__http_2e_requests"}[5m]))"#
+/// );
+/// ```
+pub fn escape_names(expr: &Expr, scheme: EscapingScheme) -> Expr {
+    let mut expr = expr.clone();
+    let f = |name: &str| escape_name(name, scheme);
+    map_names(&mut expr, &f, &f);
+    expr
+}
+
+/// reverse [`escape_names`], the names are unescaped as far as the scheme allows.
+pub fn unescape_names(expr: &Expr, scheme: EscapingScheme) -> Expr {
+    let mut expr = expr.clone();
+    let f = |name: &str| unescape_name(name, scheme);
+    map_names(&mut expr, &f, &f);
+    expr
+}
+
+/// map the metric and the label names of the expr and all its descendants,
+/// see [`escape_names`] for where they are found.
+pub(crate) fn map_names(
+    expr: &mut Expr,
+    metric: &impl Fn(&str) -> String,
+    label: &impl Fn(&str) -> String,
+) {
+    match expr {
+        Expr::VectorSelector(vs) => map_selector(vs, metric, label),
+        Expr::MatrixSelector(ms) => map_selector(&mut ms.vector_selector, metric, label),
+        Expr::Aggregate(agg) => {
+            if let Some(modifier) = &mut agg.modifier {
+                map_modifier(modifier, label);
+            }
+            if let Some(Expr::StringLiteral(param)) = agg.param.as_deref_mut() {
+                if agg.op.id() == T_COUNT_VALUES {
+                    param.val = label(&param.val);
+                }
+            }
+        }
+        Expr::Binary(ex) => {
+            if let Some(modifier) = &mut ex.modifier {
+                if let Some(matching) = &mut modifier.matching {
+                    map_modifier(matching, label);
+                }
+                match &mut modifier.card {
+                    VectorMatchCardinality::ManyToOne(labels)
+                    | VectorMatchCardinality::OneToMany(labels) => map_labels(labels, label),
+                    _ => {}
+                }
+            }
+        }
+        Expr::Call(call) => {
+            let name = call.func.name;
+            for (i, arg) in call.args.args.iter_mut().enumerate() {
+                if let Expr::StringLiteral(arg) = &mut **arg {
+                    if is_label_arg(name, i) {
+                        arg.val = label(&arg.val);
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+    for child in expr.children_mut() {
+        map_names(child, metric, label);
+    }
+}
+
+fn map_selector(
+    vs: &mut VectorSelector,
+    metric: &impl Fn(&str) -> String,
+    label: &impl Fn(&str) -> String,
+) {
+    if let Some(name) = &mut vs.name {
+        *name = metric(name);
+    }
+    vs.matchers.matchers = vs
+        .matchers
+        .matchers
+        .drain()
+        .map(|mut m| {
+            if m.name == METRIC_NAME {
+                if matches!(m.op, MatchOp::Equal | MatchOp::NotEqual) {
+                    m.value = metric(&m.value);
+                }
+            } else {
+                m.name = label(&m.name);
+            }
+            m
+        })
+        .collect();
+}
+
+fn map_modifier(modifier: &mut LabelModifier, f: &impl Fn(&str) -> String) {
+    match modifier {
+        LabelModifier::Include(labels) | LabelModifier::Exclude(labels) => map_labels(labels, f),
+    }
+}
+
+fn map_labels(labels: &mut Labels, f: &impl Fn(&str) -> String) {
+    *labels = labels.drain().map(|l| f(&l)).collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::label::{Matcher, Matchers};
+    use crate::parser::parse;
+
+    #[test]
+    fn test_escape_name() {
+        let cases = vec![
+            // name, values, dots, underscores
+            ("", "", "", ""),
+            ("foo", "foo", "foo", "foo"),
+            ("foo_bar:baz", "foo_bar:baz", "foo__bar:baz", "foo_bar:baz"),
+            ("foo.bar", "U__foo_2e_bar", "foo_dot_bar", "foo_bar"),
+            (
+                "foo_bar.baz",
+                "U__foo__bar_2e_baz",
+                "foo__bar_dot_baz",
+                "foo_bar_baz",
+            ),
+            ("0foo", "U___30_foo", "__foo", "_foo"),
+            ("http/ü", "U__http_2f__fc_", "http____", "http__"),
+            ("🔥", "U___1f525_", "__", "_"),
+            ("\u{10ffff}", "U___10ffff_", "__", "_"),
+        ];
+        for (name, values, dots, underscores) in cases {
+            assert_eq!(escape_name(name, EscapingScheme::Values), values, "{name}");
+            assert_eq!(escape_name(name, EscapingScheme::Dots), dots, "{name}");
+            assert_eq!(
+                escape_name(name, EscapingScheme::Underscores),
+                underscores,
+                "{name}"
+            );
+            assert_eq!(unescape_name(values, EscapingScheme::Values), name);
+        }
+
+        assert_eq!(
+            unescape_name("foo__bar_dot_baz", EscapingScheme::Dots),
+            "foo_bar.baz"
+        );
+        assert_eq!(
+            unescape_name("foo_bar", EscapingScheme::Underscores),
+            "foo_bar"
+        );
+
+        // invalid escapes are kept as is
+        for name in [
+            "foo_2e_bar",
+            "U__foo_",
+            "U__foo_2e",
+            "U__foo_xy_",
+            "U__foo_1234567_",
+            "U__a_0000041",
+            "U__a_41",
+            "U__foo_d800_",
+        ] {
+            assert_eq!(unescape_name(name, EscapingScheme::Values), name);
+        }
+    }
+
+    #[test]
+    fn test_escape_names() {
+        let cases = vec![
+            (
+                r#"{__name__="foo.bar", a="c"} + {__name__!="x.y", a="c"}"#,
+                r#"{__name__="U__foo_2e_bar", a="c"} + {__name__!="U__x_2e_y", a="c"}"#,
+            ),
+            (
+                r#"{__name__=~"foo.+"}[5m] offset 1m"#,
+                r#"{__name__=~"foo.+"}[5m] offset 1m"#,
+            ),
+            (
+                r#"count_values("a.b", foo) without ("c.d")"#,
+                r#"count_values without (U__c_2e_d) ("U__a_2e_b", foo)"#,
+            ),
+            (
+                r#"foo / on ("a.b") group_left ("c.d") bar"#,
+                "foo / on (U__a_2e_b) group_left (U__c_2e_d) bar",
+            ),
+            (
+                r#"label_replace(foo, "dst.x", "$1", "src.x", "a.(.*)")"#,
+                r#"label_replace(foo, "U__dst_2e_x", "$1", "U__src_2e_x", "a.(.*)")"#,
+            ),
+            (
+                r#"label_join(foo, "dst.x", ".", "a.b", "c")"#,
+                r#"label_join(foo, "U__dst_2e_x", ".", "U__a_2e_b", "c")"#,
+            ),
+        ];
+        for (query, escaped) in cases {
+            let expr = parse(query).unwrap();
+            let actual = escape_names(&expr, EscapingScheme::Values);
+            assert_eq!(actual, parse(escaped).unwrap(), "{query}");
+            assert_eq!(
+                unescape_names(&actual, EscapingScheme::Values),
+                expr,
+                "{query}"
+            );
+        }
+
+        let matchers = Matchers::one(Matcher::new(MatchOp::Equal, "a.b".into(), "c".into()));
+        let expr = Expr::new_vector_selector(Some("foo.bar".into()), matchers).unwrap();
+        let matchers = Matchers::one(Matcher::new(MatchOp::Equal, "U__a_2e_b".into(), "c".into()));
+        let escaped = Expr::new_vector_selector(Some("U__foo_2e_bar".into()), matchers).unwrap();
+        assert_eq!(escape_names(&expr, EscapingScheme::Values), escaped);
+        assert_eq!(unescape_names(&escaped, EscapingScheme::Values), expr);
+    }
+}
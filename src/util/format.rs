@@ -0,0 +1,655 @@
+// Copyright 2023 Greptime Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! pretty-printer of the exprs, breaking long queries into multiple lines.
+
+use crate::label::Labels;
+use crate::parser::ast::{needs_parens, subquery_needs_parens, unary_needs_parens};
+use crate::parser::token::{
+    T_COMMA, T_EQL, T_EQL_REGEX, T_LEFT_BRACE, T_NEQ, T_NEQ_REGEX, T_RIGHT_BRACE, T_STRING,
+};
+use crate::parser::{
+    lex, parse, BinModifier, BinaryExpr, Expr, LabelModifier, ParenExpr, SubqueryExpr,
+    VectorMatchCardinality,
+};
+use crate::util::display_duration;
+use lrpar::{Lexeme, Lexer, NonStreamingLexer};
+use std::borrow::Cow;
+
+/// where the operator of a binary expr goes when the expr is broken into lines.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OperatorPosition {
+    /// the operator is on its own line between the operands, as Prometheus does.
+    #[default]
+    Separate,
+    /// the operator starts the line of the right operand.
+    Leading,
+    /// the operator ends the line of the left operand.
+    Trailing,
+}
+
+/// FormatConfig controls the style of [`prettify`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FormatConfig {
+    /// exprs longer than this are broken into multiple lines.
+    pub max_width: usize,
+    pub indent: usize,
+    pub operator_position: OperatorPosition,
+    /// sort the labels of `by`, `without`, `on`, `ignoring` and `group_x`.
+    /// Labels are kept in a set, so unsorted labels are written in an unspecified order.
+    pub sort_labels: bool,
+}
+
+impl Default for FormatConfig {
+    fn default() -> Self {
+        Self {
+            max_width: 100,
+            indent: 2,
+            operator_position: OperatorPosition::default(),
+            sort_labels: true,
+        }
+    }
+}
+
+impl FormatConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_max_width(mut self, max_width: usize) -> Self {
+        self.max_width = max_width;
+        self
+    }
+
+    pub fn with_indent(mut self, indent: usize) -> Self {
+        self.indent = indent;
+        self
+    }
+
+    pub fn with_operator_position(mut self, operator_position: OperatorPosition) -> Self {
+        self.operator_position = operator_position;
+        self
+    }
+
+    pub fn with_sort_labels(mut self, sort_labels: bool) -> Self {
+        self.sort_labels = sort_labels;
+        self
+    }
+}
+
+/// format the expr, which is kept in one line if it fits into `max_width`,
+/// otherwise aggregations, calls and parens are broken with their
+/// contents indented, and binary exprs are broken around the operator.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::parse;
+/// use promql_parser::util::format::{prettify, FormatConfig};
+///
+/// let expr = parse("sum by (job) (rate(foo[5m])) / sum by (job) (rate(bar[5m]))").unwrap();
+/// let config = FormatConfig::new().with_max_width(40);
+/// assert_eq!(
+///     prettify(&expr, &config),
+///     "sum by (job) (rate(foo[5m]))
+/// /
+/// sum by (job) (rate(bar[5m]))"
+/// );
+/// ```
+pub fn prettify(expr: &Expr, config: &FormatConfig) -> String {
+    Printer { config }.pretty(expr, 0)
+}
+
+/// parse and format the query. The formatted query is parsed again and must
+/// be the same expr, so formatting never changes the semantics of a query.
+///
+/// Formatting is idempotent, formatting an already formatted query gives the
+/// same query, as long as `sort_labels` is set. Queries with comments are
+/// rejected, since the comments are not kept in the expr.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::util::format::{format_query, FormatConfig};
+///
+/// let config = FormatConfig::new();
+/// assert_eq!(format_query("sum(foo)by(job)", &config).unwrap(), "sum by (job) (foo)");
+/// assert_eq!(
+///     format_query("sum(foo) # total", &config).unwrap_err(),
+///     "the query has comments, which are dropped by formatting"
+/// );
+/// ```
+pub fn format_query(input: &str, config: &FormatConfig) -> Result<String, String> {
+    let expr = parse(input)?;
+    if has_comments(input)? {
+        return Err("the query has comments, which are dropped by formatting".into());
+    }
+    let formatted = prettify(&expr, config);
+    match parse(&formatted) {
+        Ok(ex) if ex == expr => Ok(formatted),
+        Ok(_) => Err(format!(
+            "formatted query {formatted} differs from the original query"
+        )),
+        Err(e) => Err(format!("formatted query {formatted} is invalid: {e}")),
+    }
+}
+
+/// whether there are comments between the tokens of the query, the rest of
+/// the text between the tokens is whitespace.
+fn has_comments(input: &str) -> Result<bool, String> {
+    let lexer = lex::lexer(input)?;
+    let mut end = 0;
+    for lexeme in lexer.iter().flatten() {
+        if input[end..lexeme.span().start()].contains('#') {
+            return Ok(true);
+        }
+        end = lexeme.span().end();
+    }
+    Ok(input[end..].contains('#'))
+}
+
+/// check if the query is already formatted, which is useful for a `--check`
+/// mode of formatters. Invalid queries are never formatted.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::util::format::{check_formatted, FormatConfig};
+///
+/// let config = FormatConfig::new();
+/// assert!(check_formatted("sum by (job) (rate(foo[5m]))", &config));
+/// assert!(!check_formatted("sum(rate(foo[5m]))by(job)", &config));
+/// assert!(!check_formatted("sum(", &config));
+/// ```
+pub fn check_formatted(input: &str, config: &FormatConfig) -> bool {
+    format_query(input, config).is_ok_and(|formatted| formatted == input)
+}
+
+/// the steps of [`truncate`], the longest kept string value and the most matchers
+/// kept in a selector.
+const TRUNCATE_STEPS: [(usize, usize); 4] = [(32, 8), (16, 4), (8, 2), (4, 0)];
+
+/// render the expr in at most `max_len` chars, for logging. Long string values
+/// and matcher lists are elided with `…` until it fits, so the structure of
+/// the query is still recognizable, and only then the end is cut off.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::parse;
+/// use promql_parser::util::format::truncate;
+///
+/// let expr = parse(r#"sum(rate(foo{a="1", b="2", path=~"/api/v1/(query|query_range|series)"}[5m]))"#)
+///     .unwrap();
+/// assert_eq!(
+///     truncate(&expr, 60),
+///     r#"sum(rate(foo{a="1",b="2",path=~"/api/v1/(query|q…"}[5m]))"#
+/// );
+/// assert_eq!(truncate(&expr, 30), "sum(rate(foo{…}[5m]))");
+/// assert_eq!(truncate(&expr, 10), "sum(rate(…");
+/// ```
+pub fn truncate(expr: &Expr, max_len: usize) -> String {
+    let full = expr.to_string();
+    let mut text = full.clone();
+    for (max_value, max_matchers) in TRUNCATE_STEPS {
+        if text.chars().count() <= max_len {
+            return text;
+        }
+        text = elide(&full, max_value, max_matchers);
+    }
+    if text.chars().count() <= max_len {
+        return text;
+    }
+    match max_len.checked_sub(1) {
+        Some(len) => {
+            let text: String = text.chars().take(len).collect();
+            format!("{}…", text.trim_end_matches('…'))
+        }
+        None => String::new(),
+    }
+}
+
+/// elide the string values longer than `max_value` chars, and the matchers of
+/// a selector after the first `max_matchers`.
+fn elide(text: &str, max_value: usize, max_matchers: usize) -> String {
+    let Ok(lexer) = lex::lexer(text) else {
+        return text.to_string();
+    };
+    let mut elided = String::with_capacity(text.len());
+    let mut end = 0;
+    // the matchers seen in the current braces, None outside of braces
+    let mut matchers: Option<usize> = None;
+    let mut skipping = false;
+    for lexeme in lexer.iter().flatten() {
+        let span = lexeme.span();
+        let token = lexeme.tok_id();
+        let gap = &text[end..span.start()];
+        end = span.end();
+        if skipping && token != T_RIGHT_BRACE {
+            continue;
+        }
+        if !skipping {
+            elided.push_str(gap);
+        }
+        match (token, matchers) {
+            (T_LEFT_BRACE, _) => matchers = Some(0),
+            (T_RIGHT_BRACE, _) => {
+                matchers = None;
+                skipping = false;
+            }
+            (T_COMMA, Some(count)) if count >= max_matchers => {
+                elided.push_str(",…");
+                skipping = true;
+                continue;
+            }
+            (_, Some(0)) if max_matchers == 0 => {
+                elided.push('…');
+                skipping = true;
+                continue;
+            }
+            (T_EQL | T_NEQ | T_EQL_REGEX | T_NEQ_REGEX, Some(count)) => matchers = Some(count + 1),
+            (T_STRING, _) if lexer.span_str(span).chars().count() > max_value => {
+                let value: String = lexer.span_str(span).chars().take(max_value).collect();
+                elided.push_str(&value);
+                elided.push('…');
+                continue;
+            }
+            _ => {}
+        }
+        elided.push_str(lexer.span_str(span));
+    }
+    elided.push_str(&text[end..]);
+    elided
+}
+
+struct Printer<'a> {
+    config: &'a FormatConfig,
+}
+
+impl Printer<'_> {
+    fn pretty(&self, expr: &Expr, indent: usize) -> String {
+        let flat = self.flat(expr);
+        if indent + flat.chars().count() <= self.config.max_width {
+            return format!("{}{flat}", pad(indent));
+        }
+
+        let inner = indent + self.config.indent;
+        match expr {
+            Expr::Aggregate(ex) => {
+                let mut args = vec![];
+                if let Some(param) = &ex.param {
+                    args.push(param.as_ref());
+                }
+                args.push(&ex.expr);
+                let head = match &ex.modifier {
+                    Some(modifier) => format!("{} {} (", ex.op, self.label_modifier(modifier)),
+                    None => format!("{}(", ex.op),
+                };
+                self.block(indent, &head, &args, ")")
+            }
+            Expr::Unary(ex) => {
+                let operand = self.pretty(&unary_operand(&ex.expr), indent);
+                format!("{}-{}", pad(indent), &operand[indent..])
+            }
+            Expr::Binary(ex) => {
+                let lhs = self.pretty(&operand(ex, &ex.lhs, false), indent);
+                let rhs = self.pretty(&operand(ex, &ex.rhs, true), indent);
+                let op = format!("{}{}", ex.op, self.bin_modifier(&ex.modifier));
+                match self.config.operator_position {
+                    OperatorPosition::Separate => format!("{lhs}\n{}{op}\n{rhs}", pad(indent)),
+                    OperatorPosition::Leading => {
+                        format!("{lhs}\n{}{op} {}", pad(indent), &rhs[indent..])
+                    }
+                    OperatorPosition::Trailing => format!("{lhs} {op}\n{rhs}"),
+                }
+            }
+            Expr::Paren(ex) => format!(
+                "{}(\n{}\n{})",
+                pad(indent),
+                self.pretty(&ex.expr, inner),
+                pad(indent)
+            ),
+            Expr::Subquery(ex) => {
+                let operand = self.pretty(&subquery_operand(&ex.expr), indent);
+                format!("{operand}{}", subquery_suffix(ex))
+            }
+            Expr::Call(call) => {
+                let args: Vec<&Expr> = call.args.args.iter().map(|e| e.as_ref()).collect();
+                self.block(indent, &format!("{}(", call.func.name), &args, ")")
+            }
+            // literals, selectors and extensions can not be broken
+            _ => format!("{}{flat}", pad(indent)),
+        }
+    }
+
+    /// writes the args one per line between head and tail.
+    fn block(&self, indent: usize, head: &str, args: &[&Expr], tail: &str) -> String {
+        let inner = indent + self.config.indent;
+        let args: Vec<String> = args.iter().map(|e| self.pretty(e, inner)).collect();
+        format!(
+            "{}{head}\n{}\n{}{tail}",
+            pad(indent),
+            args.join(",\n"),
+            pad(indent)
+        )
+    }
+
+    /// same as the Display of the expr, except the labels follow the config.
+    fn flat(&self, expr: &Expr) -> String {
+        match expr {
+            Expr::Aggregate(ex) => {
+                let mut s = ex.op.to_string();
+                if let Some(modifier) = &ex.modifier {
+                    s.push_str(&format!(" {} ", self.label_modifier(modifier)));
+                }
+                s.push('(');
+                if let Some(param) = &ex.param {
+                    s.push_str(&format!("{}, ", self.flat(param)));
+                }
+                s.push_str(&format!("{})", self.flat(&ex.expr)));
+                s
+            }
+            Expr::Unary(ex) => format!("-{}", self.flat(&unary_operand(&ex.expr))),
+            Expr::Binary(ex) => format!(
+                "{} {}{} {}",
+                self.flat(&operand(ex, &ex.lhs, false)),
+                ex.op,
+                self.bin_modifier(&ex.modifier),
+                self.flat(&operand(ex, &ex.rhs, true))
+            ),
+            Expr::Paren(ex) => format!("({})", self.flat(&ex.expr)),
+            Expr::Subquery(ex) => format!(
+                "{}{}",
+                self.flat(&subquery_operand(&ex.expr)),
+                subquery_suffix(ex)
+            ),
+            Expr::Call(call) => {
+                let args: Vec<String> = call.args.args.iter().map(|e| self.flat(e)).collect();
+                format!("{}({})", call.func.name, args.join(", "))
+            }
+            _ => expr.to_string(),
+        }
+    }
+
+    fn label_modifier(&self, modifier: &LabelModifier) -> String {
+        match modifier {
+            LabelModifier::Include(labels) => format!("by ({})", self.labels(labels)),
+            LabelModifier::Exclude(labels) => format!("without ({})", self.labels(labels)),
+        }
+    }
+
+    fn bin_modifier(&self, modifier: &Option<BinModifier>) -> String {
+        let mut s = String::new();
+        let Some(modifier) = modifier else {
+            return s;
+        };
+        if modifier.return_bool {
+            s.push_str(" bool");
+        }
+        if let Some(matching) = &modifier.matching {
+            match matching {
+                LabelModifier::Include(labels) => {
+                    s.push_str(&format!(" on ({})", self.labels(labels)))
+                }
+                LabelModifier::Exclude(labels) => {
+                    s.push_str(&format!(" ignoring ({})", self.labels(labels)))
+                }
+            }
+            match &modifier.card {
+                VectorMatchCardinality::ManyToOne(labels) => {
+                    s.push_str(&format!(" group_left ({})", self.labels(labels)))
+                }
+                VectorMatchCardinality::OneToMany(labels) => {
+                    s.push_str(&format!(" group_right ({})", self.labels(labels)))
+                }
+                _ => {}
+            }
+        }
+        s
+    }
+
+    fn labels(&self, labels: &Labels) -> String {
+        let mut labels: Vec<&str> = labels.iter().map(String::as_str).collect();
+        if self.config.sort_labels {
+            labels.sort_unstable();
+        }
+        labels.join(", ")
+    }
+}
+
+/// the operand of the binary expr, in a paren if the tree lacks one its grouping needs.
+fn operand<'a>(ex: &BinaryExpr, operand: &'a Expr, is_rhs: bool) -> Cow<'a, Expr> {
+    match needs_parens(&ex.op, operand, is_rhs) {
+        true => Cow::Owned(paren(operand)),
+        false => Cow::Borrowed(operand),
+    }
+}
+
+fn unary_operand(operand: &Expr) -> Cow<'_, Expr> {
+    match unary_needs_parens(operand) {
+        true => Cow::Owned(paren(operand)),
+        false => Cow::Borrowed(operand),
+    }
+}
+
+fn subquery_operand(operand: &Expr) -> Cow<'_, Expr> {
+    match subquery_needs_parens(operand) {
+        true => Cow::Owned(paren(operand)),
+        false => Cow::Borrowed(operand),
+    }
+}
+
+fn paren(expr: &Expr) -> Expr {
+    Expr::Paren(ParenExpr {
+        expr: Box::new(expr.clone()),
+    })
+}
+
+fn subquery_suffix(ex: &SubqueryExpr) -> String {
+    let mut s = format!("[{}:{}]", display_duration(&ex.range), ex.step);
+    if let Some(at) = &ex.at {
+        s.push_str(&format!(" {at}"));
+    }
+    if let Some(offset) = &ex.offset {
+        s.push_str(&format!(" {offset}"));
+    }
+    s
+}
+
+fn pad(indent: usize) -> String {
+    " ".repeat(indent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const QUERY: &str = r#"sum by (code, job) (rate(http_requests_total{code=~"5.."}[5m])) / on (job) group_left () sum by (job) (rate(http_requests_total[5m])) > 0.1"#;
+
+    #[test]
+    fn test_prettify_one_line() {
+        let expr = parse(QUERY).unwrap();
+        assert_eq!(
+            prettify(&expr, &FormatConfig::new().with_max_width(200)),
+            QUERY
+        );
+        assert_eq!(prettify(&expr, &FormatConfig::new()).lines().count(), 5);
+    }
+
+    #[test]
+    fn test_prettify_operator_position() {
+        let expr = parse(QUERY).unwrap();
+        let config = FormatConfig::new().with_max_width(70);
+
+        let separate = r#"sum by (code, job) (rate(http_requests_total{code=~"5.."}[5m]))
+/ on (job) group_left ()
+sum by (job) (rate(http_requests_total[5m]))
+>
+0.1"#;
+        assert_eq!(prettify(&expr, &config), separate);
+
+        let leading = r#"sum by (code, job) (rate(http_requests_total{code=~"5.."}[5m]))
+/ on (job) group_left () sum by (job) (rate(http_requests_total[5m]))
+> 0.1"#;
+        let config = config.with_operator_position(OperatorPosition::Leading);
+        assert_eq!(prettify(&expr, &config), leading);
+
+        let trailing = r#"sum by (code, job) (rate(http_requests_total{code=~"5.."}[5m])) / on (job) group_left ()
+sum by (job) (rate(http_requests_total[5m])) >
+0.1"#;
+        let config = config.with_operator_position(OperatorPosition::Trailing);
+        assert_eq!(prettify(&expr, &config), trailing);
+    }
+
+    #[test]
+    fn test_prettify_indent() {
+        let expr =
+            parse(r#"topk(5, sum without (instance) (rate(foo{job="api"}[5m] offset 1h)))[1h:]"#)
+                .unwrap();
+        let expected = r#"topk(
+    5,
+    sum without (instance) (
+        rate(
+            foo{job="api"}[5m] offset 1h
+        )
+    )
+)[1h:]"#;
+        let config = FormatConfig::new().with_max_width(30).with_indent(4);
+        assert_eq!(prettify(&expr, &config), expected);
+
+        let expr = parse("-(foo + bar)").unwrap();
+        let expected = "-(\n  foo\n  +\n  bar\n)";
+        assert_eq!(
+            prettify(&expr, &FormatConfig::new().with_max_width(5)),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_prettify_keeps_semantics() {
+        let expr = parse(QUERY).unwrap();
+        for position in [
+            OperatorPosition::Separate,
+            OperatorPosition::Leading,
+            OperatorPosition::Trailing,
+        ] {
+            let config = FormatConfig::new()
+                .with_max_width(20)
+                .with_operator_position(position);
+            assert_eq!(parse(&prettify(&expr, &config)).unwrap(), expr);
+        }
+
+        // trees built without parens get the ones their grouping needs
+        let (foo, bar) = (
+            || Expr::from(crate::parser::VectorSelector::from("foo")),
+            || Expr::from(crate::parser::VectorSelector::from("bar")),
+        );
+        let expr = -((foo() - bar()) * foo());
+        assert_eq!(
+            prettify(&expr, &FormatConfig::new()),
+            "-((foo - bar) * foo)"
+        );
+        let pretty = prettify(&expr, &FormatConfig::new().with_max_width(5));
+        assert_eq!(pretty.replace(['\n', ' '], ""), "-((foo-bar)*foo)");
+
+        let expr = Expr::from((foo() + bar()).subquery(Duration::from_secs(3600)).unwrap());
+        assert_eq!(prettify(&expr, &FormatConfig::new()), "(foo + bar)[1h:]");
+        let pretty = prettify(&expr, &FormatConfig::new().with_max_width(5));
+        assert_eq!(pretty.replace(['\n', ' '], ""), "(foo+bar)[1h:]");
+        assert_eq!(parse(&pretty).unwrap(), parse("(foo + bar)[1h:]").unwrap());
+    }
+
+    #[test]
+    fn test_format_idempotent() {
+        let queries = [
+            QUERY,
+            "-(-foo)",
+            "- -foo ^ 2",
+            "-foo ^ 2 + 1",
+            r#"foo{a="b\"c", d!~"e|f"} @ 1700000000 offset -5m"#,
+            r#"foo{a='a"b', c=~`eu\.west`}"#,
+            "max_over_time(rate(foo[1m] @ start())[1h:30s] offset 1d)",
+            "label_replace(up, \"foo\", \"$1\", \"bar\", \"(.*)\") unless ignoring (a, b) down",
+            "quantile(0.9, sum by (le) (rate(bucket[5m]))) > bool 1e10",
+            "count_values(\"value\", build_info) or vector(NaN) * -Inf",
+            "max_over_time(((a + b) * c)[5m:1m]) atan2 d",
+        ];
+        for query in queries {
+            for width in [0, 10, 40, 200] {
+                for position in [
+                    OperatorPosition::Separate,
+                    OperatorPosition::Leading,
+                    OperatorPosition::Trailing,
+                ] {
+                    let config = FormatConfig::new()
+                        .with_max_width(width)
+                        .with_operator_position(position);
+                    let formatted = format_query(query, &config).unwrap();
+                    assert_eq!(
+                        format_query(&formatted, &config).unwrap(),
+                        formatted,
+                        "{query} {width} {position:?}"
+                    );
+                    assert!(check_formatted(&formatted, &config));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_truncate() {
+        let query = r#"label_replace(foo{a="1", b="2", c="3"}, "dst", "$1", "src", "(very|long|regex|with|many|alternatives)")"#;
+        let expr = parse(query).unwrap();
+        let full = expr.to_string();
+        assert_eq!(truncate(&expr, 1000), full);
+        assert_eq!(truncate(&expr, full.chars().count()), full);
+
+        let cases = vec![
+            (
+                100,
+                r#"label_replace(foo{a="1",b="2",c="3"}, "dst", "$1", "src", "(very|long|regex|with|many|alter…")"#,
+            ),
+            (
+                80,
+                r#"label_replace(foo{a="1",b="2",c="3"}, "dst", "$1", "src", "(very|long|regex…")"#,
+            ),
+            (60, r#"label_replace(foo{…}, "dst", "$1", "src", "(ver…")"#),
+            (20, "label_replace(foo{…"),
+            (1, "…"),
+            (0, ""),
+        ];
+        for (max_len, expected) in cases {
+            assert_eq!(truncate(&expr, max_len), expected, "{max_len}");
+        }
+    }
+
+    #[test]
+    fn test_check_formatted() {
+        let config = FormatConfig::new().with_max_width(16);
+        assert!(check_formatted("sum(\n  rate(foo[5m])\n)", &config));
+        assert!(!check_formatted("sum(rate(foo[5m]))", &config));
+        assert!(!check_formatted("sum(\n  rate(foo[5m])\n)\n", &config));
+        assert!(!check_formatted("sum(\n  rate(foo[5m]) # rate\n)", &config));
+
+        // the comments are only found outside of strings
+        let config = FormatConfig::new();
+        assert!(format_query("foo # a\n+ bar", &config).is_err());
+        assert!(format_query("foo\n# a", &config).is_err());
+        assert_eq!(
+            format_query(r##"foo{a="#"}"##, &config).unwrap(),
+            r##"foo{a="#"}"##
+        );
+    }
+}
@@ -0,0 +1,122 @@
+// Copyright 2023 Greptime Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! helpers to render queries as the parameters of the Prometheus HTTP API,
+//! `/api/v1/query` and `/api/v1/query_range`.
+
+use crate::parser::{EvalStmt, Expr};
+use std::time::{Duration, SystemTime};
+
+/// render the params of an instant query, like `query=up&time=1609746000`.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::parse;
+/// use promql_parser::util::http::instant_query_params;
+/// use std::time::{Duration, SystemTime};
+///
+/// let expr = parse("rate(foo[5m])").unwrap();
+/// let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1609746000);
+/// assert_eq!(
+///     instant_query_params(&expr, time),
+///     "query=rate%28foo%5B5m%5D%29&time=1609746000"
+/// );
+/// ```
+pub fn instant_query_params(expr: &Expr, time: SystemTime) -> String {
+    format!("query={}&time={}", encode(expr), timestamp(time))
+}
+
+/// render the params of a range query, like `query=up&start=0&end=60&step=15`.
+/// The lookback delta of the [`EvalStmt`] is not part of the params.
+pub fn range_query_params(stmt: &EvalStmt) -> String {
+    format!(
+        "query={}&start={}&end={}&step={}",
+        encode(&stmt.expr),
+        timestamp(stmt.start),
+        timestamp(stmt.end),
+        seconds(&stmt.interval)
+    )
+}
+
+/// render the params of the [`EvalStmt`], which is an instant query if start equals end,
+/// otherwise a range query.
+pub fn query_params(stmt: &EvalStmt) -> String {
+    if stmt.start == stmt.end {
+        instant_query_params(&stmt.expr, stmt.start)
+    } else {
+        range_query_params(stmt)
+    }
+}
+
+fn encode(expr: &Expr) -> String {
+    urlencoding::encode(&expr.to_string()).into_owned()
+}
+
+/// unix timestamp in seconds, with millisecond precision.
+fn timestamp(time: SystemTime) -> String {
+    match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(d) => seconds(&d),
+        Err(e) => format!("-{}", seconds(&e.duration())),
+    }
+}
+
+fn seconds(d: &Duration) -> String {
+    (d.as_millis() as f64 / 1000.0).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    fn stmt(query: &str, start: u64, end: u64, step: Duration) -> EvalStmt {
+        EvalStmt {
+            expr: parse(query).unwrap(),
+            start: SystemTime::UNIX_EPOCH + Duration::from_secs(start),
+            end: SystemTime::UNIX_EPOCH + Duration::from_secs(end),
+            interval: step,
+            lookback_delta: Duration::from_secs(300),
+        }
+    }
+
+    #[test]
+    fn test_query_params() {
+        let cases = vec![
+            (
+                stmt("up", 1000, 1000, Duration::from_secs(15)),
+                "query=up&time=1000",
+            ),
+            (
+                stmt("up", 0, 3600, Duration::from_secs(15)),
+                "query=up&start=0&end=3600&step=15",
+            ),
+            (
+                stmt(r#"sum by (job) (up{job="api"})"#, 60, 120, Duration::from_millis(500)),
+                "query=sum%20by%20%28job%29%20%28up%7Bjob%3D%22api%22%7D%29&start=60&end=120&step=0.5",
+            ),
+        ];
+        for (stmt, expected) in cases {
+            assert_eq!(query_params(&stmt), expected);
+        }
+    }
+
+    #[test]
+    fn test_timestamp() {
+        let epoch = SystemTime::UNIX_EPOCH;
+        assert_eq!(timestamp(epoch), "0");
+        assert_eq!(timestamp(epoch + Duration::from_millis(1500)), "1.5");
+        assert_eq!(timestamp(epoch - Duration::from_millis(1500)), "-1.5");
+    }
+}
@@ -0,0 +1,343 @@
+// Copyright 2023 Greptime Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! sharing identical exprs, like the queries of large rule sets which are
+//! often repeated across groups and tenants.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+use crate::parser::{
+    AggregateExpr, BinaryExpr, Call, Expr, FunctionArgs, ParenExpr, SubqueryExpr, UnaryExpr,
+    VectorSelector,
+};
+
+/// the id of an expr interned in an [`ExprCache`], identical exprs have the same id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ExprId(usize);
+
+impl ExprId {
+    pub fn index(&self) -> usize {
+        self.0
+    }
+}
+
+/// ExprCache interns exprs bottom-up, each distinct node is kept once with the
+/// ids of its children, so identical subtrees are shared across all the exprs,
+/// like the selector of many alerts.
+///
+/// The nodes are looked up by a hash of the node itself and the ids of its
+/// children, and then compared, so interning an expr takes time linear in its
+/// size. The children of extensions are kept in the extension and not shared.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::parse;
+/// use promql_parser::util::intern::ExprCache;
+///
+/// let mut cache = ExprCache::new();
+/// let a = cache.intern(parse("sum by (job) (rate(foo[5m]))").unwrap());
+/// let b = cache.intern(parse("sum by(job)(rate(foo[5m]))").unwrap());
+/// let c = cache.intern(parse("max by (job) (rate(foo[5m]))").unwrap());
+/// assert_eq!(a, b);
+/// assert_ne!(a, c);
+/// // rate(foo[5m]) and foo[5m] are shared by both aggregations
+/// assert_eq!(cache.children(a), cache.children(c));
+/// assert_eq!(cache.len(), 4);
+/// assert_eq!(cache.expr(c), parse("max by (job) (rate(foo[5m]))").unwrap());
+/// ```
+#[derive(Debug, Default)]
+pub struct ExprCache {
+    /// the distinct nodes by their id, with placeholders for their children.
+    nodes: Vec<(Expr, Vec<ExprId>)>,
+    /// the ids of the nodes by their hash, see [`node_hash`].
+    ids: HashMap<u64, Vec<ExprId>>,
+    hits: usize,
+}
+
+impl ExprCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// the id of the expr, the same for all identical exprs.
+    pub fn intern(&mut self, mut expr: Expr) -> ExprId {
+        let children: Vec<ExprId> = expr
+            .children_mut()
+            .into_iter()
+            .map(|child| self.intern(std::mem::replace(child, placeholder())))
+            .collect();
+        self.insert(expr, children)
+    }
+
+    /// intern the expr, and give the ids of all its nodes in pre-order, which
+    /// is the order of their [`NodeId`](crate::parser::NodeId)s.
+    pub(crate) fn intern_nodes(&mut self, expr: &Expr) -> Vec<ExprId> {
+        let mut ids = Vec::with_capacity(expr.node_count());
+        self.intern_pre_order(expr, &mut ids);
+        ids
+    }
+
+    fn intern_pre_order(&mut self, expr: &Expr, ids: &mut Vec<ExprId>) -> ExprId {
+        let slot = ids.len();
+        ids.push(ExprId(0));
+        let children: Vec<ExprId> = expr
+            .children()
+            .into_iter()
+            .map(|child| self.intern_pre_order(child, ids))
+            .collect();
+        let children = match expr {
+            Expr::Extension(_) => vec![],
+            _ => children,
+        };
+        ids[slot] = self.insert(shallow(expr), children);
+        ids[slot]
+    }
+
+    fn insert(&mut self, node: Expr, children: Vec<ExprId>) -> ExprId {
+        let hash = node_hash(&node, &children);
+        if let Some(id) = self.find(hash, &node, &children) {
+            self.hits += 1;
+            return id;
+        }
+        let id = ExprId(self.nodes.len());
+        self.nodes.push((node, children));
+        self.ids.entry(hash).or_default().push(id);
+        id
+    }
+
+    /// the id of the interned expr identical to the given one, if there is one.
+    pub fn get(&self, expr: &Expr) -> Option<ExprId> {
+        let children = match expr {
+            Expr::Extension(_) => vec![],
+            _ => expr
+                .children()
+                .into_iter()
+                .map(|child| self.get(child))
+                .collect::<Option<Vec<_>>>()?,
+        };
+        let node = shallow(expr);
+        self.find(node_hash(&node, &children), &node, &children)
+    }
+
+    fn find(&self, hash: u64, node: &Expr, children: &[ExprId]) -> Option<ExprId> {
+        self.ids.get(&hash)?.iter().copied().find(|id| {
+            let (n, c) = &self.nodes[id.0];
+            c == children && same_node(n, node)
+        })
+    }
+
+    /// the node of the id, its children are placeholders, see [`ExprCache::children`].
+    ///
+    /// # Panics
+    ///
+    /// if the id is not from this cache.
+    pub fn node(&self, id: ExprId) -> &Expr {
+        &self.nodes[id.0].0
+    }
+
+    /// the ids of the children of the node, in the order of [`Expr::children`].
+    pub fn children(&self, id: ExprId) -> &[ExprId] {
+        &self.nodes[id.0].1
+    }
+
+    /// the whole expr of the id, built from its nodes.
+    pub fn expr(&self, id: ExprId) -> Expr {
+        let (node, children) = &self.nodes[id.0];
+        let mut expr = node.clone();
+        for (child, id) in expr.children_mut().into_iter().zip(children) {
+            *child = self.expr(*id);
+        }
+        expr
+    }
+
+    /// the number of distinct nodes.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// the number of interned nodes which were already in the cache.
+    pub fn hits(&self) -> usize {
+        self.hits
+    }
+}
+
+/// stands in for the children of the kept nodes.
+fn placeholder() -> Expr {
+    Expr::from(0.0)
+}
+
+/// the node with placeholders for its children, like kept by [`ExprCache::intern`].
+fn shallow(expr: &Expr) -> Expr {
+    let child = || Box::new(placeholder());
+    match expr {
+        Expr::Aggregate(ex) => Expr::Aggregate(AggregateExpr {
+            op: ex.op,
+            expr: child(),
+            param: ex.param.as_ref().map(|_| child()),
+            modifier: ex.modifier.clone(),
+        }),
+        Expr::Unary(_) => Expr::Unary(UnaryExpr { expr: child() }),
+        Expr::Binary(ex) => Expr::Binary(BinaryExpr {
+            op: ex.op,
+            lhs: child(),
+            rhs: child(),
+            modifier: ex.modifier.clone(),
+        }),
+        Expr::Paren(_) => Expr::Paren(ParenExpr { expr: child() }),
+        Expr::Subquery(ex) => Expr::Subquery(SubqueryExpr {
+            expr: child(),
+            offset: ex.offset.clone(),
+            at: ex.at.clone(),
+            range: ex.range,
+            step: ex.step,
+        }),
+        Expr::Call(ex) => Expr::Call(Call {
+            func: ex.func.clone(),
+            args: FunctionArgs {
+                args: ex.args.args.iter().map(|_| child()).collect(),
+            },
+        }),
+        Expr::NumberLiteral(_)
+        | Expr::StringLiteral(_)
+        | Expr::VectorSelector(_)
+        | Expr::MatrixSelector(_)
+        | Expr::Extension(_) => expr.clone(),
+    }
+}
+
+/// the hash of the node without its children, equal nodes hash the same. Some
+/// fields are not hashed, as the nodes of the same hash are compared anyway.
+fn node_hash(node: &Expr, children: &[ExprId]) -> u64 {
+    let mut state = DefaultHasher::new();
+    std::mem::discriminant(node).hash(&mut state);
+    children.hash(&mut state);
+    match node {
+        Expr::Aggregate(ex) => ex.op.id().hash(&mut state),
+        Expr::Binary(ex) => ex.op.id().hash(&mut state),
+        Expr::Subquery(ex) => (ex.range, ex.step).hash(&mut state),
+        // NaNs are equal, see same_node
+        Expr::NumberLiteral(ex) if ex.val.is_nan() => {}
+        Expr::NumberLiteral(ex) => ex.val.to_bits().hash(&mut state),
+        Expr::StringLiteral(ex) => ex.val.hash(&mut state),
+        Expr::VectorSelector(vs) => selector_hash(vs, &mut state),
+        Expr::MatrixSelector(ms) => {
+            selector_hash(&ms.vector_selector, &mut state);
+            ms.range.hash(&mut state);
+        }
+        Expr::Call(ex) => ex.func.name.hash(&mut state),
+        Expr::Extension(ex) => ex.hash(&mut state),
+        Expr::Unary(_) | Expr::Paren(_) => {}
+    }
+    state.finish()
+}
+
+/// whether the nodes are equal. Numbers are compared by their bits, so `0` and
+/// `-0` are different nodes, as `1 / -0` is `-Inf`, only NaNs are all equal.
+fn same_node(a: &Expr, b: &Expr) -> bool {
+    match (a, b) {
+        (Expr::NumberLiteral(a), Expr::NumberLiteral(b)) => {
+            a.val.to_bits() == b.val.to_bits() || a.val.is_nan() && b.val.is_nan()
+        }
+        _ => a == b,
+    }
+}
+
+/// the matchers are a set, so only their number is hashed.
+fn selector_hash(vs: &VectorSelector, state: &mut DefaultHasher) {
+    vs.name.hash(state);
+    vs.matchers.matchers.len().hash(state);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    #[test]
+    fn test_expr_cache() {
+        let mut cache = ExprCache::new();
+        assert!(cache.is_empty());
+
+        let queries = [
+            "sum(rate(foo[5m])) / 2",
+            "sum(rate(foo[5m]))/2",
+            "foo",
+            r#"{__name__="foo"}"#,
+            "foo offset 1m",
+            "sum(rate(foo[5m])) / 2",
+            "max(rate(foo[5m])) > 0",
+            "NaN + -0",
+            "NaN + 0",
+        ];
+        let ids: Vec<ExprId> = queries
+            .iter()
+            .map(|q| cache.intern(parse(q).unwrap()))
+            .collect();
+        assert_eq!(ids[0], ids[1]);
+        assert_eq!(ids[0], ids[5]);
+        assert_ne!(ids[2], ids[4]);
+        // the same canonical string, but not identical, the name is only set for `foo`
+        assert_ne!(ids[2], ids[3]);
+        // the zeros differ in their sign
+        assert_ne!(ids[7], ids[8]);
+
+        // the subtree rate(foo[5m]) is shared
+        let rate = cache.get(&parse("rate(foo[5m])").unwrap()).unwrap();
+        let sum = cache.children(ids[0])[0];
+        let max = cache.children(ids[6])[0];
+        assert_eq!(cache.children(sum), [rate]);
+        assert_eq!(cache.children(max), [rate]);
+        assert_eq!(cache.node(rate).to_string(), "rate(0)");
+
+        // foo[5m], rate, sum, 2, /, foo, {__name__="foo"}, foo offset 1m,
+        // max, 0, >, NaN, -0, + twice
+        assert_eq!(cache.len(), 15);
+        assert_eq!(cache.hits(), 14);
+
+        for (query, id) in queries.iter().zip(&ids) {
+            assert_eq!(cache.expr(*id), parse(query).unwrap(), "{query}");
+        }
+        let foo = parse("foo").unwrap();
+        assert_eq!(cache.get(&foo), Some(ids[2]));
+        assert_eq!(cache.get(&parse("bar").unwrap()), None);
+        assert_eq!(cache.get(&parse("sum(rate(foo[5m])) / 3").unwrap()), None);
+    }
+
+    #[test]
+    fn test_expr_cache_zeros() {
+        let mut cache = ExprCache::new();
+        let a = cache.intern(parse("foo / 0").unwrap());
+        let b = cache.intern(parse("foo / -0").unwrap());
+        assert_ne!(a, b);
+        assert_eq!(cache.expr(b).to_string(), "foo / -0");
+
+        let zero = |id| match cache.node(cache.children(id)[1]) {
+            Expr::NumberLiteral(n) => n.val,
+            node => panic!("not a number: {node}"),
+        };
+        assert!(zero(a).is_sign_positive());
+        assert!(zero(b).is_sign_negative());
+
+        // NaNs are all the same node
+        let nan = cache.intern(parse("NaN").unwrap());
+        assert_eq!(cache.intern(Expr::from(-f64::NAN)), nan);
+    }
+}
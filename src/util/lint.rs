@@ -0,0 +1,1084 @@
+// Copyright 2023 Greptime Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! lints for queries which are valid, but likely not what the author meant.
+
+use crate::label::{Labels, BUCKET_LABEL, METRIC_NAME};
+use crate::parser::ast::SCALAR_COMPARISON_WITHOUT_BOOL;
+use crate::parser::function::get_function;
+use crate::parser::token::{
+    token_display, T_BOTTOMK, T_BY, T_COMMA, T_COUNT_VALUES, T_GROUP_LEFT, T_GROUP_RIGHT,
+    T_IGNORING, T_LEFT_PAREN, T_ON, T_RIGHT_PAREN, T_SUM, T_TOPK, T_WITHOUT,
+};
+use crate::parser::{
+    lex, parse, AggregateExpr, BinaryExpr, Call, Expr, FunctionArgs, LabelModifier, MatrixSelector,
+    NodeId, ValueType, VectorMatchCardinality, VectorSelector,
+};
+use crate::util::display_duration;
+use lrpar::{Lexeme, Lexer, NonStreamingLexer};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LintKind {
+    /// a comparison between scalars without `bool`, like `1 > 2`. The parser
+    /// rejects it, so it is only found in built trees, the parse error of a
+    /// query has the fix as its related message instead.
+    ScalarComparisonWithoutBool,
+    /// a counter aggregated without `rate()`, like `sum(http_requests_total)`.
+    RawCounter,
+    /// a range too short to contain enough samples, like `rate(foo[15s])`.
+    ShortRange,
+    /// a selector matching all series of the metric, like `foo{job=~".*"}`.
+    MatchAllSelector,
+    /// a label listed twice in a grouping clause, like `sum by (job, job)`,
+    /// which is silently deduplicated.
+    DuplicateGroupingLabel,
+    /// an aggregation dropping the `le` label of the buckets passed to
+    /// `histogram_quantile`, like `histogram_quantile(0.9, sum(rate(foo_bucket[5m])))`.
+    DroppedBucketLabel,
+    /// an aggregation dropping a label the vector matching joins on, like
+    /// `sum by (job) (foo) * on (instance) bar`.
+    DroppedJoinLabel,
+    /// a counter function over the result of another, like `rate(rate(foo[5m])[1h:])`.
+    NestedRate,
+    /// a counter function over an aggregation, like `rate(sum(foo_total)[5m:])`,
+    /// which sees the resets of a single series as drops of the sum.
+    RateOfAggregation,
+    /// a counter function over a metric which looks like a gauge by its name,
+    /// like `increase(memory_usage_ratio[5m])`.
+    CounterFunctionOnGauge,
+    /// a `group_left` or `group_right` with an info metric on the "many" side,
+    /// like `build_info * on (job) group_left (env) up`, the info metric is
+    /// usually the "one" side the labels are copied from.
+    InvertedGroupModifier,
+    /// a join with an info metric without `on`, like `up * build_info`, which
+    /// matches on all labels, including the ones only the info metric has.
+    InfoJoinWithoutOn,
+}
+
+impl LintKind {
+    /// the code of the lint in a [`Diagnostic`](crate::util::diagnostic::Diagnostic),
+    /// like `short-range`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            LintKind::ScalarComparisonWithoutBool => "scalar-comparison-without-bool",
+            LintKind::RawCounter => "raw-counter",
+            LintKind::ShortRange => "short-range",
+            LintKind::MatchAllSelector => "match-all-selector",
+            LintKind::DuplicateGroupingLabel => "duplicate-grouping-label",
+            LintKind::DroppedBucketLabel => "dropped-bucket-label",
+            LintKind::DroppedJoinLabel => "dropped-join-label",
+            LintKind::NestedRate => "nested-rate",
+            LintKind::RateOfAggregation => "rate-of-aggregation",
+            LintKind::CounterFunctionOnGauge => "counter-function-on-gauge",
+            LintKind::InvertedGroupModifier => "inverted-group-modifier",
+            LintKind::InfoJoinWithoutOn => "info-join-without-on",
+        }
+    }
+}
+
+/// Lint is a finding in the expr. `node` is the offending subtree, and `fix`
+/// is the subtree to replace it with, if the finding can be fixed mechanically.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lint {
+    pub kind: LintKind,
+    pub message: String,
+    /// the id of `node` in the linted expr.
+    pub id: NodeId,
+    pub node: Expr,
+    pub fix: Option<Expr>,
+}
+
+/// Options of the lints.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LintOptions {
+    /// the scrape interval of the metrics, ranges should cover at least 4 scrapes.
+    pub scrape_interval: Duration,
+    /// the lints which are not reported, all lints are enabled by default.
+    pub disabled: HashSet<LintKind>,
+}
+
+impl Default for LintOptions {
+    fn default() -> Self {
+        Self {
+            scrape_interval: Duration::from_secs(15),
+            disabled: HashSet::new(),
+        }
+    }
+}
+
+impl LintOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_scrape_interval(mut self, scrape_interval: Duration) -> Self {
+        self.scrape_interval = scrape_interval;
+        self
+    }
+
+    pub fn with_lint(mut self, kind: LintKind, enabled: bool) -> Self {
+        if enabled {
+            self.disabled.remove(&kind);
+        } else {
+            self.disabled.insert(kind);
+        }
+        self
+    }
+
+    pub fn is_enabled(&self, kind: LintKind) -> bool {
+        !self.disabled.contains(&kind)
+    }
+
+    /// the shortest range of a function, functions looking at the last two samples
+    /// only need 2 scrapes, others 4.
+    fn min_range(&self, func: &str) -> Duration {
+        match func {
+            "irate" | "idelta" => self.scrape_interval * 2,
+            _ => self.scrape_interval * 4,
+        }
+    }
+}
+
+/// functions over counters and gauges which need at least two samples in the range.
+const RANGE_FUNCTIONS: [&str; 6] = ["rate", "irate", "increase", "delta", "idelta", "deriv"];
+
+/// functions over counters, which handle the counter resets.
+const COUNTER_FUNCTIONS: [&str; 3] = ["rate", "irate", "increase"];
+
+/// suffixes of metric names which are gauges by convention.
+const GAUGE_SUFFIXES: [&str; 8] = [
+    "_ratio",
+    "_percent",
+    "_celsius",
+    "_info",
+    "_limit",
+    "_usage",
+    "_in_flight",
+    "_temperature",
+];
+
+/// functions over classic histograms, which need the `le` label of the buckets.
+const BUCKET_FUNCTIONS: [&str; 2] = ["histogram_quantile", "histogram_fraction"];
+
+/// lint the expr, the lints are in depth-first order.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::parse;
+/// use promql_parser::util::lint::{apply_fixes, lint, LintKind, LintOptions};
+///
+/// let expr = parse("sum(http_requests_total) / sum(rate(errors_total[15s]))").unwrap();
+/// let lints = lint(&expr, &LintOptions::new());
+/// assert_eq!(lints[0].kind, LintKind::RawCounter);
+/// assert_eq!(lints[1].kind, LintKind::ShortRange);
+///
+/// let fixed = apply_fixes(&expr, &lints);
+/// assert_eq!(
+///     fixed.to_string(),
+///     "sum(rate(http_requests_total[1m])) / sum(rate(errors_total[1m]))"
+/// );
+/// ```
+pub fn lint(expr: &Expr, options: &LintOptions) -> Vec<Lint> {
+    let mut lints = vec![];
+    lint_expr(expr, &mut 0, options, &mut lints);
+    lints.retain(|l| options.is_enabled(l.kind));
+    lints
+}
+
+/// `next` is the id of the expr in pre-order, it is advanced past the subtree.
+fn lint_expr(expr: &Expr, next: &mut usize, options: &LintOptions, lints: &mut Vec<Lint>) {
+    let id = NodeId::new(*next);
+    match expr {
+        Expr::Binary(ex) => lint_binary(ex, id, lints),
+        Expr::VectorSelector(vs) => lint_selector(expr, id, vs, lints),
+        Expr::MatrixSelector(ms) => lint_selector(expr, id, &ms.vector_selector, lints),
+        Expr::Aggregate(ex) => {
+            if let Expr::VectorSelector(vs) = &*ex.expr {
+                match &vs.name {
+                    Some(name) if name.ends_with("_total") => lints.push(Lint {
+                        kind: LintKind::RawCounter,
+                        message: format!(
+                            "counter {name} is aggregated directly, use rate() or increase() first"
+                        ),
+                        id: descendant_id(expr, id, &ex.expr),
+                        node: *ex.expr.clone(),
+                        fix: Some(rate(vs.clone(), options.min_range("rate"))),
+                    }),
+                    _ => {}
+                }
+            }
+        }
+        Expr::Call(call) if RANGE_FUNCTIONS.contains(&call.func.name) => {
+            if COUNTER_FUNCTIONS.contains(&call.func.name) {
+                lint_counter_function(call, id, lints);
+            }
+            let min_range = options.min_range(call.func.name);
+            for arg in &call.args.args {
+                match &**arg {
+                    Expr::MatrixSelector(ms) if ms.range < min_range => lints.push(Lint {
+                        kind: LintKind::ShortRange,
+                        message: format!(
+                            "range {} of {} is shorter than {}, which may not contain enough samples",
+                            display_duration(&ms.range),
+                            call.func.name,
+                            display_duration(&min_range)
+                        ),
+                        id: descendant_id(expr, id, arg),
+                        node: *arg.clone(),
+                        fix: Some(Expr::MatrixSelector(MatrixSelector {
+                            vector_selector: ms.vector_selector.clone(),
+                            range: min_range,
+                        })),
+                    }),
+                    _ => {}
+                }
+            }
+        }
+        Expr::Call(call) if BUCKET_FUNCTIONS.contains(&call.func.name) => {
+            let last = call.args.args.last().map(|e| unwrap_parens(e));
+            if let Some(node @ Expr::Aggregate(agg)) = last {
+                if !keeps_label(agg, BUCKET_LABEL) && has_bucket_selector(&agg.expr) {
+                    let mut fixed = agg.clone();
+                    fixed.modifier = Some(match fixed.modifier.take() {
+                        Some(LabelModifier::Include(mut labels)) => {
+                            labels.insert(BUCKET_LABEL.into());
+                            LabelModifier::Include(labels)
+                        }
+                        Some(LabelModifier::Exclude(mut labels)) => {
+                            labels.remove(BUCKET_LABEL);
+                            LabelModifier::Exclude(labels)
+                        }
+                        None => LabelModifier::Include(HashSet::from([BUCKET_LABEL.into()])),
+                    });
+                    lints.push(Lint {
+                        kind: LintKind::DroppedBucketLabel,
+                        message: format!(
+                            "{node} drops the {BUCKET_LABEL} label needed by {}",
+                            call.func.name
+                        ),
+                        id: descendant_id(expr, id, node),
+                        node: node.clone(),
+                        fix: Some(Expr::Aggregate(fixed)),
+                    });
+                }
+            }
+        }
+        _ => {}
+    }
+
+    *next += 1;
+    for child in expr.children() {
+        lint_expr(child, next, options, lints);
+    }
+}
+
+/// the id of a node of the subtree, the ids of the subtree follow the id of its root.
+fn descendant_id(subtree: &Expr, id: NodeId, node: &Expr) -> NodeId {
+    let offset = subtree
+        .node_id(node)
+        .expect("the node is in the subtree")
+        .index();
+    NodeId::new(id.index() + offset)
+}
+
+fn lint_binary(ex: &BinaryExpr, id: NodeId, lints: &mut Vec<Lint>) {
+    let return_bool = ex.modifier.as_ref().is_some_and(|m| m.return_bool);
+    if ex.op.is_comparison_operator()
+        && !return_bool
+        && ex.lhs.value_type() == ValueType::Scalar
+        && ex.rhs.value_type() == ValueType::Scalar
+    {
+        let modifier = ex
+            .modifier
+            .clone()
+            .unwrap_or_default()
+            .with_return_bool(true);
+        lints.push(Lint {
+            kind: LintKind::ScalarComparisonWithoutBool,
+            message: SCALAR_COMPARISON_WITHOUT_BOOL.into(),
+            id,
+            node: Expr::Binary(ex.clone()),
+            fix: Some(Expr::Binary(BinaryExpr {
+                modifier: Some(modifier),
+                ..ex.clone()
+            })),
+        });
+    }
+
+    lint_info_join(ex, id, lints);
+
+    let Some(modifier) = &ex.modifier else {
+        return;
+    };
+    // the labels each side must keep for the matching to work
+    let mut needed = vec![];
+    if let Some(LabelModifier::Include(labels)) = &modifier.matching {
+        let clause = format!("on ({})", sorted(labels).join(", "));
+        for label in sorted(labels) {
+            needed.push((&ex.lhs, label, clause.clone()));
+            needed.push((&ex.rhs, label, clause.clone()));
+        }
+    }
+    // the labels copied from the "one" side
+    let one = match &modifier.card {
+        VectorMatchCardinality::ManyToOne(labels) => Some((&ex.rhs, labels)),
+        VectorMatchCardinality::OneToMany(labels) => Some((&ex.lhs, labels)),
+        _ => None,
+    };
+    if let Some((side, labels)) = one {
+        let clause = modifier.card.to_string();
+        for label in sorted(labels) {
+            needed.push((side, label, clause.clone()));
+        }
+    }
+
+    for (side, label, clause) in needed {
+        if let Expr::Aggregate(agg) = unwrap_parens(side) {
+            if !keeps_label(agg, label) {
+                lints.push(Lint {
+                    kind: LintKind::DroppedJoinLabel,
+                    message: format!("label {label} of {clause} is dropped by {agg}"),
+                    id,
+                    node: Expr::Binary(ex.clone()),
+                    fix: None,
+                });
+            }
+        }
+    }
+}
+
+/// the joins against info metrics, like `up * on (job) group_left (version) build_info`,
+/// which only work with `on` and the info metric on the "one" side.
+fn lint_info_join(ex: &BinaryExpr, id: NodeId, lints: &mut Vec<Lint>) {
+    if ex.lhs.value_type() != ValueType::Vector || ex.rhs.value_type() != ValueType::Vector {
+        return;
+    }
+    let (lhs, rhs) = (info_metric(&ex.lhs), info_metric(&ex.rhs));
+    let name = match (lhs, rhs) {
+        (Some(name), None) | (None, Some(name)) => name,
+        _ => return,
+    };
+    let modifier = ex.modifier.clone().unwrap_or_default();
+    let (many, swapped) = match &modifier.card {
+        VectorMatchCardinality::ManyToOne(labels) => {
+            (lhs, VectorMatchCardinality::OneToMany(labels.clone()))
+        }
+        VectorMatchCardinality::OneToMany(labels) => {
+            (rhs, VectorMatchCardinality::ManyToOne(labels.clone()))
+        }
+        _ => (None, modifier.card.clone()),
+    };
+    if many.is_some() {
+        let clause = modifier.card.to_string();
+        let replacement = swapped.to_string();
+        lints.push(Lint {
+            kind: LintKind::InvertedGroupModifier,
+            message: format!(
+                "info metric {name} is on the many side of {clause}, use {replacement} to copy its labels"
+            ),
+            id,
+            node: Expr::Binary(ex.clone()),
+            fix: Some(Expr::Binary(BinaryExpr {
+                modifier: Some(modifier.clone().with_card(swapped)),
+                ..ex.clone()
+            })),
+        });
+    }
+    if !matches!(modifier.matching, Some(LabelModifier::Include(_))) {
+        lints.push(Lint {
+            kind: LintKind::InfoJoinWithoutOn,
+            message: format!(
+                "join with info metric {name} without on, which matches on its info labels as well"
+            ),
+            id,
+            node: Expr::Binary(ex.clone()),
+            fix: None,
+        });
+    }
+}
+
+/// the name of the info metric, like `build_info`, the expr selects, also through
+/// aggregations like `max by (job, version) (build_info)`.
+fn info_metric(expr: &Expr) -> Option<&str> {
+    match unwrap_parens(expr) {
+        Expr::VectorSelector(vs) => vs.metric_name().filter(|name| name.ends_with("_info")),
+        Expr::Aggregate(agg) if agg.op.id() != T_COUNT_VALUES => info_metric(&agg.expr),
+        _ => None,
+    }
+}
+
+/// the misuses of `rate`, `irate` and `increase`.
+fn lint_counter_function(call: &Call, id: NodeId, lints: &mut Vec<Lint>) {
+    let func = call.func.name;
+    let node = Expr::Call(call.clone());
+    match call.args.args.first().map(|e| &**e) {
+        Some(Expr::Subquery(sq)) => match unwrap_parens(&sq.expr) {
+            Expr::Call(inner) if COUNTER_FUNCTIONS.contains(&inner.func.name) => lints.push(Lint {
+                kind: LintKind::NestedRate,
+                message: format!(
+                    "{func} over the result of {}, which is not a counter",
+                    inner.func.name
+                ),
+                id,
+                node,
+                fix: None,
+            }),
+            Expr::Aggregate(agg) => {
+                // `rate(sum(foo)[5m:])` is `sum(rate(foo[5m]))`, if nothing else is in between
+                let fix = match &*agg.expr {
+                    Expr::VectorSelector(vs)
+                        if agg.op.id() == T_SUM && sq.offset.is_none() && sq.at.is_none() =>
+                    {
+                        let mut fixed = agg.clone();
+                        fixed.expr = Box::new(Expr::Call(Call {
+                            func: call.func.clone(),
+                            args: FunctionArgs::new_args(Expr::MatrixSelector(MatrixSelector {
+                                vector_selector: vs.clone(),
+                                range: sq.range,
+                            })),
+                        }));
+                        Some(Expr::Aggregate(fixed))
+                    }
+                    _ => None,
+                };
+                lints.push(Lint {
+                    kind: LintKind::RateOfAggregation,
+                    message: format!(
+                        "{func} over the aggregation {agg}, aggregate after {func} instead"
+                    ),
+                    id,
+                    node,
+                    fix,
+                })
+            }
+            _ => {}
+        },
+        Some(Expr::MatrixSelector(ms)) => {
+            let Some(name) = ms.vector_selector.metric_name() else {
+                return;
+            };
+            if !GAUGE_SUFFIXES.iter().any(|suffix| name.ends_with(suffix)) {
+                return;
+            }
+            let replacement = match func {
+                "rate" => "deriv",
+                "irate" => "idelta",
+                _ => "delta",
+            };
+            let mut fixed = call.clone();
+            fixed.func = get_function(replacement).expect("gauge functions are builtin");
+            lints.push(Lint {
+                kind: LintKind::CounterFunctionOnGauge,
+                message: format!(
+                    "{func} is for counters, but {name} looks like a gauge, use {replacement}"
+                ),
+                id,
+                node,
+                fix: Some(Expr::Call(fixed)),
+            })
+        }
+        _ => {}
+    }
+}
+
+fn sorted(labels: &Labels) -> Vec<&str> {
+    let mut labels: Vec<&str> = labels.iter().map(String::as_str).collect();
+    labels.sort_unstable();
+    labels
+}
+
+fn unwrap_parens(expr: &Expr) -> &Expr {
+    match expr {
+        Expr::Paren(paren) => unwrap_parens(&paren.expr),
+        _ => expr,
+    }
+}
+
+/// whether the label is kept in the result of the aggregation, `topk` and
+/// `bottomk` return the series as they are.
+fn keeps_label(agg: &AggregateExpr, label: &str) -> bool {
+    if matches!(agg.op.id(), T_TOPK | T_BOTTOMK) {
+        return true;
+    }
+    match &agg.modifier {
+        Some(LabelModifier::Include(labels)) => labels.contains(label),
+        Some(LabelModifier::Exclude(labels)) => !labels.contains(label),
+        None => false,
+    }
+}
+
+fn has_bucket_selector(expr: &Expr) -> bool {
+    let vs = match expr {
+        Expr::VectorSelector(vs) => vs,
+        Expr::MatrixSelector(ms) => &ms.vector_selector,
+        _ => return expr.children().into_iter().any(has_bucket_selector),
+    };
+    vs.metric_name()
+        .is_some_and(|name| name.ends_with("_bucket"))
+}
+
+/// a selector is flagged if all matchers besides the metric name match any value,
+/// bare metric names are fine.
+fn lint_selector(expr: &Expr, id: NodeId, vs: &VectorSelector, lints: &mut Vec<Lint>) {
+    let mut matchers = vs
+        .matchers
+        .matchers
+        .iter()
+        .filter(|m| m.name != METRIC_NAME)
+        .peekable();
+    if matchers.peek().is_some() && matchers.all(|m| m.matches_any_value()) {
+        let message = match &vs.name {
+            Some(name) => format!("selector {expr} matches all series of {name}"),
+            None => format!("selector {expr} matches all series"),
+        };
+        lints.push(Lint {
+            kind: LintKind::MatchAllSelector,
+            message,
+            id,
+            node: expr.clone(),
+            fix: None,
+        });
+    }
+}
+
+fn rate(vs: VectorSelector, range: Duration) -> Expr {
+    let ms = Expr::MatrixSelector(MatrixSelector {
+        vector_selector: vs,
+        range,
+    });
+    let func = get_function("rate").expect("rate is a builtin function");
+    Expr::Call(Call {
+        func,
+        args: FunctionArgs::new_args(ms),
+    })
+}
+
+/// parse and lint the query. Besides the lints of [lint], this finds the
+/// issues which are lost in the AST, like duplicate grouping labels.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::util::lint::{lint_query, LintKind, LintOptions};
+///
+/// let lints = lint_query("sum by (job, job) (foo)", &LintOptions::new()).unwrap();
+/// assert_eq!(lints[0].kind, LintKind::DuplicateGroupingLabel);
+/// assert_eq!(lints[0].message, "label job is listed more than once in by (job, job)");
+/// assert_eq!(lints[0].node.to_string(), "sum by (job) (foo)");
+/// ```
+pub fn lint_query(input: &str, options: &LintOptions) -> Result<Vec<Lint>, String> {
+    let expr = parse(input)?;
+    lint_parsed(input, &expr, options)
+}
+
+/// same as [`lint_query`], for the expr already parsed from the input.
+pub(crate) fn lint_parsed(
+    input: &str,
+    expr: &Expr,
+    options: &LintOptions,
+) -> Result<Vec<Lint>, String> {
+    let mut lints = lint(expr, options);
+    if !options.is_enabled(LintKind::DuplicateGroupingLabel) {
+        return Ok(lints);
+    }
+    for clause in grouping_clauses(input)? {
+        let (mut seen, mut reported) = (HashSet::new(), HashSet::new());
+        let duplicates: Vec<&String> = clause
+            .labels
+            .iter()
+            .filter(|l| !seen.insert(*l) && reported.insert(*l))
+            .collect();
+        if duplicates.is_empty() {
+            continue;
+        }
+        // the grouping labels are replaced by a marker, and the node with the
+        // marker has the same id as the node of the clause in the original tree.
+        let marked = format!(
+            "{}{GROUPING_MARKER}{}",
+            &input[..clause.labels_span.0],
+            &input[clause.labels_span.1..]
+        );
+        let node = parse(&marked).ok().and_then(|marked| {
+            let found = marked.find(|e| {
+                grouping_labels(e, clause.token).is_some_and(|l| l.contains(GROUPING_MARKER))
+            });
+            let id = found.first()?.id;
+            Some((id, expr.get_node(id)?.clone()))
+        });
+        let Some((id, node)) = node else {
+            continue;
+        };
+
+        let clause_str = format!(
+            "{} ({})",
+            token_display(clause.token),
+            clause.labels.join(", ")
+        );
+        for label in duplicates {
+            lints.push(Lint {
+                kind: LintKind::DuplicateGroupingLabel,
+                message: format!("label {label} is listed more than once in {clause_str}"),
+                id,
+                node: node.clone(),
+                fix: None,
+            });
+        }
+    }
+    Ok(lints)
+}
+
+const GROUPING_MARKER: &str = "__lint_grouping_marker__";
+
+/// a grouping clause as written in the query, like `by (a, a)`.
+struct GroupingClause {
+    token: u8,
+    labels: Vec<String>,
+    /// the byte range between the parens.
+    labels_span: (usize, usize),
+}
+
+fn grouping_clauses(input: &str) -> Result<Vec<GroupingClause>, String> {
+    let lexer = lex::lexer(input)?;
+    let lexemes: Vec<_> = lexer.iter().flatten().collect();
+    let mut clauses = vec![];
+    for (i, lexeme) in lexemes.iter().enumerate() {
+        let token = lexeme.tok_id();
+        if ![
+            T_BY,
+            T_WITHOUT,
+            T_ON,
+            T_IGNORING,
+            T_GROUP_LEFT,
+            T_GROUP_RIGHT,
+        ]
+        .contains(&token)
+        {
+            continue;
+        }
+        let Some(left) = lexemes.get(i + 1).filter(|l| l.tok_id() == T_LEFT_PAREN) else {
+            continue;
+        };
+        let Some(right) = lexemes[i + 2..]
+            .iter()
+            .find(|l| l.tok_id() == T_RIGHT_PAREN)
+        else {
+            continue;
+        };
+        let labels = lexemes[i + 2..]
+            .iter()
+            .take_while(|l| l.tok_id() != T_RIGHT_PAREN)
+            .filter(|l| l.tok_id() != T_COMMA)
+            .map(|l| {
+                lexer
+                    .span_str(l.span())
+                    .trim_matches(['"', '\'', '`'])
+                    .to_string()
+            })
+            .collect();
+        clauses.push(GroupingClause {
+            token,
+            labels,
+            labels_span: (left.span().end(), right.span().start()),
+        });
+    }
+    Ok(clauses)
+}
+
+/// the labels of the grouping clause of the node.
+fn grouping_labels(expr: &Expr, clause: u8) -> Option<&Labels> {
+    match (expr, clause) {
+        (Expr::Aggregate(ex), T_BY) => match &ex.modifier {
+            Some(LabelModifier::Include(labels)) => Some(labels),
+            _ => None,
+        },
+        (Expr::Aggregate(ex), T_WITHOUT) => match &ex.modifier {
+            Some(LabelModifier::Exclude(labels)) => Some(labels),
+            _ => None,
+        },
+        (Expr::Binary(ex), T_ON) => match ex.modifier.as_ref()?.matching.as_ref()? {
+            LabelModifier::Include(labels) => Some(labels),
+            _ => None,
+        },
+        (Expr::Binary(ex), T_IGNORING) => match ex.modifier.as_ref()?.matching.as_ref()? {
+            LabelModifier::Exclude(labels) => Some(labels),
+            _ => None,
+        },
+        (Expr::Binary(ex), T_GROUP_LEFT) => match &ex.modifier.as_ref()?.card {
+            VectorMatchCardinality::ManyToOne(labels) => Some(labels),
+            _ => None,
+        },
+        (Expr::Binary(ex), T_GROUP_RIGHT) => match &ex.modifier.as_ref()?.card {
+            VectorMatchCardinality::OneToMany(labels) => Some(labels),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// replace the nodes of the lints with their fixes, lints without a fix are ignored.
+/// Only the node with the id of the lint is replaced, the first fix of a node
+/// wins, and the fixes inside a replaced node are not applied.
+pub fn apply_fixes(expr: &Expr, lints: &[Lint]) -> Expr {
+    let mut fixes = HashMap::new();
+    for lint in lints {
+        if let Some(fix) = &lint.fix {
+            fixes.entry(lint.id).or_insert(fix);
+        }
+    }
+    let mut expr = expr.clone();
+    replace(&mut expr, &mut 0, &fixes);
+    expr
+}
+
+/// `next` is the id of the expr in pre-order, it is advanced past the subtree.
+fn replace(expr: &mut Expr, next: &mut usize, fixes: &HashMap<NodeId, &Expr>) {
+    if let Some(fix) = fixes.get(&NodeId::new(*next)) {
+        *next += expr.node_count();
+        *expr = (*fix).clone();
+        return;
+    }
+    if let Expr::Extension(_) = expr {
+        // the children of extensions can not be replaced
+        *next += expr.node_count();
+        return;
+    }
+    *next += 1;
+    for child in expr.children_mut() {
+        replace(child, next, fixes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::token::T_GTR;
+    use crate::parser::{parse, TokenType};
+
+    fn lint_kinds(query: &str) -> Vec<LintKind> {
+        lint(&parse(query).unwrap(), &LintOptions::new())
+            .into_iter()
+            .map(|l| l.kind)
+            .collect()
+    }
+
+    #[test]
+    fn test_lint() {
+        assert_eq!(lint_kinds("rate(foo_total[5m])"), vec![]);
+        assert_eq!(lint_kinds("sum(rate(foo_total[5m]))"), vec![]);
+        assert_eq!(lint_kinds("sum(foo)"), vec![]);
+        assert_eq!(lint_kinds("foo_total"), vec![]);
+        assert_eq!(lint_kinds("1 > bool 2"), vec![]);
+        assert_eq!(lint_kinds("irate(foo[30s])"), vec![]);
+        assert_eq!(lint_kinds("max_over_time(foo[15s])"), vec![]);
+
+        assert_eq!(
+            lint_kinds("sum by (job) (foo_total)"),
+            vec![LintKind::RawCounter]
+        );
+        assert_eq!(lint_kinds("rate(foo[30s])"), vec![LintKind::ShortRange]);
+        assert_eq!(lint_kinds("irate(foo[15s])"), vec![LintKind::ShortRange]);
+        assert_eq!(
+            lint_kinds("topk(3, sum(a_total)) / increase(b[10s])"),
+            vec![LintKind::RawCounter, LintKind::ShortRange]
+        );
+
+        assert_eq!(lint_kinds(r#"foo{job="a"}"#), vec![]);
+        assert_eq!(lint_kinds(r#"foo{job!="", env="b"}"#), vec![]);
+        assert_eq!(lint_kinds(r#"{__name__="foo"}"#), vec![]);
+        assert_eq!(
+            lint_kinds(r#"foo{job=~".*"}"#),
+            vec![LintKind::MatchAllSelector]
+        );
+        assert_eq!(
+            lint_kinds(r#"rate(foo{job!="", env=~".+"}[5m])"#),
+            vec![LintKind::MatchAllSelector]
+        );
+        assert_eq!(lint_kinds(r#"{job!=""}"#), vec![LintKind::MatchAllSelector]);
+
+        let lints = lint(&parse(r#"foo{job=~".*"}"#).unwrap(), &LintOptions::new());
+        assert_eq!(
+            lints[0].message,
+            r#"selector foo{job=~".*"} matches all series of foo"#
+        );
+        assert_eq!(lints[0].fix, None);
+
+        let lints = lint(&parse("rate(foo[30s])").unwrap(), &LintOptions::new());
+        assert_eq!(
+            lints[0].message,
+            "range 30s of rate is shorter than 1m, which may not contain enough samples"
+        );
+        let options = LintOptions::new().with_scrape_interval(Duration::from_secs(5));
+        assert!(lint(&parse("rate(foo[30s])").unwrap(), &options).is_empty());
+    }
+
+    #[test]
+    fn test_dropped_labels() {
+        assert_eq!(
+            lint_kinds("histogram_quantile(0.9, sum by (le) (rate(foo_bucket[5m])))"),
+            vec![]
+        );
+        assert_eq!(
+            lint_kinds("histogram_quantile(0.9, sum without (job) (rate(foo_bucket[5m])))"),
+            vec![]
+        );
+        // native histograms have no le label
+        assert_eq!(
+            lint_kinds("histogram_quantile(0.9, sum(rate(foo[5m])))"),
+            vec![]
+        );
+        assert_eq!(
+            lint_kinds("histogram_quantile(0.9, (sum by (job) (rate(foo_bucket[5m]))))"),
+            vec![LintKind::DroppedBucketLabel]
+        );
+        assert_eq!(
+            lint_kinds("histogram_fraction(0, 1, max without (le) (foo_bucket))"),
+            vec![LintKind::DroppedBucketLabel]
+        );
+
+        let expr = parse("histogram_quantile(0.9, sum(rate(foo_bucket[5m])))").unwrap();
+        let lints = lint(&expr, &LintOptions::new());
+        assert_eq!(
+            lints[0].message,
+            "sum(rate(foo_bucket[5m])) drops the le label needed by histogram_quantile"
+        );
+        assert_eq!(
+            apply_fixes(&expr, &lints).to_string(),
+            "histogram_quantile(0.9, sum by (le) (rate(foo_bucket[5m])))"
+        );
+
+        assert_eq!(
+            lint_kinds("sum by (instance) (foo) * on (instance) bar"),
+            vec![]
+        );
+        assert_eq!(
+            lint_kinds("foo * on (instance) group_left (team) topk(1, bar)"),
+            vec![]
+        );
+        assert_eq!(
+            lint_kinds("foo * on (instance) group_left (team) max by (instance) (bar)"),
+            vec![LintKind::DroppedJoinLabel]
+        );
+        assert_eq!(
+            lint_kinds("(sum without (instance) (foo)) / ignoring (job) bar"),
+            vec![]
+        );
+
+        let expr =
+            parse("sum by (job) (foo) * on (instance, job) group_right (team) sum(bar)").unwrap();
+        let messages: Vec<String> = lint(&expr, &LintOptions::new())
+            .into_iter()
+            .map(|l| l.message)
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                "label instance of on (instance, job) is dropped by sum by (job) (foo)",
+                "label instance of on (instance, job) is dropped by sum(bar)",
+                "label job of on (instance, job) is dropped by sum(bar)",
+                "label team of group_right (team) is dropped by sum by (job) (foo)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_counter_functions() {
+        assert_eq!(lint_kinds("rate(foo_total[5m])[1h:]"), vec![]);
+        assert_eq!(lint_kinds("max_over_time(rate(foo[5m])[1h:])"), vec![]);
+        assert_eq!(lint_kinds("delta(memory_usage_ratio[5m])"), vec![]);
+        assert_eq!(
+            lint_kinds("rate((irate(foo[1m]))[1h:])"),
+            vec![LintKind::NestedRate]
+        );
+        assert_eq!(
+            lint_kinds("increase(max by (job) (foo)[1h:])"),
+            vec![LintKind::RateOfAggregation]
+        );
+        assert_eq!(
+            lint_kinds("irate(cpu_usage[1m])"),
+            vec![LintKind::CounterFunctionOnGauge]
+        );
+
+        let expr =
+            parse("rate(sum by (job) (foo)[5m:]) + increase(memory_usage_ratio[5m])").unwrap();
+        let lints = lint(&expr, &LintOptions::new());
+        assert_eq!(
+            lints[0].message,
+            "rate over the aggregation sum by (job) (foo), aggregate after rate instead"
+        );
+        assert_eq!(
+            lints[1].message,
+            "increase is for counters, but memory_usage_ratio looks like a gauge, use delta"
+        );
+        assert_eq!(
+            apply_fixes(&expr, &lints).to_string(),
+            "sum by (job) (rate(foo[5m])) + delta(memory_usage_ratio[5m])"
+        );
+
+        // each lint can be disabled
+        let options = LintOptions::new()
+            .with_lint(LintKind::RateOfAggregation, false)
+            .with_lint(LintKind::CounterFunctionOnGauge, false);
+        assert!(lint(&expr, &options).is_empty());
+        let options = options.with_lint(LintKind::CounterFunctionOnGauge, true);
+        assert_eq!(lint(&expr, &options).len(), 1);
+        let options = LintOptions::new().with_lint(LintKind::DuplicateGroupingLabel, false);
+        assert!(lint_query("sum by (job, job) (foo)", &options)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_info_joins() {
+        assert_eq!(
+            lint_kinds("up * on (job) group_left (version) build_info"),
+            vec![]
+        );
+        assert_eq!(
+            lint_kinds("max by (job, version) (build_info) * on (job) group_right (version) up"),
+            vec![]
+        );
+        assert_eq!(lint_kinds("up * on (job) foo_info"), vec![]);
+        assert_eq!(lint_kinds("foo_info and bar_info"), vec![]);
+        assert_eq!(lint_kinds("build_info > 0"), vec![]);
+        assert_eq!(
+            lint_kinds("count_values(\"version\", build_info) * up"),
+            vec![]
+        );
+        assert_eq!(
+            lint_kinds("up * build_info"),
+            vec![LintKind::InfoJoinWithoutOn]
+        );
+        assert_eq!(
+            lint_kinds("up * ignoring (version) group_left (version) (build_info)"),
+            vec![LintKind::InfoJoinWithoutOn]
+        );
+        assert_eq!(
+            lint_kinds("up * on (job) group_right (version) build_info"),
+            vec![LintKind::InvertedGroupModifier]
+        );
+
+        let expr =
+            parse("sum by (job, version) (build_info) * on (job) group_left (version) up").unwrap();
+        let lints = lint(&expr, &LintOptions::new());
+        assert_eq!(lints.len(), 1);
+        assert_eq!(
+            lints[0].message,
+            "info metric build_info is on the many side of group_left (version), \
+             use group_right (version) to copy its labels"
+        );
+        assert_eq!(
+            apply_fixes(&expr, &lints).to_string(),
+            "sum by (job, version) (build_info) * on (job) group_right (version) up"
+        );
+
+        let lints = lint(&parse("up / target_info").unwrap(), &LintOptions::new());
+        assert_eq!(
+            lints[0].message,
+            "join with info metric target_info without on, which matches on its info labels as well"
+        );
+    }
+
+    #[test]
+    fn test_scalar_comparison() {
+        // the parser rejects it, but it can be built by hand.
+        let expr = Expr::Binary(BinaryExpr {
+            op: TokenType::new(T_GTR),
+            lhs: Box::new(Expr::from(1.0)),
+            rhs: Box::new(Expr::from(2.0)),
+            modifier: None,
+        });
+        let lints = lint(&expr, &LintOptions::new());
+        assert_eq!(lints.len(), 1);
+        assert_eq!(lints[0].kind, LintKind::ScalarComparisonWithoutBool);
+        assert_eq!(apply_fixes(&expr, &lints), parse("1 > bool 2").unwrap());
+    }
+
+    #[test]
+    fn test_lint_query_duplicate_labels() {
+        let lints = |query: &str| -> Vec<(String, String)> {
+            lint_query(query, &LintOptions::new())
+                .unwrap()
+                .into_iter()
+                .filter(|l| l.kind == LintKind::DuplicateGroupingLabel)
+                .map(|l| (l.message, l.node.to_string()))
+                .collect()
+        };
+        assert!(lints("sum by (job, instance) (foo)").is_empty());
+        assert!(lints("foo + on (a) group_left (b) bar").is_empty());
+        assert_eq!(
+            lints("sum(sum(foo) by (a, a)) by (a)"),
+            vec![(
+                "label a is listed more than once in by (a, a)".to_string(),
+                "sum by (a) (foo)".to_string()
+            )]
+        );
+        assert_eq!(
+            lints("foo + ignoring (a, b, a, b, a) group_right (c, c) bar"),
+            vec![
+                (
+                    "label a is listed more than once in ignoring (a, b, a, b, a)".to_string(),
+                    "foo + ignoring (a, b) group_right (c) bar".to_string()
+                ),
+                (
+                    "label b is listed more than once in ignoring (a, b, a, b, a)".to_string(),
+                    "foo + ignoring (a, b) group_right (c) bar".to_string()
+                ),
+                (
+                    "label c is listed more than once in group_right (c, c)".to_string(),
+                    "foo + ignoring (a, b) group_right (c) bar".to_string()
+                ),
+            ]
+        );
+        assert_eq!(
+            lints("sum without (a, a) (foo) + sum without (a, a) (bar)")
+                .into_iter()
+                .map(|(_, node)| node)
+                .collect::<Vec<_>>(),
+            vec!["sum without (a) (foo)", "sum without (a) (bar)"]
+        );
+        assert!(lint_query("sum by (a, a) (", &LintOptions::new()).is_err());
+    }
+
+    #[test]
+    fn test_apply_fixes() {
+        let cases = vec![
+            ("sum(foo_total)", "sum(rate(foo_total[1m]))"),
+            (
+                "sum(foo_total offset 1h) + sum(foo_total offset 1h)",
+                "sum(rate(foo_total[1m] offset 1h)) + sum(rate(foo_total[1m] offset 1h))",
+            ),
+            ("rate(foo[10s]) > 1", "rate(foo[1m]) > 1"),
+            ("irate(foo[10s])", "irate(foo[30s])"),
+        ];
+        for (query, fixed) in cases {
+            let expr = parse(query).unwrap();
+            let lints = lint(&expr, &LintOptions::new());
+            assert_eq!(apply_fixes(&expr, &lints), parse(fixed).unwrap(), "{query}");
+            for lint in &lints {
+                assert_eq!(expr.get_node(lint.id), Some(&lint.node), "{query}");
+            }
+        }
+
+        // only the node of the lint is fixed, not the equal subtrees
+        let expr = parse("sum(foo_total offset 1h) + sum(foo_total offset 1h)").unwrap();
+        let lints = lint(&expr, &LintOptions::new());
+        assert_eq!(
+            apply_fixes(&expr, &lints[1..]).to_string(),
+            "sum(foo_total offset 1h) + sum(rate(foo_total[1m] offset 1h))"
+        );
+    }
+}
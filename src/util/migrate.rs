@@ -0,0 +1,637 @@
+// Copyright 2023 Greptime Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! rewrites migrating queries to newer PromQL features.
+
+use crate::label::{MatchOp, Matcher, BUCKET_LABEL, METRIC_NAME};
+use crate::parser::function::get_function;
+use crate::parser::token::T_SUM;
+use crate::parser::{Call, Expr, FunctionArgs, LabelModifier, NodeId, VectorSelector};
+use crate::util::escape::map_names;
+use std::cell::Cell;
+
+/// functions over counters which also work on native histograms.
+const COUNTER_FUNCTIONS: [&str; 3] = ["rate", "increase", "irate"];
+
+/// rewrite the queries over classic histograms to native histograms.
+/// `is_histogram` tells whether a metric, like `foo` for `foo_bucket`, has
+/// been migrated to a native histogram.
+///
+/// - `histogram_quantile(0.9, sum by (le) (rate(foo_bucket[5m])))` becomes
+///   `histogram_quantile(0.9, sum(rate(foo[5m])))`, and the same for `histogram_fraction`.
+/// - `rate(foo_count[5m])` becomes `histogram_count(rate(foo[5m]))`, and `foo_sum`
+///   becomes `histogram_sum(foo)`.
+///
+/// Other uses of the series, like a `le` matcher or aggregations other than `sum`, are left untouched, as they
+/// have no direct native equivalent.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::parse;
+/// use promql_parser::util::migrate::native_histograms;
+///
+/// let expr = parse(
+///     "histogram_quantile(0.9, sum by (job, le) (rate(foo_bucket[5m]))) \
+///      / (rate(foo_sum[5m]) / rate(foo_count[5m]))",
+/// )
+/// .unwrap();
+/// assert_eq!(
+///     native_histograms(&expr, |name| name == "foo").to_string(),
+///     "histogram_quantile(0.9, sum by (job) (rate(foo[5m]))) \
+///      / (histogram_sum(rate(foo[5m])) / histogram_count(rate(foo[5m])))"
+/// );
+/// ```
+pub fn native_histograms(expr: &Expr, is_histogram: impl Fn(&str) -> bool) -> Expr {
+    let mut expr = expr.clone();
+    migrate_histograms(&mut expr, &is_histogram);
+    expr
+}
+
+/// rename the deprecated functions to their replacements, like `holt_winters`
+/// to `double_exponential_smoothing`, as listed in the function registry.
+///
+/// the replacement may be an experimental function, which has to be enabled
+/// by [`ParseOptions::with_experimental_functions`] to parse the result.
+///
+/// [`ParseOptions::with_experimental_functions`]: crate::parser::ParseOptions::with_experimental_functions
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::parse;
+/// use promql_parser::util::migrate::deprecated_functions;
+///
+/// let expr = parse("holt_winters(rate(foo[5m])[10m:], 0.5, 0.5) > 0").unwrap();
+/// assert_eq!(
+///     deprecated_functions(&expr).to_string(),
+///     "double_exponential_smoothing(rate(foo[5m])[10m:], 0.5, 0.5) > 0"
+/// );
+/// ```
+pub fn deprecated_functions(expr: &Expr) -> Expr {
+    let mut expr = expr.clone();
+    rename_deprecated(&mut expr);
+    expr
+}
+
+/// rename the label everywhere its name is written: the matchers, the `by`,
+/// `without`, `on`, `ignoring` and `group_left`/`group_right` labels, the label
+/// of `count_values` and the label arguments of `label_replace`, `label_join`,
+/// `sort_by_label` and `sort_by_label_desc`. Metric names are left untouched,
+/// also if they are the same as the label.
+///
+/// fails if the query uses both labels, as the renamed label would be merged
+/// with the one already there, like `on (env) group_right (environment)`.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::parse;
+/// use promql_parser::util::migrate::rename_label;
+///
+/// let expr = parse(
+///     r#"sum by (env) (rate(foo{env="prod"}[5m])) / on (env) group_left (team) bar"#,
+/// )
+/// .unwrap();
+/// assert_eq!(
+///     rename_label(&expr, "env", "environment").unwrap().to_string(),
+///     r#"sum by (environment) (rate(foo{environment="prod"}[5m])) / on (environment) group_left (team) bar"#
+/// );
+///
+/// let expr = parse("foo * on (env) group_right (environment) bar").unwrap();
+/// assert!(rename_label(&expr, "env", "environment").is_err());
+/// ```
+pub fn rename_label(expr: &Expr, from: &str, to: &str) -> Result<Expr, String> {
+    let mut expr = expr.clone();
+    let (has_from, has_to) = (Cell::new(false), Cell::new(false));
+    let label = |name: &str| match name == from {
+        true => {
+            has_from.set(true);
+            to.to_string()
+        }
+        false => {
+            has_to.set(has_to.get() || name == to);
+            name.to_string()
+        }
+    };
+    map_names(&mut expr, &str::to_string, &label);
+    if has_from.get() && has_to.get() {
+        return Err(format!(
+            "can not rename label {from:?} to {to:?}, which is already used in the query"
+        ));
+    }
+    Ok(expr)
+}
+
+/// the result of [`rename_label_values`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValueRenames {
+    /// the query with the renamed values.
+    pub expr: Expr,
+    /// the regex matchers of the label which match an old value and its new value
+    /// differently, but are no literal alternation like `a|b`, so they could not be
+    /// rewritten and are left as they are, by the id of their selector.
+    pub skipped: Vec<(NodeId, Matcher)>,
+}
+
+/// rename the values of the label in its matchers, with the `(old, new)` pairs
+/// of `renames`, like a renamed cluster. The values of `=` and `!=` matchers are
+/// replaced, and so are the alternatives of regex matchers written as a literal
+/// alternation like `=~"a|b"`, where a new value is escaped if it has regex
+/// metacharacters. Other regex matchers are kept, and reported in
+/// [`ValueRenames::skipped`] if the rename changes what they match, which like in
+/// Prometheus is a match of the whole value.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::parse;
+/// use promql_parser::util::migrate::rename_label_values;
+///
+/// let expr = parse(r#"foo{cluster="eu1"} / bar{cluster=~"eu1|us1"} + baz{cluster=~"eu.*"}"#)
+///     .unwrap();
+/// let renamed = rename_label_values(&expr, "cluster", &[("eu1", "eu-west-1")]);
+/// assert_eq!(
+///     renamed.expr.to_string(),
+///     r#"foo{cluster="eu-west-1"} / bar{cluster=~"eu-west-1|us1"} + baz{cluster=~"eu.*"}"#
+/// );
+/// assert!(renamed.skipped.is_empty());
+/// ```
+pub fn rename_label_values(expr: &Expr, label: &str, renames: &[(&str, &str)]) -> ValueRenames {
+    let skipped = expr
+        .nodes()
+        .into_iter()
+        .flat_map(|(id, node)| {
+            let vs = match node {
+                Expr::VectorSelector(vs) => Some(vs),
+                Expr::MatrixSelector(ms) => Some(&ms.vector_selector),
+                _ => None,
+            };
+            vs.into_iter()
+                .flat_map(|vs| vs.matchers.matchers.iter())
+                .filter(|m| m.name == label && rename_value(m, renames).is_none())
+                .map(move |m| (id, m.clone()))
+        })
+        .collect();
+    let mut expr = expr.clone();
+    rename_values(&mut expr, label, renames);
+    ValueRenames { expr, skipped }
+}
+
+fn rename_values(expr: &mut Expr, label: &str, renames: &[(&str, &str)]) {
+    let vs = match expr {
+        Expr::VectorSelector(vs) => vs,
+        Expr::MatrixSelector(ms) => &mut ms.vector_selector,
+        _ => {
+            for child in expr.children_mut() {
+                rename_values(child, label, renames);
+            }
+            return;
+        }
+    };
+    vs.matchers.matchers = vs
+        .matchers
+        .matchers
+        .drain()
+        .map(|m| match m.name == label {
+            true => rename_value(&m, renames).unwrap_or(m),
+            false => m,
+        })
+        .collect();
+}
+
+/// the matcher with the renamed values, None if it is a regex which can not be
+/// rewritten and matches differently after the rename.
+fn rename_value(m: &Matcher, renames: &[(&str, &str)]) -> Option<Matcher> {
+    let new_value = |value: &str| {
+        renames
+            .iter()
+            .find(|(old, _)| *old == value)
+            .map(|(_, new)| *new)
+    };
+    match &m.op {
+        MatchOp::Equal | MatchOp::NotEqual => {
+            let value = new_value(&m.value).unwrap_or(&m.value);
+            Some(Matcher::new(
+                m.op.clone(),
+                m.name.clone(),
+                value.to_string(),
+            ))
+        }
+        MatchOp::Re(_) | MatchOp::NotRe(_) if !m.value.split('|').all(is_literal) => renames
+            .iter()
+            .all(|(old, new)| m.is_full_match(old) == m.is_full_match(new))
+            .then(|| m.clone()),
+        MatchOp::Re(_) | MatchOp::NotRe(_) => {
+            let mut values: Vec<String> = vec![];
+            for value in m.value.split('|') {
+                let value = match new_value(value) {
+                    Some(new) if !is_literal(new) => regex::escape(new),
+                    new => new.unwrap_or(value).to_string(),
+                };
+                if !values.contains(&value) {
+                    values.push(value);
+                }
+            }
+            let value = values.join("|");
+            match (&m.op, value == m.value) {
+                (_, true) => Some(m.clone()),
+                (MatchOp::Re(_), false) => Matcher::re(m.name.clone(), value).ok(),
+                _ => Matcher::not_re(m.name.clone(), value).ok(),
+            }
+        }
+    }
+}
+
+/// whether the regex matches exactly the string, without any special characters.
+fn is_literal(re: &str) -> bool {
+    !re.is_empty() && !re.contains(|c| r".*+?()[]{}|^$\".contains(c))
+}
+
+fn rename_deprecated(expr: &mut Expr) {
+    if let Expr::Call(call) = expr {
+        if let Some(func) = call.func.replacement.and_then(get_function) {
+            call.func = func;
+        }
+    }
+    for child in expr.children_mut() {
+        rename_deprecated(child);
+    }
+}
+
+fn migrate_histograms(expr: &mut Expr, is_histogram: &impl Fn(&str) -> bool) {
+    match expr {
+        Expr::Call(call) if is_bucket_function(call.func.name) => {
+            if let Some(arg) = call.args.args.last_mut() {
+                let mut native = (**arg).clone();
+                if strip_buckets(&mut native, is_histogram) {
+                    **arg = native;
+                }
+            }
+        }
+        Expr::Call(call) if COUNTER_FUNCTIONS.contains(&call.func.name) => {
+            if let Some(Expr::MatrixSelector(ms)) = call.args.args.first().map(|e| &**e) {
+                if let Some((func, base)) = sum_or_count(&ms.vector_selector, is_histogram) {
+                    let mut native = expr.clone();
+                    if let Expr::Call(call) = &mut native {
+                        if let Some(Expr::MatrixSelector(ms)) =
+                            call.args.args.first_mut().map(|e| &mut **e)
+                        {
+                            rename_selector(&mut ms.vector_selector, &base);
+                        }
+                    }
+                    *expr = wrap(func, native);
+                }
+            }
+            return;
+        }
+        Expr::VectorSelector(vs) => {
+            if let Some((func, base)) = sum_or_count(vs, is_histogram) {
+                let mut vs = vs.clone();
+                rename_selector(&mut vs, &base);
+                *expr = wrap(func, Expr::VectorSelector(vs));
+            }
+            return;
+        }
+        _ => {}
+    }
+    for child in expr.children_mut() {
+        migrate_histograms(child, is_histogram);
+    }
+}
+
+fn is_bucket_function(name: &str) -> bool {
+    name == "histogram_quantile" || name == "histogram_fraction"
+}
+
+/// remove the `le` grouping and the `_bucket` suffix of the classic histogram,
+/// false if the expr is not of the known shape, and it may be partially changed.
+fn strip_buckets(expr: &mut Expr, is_histogram: &impl Fn(&str) -> bool) -> bool {
+    match expr {
+        Expr::Aggregate(agg) => {
+            let keeps_le = match &mut agg.modifier {
+                Some(LabelModifier::Include(labels)) => labels.remove(BUCKET_LABEL),
+                Some(LabelModifier::Exclude(labels)) => !labels.contains(BUCKET_LABEL),
+                None => false,
+            };
+            if matches!(&agg.modifier, Some(LabelModifier::Include(labels)) if labels.is_empty()) {
+                agg.modifier = None;
+            }
+            agg.op.id() == T_SUM && keeps_le && strip_buckets(&mut agg.expr, is_histogram)
+        }
+        Expr::Paren(paren) => strip_buckets(&mut paren.expr, is_histogram),
+        Expr::Call(call) if COUNTER_FUNCTIONS.contains(&call.func.name) => {
+            match call.args.args.first_mut().map(|e| &mut **e) {
+                Some(Expr::MatrixSelector(ms)) => {
+                    strip_bucket_selector(&mut ms.vector_selector, is_histogram)
+                }
+                _ => false,
+            }
+        }
+        Expr::VectorSelector(vs) => strip_bucket_selector(vs, is_histogram),
+        _ => false,
+    }
+}
+
+fn strip_bucket_selector(vs: &mut VectorSelector, is_histogram: &impl Fn(&str) -> bool) -> bool {
+    let base = match vs
+        .metric_name()
+        .and_then(|name| name.strip_suffix("_bucket"))
+    {
+        Some(base) if is_histogram(base) => base.to_string(),
+        _ => return false,
+    };
+    if vs.matchers.matchers.iter().any(|m| m.name == BUCKET_LABEL) {
+        return false;
+    }
+    rename_selector(vs, &base);
+    true
+}
+
+/// the function replacing the `_sum` or `_count` series, with the name of the histogram.
+fn sum_or_count(
+    vs: &VectorSelector,
+    is_histogram: &impl Fn(&str) -> bool,
+) -> Option<(&'static str, String)> {
+    let name = vs.metric_name()?;
+    let (func, base) = match (name.strip_suffix("_sum"), name.strip_suffix("_count")) {
+        (Some(base), _) => ("histogram_sum", base),
+        (_, Some(base)) => ("histogram_count", base),
+        _ => return None,
+    };
+    is_histogram(base).then(|| (func, base.to_string()))
+}
+
+/// rename the metric of the selector, in both the name and the `__name__` matcher.
+fn rename_selector(vs: &mut VectorSelector, name: &str) {
+    let had_matcher = vs.matchers.matchers.iter().any(|m| m.name == METRIC_NAME);
+    vs.matchers.matchers.retain(|m| m.name != METRIC_NAME);
+    if had_matcher {
+        vs.matchers.matchers.insert(Matcher::eq(METRIC_NAME, name));
+    }
+    if vs.name.is_some() {
+        vs.name = Some(name.to_string());
+    }
+}
+
+fn wrap(func: &str, expr: Expr) -> Expr {
+    let func = get_function(func).expect("histogram functions are builtin");
+    Expr::Call(Call {
+        func,
+        args: FunctionArgs::new_args(expr),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{parse, parse_with_options, ParseOptions};
+
+    #[test]
+    fn test_native_histograms() {
+        let is_histogram = |name: &str| name == "foo" || name == "bar";
+        let cases = vec![
+            (
+                "histogram_quantile(0.9, sum by (le) (rate(foo_bucket[5m])))",
+                "histogram_quantile(0.9, sum(rate(foo[5m])))",
+            ),
+            (
+                "histogram_quantile(0.9, sum(rate(foo_bucket[5m])) by (job, le))",
+                "histogram_quantile(0.9, sum by (job) (rate(foo[5m])))",
+            ),
+            (
+                "histogram_quantile(0.9, sum without (instance) (increase(foo_bucket[1h])))",
+                "histogram_quantile(0.9, sum without (instance) (increase(foo[1h])))",
+            ),
+            (
+                r#"histogram_quantile(0.5, rate({__name__="foo_bucket", job="a"}[5m]))"#,
+                r#"histogram_quantile(0.5, rate({__name__="foo", job="a"}[5m]))"#,
+            ),
+            (
+                "histogram_fraction(0, 0.2, sum by (le) ((rate(bar_bucket[5m]))))",
+                "histogram_fraction(0, 0.2, sum((rate(bar[5m]))))",
+            ),
+            (
+                "sum(rate(foo_sum[5m])) / sum(rate(foo_count[5m]))",
+                "sum(histogram_sum(rate(foo[5m]))) / sum(histogram_count(rate(foo[5m])))",
+            ),
+            ("foo_count > 0", "histogram_count(foo) > 0"),
+            // not a histogram, or not a known shape
+            (
+                "histogram_quantile(0.9, sum by (le) (rate(baz_bucket[5m])))",
+                "histogram_quantile(0.9, sum by (le) (rate(baz_bucket[5m])))",
+            ),
+            (
+                "histogram_quantile(0.9, sum(rate(foo_bucket[5m])))",
+                "histogram_quantile(0.9, sum(rate(foo_bucket[5m])))",
+            ),
+            (
+                r#"histogram_quantile(0.9, sum by (le) (rate(foo_bucket{le="1"}[5m])))"#,
+                r#"histogram_quantile(0.9, sum by (le) (rate(foo_bucket{le="1"}[5m])))"#,
+            ),
+            (
+                "histogram_quantile(0.9, max by (le) (rate(foo_bucket[5m])))",
+                "histogram_quantile(0.9, max by (le) (rate(foo_bucket[5m])))",
+            ),
+            ("rate(baz_count[5m])", "rate(baz_count[5m])"),
+            (
+                "max_over_time(foo_count[5m])",
+                "max_over_time(foo_count[5m])",
+            ),
+            ("foo_bucket", "foo_bucket"),
+        ];
+        for (query, expected) in cases {
+            let expr = native_histograms(&parse(query).unwrap(), is_histogram);
+            assert_eq!(expr, parse(expected).unwrap(), "{query}");
+        }
+    }
+
+    #[test]
+    fn test_rename_label() {
+        let options = ParseOptions::new().with_experimental_functions(true);
+        let cases = vec![
+            (
+                r#"env{env="a", env!~"b.*", job="env"}"#,
+                r#"env{environment="a", environment!~"b.*", job="env"}"#,
+            ),
+            (
+                "sum without (env, job) (rate(foo[5m] offset 1m)) > bool on (env) bar",
+                "sum without (environment, job) (rate(foo[5m] offset 1m)) > bool on (environment) bar",
+            ),
+            (
+                "foo * ignoring (env) group_right (env, team) bar",
+                "foo * ignoring (environment) group_right (environment, team) bar",
+            ),
+            (
+                r#"count_values("env", foo) or count_values("job", foo)"#,
+                r#"count_values("environment", foo) or count_values("job", foo)"#,
+            ),
+            (
+                r#"label_replace(foo, "env", "$1", "env", "(.*)")"#,
+                r#"label_replace(foo, "environment", "$1", "environment", "(.*)")"#,
+            ),
+            (
+                r#"label_join(foo, "env", "-", "env", "job")"#,
+                r#"label_join(foo, "environment", "-", "environment", "job")"#,
+            ),
+            (
+                r#"sort_by_label_desc(sort_by_label(foo, "env", "job"), "env")"#,
+                r#"sort_by_label_desc(sort_by_label(foo, "environment", "job"), "environment")"#,
+            ),
+            // the replacement and the regex are not label names
+            (
+                r#"label_replace(foo, "dst", "env", "src", "env")"#,
+                r#"label_replace(foo, "dst", "env", "src", "env")"#,
+            ),
+            (
+                r#"max_over_time(foo{env="a"}[1h:]) + topk(1, bar) by (env)"#,
+                r#"max_over_time(foo{environment="a"}[1h:]) + topk(1, bar) by (environment)"#,
+            ),
+        ];
+        for (query, expected) in cases {
+            let expr = parse_with_options(query, &options).unwrap();
+            let expr = rename_label(&expr, "env", "environment").unwrap();
+            assert_eq!(
+                expr,
+                parse_with_options(expected, &options).unwrap(),
+                "{query}"
+            );
+        }
+
+        // the query already uses the new label
+        for query in [
+            "foo * on (env) group_right (environment) bar",
+            r#"foo{env="a", environment="b"}"#,
+            r#"sort_by_label(sum by (env, environment) (foo), "env")"#,
+        ] {
+            assert_eq!(
+                rename_label(&parse_with_options(query, &options).unwrap(), "env", "environment"),
+                Err(r#"can not rename label "env" to "environment", which is already used in the query"#.into()),
+                "{query}"
+            );
+        }
+        // which is fine if the old label is not used
+        let expr = parse(r#"foo{environment="b"}"#).unwrap();
+        assert_eq!(rename_label(&expr, "env", "environment"), Ok(expr));
+    }
+
+    #[test]
+    fn test_rename_label_values() {
+        let renames = [("eu1", "eu-west-1"), ("us1", "us-east-1")];
+        let cases = vec![
+            (
+                r#"foo{cluster="eu1", job="eu1"} + rate(bar{cluster!="us1"}[5m])"#,
+                r#"foo{cluster="eu-west-1", job="eu1"} + rate(bar{cluster!="us-east-1"}[5m])"#,
+                vec![],
+            ),
+            (
+                r#"foo{cluster=~"eu1|ap1"} or foo{cluster!~"us1|eu1"}"#,
+                r#"foo{cluster=~"eu-west-1|ap1"} or foo{cluster!~"us-east-1|eu-west-1"}"#,
+                vec![],
+            ),
+            // both values renamed to the same value
+            (
+                r#"foo{cluster=~"eu1|eu-west-1"}"#,
+                r#"foo{cluster=~"eu-west-1"}"#,
+                vec![],
+            ),
+            // a regex which matches the renames the same way is kept
+            (r#"foo{cluster=~".+1"}"#, r#"foo{cluster=~".+1"}"#, vec![]),
+            (
+                r#"sum(foo{cluster=~"eu.*"}) / sum(max_over_time(foo{cluster=~"(eu|us)1"}[5m]))"#,
+                r#"sum(foo{cluster=~"eu.*"}) / sum(max_over_time(foo{cluster=~"(eu|us)1"}[5m]))"#,
+                vec![(5, r#"cluster=~"(eu|us)1""#)],
+            ),
+            (
+                r#"foo{cluster!~"eu1|a.c"}"#,
+                r#"foo{cluster!~"eu1|a.c"}"#,
+                vec![(0, r#"cluster!~"eu1|a.c""#)],
+            ),
+        ];
+        for (query, expected, skipped) in cases {
+            let renamed = rename_label_values(&parse(query).unwrap(), "cluster", &renames);
+            assert_eq!(renamed.expr, parse(expected).unwrap(), "{query}");
+            assert_eq!(parse(&renamed.expr.to_string()).unwrap(), renamed.expr);
+            let skipped: Vec<_> = skipped
+                .into_iter()
+                .map(|(id, m)| (id, m.to_string()))
+                .collect();
+            let actual: Vec<_> = renamed
+                .skipped
+                .iter()
+                .map(|(id, m)| (id.index(), m.to_string()))
+                .collect();
+            assert_eq!(actual, skipped, "{query}");
+        }
+
+        // the regex must match the whole value, so it matches eu1 but not xeu1x
+        let expr = parse(r#"foo{cluster=~"e.1"}"#).unwrap();
+        let renamed = rename_label_values(&expr, "cluster", &[("eu1", "xeu1x")]);
+        assert_eq!(renamed.expr, expr);
+        assert_eq!(renamed.skipped.len(), 1);
+
+        // a new value with regex metacharacters is escaped in regex matchers
+        let expr = parse(r#"foo{cluster=~"eu1|us1"} + foo{cluster="eu1"}"#).unwrap();
+        let renamed = rename_label_values(&expr, "cluster", &[("eu1", "eu.west")]);
+        assert_eq!(
+            renamed.expr.to_string(),
+            r#"foo{cluster=~"eu\\.west|us1"} + foo{cluster="eu.west"}"#
+        );
+        assert!(renamed.skipped.is_empty());
+        let (_, Expr::VectorSelector(vs)) = renamed.expr.nodes()[1] else {
+            unreachable!()
+        };
+        let m = vs.matchers.matchers.iter().find(|m| m.name == "cluster");
+        assert!(m.unwrap().is_full_match("eu.west"));
+        assert!(!m.unwrap().is_full_match("euxwest"));
+
+        // and the query written back matches the same
+        let reparsed = parse(&renamed.expr.to_string()).unwrap();
+        assert_eq!(reparsed, renamed.expr);
+        let (_, Expr::VectorSelector(vs)) = reparsed.nodes()[1] else {
+            unreachable!()
+        };
+        let m = vs.matchers.matchers.iter().find(|m| m.name == "cluster");
+        assert!(m.unwrap().is_full_match("eu.west"));
+        assert!(!m.unwrap().is_full_match("euxwest"));
+    }
+
+    #[test]
+    fn test_deprecated_functions() {
+        let options = ParseOptions::default().with_experimental_functions(true);
+        let cases = vec![
+            (
+                "holt_winters(foo[5m], 0.3, 0.7)",
+                "double_exponential_smoothing(foo[5m], 0.3, 0.7)",
+            ),
+            (
+                "sum by (job) (holt_winters(holt_winters(foo[5m], 0.3, 0.7)[1h:], 0.1, 0.1))",
+                "sum by (job) (double_exponential_smoothing(double_exponential_smoothing(foo[5m], 0.3, 0.7)[1h:], 0.1, 0.1))",
+            ),
+            (
+                "topk(scalar(holt_winters(foo[5m], 0.3, 0.7)), bar)",
+                "topk(scalar(double_exponential_smoothing(foo[5m], 0.3, 0.7)), bar)",
+            ),
+            ("rate(foo[5m]) + holt_winters", "rate(foo[5m]) + holt_winters"),
+        ];
+        for (query, expected) in cases {
+            let expr = deprecated_functions(&parse(query).unwrap());
+            assert_eq!(
+                expr,
+                parse_with_options(expected, &options).unwrap(),
+                "{query}"
+            );
+        }
+    }
+}
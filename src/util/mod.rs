@@ -14,10 +14,37 @@
 
 //! Internal utilities for parser.
 
+pub mod annotations;
+pub mod capabilities;
+pub mod complete;
+pub mod conversion;
+pub mod diagnostic;
 pub mod duration;
+pub mod escape;
+pub mod format;
+#[cfg(feature = "url")]
+pub mod http;
+pub mod intern;
+pub mod lint;
+pub mod migrate;
 pub mod number;
+pub mod patterns;
+pub mod propagation;
+pub mod pushdown;
+pub mod rewrite;
+#[cfg(feature = "rules")]
+pub mod rules;
+pub mod schedule;
+pub mod selectivity;
+pub mod series;
+pub mod shape;
+pub mod source_map;
+pub mod step;
+pub mod subquery;
+pub mod summary;
+pub mod template;
 mod visitor;
 
-pub use duration::parse_duration;
+pub use duration::{display_duration, parse_duration};
 pub use number::parse_str_radix;
-pub use visitor::{walk_expr, ExprVisitor};
+pub use visitor::{walk_expr, walk_expr_with_ancestors, AncestorVisitor, Ancestors, ExprVisitor};
@@ -12,9 +12,13 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-/// parse str radix from golang format, but: if 8 or 9 is included
-/// in octal literal, it will be treated as decimal literal.
-/// This function panics if str is not dec, oct, hex format
+/// parse str radix from golang format, which mirrors how Prometheus parses number
+/// literals: the input is first parsed as an integer, where `0x` prefix means hex
+/// and leading `0` means octal, and if that fails it falls back to a float.
+///
+/// This means that if 8 or 9 is included in octal literal, or the octal literal
+/// contains a fraction or an exponent, it will be treated as decimal literal.
+/// Special values `Inf` and `NaN` are case-insensitive.
 pub fn parse_str_radix(s: &str) -> Result<f64, String> {
     let st: String = s
         .chars()
@@ -22,50 +26,63 @@ pub fn parse_str_radix(s: &str) -> Result<f64, String> {
         .filter(|c| !c.is_whitespace())
         .collect();
 
-    let mut is_not_decimal = false;
-    if st.contains('x') {
-        is_not_decimal = true;
+    if let Some(i) = parse_int_radix(&st) {
+        return Ok(i as f64);
     }
 
-    if !is_not_decimal
-        && (st.starts_with("-0") || st.starts_with("+0") || st.starts_with('0'))
-        && !st.contains('.')
-        && !st.contains('8')
-        && !st.contains('9')
-        && !st.eq("0")
-        && !st.eq("+0")
-        && !st.eq("-0")
-    {
-        is_not_decimal = true;
+    if is_hex_literal(&st) {
+        return Err(format!("ParseFloatError. {s} can't be parsed into i64"));
     }
 
-    if is_not_decimal {
-        let i = if st.starts_with("-0x") {
-            i64::from_str_radix(st.strip_prefix("-0x").unwrap(), 16).map(|x| -x)
-        } else if st.starts_with("+0x") {
-            i64::from_str_radix(st.strip_prefix("+0x").unwrap(), 16)
-        } else if st.starts_with("0x") {
-            i64::from_str_radix(st.strip_prefix("0x").unwrap(), 16)
-        } else if st.starts_with("-0") {
-            i64::from_str_radix(st.strip_prefix("-0").unwrap(), 8).map(|x| -x)
-        } else if st.starts_with("+0") {
-            i64::from_str_radix(st.strip_prefix("+0").unwrap(), 8)
-        } else {
-            i64::from_str_radix(st.strip_prefix('0').unwrap(), 8) // starts with '0'
-        };
-        return i
-            .map(|x| x as f64)
-            .map_err(|_| format!("ParseFloatError. {s} can't be parsed into i64"));
-    }
     st.parse()
         .map_err(|_| format!("ParseFloatError. {s} can't be parsed into f64"))
 }
 
+/// split the optional sign from the literal, return true if it is negative.
+fn split_sign(s: &str) -> (bool, &str) {
+    if let Some(st) = s.strip_prefix('-') {
+        (true, st)
+    } else if let Some(st) = s.strip_prefix('+') {
+        (false, st)
+    } else {
+        (false, s)
+    }
+}
+
+fn is_hex_literal(s: &str) -> bool {
+    split_sign(s).1.starts_with("0x")
+}
+
+/// the same as `strconv.ParseInt(s, 0, 64)` in golang, without underscore support.
+fn parse_int_radix(s: &str) -> Option<i64> {
+    let (neg, st) = split_sign(s);
+    let (digits, radix) = if let Some(hex) = st.strip_prefix("0x") {
+        (hex, 16)
+    } else if st.len() > 1 && st.starts_with('0') {
+        (&st[1..], 8)
+    } else {
+        (st, 10)
+    };
+
+    // from_str_radix accepts a sign, which is not expected after the prefix
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return None;
+    }
+
+    let i = i64::from_str_radix(digits, radix).ok()?;
+    if neg {
+        Some(-i)
+    } else {
+        Some(i)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
+    #[allow(clippy::approx_constant)]
     fn test_parse_str_radix() {
         assert_eq!(parse_str_radix("0x2f").unwrap(), 47_f64);
         assert_eq!(parse_str_radix("+0x2f").unwrap(), 47_f64);
@@ -86,7 +103,29 @@ mod tests {
         assert_eq!(parse_str_radix("-0").unwrap(), 0_f64);
         assert_eq!(parse_str_radix("0").unwrap(), 0_f64);
 
+        assert_eq!(parse_str_radix("0X1F").unwrap(), 31_f64);
+        assert_eq!(parse_str_radix("0x1e").unwrap(), 30_f64);
+        assert_eq!(parse_str_radix("0755e1").unwrap(), 7550_f64);
+        assert_eq!(parse_str_radix("0755.5").unwrap(), 755.5_f64);
+        assert_eq!(parse_str_radix("0e1").unwrap(), 0_f64);
+        assert_eq!(parse_str_radix("00").unwrap(), 0_f64);
+        assert_eq!(parse_str_radix(".5e1").unwrap(), 5_f64);
+        assert_eq!(parse_str_radix("5.").unwrap(), 5_f64);
+        assert_eq!(parse_str_radix("1e+3").unwrap(), 1000_f64);
+        assert_eq!(
+            parse_str_radix("0777777777777777777777777").unwrap(),
+            777777777777777777777777_f64
+        );
+        assert_eq!(parse_str_radix("Inf").unwrap(), f64::INFINITY);
+        assert_eq!(parse_str_radix("-iNF").unwrap(), f64::NEG_INFINITY);
+        assert!(parse_str_radix("NaN").unwrap().is_nan());
+        assert!(parse_str_radix("nan").unwrap().is_nan());
+
         assert!(parse_str_radix("rust").is_err());
+        assert!(parse_str_radix("0x").is_err());
+        assert!(parse_str_radix("0x1.8").is_err());
+        assert!(parse_str_radix("0xffffffffffffffffff").is_err());
+        assert!(parse_str_radix("5e").is_err());
         assert!(parse_str_radix("0xgolang").is_err());
         assert!(parse_str_radix("0clojure").is_err());
     }
@@ -0,0 +1,224 @@
+// Copyright 2023 Greptime Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! constructors of common query patterns, like the error ratio of a service.
+
+use crate::label::{Labels, BUCKET_LABEL};
+use crate::parser::function::get_function;
+use crate::parser::token::{T_DIV, T_LSS, T_SUB, T_SUM};
+use crate::parser::{type_check, Expr, FunctionArgs, LabelModifier, VectorSelector};
+use std::time::Duration;
+
+/// the ratio of the rate of the errors to the rate of all requests, like
+/// `sum(rate(errors[5m])) / sum(rate(requests[5m]))`.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::label::Matcher;
+/// use promql_parser::parser::VectorSelector;
+/// use promql_parser::util::patterns::error_ratio;
+/// use std::time::Duration;
+///
+/// let errors = VectorSelector::builder("http_requests_total")
+///     .matcher(Matcher::re("code", "5..").unwrap())
+///     .build()
+///     .unwrap();
+/// let total = VectorSelector::from("http_requests_total");
+/// let expr = error_ratio(errors, total, Duration::from_secs(300)).unwrap();
+/// assert_eq!(
+///     expr.to_string(),
+///     r#"sum(rate(http_requests_total{code=~"5.."}[5m])) / sum(rate(http_requests_total[5m]))"#
+/// );
+/// ```
+pub fn error_ratio(
+    errors: VectorSelector,
+    total: VectorSelector,
+    range: Duration,
+) -> Result<Expr, String> {
+    let expr = Expr::new_binary_expr(
+        sum_rate(errors, range, None)?,
+        T_DIV,
+        None,
+        sum_rate(total, range, None)?,
+    )?;
+    type_check(expr)
+}
+
+/// the quantile of the latency in the classic histogram `buckets`, grouped by
+/// the labels, like `histogram_quantile(0.9, sum by (le) (rate(foo_bucket[5m])))`.
+pub fn latency_quantile(
+    quantile: f64,
+    buckets: VectorSelector,
+    range: Duration,
+    by: &[&str],
+) -> Result<Expr, String> {
+    if !(0.0..=1.0).contains(&quantile) {
+        return Err(format!("quantile must be between 0 and 1, got {quantile}"));
+    }
+    let mut labels: Labels = by.iter().map(|l| l.to_string()).collect();
+    labels.insert(BUCKET_LABEL.to_string());
+    let grouping = LabelModifier::Include(labels);
+    let args = FunctionArgs::new_args(Expr::from(quantile)).push(sum_rate(
+        buckets,
+        range,
+        Some(grouping),
+    )?);
+    type_check(call("histogram_quantile", args)?)
+}
+
+/// the 99th percentile latency, see [`latency_quantile`].
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::VectorSelector;
+/// use promql_parser::util::patterns::p99_latency;
+/// use std::time::Duration;
+///
+/// let buckets = VectorSelector::from("http_request_duration_seconds_bucket");
+/// let expr = p99_latency(buckets, Duration::from_secs(300), &["job"]).unwrap();
+/// assert_eq!(
+///     expr.to_string(),
+///     "histogram_quantile(0.99, sum by (job, le) (rate(http_request_duration_seconds_bucket[5m])))"
+/// );
+/// ```
+pub fn p99_latency(buckets: VectorSelector, range: Duration, by: &[&str]) -> Result<Expr, String> {
+    latency_quantile(0.99, buckets, range, by)
+}
+
+/// the availability, one minus the [`error_ratio`], where it is below the
+/// objective, like `1 - sum(rate(errors[30d])) / sum(rate(requests[30d])) < 0.999`.
+/// The result is empty while the objective is met, so it can be the expr of an alert.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::VectorSelector;
+/// use promql_parser::util::patterns::availability_slo;
+/// use std::time::Duration;
+///
+/// let errors = VectorSelector::from("errors_total");
+/// let total = VectorSelector::from("requests_total");
+/// let expr = availability_slo(errors, total, Duration::from_secs(3600), 0.999).unwrap();
+/// assert_eq!(
+///     expr.to_string(),
+///     "1 - sum(rate(errors_total[1h])) / sum(rate(requests_total[1h])) < 0.999"
+/// );
+/// ```
+pub fn availability_slo(
+    errors: VectorSelector,
+    total: VectorSelector,
+    range: Duration,
+    objective: f64,
+) -> Result<Expr, String> {
+    if !(0.0..=1.0).contains(&objective) {
+        return Err(format!(
+            "objective must be between 0 and 1, got {objective}"
+        ));
+    }
+    let availability = Expr::new_binary_expr(
+        Expr::from(1.0),
+        T_SUB,
+        None,
+        error_ratio(errors, total, range)?,
+    )?;
+    type_check(Expr::new_binary_expr(
+        availability,
+        T_LSS,
+        None,
+        Expr::from(objective),
+    )?)
+}
+
+/// `sum(rate(vs[range]))`, with the grouping if any.
+fn sum_rate(
+    vs: VectorSelector,
+    range: Duration,
+    grouping: Option<LabelModifier>,
+) -> Result<Expr, String> {
+    let ms = Expr::new_matrix_selector(Expr::from(vs), range)?;
+    let rate = call("rate", FunctionArgs::new_args(ms))?;
+    Expr::new_aggregate_expr(T_SUM, grouping, FunctionArgs::new_args(rate))
+}
+
+fn call(name: &str, args: FunctionArgs) -> Result<Expr, String> {
+    let func = get_function(name).ok_or_else(|| format!("unknown function {name}"))?;
+    Expr::new_call(func, args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    #[test]
+    fn test_patterns() {
+        let range = Duration::from_secs(300);
+        let expr = error_ratio(
+            VectorSelector::from("errors_total"),
+            VectorSelector::from("requests_total"),
+            range,
+        )
+        .unwrap();
+        assert_eq!(
+            expr,
+            parse("sum(rate(errors_total[5m])) / sum(rate(requests_total[5m]))").unwrap()
+        );
+
+        let expr = latency_quantile(0.5, VectorSelector::from("foo_bucket"), range, &[]).unwrap();
+        assert_eq!(
+            expr,
+            parse("histogram_quantile(0.5, sum by (le) (rate(foo_bucket[5m])))").unwrap()
+        );
+        let expr = p99_latency(VectorSelector::from("foo_bucket"), range, &["job", "le"]).unwrap();
+        assert_eq!(
+            expr,
+            parse("histogram_quantile(0.99, sum by (job, le) (rate(foo_bucket[5m])))").unwrap()
+        );
+        assert_eq!(
+            latency_quantile(2.0, VectorSelector::from("foo_bucket"), range, &[]),
+            Err("quantile must be between 0 and 1, got 2".into())
+        );
+
+        let expr = availability_slo(
+            VectorSelector::from("errors_total"),
+            VectorSelector::from("requests_total"),
+            range,
+            0.99,
+        )
+        .unwrap();
+        assert_eq!(
+            expr,
+            parse("1 - sum(rate(errors_total[5m])) / sum(rate(requests_total[5m])) < 0.99")
+                .unwrap()
+        );
+        assert!(availability_slo(
+            VectorSelector::from("errors_total"),
+            VectorSelector::from("requests_total"),
+            range,
+            99.9,
+        )
+        .is_err());
+
+        // the selectors are checked like in a query
+        let offset = VectorSelector::from("errors_total")
+            .offset_expr(crate::parser::Offset::Pos(range))
+            .unwrap();
+        assert_eq!(
+            error_ratio(offset, VectorSelector::from("requests_total"), range),
+            Err("no offset modifiers allowed before range".into())
+        );
+    }
+}
@@ -0,0 +1,368 @@
+// Copyright 2023 Greptime Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! which labels can be on the series of the result of an expr, for checks like
+//! the `{{ $labels.foo }}` of an alert annotation.
+
+use crate::label::{Labels, MatchOp, METRIC_NAME};
+use crate::parser::token::{T_BOTTOMK, T_COUNT_VALUES, T_TOPK};
+use crate::parser::{
+    AggregateExpr, BinaryExpr, Call, Expr, LabelModifier, NodeId, ValueType, VectorMatchCardinality,
+};
+use std::collections::BTreeSet;
+
+/// the functions which keep the metric name, all others drop it.
+const NAME_KEEPING_FUNCTIONS: [&str; 6] = [
+    "first_over_time",
+    "label_join",
+    "label_replace",
+    "last_over_time",
+    "sort",
+    "sort_desc",
+];
+
+/// LabelSet is the labels which can be on the series of a result. The labels
+/// of the selected series are not known, so a selector can have any label.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LabelSet {
+    /// at most these labels, like the result of `sum by (job)`.
+    Only(BTreeSet<String>),
+    /// any label but these, like the result of `sum without (job)`.
+    Except(BTreeSet<String>),
+}
+
+impl LabelSet {
+    fn any() -> Self {
+        LabelSet::Except(BTreeSet::new())
+    }
+
+    fn none() -> Self {
+        LabelSet::Only(BTreeSet::new())
+    }
+
+    /// whether the label can be on the series.
+    pub fn contains(&self, label: &str) -> bool {
+        match self {
+            LabelSet::Only(labels) => labels.contains(label),
+            LabelSet::Except(labels) => !labels.contains(label),
+        }
+    }
+
+    fn keep(self, keep: &Labels) -> Self {
+        let keep = keep.iter().cloned();
+        match self {
+            LabelSet::Only(labels) => LabelSet::Only(keep.filter(|l| labels.contains(l)).collect()),
+            LabelSet::Except(labels) => {
+                LabelSet::Only(keep.filter(|l| !labels.contains(l)).collect())
+            }
+        }
+    }
+
+    fn remove<'a>(self, remove: impl IntoIterator<Item = &'a String>) -> Self {
+        match self {
+            LabelSet::Only(mut labels) => {
+                for l in remove {
+                    labels.remove(l);
+                }
+                LabelSet::Only(labels)
+            }
+            LabelSet::Except(mut labels) => {
+                labels.extend(remove.into_iter().cloned());
+                LabelSet::Except(labels)
+            }
+        }
+    }
+
+    fn insert<'a>(self, insert: impl IntoIterator<Item = &'a String>) -> Self {
+        match self {
+            LabelSet::Only(mut labels) => {
+                labels.extend(insert.into_iter().cloned());
+                LabelSet::Only(labels)
+            }
+            LabelSet::Except(mut labels) => {
+                for l in insert {
+                    labels.remove(l);
+                }
+                LabelSet::Except(labels)
+            }
+        }
+    }
+
+    fn union(self, other: Self) -> Self {
+        match (self, other) {
+            (LabelSet::Only(a), LabelSet::Only(b)) => LabelSet::Only(&a | &b),
+            (LabelSet::Only(a), LabelSet::Except(b)) | (LabelSet::Except(b), LabelSet::Only(a)) => {
+                LabelSet::Except(&b - &a)
+            }
+            (LabelSet::Except(a), LabelSet::Except(b)) => LabelSet::Except(&a & &b),
+        }
+    }
+
+    fn without_name(self) -> Self {
+        self.remove([&METRIC_NAME.to_string()])
+    }
+}
+
+/// the labels which can be on the series of the result of the expr, as the
+/// Prometheus engine propagates them. Scalars and strings have no labels.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::parse;
+/// use promql_parser::util::propagation::{result_labels, LabelSet};
+///
+/// let expr = parse(r#"sum by (job, instance) (rate(errors_total[5m])) > 0"#).unwrap();
+/// let labels = result_labels(&expr);
+/// assert!(labels.contains("instance"));
+/// assert!(!labels.contains("path"));
+///
+/// let expr = parse(r#"label_replace(up, "host", "$1", "instance", "(.*):.*") == 0"#).unwrap();
+/// let labels = result_labels(&expr);
+/// assert!(labels.contains("host"));
+/// assert!(labels.contains("__name__"));
+/// assert_eq!(result_labels(&parse("sum(up)").unwrap()), LabelSet::Only(Default::default()));
+/// ```
+pub fn result_labels(expr: &Expr) -> LabelSet {
+    if matches!(expr.value_type(), ValueType::Scalar | ValueType::String) {
+        return LabelSet::none();
+    }
+    match expr {
+        Expr::VectorSelector(_) | Expr::MatrixSelector(_) | Expr::Extension(_) => LabelSet::any(),
+        Expr::Paren(ex) => result_labels(&ex.expr),
+        Expr::Subquery(ex) => result_labels(&ex.expr),
+        Expr::Unary(ex) => result_labels(&ex.expr).without_name(),
+        Expr::Aggregate(ex) => aggregate_labels(ex),
+        Expr::Binary(ex) => binary_labels(ex),
+        Expr::Call(call) => call_labels(call),
+        Expr::NumberLiteral(_) | Expr::StringLiteral(_) => LabelSet::none(),
+    }
+}
+
+/// the [`result_labels`] of all nodes of the expr, in pre-order.
+pub fn node_result_labels(expr: &Expr) -> Vec<(NodeId, LabelSet)> {
+    expr.nodes()
+        .into_iter()
+        .map(|(id, node)| (id, result_labels(node)))
+        .collect()
+}
+
+fn aggregate_labels(ex: &AggregateExpr) -> LabelSet {
+    // topk and bottomk return the series as they are
+    if matches!(ex.op.id(), T_TOPK | T_BOTTOMK) {
+        return result_labels(&ex.expr);
+    }
+    let labels = match &ex.modifier {
+        Some(LabelModifier::Include(labels)) => result_labels(&ex.expr).keep(labels),
+        Some(LabelModifier::Exclude(labels)) => {
+            result_labels(&ex.expr).remove(labels).without_name()
+        }
+        None => LabelSet::none(),
+    };
+    match ex.param.as_deref() {
+        Some(Expr::StringLiteral(label)) if ex.op.id() == T_COUNT_VALUES => {
+            labels.insert([&label.val])
+        }
+        _ => labels,
+    }
+}
+
+fn binary_labels(ex: &BinaryExpr) -> LabelSet {
+    let (lhs, rhs) = (result_labels(&ex.lhs), result_labels(&ex.rhs));
+    let drops_name = !ex.op.is_comparison_operator() || ex.return_bool();
+    if ex.lhs.value_type() != ValueType::Vector || ex.rhs.value_type() != ValueType::Vector {
+        let labels = if ex.lhs.value_type() == ValueType::Vector {
+            lhs
+        } else {
+            rhs
+        };
+        return if drops_name {
+            labels.without_name()
+        } else {
+            labels
+        };
+    }
+
+    if ex.op.is_set_operator() {
+        return match ex.op.to_string().as_str() {
+            "or" => lhs.union(rhs),
+            _ => lhs,
+        };
+    }
+
+    let modifier = ex.modifier.as_ref();
+    let labels = match modifier.map(|m| &m.card) {
+        Some(VectorMatchCardinality::ManyToOne(include)) => lhs.insert(include),
+        Some(VectorMatchCardinality::OneToMany(include)) => rhs.insert(include),
+        _ => match modifier.and_then(|m| m.matching.as_ref()) {
+            Some(LabelModifier::Include(on)) => lhs.keep(on),
+            Some(LabelModifier::Exclude(ignoring)) => lhs.remove(ignoring),
+            None => lhs,
+        },
+    };
+    if drops_name {
+        labels.without_name()
+    } else {
+        labels
+    }
+}
+
+fn call_labels(call: &Call) -> LabelSet {
+    let name = call.func.name;
+    let arg = |idx: usize| call.args.args.get(idx).map(|arg| arg.as_ref());
+    let labels = match name {
+        "absent" | "absent_over_time" => return absent_labels(arg(0)),
+        "vector" => return LabelSet::none(),
+        "label_replace" | "label_join" => {
+            let labels = arg(0).map_or_else(LabelSet::any, result_labels);
+            match arg(1) {
+                Some(Expr::StringLiteral(dst)) => labels.insert([&dst.val]),
+                _ => labels,
+            }
+        }
+        "histogram_quantile" | "histogram_fraction" => {
+            let vector = call.args.args.last().map(|arg| arg.as_ref());
+            vector
+                .map_or_else(LabelSet::any, result_labels)
+                .remove([&"le".to_string()])
+        }
+        _ => call
+            .args
+            .args
+            .iter()
+            .map(|arg| arg.as_ref())
+            .find(|arg| matches!(arg.value_type(), ValueType::Vector | ValueType::Matrix))
+            .map_or_else(LabelSet::none, result_labels),
+    };
+    if NAME_KEEPING_FUNCTIONS.contains(&name) {
+        labels
+    } else {
+        labels.without_name()
+    }
+}
+
+/// `absent` returns the labels of the equality matchers of its selector.
+fn absent_labels(arg: Option<&Expr>) -> LabelSet {
+    let vs = match arg {
+        Some(Expr::VectorSelector(vs)) => vs,
+        Some(Expr::MatrixSelector(ms)) => &ms.vector_selector,
+        _ => return LabelSet::none(),
+    };
+    LabelSet::Only(
+        vs.matchers
+            .matchers
+            .iter()
+            .filter(|m| m.op == MatchOp::Equal && m.name != METRIC_NAME)
+            .map(|m| m.name.clone())
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    fn only(labels: &[&str]) -> LabelSet {
+        LabelSet::Only(labels.iter().map(|l| l.to_string()).collect())
+    }
+
+    fn except(labels: &[&str]) -> LabelSet {
+        LabelSet::Except(labels.iter().map(|l| l.to_string()).collect())
+    }
+
+    #[test]
+    fn test_result_labels() {
+        let cases = vec![
+            ("foo", except(&[])),
+            ("1 + time()", only(&[])),
+            ("foo[5m] offset 1m", except(&[])),
+            ("-foo", except(&["__name__"])),
+            ("rate(foo[5m])", except(&["__name__"])),
+            ("last_over_time(foo[5m])", except(&[])),
+            ("max_over_time(rate(foo[5m])[1h:])", except(&["__name__"])),
+            ("sum(foo)", only(&[])),
+            ("sum by (job, __name__) (foo)", only(&["__name__", "job"])),
+            ("sum by (job) (sum by (instance) (foo))", only(&[])),
+            ("sum without (job) (foo)", except(&["__name__", "job"])),
+            ("topk(3, sum by (job) (foo))", only(&["job"])),
+            (r#"count_values("value", foo)"#, only(&["value"])),
+            (
+                r#"count_values by (job) ("value", foo)"#,
+                only(&["job", "value"]),
+            ),
+            ("foo * 2", except(&["__name__"])),
+            ("2 > foo", except(&[])),
+            ("foo > bool 2", except(&["__name__"])),
+            ("foo > bar", except(&[])),
+            ("foo / on (job) bar", only(&["job"])),
+            ("foo / ignoring (job) bar", except(&["__name__", "job"])),
+            (
+                "sum by (job) (foo) * on (job) group_left (team) bar",
+                only(&["job", "team"]),
+            ),
+            (
+                "sum by (job) (foo) * on (job) group_right (team) sum by (job, instance) (bar)",
+                only(&["instance", "job", "team"]),
+            ),
+            ("sum by (job) (foo) and bar", only(&["job"])),
+            (
+                "sum by (job) (foo) or sum by (team) (bar)",
+                only(&["job", "team"]),
+            ),
+            (
+                "sum without (job) (foo) or sum by (job) (bar)",
+                except(&["__name__"]),
+            ),
+            (
+                "sum without (a, b) (foo) or sum without (b) (bar)",
+                except(&["__name__", "b"]),
+            ),
+            (
+                r#"label_replace(sum by (job) (foo), "team", "$1", "job", "(.*)")"#,
+                only(&["job", "team"]),
+            ),
+            (
+                r#"label_join(sum without (team) (foo), "team", ",", "a", "b")"#,
+                except(&["__name__"]),
+            ),
+            (
+                "histogram_quantile(0.9, sum by (job, le) (rate(foo_bucket[5m])))",
+                only(&["job"]),
+            ),
+            (r#"absent(foo{job="api", path=~"/.*"})"#, only(&["job"])),
+            ("absent(sum(foo))", only(&[])),
+            ("vector(1)", only(&[])),
+            ("scalar(foo)", only(&[])),
+        ];
+        for (input, expected) in cases {
+            let expr = parse(input).unwrap();
+            assert_eq!(result_labels(&expr), expected, "{input}");
+        }
+
+        let expr = parse("sum by (job) (rate(foo[5m]))").unwrap();
+        let labels: Vec<(usize, LabelSet)> = node_result_labels(&expr)
+            .into_iter()
+            .map(|(id, labels)| (id.index(), labels))
+            .collect();
+        assert_eq!(
+            labels,
+            [
+                (0, only(&["job"])),
+                (1, except(&["__name__"])),
+                (2, except(&[]))
+            ]
+        );
+    }
+}
@@ -0,0 +1,355 @@
+// Copyright 2023 Greptime Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! structured filters of the selectors, for engines pushing them down into
+//! their indexes.
+
+use std::time::Duration;
+
+use regex_syntax::hir::literal::{ExtractKind, Extractor};
+
+use crate::label::{MatchOp, Matcher, METRIC_NAME};
+use crate::parser::token::token_display;
+use crate::parser::{AtModifier, Expr, LabelModifier, Offset, VectorSelector};
+
+/// the filters of a selector, with the hints about how it is used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelectorFilter {
+    /// the name of the selector, or the value of an equal `__name__` matcher.
+    pub metric: Option<String>,
+    /// the other matchers, sorted by label, in the order of the selector for the same label.
+    pub filters: Vec<LabelFilter>,
+    pub hints: PushdownHints,
+}
+
+/// a matcher of a label.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LabelFilter {
+    pub label: String,
+    pub op: FilterOp,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterOp {
+    Equal(String),
+    NotEqual(String),
+    Regex(RegexFilter),
+    NotRegex(RegexFilter),
+}
+
+/// a regex matcher with the literals extracted from the pattern, which is
+/// matched against the whole value as in Prometheus.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegexFilter {
+    pub pattern: String,
+    /// every value matched starts with one of the prefixes, None if any value may match.
+    pub prefixes: Option<Vec<String>>,
+    /// the prefixes are the only values matched, like for `a|b`, so the regex
+    /// can be pushed down as a set of equal values.
+    pub exact: bool,
+}
+
+/// where a selector is used in the query.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PushdownHints {
+    /// the range of a matrix selector.
+    pub range: Option<Duration>,
+    /// the offset of the selector plus the offsets of the enclosing subqueries,
+    /// up to the first one with an `@`, which pins the time.
+    pub offset: Option<Offset>,
+    /// the `@` of the selector, or else of the innermost enclosing subquery.
+    pub at: Option<AtModifier>,
+    /// the innermost function the selector is passed to, like `rate`.
+    pub function: Option<&'static str>,
+    /// the innermost aggregation over the selector, like `sum`, and its grouping.
+    pub aggregation: Option<&'static str>,
+    pub grouping: Option<LabelModifier>,
+    /// the selector is evaluated at the steps of a subquery.
+    pub in_subquery: bool,
+}
+
+/// the filters of all vector and matrix selectors, including the ones in
+/// aggregation params, in the order of the query.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::parse;
+/// use promql_parser::util::pushdown::{selector_filters, FilterOp};
+///
+/// let expr = parse(r#"sum by (job) (rate(http_requests_total{code=~"5..|429", path=~"/api/.*"}[5m]))"#).unwrap();
+/// let filters = selector_filters(&expr);
+/// assert_eq!(filters.len(), 1);
+///
+/// let filter = &filters[0];
+/// assert_eq!(filter.metric.as_deref(), Some("http_requests_total"));
+/// let FilterOp::Regex(code) = &filter.filters[0].op else { unreachable!() };
+/// assert_eq!(code.prefixes, Some(vec!["429".to_string(), "5".to_string()]));
+/// let FilterOp::Regex(path) = &filter.filters[1].op else { unreachable!() };
+/// assert_eq!(path.prefixes, Some(vec!["/api/".to_string()]));
+/// assert!(!path.exact);
+///
+/// assert_eq!(filter.hints.function, Some("rate"));
+/// assert_eq!(filter.hints.aggregation, Some("sum"));
+/// assert_eq!(filter.hints.grouping.as_ref().unwrap().to_string(), "by (job)");
+/// ```
+pub fn selector_filters(expr: &Expr) -> Vec<SelectorFilter> {
+    let mut filters = vec![];
+    collect_filters(expr, &PushdownHints::default(), &mut filters);
+    filters
+}
+
+fn collect_filters(expr: &Expr, hints: &PushdownHints, filters: &mut Vec<SelectorFilter>) {
+    let mut hints = hints.clone();
+    match expr {
+        Expr::VectorSelector(vs) => {
+            filters.push(selector_filter(vs, hints));
+            return;
+        }
+        Expr::MatrixSelector(ms) => {
+            hints.range = Some(ms.range);
+            filters.push(selector_filter(&ms.vector_selector, hints));
+            return;
+        }
+        Expr::Call(call) => hints.function = Some(call.func.name),
+        Expr::Aggregate(agg) => {
+            hints.aggregation = Some(token_display(agg.op.id()));
+            hints.grouping = agg.modifier.clone();
+        }
+        Expr::Subquery(sq) => {
+            hints.in_subquery = true;
+            // the time is pinned, the outer offsets do not move it
+            if sq.at.is_some() {
+                hints.at = sq.at.clone();
+                hints.offset = sq.offset.clone();
+            } else {
+                hints.offset = add_offsets(&hints.offset, &sq.offset);
+            }
+        }
+        _ => {}
+    }
+    for child in expr.children() {
+        collect_filters(child, &hints, filters);
+    }
+}
+
+fn selector_filter(vs: &VectorSelector, mut hints: PushdownHints) -> SelectorFilter {
+    if vs.at.is_some() {
+        hints.at = vs.at.clone();
+        hints.offset = vs.offset.clone();
+    } else {
+        hints.offset = add_offsets(&hints.offset, &vs.offset);
+    }
+
+    let is_name = |m: &Matcher| m.name == METRIC_NAME && m.op == MatchOp::Equal;
+    let metric = vs.metric_name().map(String::from);
+    let mut matchers: Vec<&Matcher> = vs
+        .matchers
+        .matchers
+        .iter()
+        .filter(|m| !(is_name(m) && Some(&m.value) == metric.as_ref()))
+        .collect();
+    matchers.sort_by(|a, b| {
+        (&a.name, op_order(&a.op), &a.value).cmp(&(&b.name, op_order(&b.op), &b.value))
+    });
+    let filters = matchers
+        .into_iter()
+        .map(|m| LabelFilter {
+            label: m.name.clone(),
+            op: match &m.op {
+                MatchOp::Equal => FilterOp::Equal(m.value.clone()),
+                MatchOp::NotEqual => FilterOp::NotEqual(m.value.clone()),
+                MatchOp::Re(_) => FilterOp::Regex(RegexFilter::new(&m.value)),
+                MatchOp::NotRe(_) => FilterOp::NotRegex(RegexFilter::new(&m.value)),
+            },
+        })
+        .collect();
+
+    SelectorFilter {
+        metric,
+        filters,
+        hints,
+    }
+}
+
+/// the offsets of nested exprs add up, the outer offset is kept on overflow.
+fn add_offsets(outer: &Option<Offset>, inner: &Option<Offset>) -> Option<Offset> {
+    match (outer, inner) {
+        (Some(outer), Some(inner)) => outer.checked_add(inner).or(Some(outer.clone())),
+        (outer, inner) => outer.clone().or(inner.clone()),
+    }
+}
+
+/// the more selective ops first.
+fn op_order(op: &MatchOp) -> u8 {
+    match op {
+        MatchOp::Equal => 0,
+        MatchOp::Re(_) => 1,
+        MatchOp::NotEqual => 2,
+        MatchOp::NotRe(_) => 3,
+    }
+}
+
+impl RegexFilter {
+    /// extract the literal prefixes of the pattern, the pattern is a valid
+    /// regex as it is parsed already.
+    pub fn new(pattern: &str) -> Self {
+        let seq = regex_syntax::parse(pattern)
+            .ok()
+            .map(|hir| Extractor::new().kind(ExtractKind::Prefix).extract(&hir));
+        let literals = seq.as_ref().and_then(|seq| seq.literals());
+        let prefixes: Option<Vec<String>> = literals.and_then(|literals| {
+            literals
+                .iter()
+                // an empty prefix does not constrain the values
+                .map(|l| {
+                    let constrained = l.is_exact() || !l.as_bytes().is_empty();
+                    constrained
+                        .then(|| String::from_utf8(l.as_bytes().to_vec()).ok())
+                        .flatten()
+                })
+                .collect()
+        });
+        let exact = prefixes.is_some() && seq.is_some_and(|seq| seq.is_exact());
+        let prefixes = prefixes.map(|mut prefixes| {
+            prefixes.sort();
+            prefixes.dedup();
+            prefixes
+        });
+        Self {
+            pattern: pattern.to_string(),
+            prefixes,
+            exact,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    #[test]
+    fn test_regex_filter() {
+        let cases: Vec<(&str, Option<Vec<&str>>, bool)> = vec![
+            ("foo", Some(vec!["foo"]), true),
+            ("foo|bar", Some(vec!["bar", "foo"]), true),
+            ("(foo|bar)-baz", Some(vec!["bar-baz", "foo-baz"]), true),
+            ("foo.*", Some(vec!["foo"]), false),
+            ("api-(a|b).+", Some(vec!["api-a", "api-b"]), false),
+            ("5..", Some(vec!["5"]), false),
+            ("", Some(vec![""]), true),
+            (".*", None, false),
+            (".*foo", None, false),
+            ("foo|.*", None, false),
+        ];
+        for (pattern, prefixes, exact) in cases {
+            let filter = RegexFilter::new(pattern);
+            let expected: Option<Vec<String>> =
+                prefixes.map(|p| p.into_iter().map(String::from).collect());
+            assert_eq!(filter.prefixes, expected, "{pattern}");
+            assert_eq!(filter.exact, exact, "{pattern}");
+        }
+    }
+
+    #[test]
+    fn test_selector_filters() {
+        let expr = parse(
+            r#"topk(3, max_over_time(rate({__name__="foo", b!="x", a=~"y.*", a="z"}[5m])[1h:] offset 1d))
+               + on (a) count without (c) ({__name__=~"bar.*"} @ 100)"#,
+        )
+        .unwrap();
+        let filters = selector_filters(&expr);
+        assert_eq!(filters.len(), 2);
+
+        let foo = &filters[0];
+        assert_eq!(foo.metric.as_deref(), Some("foo"));
+        let labels: Vec<(&str, &FilterOp)> = foo
+            .filters
+            .iter()
+            .map(|f| (f.label.as_str(), &f.op))
+            .collect();
+        assert_eq!(
+            labels,
+            vec![
+                ("a", &FilterOp::Equal("z".into())),
+                ("a", &FilterOp::Regex(RegexFilter::new("y.*"))),
+                ("b", &FilterOp::NotEqual("x".into())),
+            ]
+        );
+        assert_eq!(
+            foo.hints,
+            PushdownHints {
+                range: Some(Duration::from_secs(300)),
+                function: Some("rate"),
+                offset: Some(Offset::Pos(Duration::from_secs(86400))),
+                aggregation: Some("topk"),
+                in_subquery: true,
+                ..Default::default()
+            }
+        );
+
+        let bar = &filters[1];
+        assert_eq!(bar.metric, None);
+        assert_eq!(bar.filters[0].label, METRIC_NAME);
+        assert_eq!(bar.hints.aggregation, Some("count"));
+        assert_eq!(
+            bar.hints.grouping.as_ref().unwrap().to_string(),
+            "without (c)"
+        );
+        assert_eq!(bar.hints.at.as_ref().unwrap().to_string(), "@ 100.000");
+        assert!(!bar.hints.in_subquery);
+        assert_eq!(bar.hints.function, None);
+    }
+
+    #[test]
+    fn test_subquery_hints() {
+        let cases = vec![
+            ("foo[1h:] offset 1d", Some("offset 1d"), None),
+            ("(foo offset 1h)[1h:] offset 1d", Some("offset 1d1h"), None),
+            ("(foo offset -1d)[1h:] offset 1h", Some("offset -23h"), None),
+            ("(foo offset 1d)[1h:] offset -1d", Some("offset 0s"), None),
+            (
+                "max_over_time(foo[5m:] @ 100)[1h:] @ 200",
+                None,
+                Some("@ 100.000"),
+            ),
+            ("(foo @ 50)[1h:] @ 200", None, Some("@ 50.000")),
+            // an @ drops the offsets of the enclosing subqueries
+            (
+                "max_over_time((foo @ 100)[1h:] offset 1d)",
+                None,
+                Some("@ 100.000"),
+            ),
+            (
+                "(foo @ 100 offset 1m)[1h:] offset 1d",
+                Some("offset 1m"),
+                Some("@ 100.000"),
+            ),
+            (
+                "max_over_time((foo offset 1h)[1h:] @ 100 offset 1m)[2h:] offset 1d",
+                Some("offset 1h1m"),
+                Some("@ 100.000"),
+            ),
+        ];
+        for (query, offset, at) in cases {
+            let filters = selector_filters(&parse(query).unwrap());
+            let hints = &filters[0].hints;
+            let actual = hints.offset.as_ref().map(ToString::to_string);
+            assert_eq!(actual.as_deref(), offset, "{query}");
+            let actual = hints.at.as_ref().map(ToString::to_string);
+            assert_eq!(actual.as_deref(), at, "{query}");
+        }
+    }
+}
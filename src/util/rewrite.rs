@@ -0,0 +1,525 @@
+// Copyright 2023 Greptime Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! rewrites of the exprs, preparing them for evaluation.
+
+use crate::parser::{AtModifier, EvalStmt, Expr, NodeId, Offset, SubqueryStep};
+use std::time::{Duration, SystemTime};
+
+/// replace `@ start()` and `@ end()` with the start and end of the evaluation.
+/// As in Prometheus, they are the start and end of the whole query, also inside
+/// subqueries, so the result can be evaluated and cached on its own.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::{parse, EvalStmt};
+/// use promql_parser::util::rewrite::resolve_at_modifiers;
+/// use std::time::{Duration, SystemTime};
+///
+/// let stmt = EvalStmt {
+///     expr: parse("foo @ start() - max_over_time(foo[1h:] @ end())").unwrap(),
+///     start: SystemTime::UNIX_EPOCH + Duration::from_secs(1000),
+///     end: SystemTime::UNIX_EPOCH + Duration::from_secs(2000),
+///     interval: Duration::from_secs(60),
+///     lookback_delta: Duration::from_secs(300),
+/// };
+/// assert_eq!(
+///     resolve_at_modifiers(&stmt).to_string(),
+///     "foo @ 1000.000 - max_over_time(foo[1h:] @ 2000.000)"
+/// );
+/// ```
+pub fn resolve_at_modifiers(stmt: &EvalStmt) -> Expr {
+    let mut expr = stmt.expr.clone();
+    for_each_mut(&mut expr, &mut |e| {
+        let at = match e {
+            Expr::VectorSelector(vs) => &mut vs.at,
+            Expr::MatrixSelector(ms) => &mut ms.vector_selector.at,
+            Expr::Subquery(sq) => &mut sq.at,
+            _ => return,
+        };
+        *at = match at.take() {
+            Some(AtModifier::Start) => Some(AtModifier::At(stmt.start)),
+            Some(AtModifier::End) => Some(AtModifier::At(stmt.end)),
+            at => at,
+        };
+    });
+    expr
+}
+
+/// ResolvedAt is an `@` modifier of a query, resolved by [`resolved_at_times`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedAt {
+    /// the selector or subquery with the modifier, the id is the same in
+    /// the query and in the result of [`resolve_at_modifiers`].
+    pub id: NodeId,
+    /// the time of the modifier, `start()` and `end()` are the start and end
+    /// of the whole query, also inside subqueries.
+    pub at: SystemTime,
+    /// the time the node is evaluated at, which is `at` moved by the offset.
+    /// It is the end of the range for range selectors and subqueries.
+    pub eval_time: SystemTime,
+}
+
+/// the `@` modifiers of the query in pre-order, with the times they resolve
+/// to. The times do not depend on the enclosing subqueries, so they can be
+/// part of the cache key of the nodes. It is an error if an offset moves the
+/// time out of the range of [`SystemTime`].
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::{parse, EvalStmt};
+/// use promql_parser::util::rewrite::resolved_at_times;
+/// use std::time::{Duration, SystemTime};
+///
+/// let time = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+/// let stmt = EvalStmt {
+///     expr: parse("max_over_time((foo @ end())[1h:5m] @ start() offset 1m)").unwrap(),
+///     start: time(4000),
+///     end: time(8000),
+///     interval: Duration::from_secs(60),
+///     lookback_delta: Duration::from_secs(300),
+/// };
+/// let times: Vec<_> = resolved_at_times(&stmt)
+///     .unwrap()
+///     .iter()
+///     .map(|r| (stmt.expr.get_node(r.id).unwrap().to_string(), r.at, r.eval_time))
+///     .collect();
+/// assert_eq!(
+///     times,
+///     [
+///         ("(foo @ end())[1h:5m] @ start() offset 1m".to_string(), time(4000), time(3940)),
+///         ("foo @ end()".to_string(), time(8000), time(8000)),
+///     ]
+/// );
+/// ```
+pub fn resolved_at_times(stmt: &EvalStmt) -> Result<Vec<ResolvedAt>, String> {
+    let mut times = vec![];
+    for (id, node) in stmt.expr.nodes() {
+        let (at, offset) = match node {
+            Expr::VectorSelector(vs) => (&vs.at, &vs.offset),
+            Expr::MatrixSelector(ms) => (&ms.vector_selector.at, &ms.vector_selector.offset),
+            Expr::Subquery(sq) => (&sq.at, &sq.offset),
+            _ => continue,
+        };
+        let at = match at {
+            Some(AtModifier::Start) => stmt.start,
+            Some(AtModifier::End) => stmt.end,
+            Some(AtModifier::At(at)) => *at,
+            None => continue,
+        };
+        let eval_time = match offset {
+            Some(Offset::Pos(d)) => at.checked_sub(*d),
+            Some(Offset::Neg(d)) => at.checked_add(*d),
+            None => Some(at),
+        }
+        .ok_or_else(|| format!("the offset of {node} moves its time out of range"))?;
+        times.push(ResolvedAt { id, at, eval_time });
+    }
+    Ok(times)
+}
+
+/// set the step of the subqueries without one, like `foo[1h:]`, to the default
+/// evaluation interval, as the Prometheus engine does before execution.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::parse;
+/// use promql_parser::util::rewrite::fill_subquery_steps;
+/// use std::time::Duration;
+///
+/// let expr = parse("max_over_time(rate(foo[5m])[1h:])").unwrap();
+/// assert_eq!(
+///     fill_subquery_steps(&expr, Duration::from_secs(30)).to_string(),
+///     "max_over_time(rate(foo[5m])[1h:30s])"
+/// );
+/// ```
+pub fn fill_subquery_steps(expr: &Expr, interval: Duration) -> Expr {
+    let mut expr = expr.clone();
+    for_each_mut(&mut expr, &mut |e| {
+        if let Expr::Subquery(sq) = e {
+            if sq.step == SubqueryStep::Default {
+                sq.step = SubqueryStep::Resolved(interval);
+            }
+        }
+    });
+    expr
+}
+
+/// convert the `@` modifiers into offsets relative to the evaluation time, for
+/// engines without `@` support. `foo @ 100 offset 10s` evaluated at 1000 reads
+/// the same samples as `foo offset 15m10s`, and `@ start()` and `@ end()` are the
+/// evaluation time itself, as for an instant query.
+///
+/// The result only holds for instant queries, and `@` modifiers inside subqueries
+/// are an error, since the subquery evaluates them at many times. It is also an
+/// error if the offset is out of the range of a duration in a query, about 292y.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::parse;
+/// use promql_parser::util::rewrite::at_to_offset;
+/// use std::time::{Duration, SystemTime};
+///
+/// let expr = parse("rate(foo[5m] @ 1000 offset 1m) / bar @ end()").unwrap();
+/// let time = SystemTime::UNIX_EPOCH + Duration::from_secs(4600);
+/// assert_eq!(
+///     at_to_offset(&expr, time).unwrap().to_string(),
+///     "rate(foo[5m] offset 1h1m) / bar"
+/// );
+/// ```
+pub fn at_to_offset(expr: &Expr, time: SystemTime) -> Result<Expr, String> {
+    let mut expr = expr.clone();
+    downgrade_at(&mut expr, time, false)?;
+    Ok(expr)
+}
+
+fn downgrade_at(expr: &mut Expr, time: SystemTime, in_subquery: bool) -> Result<(), String> {
+    let (at, offset) = match expr {
+        Expr::VectorSelector(vs) => (&mut vs.at, &mut vs.offset),
+        Expr::MatrixSelector(ms) => (&mut ms.vector_selector.at, &mut ms.vector_selector.offset),
+        Expr::Subquery(sq) => (&mut sq.at, &mut sq.offset),
+        _ => {
+            for child in expr.children_mut() {
+                downgrade_at(child, time, in_subquery)?;
+            }
+            return Ok(());
+        }
+    };
+
+    if let Some(at) = at.take() {
+        if in_subquery {
+            return Err(format!(
+                "{at} inside a subquery can not be converted to an offset"
+            ));
+        }
+        let at_time = match at {
+            AtModifier::At(at) => at,
+            AtModifier::Start | AtModifier::End => time,
+        };
+        // the samples are read at `at - offset`, which is `time - new_offset`
+        let nanos = signed_nanos(time, at_time) + offset.as_ref().map_or(0, offset_nanos);
+        // like parsed durations, the offset must fit in an i64 of nanoseconds
+        let d = i64::try_from(nanos.unsigned_abs())
+            .map(|n| Duration::from_nanos(n as u64))
+            .map_err(|_| format!("{at} is too far from the time to be converted to an offset"))?;
+        *offset = match nanos {
+            0 => None,
+            n if n > 0 => Some(Offset::Pos(d)),
+            _ => Some(Offset::Neg(d)),
+        };
+    }
+
+    if let Expr::Subquery(sq) = expr {
+        downgrade_at(&mut sq.expr, time, true)?;
+    }
+    Ok(())
+}
+
+/// pin the query to the time, so it returns the same values as the instant
+/// query at that time whenever it is run, for reproducing a result later.
+/// The selectors and subqueries without `@` get `@ <time>`, and `@ start()`
+/// and `@ end()` are the time itself. The selectors inside subqueries are
+/// evaluated at the steps of the now pinned subquery, so they are kept.
+///
+/// `time()` and the functions defaulting to it still depend on when the query runs.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::parse;
+/// use promql_parser::util::rewrite::pin;
+/// use std::time::{Duration, SystemTime};
+///
+/// let expr = parse("rate(foo[5m]) / bar @ 100 + max_over_time(baz[1h:1m])").unwrap();
+/// let time = SystemTime::UNIX_EPOCH + Duration::from_secs(4600);
+/// assert_eq!(
+///     pin(&expr, time).to_string(),
+///     "rate(foo[5m] @ 4600.000) / bar @ 100.000 + max_over_time(baz[1h:1m] @ 4600.000)"
+/// );
+/// ```
+pub fn pin(expr: &Expr, time: SystemTime) -> Expr {
+    let mut expr = expr.clone();
+    pin_at(&mut expr, time, false);
+    expr
+}
+
+fn pin_at(expr: &mut Expr, time: SystemTime, in_subquery: bool) {
+    let at = match expr {
+        Expr::VectorSelector(vs) => &mut vs.at,
+        Expr::MatrixSelector(ms) => &mut ms.vector_selector.at,
+        Expr::Subquery(sq) => &mut sq.at,
+        _ => {
+            for child in expr.children_mut() {
+                pin_at(child, time, in_subquery);
+            }
+            return;
+        }
+    };
+
+    *at = match at.take() {
+        None if !in_subquery => Some(AtModifier::At(time)),
+        Some(AtModifier::Start | AtModifier::End) => Some(AtModifier::At(time)),
+        at => at,
+    };
+
+    if let Expr::Subquery(sq) = expr {
+        pin_at(&mut sq.expr, time, true);
+    }
+}
+
+/// `a - b` in nanoseconds.
+fn signed_nanos(a: SystemTime, b: SystemTime) -> i128 {
+    match a.duration_since(b) {
+        Ok(d) => d.as_nanos() as i128,
+        Err(e) => -(e.duration().as_nanos() as i128),
+    }
+}
+
+fn offset_nanos(offset: &Offset) -> i128 {
+    match offset {
+        Offset::Pos(d) => d.as_nanos() as i128,
+        Offset::Neg(d) => -(d.as_nanos() as i128),
+    }
+}
+
+/// call f on the expr and all its descendants, in pre-order.
+fn for_each_mut(expr: &mut Expr, f: &mut impl FnMut(&mut Expr)) {
+    f(expr);
+    for child in expr.children_mut() {
+        for_each_mut(child, f);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    fn eval_stmt(query: &str, start: u64, end: u64) -> EvalStmt {
+        EvalStmt {
+            expr: parse(query).unwrap(),
+            start: SystemTime::UNIX_EPOCH + Duration::from_secs(start),
+            end: SystemTime::UNIX_EPOCH + Duration::from_secs(end),
+            interval: Duration::from_secs(15),
+            lookback_delta: Duration::from_secs(300),
+        }
+    }
+
+    #[test]
+    fn test_resolve_at_modifiers() {
+        let cases = vec![
+            ("foo", "foo"),
+            ("foo @ 10", "foo @ 10"),
+            ("foo @ start()", "foo @ 100"),
+            (
+                "rate(foo[5m] @ end() offset 1m)",
+                "rate(foo[5m] @ 200 offset 1m)",
+            ),
+            (
+                "sum_over_time(rate(foo[5m] @ start())[1h:1m] @ end())",
+                "sum_over_time(rate(foo[5m] @ 100)[1h:1m] @ 200)",
+            ),
+            (
+                "topk(scalar(foo @ end()), -bar @ start())",
+                "topk(scalar(foo @ 200), -bar @ 100)",
+            ),
+            (
+                "max_over_time((foo @ start() + bar)[10m:] offset 1h)",
+                "max_over_time((foo @ 100 + bar)[10m:] offset 1h)",
+            ),
+        ];
+        for (query, expected) in cases {
+            let resolved = resolve_at_modifiers(&eval_stmt(query, 100, 200));
+            assert_eq!(resolved, parse(expected).unwrap(), "{query}");
+        }
+    }
+
+    #[test]
+    fn test_resolved_at_times() {
+        // the times of nested modifiers, as the Prometheus engine evaluates them
+        let cases = vec![
+            ("foo", vec![]),
+            (
+                "foo @ 50 offset -10s",
+                vec![("foo @ 50.000 offset -10s", 50, 60)],
+            ),
+            (
+                "(foo @ end())[1h:5m] @ start()",
+                vec![
+                    ("(foo @ end())[1h:5m] @ start()", 100, 100),
+                    ("foo @ end()", 200, 200),
+                ],
+            ),
+            (
+                "foo @ end() offset 1m [1h:5m] offset 10s",
+                vec![("foo @ end() offset 1m", 200, 140)],
+            ),
+            (
+                "max_over_time(rate(foo[5m] @ start())[1h:] @ end() offset 1m) + bar @ 10",
+                vec![
+                    ("rate(foo[5m] @ start())[1h:] @ end() offset 1m", 200, 140),
+                    ("foo[5m] @ start()", 100, 100),
+                    ("bar @ 10.000", 10, 10),
+                ],
+            ),
+        ];
+        for (query, expected) in cases {
+            let stmt = eval_stmt(query, 100, 200);
+            let resolved = resolve_at_modifiers(&stmt);
+            let times: Vec<_> = resolved_at_times(&stmt)
+                .unwrap()
+                .into_iter()
+                .map(|r| {
+                    // the ids are the same after the resolution
+                    assert!(resolved.get_node(r.id).unwrap().to_string().contains('@'));
+                    let secs = |t: SystemTime| t.duration_since(SystemTime::UNIX_EPOCH).unwrap();
+                    (
+                        stmt.expr.get_node(r.id).unwrap().to_string(),
+                        secs(r.at).as_secs(),
+                        secs(r.eval_time).as_secs(),
+                    )
+                })
+                .collect();
+            let expected: Vec<_> = expected
+                .into_iter()
+                .map(|(node, at, eval_time)| (node.to_string(), at, eval_time))
+                .collect();
+            assert_eq!(times, expected, "{query}");
+        }
+
+        // an offset which moves the time out of the range of SystemTime
+        let mut stmt = eval_stmt("foo @ 10", 100, 200);
+        if let Expr::VectorSelector(vs) = &mut stmt.expr {
+            vs.offset = Some(Offset::Pos(Duration::MAX));
+        }
+        assert!(resolved_at_times(&stmt)
+            .unwrap_err()
+            .contains("moves its time out of range"));
+    }
+
+    #[test]
+    fn test_fill_subquery_steps() {
+        let interval = Duration::from_secs(60);
+        let cases = vec![
+            ("foo", "foo"),
+            ("rate(foo[5m])", "rate(foo[5m])"),
+            ("foo[1h:]", "foo[1h:1m]"),
+            ("foo[1h:5m]", "foo[1h:5m]"),
+            (
+                "max_over_time(rate(foo[5m])[30m:] offset 1h)",
+                "max_over_time(rate(foo[5m])[30m:1m] offset 1h)",
+            ),
+            (
+                "max_over_time(avg_over_time(foo[10m:])[1h:10s])",
+                "max_over_time(avg_over_time(foo[10m:1m])[1h:10s])",
+            ),
+        ];
+        for (query, expected) in cases {
+            let expr = fill_subquery_steps(&parse(query).unwrap(), interval);
+            assert_eq!(expr.to_string(), expected, "{query}");
+        }
+
+        // the filled steps are kept apart from the written ones
+        let expr = parse("max_over_time(avg_over_time(foo[10m:])[1h:10s])").unwrap();
+        let steps: Vec<SubqueryStep> = fill_subquery_steps(&expr, interval)
+            .nodes()
+            .into_iter()
+            .filter_map(|(_, node)| match node {
+                Expr::Subquery(sq) => Some(sq.step),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            steps,
+            vec![
+                SubqueryStep::Explicit(Duration::from_secs(10)),
+                SubqueryStep::Resolved(interval)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_at_to_offset() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let cases = vec![
+            ("foo", "foo"),
+            ("foo offset 5m", "foo offset 5m"),
+            ("foo @ 400", "foo offset 10m"),
+            ("foo @ 1000", "foo"),
+            ("foo @ 1060", "foo offset -1m"),
+            ("foo @ 1060 offset 5m", "foo offset 4m"),
+            ("foo @ 400 offset -5m", "foo offset 5m"),
+            ("foo @ 999.5", "foo offset 500ms"),
+            ("foo @ start() offset 1m", "foo offset 1m"),
+            (
+                "max_over_time(rate(foo[5m])[1h:1m] @ 400)",
+                "max_over_time(rate(foo[5m])[1h:1m] offset 10m)",
+            ),
+            ("sum(foo @ 400) + bar @ end()", "sum(foo offset 10m) + bar"),
+        ];
+        for (query, expected) in cases {
+            let expr = at_to_offset(&parse(query).unwrap(), time).unwrap();
+            assert_eq!(expr, parse(expected).unwrap(), "{query}");
+        }
+
+        let expr = parse("max_over_time(rate(foo[5m] @ 400)[1h:1m])").unwrap();
+        assert_eq!(
+            at_to_offset(&expr, time),
+            Err("@ 400.000 inside a subquery can not be converted to an offset".into())
+        );
+        let expr = parse("max_over_time(foo[1h:1m] @ 400)[2h:]").unwrap();
+        assert!(at_to_offset(&expr, time).is_err());
+
+        // the offset would not fit in a duration
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        for query in ["foo @ 1e15", "foo @ -1e15", "foo @ 1e10 offset -200y"] {
+            let err = at_to_offset(&parse(query).unwrap(), time).unwrap_err();
+            assert!(err.ends_with("is too far from the time to be converted to an offset"));
+        }
+    }
+
+    #[test]
+    fn test_pin() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let cases = vec![
+            ("1 + time()", "1 + time()"),
+            ("foo", "foo @ 1000"),
+            ("foo @ 400 offset 5m", "foo @ 400 offset 5m"),
+            ("foo @ end() offset 5m", "foo @ 1000 offset 5m"),
+            ("rate(foo[5m] offset 1m)", "rate(foo[5m] @ 1000 offset 1m)"),
+            (
+                "sum by (job) (foo) / on (job) bar @ start()",
+                "sum by (job) (foo @ 1000) / on (job) bar @ 1000",
+            ),
+            (
+                "max_over_time(rate(foo[5m])[1h:1m])",
+                "max_over_time(rate(foo[5m])[1h:1m] @ 1000)",
+            ),
+            (
+                "max_over_time((foo @ start() + bar @ 400)[1h:1m] @ 600)",
+                "max_over_time((foo @ 1000 + bar @ 400)[1h:1m] @ 600)",
+            ),
+        ];
+        for (query, expected) in cases {
+            let expr = pin(&parse(query).unwrap(), time);
+            assert_eq!(expr, parse(expected).unwrap(), "{query}");
+            // pinning again changes nothing
+            assert_eq!(pin(&expr, SystemTime::UNIX_EPOCH), expr, "{query}");
+        }
+    }
+}
@@ -0,0 +1,331 @@
+// Copyright 2023 Greptime Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! lint the queries of Prometheus rule files, like
+//!
+//! ```yaml
+//! groups:
+//!   - name: example
+//!     rules:
+//!       - alert: HighErrorRate
+//!         expr: sum(rate(errors_total[5m])) > 1
+//! ```
+
+use std::fmt;
+
+use yaml_rust2::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust2::scanner::{Marker, TScalarStyle};
+
+use crate::label::NameValidationScheme;
+use crate::parser::{parse_with_diagnostic, ParseOptions};
+use crate::util::lint::{lint_parsed, LintKind, LintOptions};
+
+/// a finding in a rule file, anchored at a 1-based line and column.
+///
+/// lints are anchored at the start of the `expr`, parse errors at the
+/// reported position if it can be located in the file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleDiagnostic {
+    pub line: usize,
+    pub column: usize,
+    /// the name of the alerting or recording rule.
+    pub rule: Option<String>,
+    /// the lint found, none for queries which fail to parse.
+    pub kind: Option<LintKind>,
+    pub message: String,
+}
+
+impl fmt::Display for RuleDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: ", self.line, self.column)?;
+        if let Some(rule) = &self.rule {
+            write!(f, "{rule}: ")?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+/// an `expr` of a rule, with where its value starts in the file.
+#[derive(Debug, Clone)]
+struct RuleExpr {
+    rule: Option<String>,
+    expr: String,
+    style: TScalarStyle,
+    marker: Marker,
+}
+
+/// parse every `expr` in the rule file and lint it. The rules are the items
+/// of any `rules` list, so rules nested in other documents, like the `spec`
+/// of a `PrometheusRule`, are checked too.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::util::lint::LintOptions;
+/// use promql_parser::util::rules::lint_rules;
+///
+/// let rules = "
+/// groups:
+///   - name: example
+///     rules:
+///       - record: job:errors:sum
+///         expr: sum(errors_total) by (job)
+///       - alert: Broken
+///         expr: rate(foo)
+/// ";
+/// let diagnostics = lint_rules(rules, &LintOptions::default()).unwrap();
+/// let diagnostics: Vec<String> = diagnostics.iter().map(|d| d.to_string()).collect();
+/// assert_eq!(
+///     diagnostics,
+///     vec![
+///         "6:15: job:errors:sum: counter errors_total is aggregated directly, use rate() or increase() first",
+///         "8:15: Broken: expected type matrix in call to function 'rate', got vector",
+///     ]
+/// );
+/// ```
+pub fn lint_rules(content: &str, options: &LintOptions) -> Result<Vec<RuleDiagnostic>, String> {
+    let mut diagnostics = vec![];
+    // the names are not checked, like in [`crate::parser::parse`]
+    let parse_options = ParseOptions::new().with_name_validation_scheme(NameValidationScheme::Utf8);
+    for rule in rule_exprs(content)? {
+        let (line, column) = rule.position(None);
+        let lints = parse_with_diagnostic(&rule.expr, &parse_options)
+            .map_err(|d| (d.message, d.span))
+            .and_then(|expr| lint_parsed(&rule.expr, &expr, options).map_err(|e| (e, None)));
+        match lints {
+            Ok(lints) => diagnostics.extend(lints.into_iter().map(|lint| RuleDiagnostic {
+                line,
+                column,
+                rule: rule.rule.clone(),
+                kind: Some(lint.kind),
+                message: lint.message,
+            })),
+            Err((message, span)) => {
+                let (line, column) = rule.position(span.map(|span| span.start));
+                diagnostics.push(RuleDiagnostic {
+                    line,
+                    column,
+                    rule: rule.rule.clone(),
+                    kind: None,
+                    message,
+                });
+            }
+        }
+    }
+    Ok(diagnostics)
+}
+
+impl RuleExpr {
+    /// the 1-based line and column of the byte offset in the expr, or of the
+    /// start of the expr if the offset can not be mapped back to the file.
+    fn position(&self, offset: Option<usize>) -> (usize, usize) {
+        let (line, column) = (self.marker.line(), self.marker.col() + 1);
+        let offset = match offset {
+            Some(offset) if self.expr.is_char_boundary(offset) => offset,
+            _ => return (line, column),
+        };
+        let before = &self.expr[..offset];
+        match self.style {
+            TScalarStyle::Literal => match before.rfind('\n') {
+                Some(i) => (
+                    line + before.matches('\n').count(),
+                    column + before[i + 1..].chars().count(),
+                ),
+                None => (line, column + before.chars().count()),
+            },
+            TScalarStyle::Plain if !self.expr.contains('\n') => {
+                (line, column + before.chars().count())
+            }
+            // the quote is not part of the value
+            TScalarStyle::SingleQuoted if !self.expr.contains(['\n', '\'']) => {
+                (line, column + 1 + before.chars().count())
+            }
+            TScalarStyle::DoubleQuoted if !self.expr.contains(['\n', '"', '\\']) => {
+                (line, column + 1 + before.chars().count())
+            }
+            _ => (line, column),
+        }
+    }
+}
+
+/// the events of the yaml documents, with where they start.
+#[derive(Default)]
+struct Events(Vec<(Event, Marker)>);
+
+impl MarkedEventReceiver for Events {
+    fn on_event(&mut self, ev: Event, mark: Marker) {
+        self.0.push((ev, mark));
+    }
+}
+
+fn rule_exprs(content: &str) -> Result<Vec<RuleExpr>, String> {
+    let mut events = Events::default();
+    Parser::new_from_str(content)
+        .load(&mut events, true)
+        .map_err(|e| {
+            let marker = e.marker();
+            format!("{}:{}: {}", marker.line(), marker.col() + 1, e.info())
+        })?;
+    let mut rules = vec![];
+    let mut events = events.0.into_iter().peekable();
+    while events.peek().is_some() {
+        collect_node(&mut events, false, &mut rules);
+    }
+    Ok(rules)
+}
+
+/// consume one node from the events, collecting the rules in it.
+/// `in_rules` tells whether the node is the list of a `rules` key.
+fn collect_node(
+    events: &mut std::iter::Peekable<impl Iterator<Item = (Event, Marker)>>,
+    in_rules: bool,
+    rules: &mut Vec<RuleExpr>,
+) {
+    match events.next() {
+        Some((Event::SequenceStart(..), _)) => {
+            while !matches!(events.peek(), Some((Event::SequenceEnd, _)) | None) {
+                match events.peek() {
+                    Some((Event::MappingStart(..), _)) if in_rules => collect_rule(events, rules),
+                    _ => collect_node(events, false, rules),
+                }
+            }
+            events.next();
+        }
+        Some((Event::MappingStart(..), _)) => {
+            while !matches!(events.peek(), Some((Event::MappingEnd, _)) | None) {
+                let in_rules =
+                    matches!(events.next(), Some((Event::Scalar(key, ..), _)) if key == "rules");
+                collect_node(events, in_rules, rules);
+            }
+            events.next();
+        }
+        _ => {}
+    }
+}
+
+/// consume the mapping of a rule, the next event is the start of it.
+fn collect_rule(
+    events: &mut std::iter::Peekable<impl Iterator<Item = (Event, Marker)>>,
+    rules: &mut Vec<RuleExpr>,
+) {
+    events.next();
+    let (mut name, mut expr) = (None, None);
+    while !matches!(events.peek(), Some((Event::MappingEnd, _)) | None) {
+        let key = match events.next() {
+            Some((Event::Scalar(key, ..), _)) => Some(key),
+            _ => None,
+        };
+        match (key.as_deref(), events.peek()) {
+            (Some(key), Some((Event::Scalar(value, style, ..), marker))) => {
+                match key {
+                    "expr" => expr = Some((value.clone(), *style, *marker)),
+                    "alert" | "record" => name = Some(value.clone()),
+                    _ => {}
+                }
+                events.next();
+            }
+            _ => collect_node(events, false, rules),
+        }
+    }
+    events.next();
+    if let Some((expr, style, marker)) = expr {
+        rules.push(RuleExpr {
+            rule: name,
+            expr,
+            style,
+            marker,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diagnostics(content: &str) -> Vec<String> {
+        lint_rules(content, &LintOptions::default())
+            .unwrap()
+            .iter()
+            .map(|d| d.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_lint_rules() {
+        let content = r#"
+groups:
+- name: a
+  rules:
+  - alert: Plain
+    expr: sum(foo{a="b"
+  - alert: Single
+    expr: 'sum(foo) by (job, job)'
+  - alert: Double
+    expr: "rate(foo[5m]"
+  - record: literal
+    expr: |
+      sum(
+        rate(foo[5m]) by (job)
+  - record: folded
+    expr: >
+      sum by (job)
+      (foo_total)
+  - record: ok
+    expr: sum(rate(foo_total[5m]))
+- name: b
+  rules:
+  - expr: 1 > 2
+"#;
+        assert_eq!(
+            diagnostics(content),
+            vec![
+                "6:18: Plain: unexpected end of input inside braces, unclosed left brace at position 7",
+                "8:11: Single: label job is listed more than once in by (job, job)",
+                "10:16: Double: unclosed left parenthesis at position 4",
+                "13:10: literal: unclosed left parenthesis at position 3",
+                "17:7: folded: counter foo_total is aggregated directly, use rate() or increase() first",
+                "23:11: comparisons between scalars must use BOOL modifier",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lint_rules_nested() {
+        let content = "
+apiVersion: monitoring.coreos.com/v1
+kind: PrometheusRule
+spec:
+  groups:
+    - name: a
+      rules:
+        - alert: A
+          expr: sum(foo_total)
+          labels:
+            expr: not a rule
+---
+groups: []
+";
+        assert_eq!(
+            diagnostics(content),
+            vec![
+                "9:17: A: counter foo_total is aggregated directly, use rate() or increase() first"
+            ]
+        );
+
+        assert_eq!(
+            lint_rules("groups:\n  - name: \"a\n", &LintOptions::default()).unwrap_err(),
+            "2:11: while scanning a quoted scalar, found unexpected end of stream"
+        );
+    }
+}
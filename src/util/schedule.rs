@@ -0,0 +1,422 @@
+// Copyright 2023 Greptime Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! evaluation schedules of the selectors, which are the time windows of the
+//! samples a query reads.
+
+use crate::parser::{AtModifier, EvalStmt, Expr, Offset, SubqueryExpr, VectorSelector};
+use std::time::{Duration, SystemTime};
+
+/// Schedule of a selector, it is evaluated at the timestamps from `start` to `end`
+/// every `step`, and each evaluation reads the samples in `(t - range, t]`.
+/// The timestamps already have the `@` and `offset` modifiers applied. The
+/// `end` is before the `start` if the selector is never evaluated, like in a
+/// subquery whose range is shorter than its step.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelectorSchedule {
+    pub selector: VectorSelector,
+    /// the range of a matrix selector, or the lookback delta of a vector selector.
+    pub range: Duration,
+    pub start: SystemTime,
+    pub end: SystemTime,
+    /// zero if the selector is evaluated once.
+    pub step: Duration,
+}
+
+impl SelectorSchedule {
+    /// number of evaluations of the selector.
+    pub fn evaluations(&self) -> u64 {
+        let Ok(duration) = self.end.duration_since(self.start) else {
+            return 0;
+        };
+        match self.step.as_millis() {
+            0 => 1,
+            step => u64::try_from(duration.as_millis() / step)
+                .unwrap_or(u64::MAX)
+                .saturating_add(1),
+        }
+    }
+
+    /// the evaluation timestamps of the selector.
+    pub fn timestamps(&self) -> impl Iterator<Item = SystemTime> + '_ {
+        let step = u64::try_from(self.step.as_millis()).unwrap_or(u64::MAX);
+        (0..self.evaluations()).map_while(move |i| {
+            let offset = Duration::from_millis(step.checked_mul(i)?);
+            self.start.checked_add(offset)
+        })
+    }
+
+    /// the earliest sample time the selector may read, which is excluded. It
+    /// is an error if it is before the earliest [`SystemTime`].
+    pub fn min_time(&self) -> Result<SystemTime, String> {
+        self.start
+            .checked_sub(self.range)
+            .ok_or_else(|| format!("the range of {} starts out of range", self.selector))
+    }
+
+    /// the latest sample time the selector may read.
+    pub fn max_time(&self) -> SystemTime {
+        self.end
+    }
+}
+
+/// compute the evaluation schedules of all selectors in the statement, in
+/// depth-first order. Subqueries evaluate their inner expr at the timestamps
+/// aligned to their step, in the same way as the Prometheus engine. It is an
+/// error if the `@` and `offset` modifiers move a time out of the i64
+/// milliseconds the Prometheus engine works with.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::{parse, EvalStmt};
+/// use promql_parser::util::schedule::selector_schedules;
+/// use std::time::{Duration, SystemTime};
+///
+/// let stmt = EvalStmt {
+///     expr: parse("max_over_time(rate(foo[5m])[1h:10m] offset 1h)").unwrap(),
+///     start: SystemTime::UNIX_EPOCH + Duration::from_secs(7200),
+///     end: SystemTime::UNIX_EPOCH + Duration::from_secs(7200),
+///     interval: Duration::from_secs(60),
+///     lookback_delta: Duration::from_secs(300),
+/// };
+/// let schedules = selector_schedules(&stmt).unwrap();
+/// let schedule = &schedules[0];
+/// assert_eq!(schedule.evaluations(), 6);
+/// assert_eq!(schedule.start, SystemTime::UNIX_EPOCH + Duration::from_secs(600));
+/// assert_eq!(schedule.end, SystemTime::UNIX_EPOCH + Duration::from_secs(3600));
+/// assert_eq!(schedule.min_time(), Ok(SystemTime::UNIX_EPOCH + Duration::from_secs(300)));
+///
+/// let mut stmt = stmt;
+/// stmt.expr = parse("foo @ 9223372036854000 offset -1y").unwrap();
+/// assert!(selector_schedules(&stmt).is_err());
+/// ```
+pub fn selector_schedules(stmt: &EvalStmt) -> Result<Vec<SelectorSchedule>, String> {
+    let out_of_range = || "the time range of the query is out of range".to_string();
+    let ctx = Context {
+        query_start: to_millis(stmt.start).ok_or_else(out_of_range)?,
+        query_end: to_millis(stmt.end).ok_or_else(out_of_range)?,
+        lookback_delta: stmt.lookback_delta,
+        interval: millis(stmt.interval),
+    };
+    let grid = Grid {
+        start: ctx.query_start,
+        end: ctx.query_end,
+        step: if ctx.query_start == ctx.query_end {
+            0
+        } else {
+            ctx.interval
+        },
+    };
+    let mut schedules = vec![];
+    ctx.collect(&stmt.expr, grid, &mut schedules)?;
+    Ok(schedules)
+}
+
+/// evaluation timestamps in milliseconds.
+#[derive(Debug, Clone, Copy)]
+struct Grid {
+    start: i64,
+    end: i64,
+    step: i64,
+}
+
+struct Context {
+    query_start: i64,
+    query_end: i64,
+    lookback_delta: Duration,
+    interval: i64,
+}
+
+impl Context {
+    fn collect(
+        &self,
+        expr: &Expr,
+        grid: Grid,
+        schedules: &mut Vec<SelectorSchedule>,
+    ) -> Result<(), String> {
+        let out_of_range = || format!("the time of {expr} is out of range");
+        match expr {
+            Expr::VectorSelector(vs) => {
+                let schedule = self.schedule(vs, self.lookback_delta, grid);
+                schedules.push(schedule.ok_or_else(out_of_range)?);
+            }
+            Expr::MatrixSelector(ms) => {
+                let schedule = self.schedule(&ms.vector_selector, ms.range, grid);
+                schedules.push(schedule.ok_or_else(out_of_range)?);
+            }
+            Expr::Subquery(sq) => {
+                let inner = self.subquery(sq, grid).ok_or_else(out_of_range)?;
+                self.collect(&sq.expr, inner, schedules)?;
+            }
+            _ => {
+                for child in expr.children() {
+                    self.collect(child, grid, schedules)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// the grid the inner expr of the subquery is evaluated at.
+    fn subquery(&self, sq: &SubqueryExpr, grid: Grid) -> Option<Grid> {
+        let outer = self.apply(&sq.at, &sq.offset, grid)?;
+        let step = sq.step.duration().map_or(self.interval, millis).max(1);
+        // the first timestamp after the range start aligned to the step
+        let min = outer.start.checked_sub(millis(sq.range))?;
+        let mut start = min.div_euclid(step).checked_mul(step)?;
+        if start <= min {
+            start = start.checked_add(step)?;
+        }
+        Some(Grid {
+            start,
+            end: outer.end,
+            step,
+        })
+    }
+
+    fn schedule(
+        &self,
+        vs: &VectorSelector,
+        range: Duration,
+        grid: Grid,
+    ) -> Option<SelectorSchedule> {
+        let grid = self.apply(&vs.at, &vs.offset, grid)?;
+        // the last timestamp on the grid, if the end is not aligned
+        let end = match grid.step {
+            0 => grid.start,
+            _ if grid.end < grid.start => grid.end,
+            step => grid
+                .start
+                .checked_add(grid.end.checked_sub(grid.start)? / step * step)?,
+        };
+        Some(SelectorSchedule {
+            selector: vs.clone(),
+            range,
+            start: from_millis(grid.start),
+            end: from_millis(end),
+            step: Duration::from_millis(grid.step as u64),
+        })
+    }
+
+    /// apply the `@` and `offset` modifiers to the grid, None if they move it
+    /// out of range.
+    fn apply(&self, at: &Option<AtModifier>, offset: &Option<Offset>, grid: Grid) -> Option<Grid> {
+        let mut grid = match at {
+            Some(AtModifier::Start) => self.pinned(self.query_start),
+            Some(AtModifier::End) => self.pinned(self.query_end),
+            Some(AtModifier::At(at)) => self.pinned(to_millis(*at)?),
+            None => grid,
+        };
+        let offset = offset
+            .as_ref()
+            .map_or(Some(0), |o| i64::try_from(o.as_millis()).ok())?;
+        grid.start = grid.start.checked_sub(offset)?;
+        grid.end = grid.end.checked_sub(offset)?;
+        Some(grid)
+    }
+
+    fn pinned(&self, at: i64) -> Grid {
+        Grid {
+            start: at,
+            end: at,
+            step: 0,
+        }
+    }
+}
+
+/// the milliseconds of the duration, saturated at i64::MAX.
+fn millis(d: Duration) -> i64 {
+    i64::try_from(d.as_millis()).unwrap_or(i64::MAX)
+}
+
+/// the milliseconds since the epoch, None if they do not fit in an i64.
+fn to_millis(time: SystemTime) -> Option<i64> {
+    match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(d) => i64::try_from(d.as_millis()).ok(),
+        Err(e) => i64::try_from(e.duration().as_millis()).ok()?.checked_neg(),
+    }
+}
+
+fn from_millis(ms: i64) -> SystemTime {
+    let d = Duration::from_millis(ms.unsigned_abs());
+    if ms >= 0 {
+        SystemTime::UNIX_EPOCH + d
+    } else {
+        SystemTime::UNIX_EPOCH - d
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    /// (name, range, start, end, step) in seconds
+    fn schedules(query: &str, start: i64, end: i64) -> Vec<(String, u64, i64, i64, u64)> {
+        let stmt = EvalStmt {
+            expr: parse(query).unwrap(),
+            start: from_millis(start * 1000),
+            end: from_millis(end * 1000),
+            interval: Duration::from_secs(60),
+            lookback_delta: Duration::from_secs(300),
+        };
+        selector_schedules(&stmt)
+            .unwrap()
+            .into_iter()
+            .map(|s| {
+                (
+                    s.selector.name.clone().unwrap_or_default(),
+                    s.range.as_secs(),
+                    to_millis(s.start).unwrap() / 1000,
+                    to_millis(s.end).unwrap() / 1000,
+                    s.step.as_secs(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_selector_schedules() {
+        assert_eq!(
+            schedules("foo", 1000, 1000),
+            vec![("foo".into(), 300, 1000, 1000, 0)]
+        );
+        assert_eq!(
+            schedules("foo", 1000, 2000),
+            vec![("foo".into(), 300, 1000, 1960, 60)]
+        );
+        assert_eq!(
+            schedules("rate(foo[5m] offset 10m) / bar @ 500", 1000, 2000),
+            vec![
+                ("foo".into(), 300, 400, 1360, 60),
+                ("bar".into(), 300, 500, 500, 0)
+            ]
+        );
+        assert_eq!(
+            schedules("foo @ end() offset -1m + bar @ start()", 1000, 2000),
+            vec![
+                ("foo".into(), 300, 2060, 2060, 0),
+                ("bar".into(), 300, 1000, 1000, 0)
+            ]
+        );
+        assert_eq!(
+            schedules("max_over_time(foo[1h:10m])", 7200, 7800),
+            vec![("foo".into(), 300, 4200, 7800, 600)]
+        );
+        assert_eq!(
+            schedules("max_over_time(foo[1h:])", 7230, 7230),
+            vec![("foo".into(), 300, 3660, 7200, 60)]
+        );
+        assert_eq!(
+            schedules("max_over_time(foo[10m:5m] @ 1000)", 0, 100000),
+            vec![("foo".into(), 300, 600, 900, 300)]
+        );
+        assert_eq!(
+            schedules(
+                "max_over_time(max_over_time(rate(foo[1m])[10m:1m])[1h:10m])",
+                7200,
+                7200
+            ),
+            vec![("foo".into(), 60, 3660, 7200, 60)]
+        );
+        assert_eq!(
+            schedules(
+                "topk(scalar(foo), max_over_time(bar[5m:] @ 100))",
+                1000,
+                1000
+            ),
+            vec![
+                ("foo".into(), 300, 1000, 1000, 0),
+                ("bar".into(), 300, -180, 60, 60)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_schedule_timestamps() {
+        let stmt = EvalStmt {
+            expr: parse("foo offset 1m").unwrap(),
+            start: from_millis(0),
+            end: from_millis(150_000),
+            interval: Duration::from_secs(60),
+            lookback_delta: Duration::from_secs(300),
+        };
+        let schedule = &selector_schedules(&stmt).unwrap()[0];
+        assert_eq!(schedule.evaluations(), 3);
+        let timestamps: Vec<i64> = schedule.timestamps().flat_map(to_millis).collect();
+        assert_eq!(timestamps, vec![-60_000, 0, 60_000]);
+        assert_eq!(to_millis(schedule.min_time().unwrap()), Some(-360_000));
+        assert_eq!(to_millis(schedule.max_time()), Some(60_000));
+
+        // no step of the subquery is in its range
+        let stmt = EvalStmt {
+            expr: parse("max_over_time(foo[1m:5m])").unwrap(),
+            start: from_millis(1_000_000),
+            end: from_millis(1_000_000),
+            interval: Duration::from_secs(60),
+            lookback_delta: Duration::from_secs(300),
+        };
+        let schedule = &selector_schedules(&stmt).unwrap()[0];
+        assert_eq!(schedule.evaluations(), 0);
+        assert_eq!(schedule.timestamps().count(), 0);
+
+        let schedule = SelectorSchedule {
+            step: Duration::from_secs(365 * 86400),
+            start: from_millis(0),
+            end: from_millis(200 * 365 * 86400 * 1000),
+            ..schedule.clone()
+        };
+        assert_eq!(schedule.evaluations(), 201);
+        assert_eq!(schedule.timestamps().last(), Some(schedule.end));
+    }
+
+    #[test]
+    fn test_schedules_out_of_range() {
+        let stmt = |query| EvalStmt {
+            expr: parse(query).unwrap(),
+            start: from_millis(0),
+            end: from_millis(3_600_000),
+            interval: Duration::from_secs(60),
+            lookback_delta: Duration::from_secs(300),
+        };
+
+        // the modifiers move the time past the i64 milliseconds
+        let cases = [
+            "foo @ 9223372036854000 offset -1y",
+            "foo @ -9223372036854000 offset 1y",
+            "rate(foo[5m] @ 9223372036854000 offset -1y)",
+            "max_over_time(foo[1h:] @ -9223372036854000 offset 1y)",
+            "max_over_time((foo offset 1y)[1h:] @ -9223372036854000)",
+            "max_over_time(foo[1y:1m] @ -9223372036854000)",
+        ];
+        for query in cases {
+            let err = selector_schedules(&stmt(query)).unwrap_err();
+            assert!(err.ends_with("is out of range"), "{query}: {err}");
+        }
+
+        // and stay within them near the bounds
+        let schedules = selector_schedules(&stmt("foo @ 9223372036854000 offset 1y")).unwrap();
+        assert_eq!(schedules[0].evaluations(), 1);
+        let schedules = selector_schedules(&stmt("foo @ -9223372036854000 offset -1y")).unwrap();
+        assert_eq!(schedules[0].evaluations(), 1);
+
+        let schedule = SelectorSchedule {
+            start: SystemTime::UNIX_EPOCH,
+            range: Duration::MAX,
+            ..schedules[0].clone()
+        };
+        assert!(schedule.min_time().is_err());
+    }
+}
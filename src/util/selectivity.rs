@@ -0,0 +1,176 @@
+// Copyright 2023 Greptime Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! estimates of the number of series selected by a query, from the estimates
+//! of the caller about the series of the metrics and their matchers.
+
+use crate::label::{MatchOp, Matcher, METRIC_NAME};
+use crate::parser::{Expr, NodeId, VectorSelector};
+
+/// the source of the estimates, like the statistics of an index.
+pub trait SelectivityEstimator {
+    /// the number of series of the metric, of all metrics if it is None, like
+    /// for `{job="api"}`.
+    fn series_count(&self, metric: Option<&str>) -> f64;
+
+    /// the fraction of the series of the metric kept by the matcher, between
+    /// 0 and 1. Matchers keep all series by default.
+    fn selectivity(&self, _metric: Option<&str>, _matcher: &Matcher) -> f64 {
+        1.0
+    }
+}
+
+/// the estimated series of the selectors of a query.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SeriesEstimate {
+    /// the vector and matrix selectors with their estimates, in pre-order.
+    pub selectors: Vec<(NodeId, f64)>,
+    /// the sum of the selectors, the series the query reads.
+    pub total: f64,
+}
+
+/// the estimated series of the selector, the series of the metric times the
+/// selectivities of its matchers, which are assumed to be independent.
+pub fn estimate_selector(vs: &VectorSelector, estimator: &impl SelectivityEstimator) -> f64 {
+    let metric = vs.metric_name();
+    let mut selectivities: Vec<f64> = vs
+        .matchers
+        .matchers
+        .iter()
+        .filter(|m| {
+            !(m.op == MatchOp::Equal && m.name == METRIC_NAME && Some(m.value.as_str()) == metric)
+        })
+        .map(|m| estimator.selectivity(metric, m).clamp(0.0, 1.0))
+        .collect();
+    // the matchers are unordered, sort them for a stable product
+    selectivities.sort_by(f64::total_cmp);
+    selectivities
+        .into_iter()
+        .fold(estimator.series_count(metric).max(0.0), |n, s| n * s)
+}
+
+/// the estimated series of all vector and matrix selectors of the query,
+/// including the ones in aggregation params.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::label::Matcher;
+/// use promql_parser::parser::parse;
+/// use promql_parser::util::selectivity::{estimate_series, SelectivityEstimator};
+///
+/// struct Stats;
+///
+/// impl SelectivityEstimator for Stats {
+///     fn series_count(&self, metric: Option<&str>) -> f64 {
+///         match metric {
+///             Some("http_requests_total") => 1000.0,
+///             Some(_) => 100.0,
+///             None => 100_000.0,
+///         }
+///     }
+///
+///     fn selectivity(&self, _metric: Option<&str>, matcher: &Matcher) -> f64 {
+///         match matcher.name.as_str() {
+///             "job" => 0.1,
+///             _ => 0.5,
+///         }
+///     }
+/// }
+///
+/// let expr = parse(r#"sum(rate(http_requests_total{job="api"}[5m])) / sum(up)"#).unwrap();
+/// let estimate = estimate_series(&expr, &Stats);
+/// assert_eq!(estimate.selectors.len(), 2);
+/// assert_eq!(estimate.selectors[0].1, 100.0);
+/// assert_eq!(estimate.total, 200.0);
+/// ```
+pub fn estimate_series(expr: &Expr, estimator: &impl SelectivityEstimator) -> SeriesEstimate {
+    let selectors: Vec<(NodeId, f64)> = expr
+        .nodes()
+        .into_iter()
+        .filter_map(|(id, node)| {
+            let vs = match node {
+                Expr::VectorSelector(vs) => vs,
+                Expr::MatrixSelector(ms) => &ms.vector_selector,
+                _ => return None,
+            };
+            Some((id, estimate_selector(vs, estimator)))
+        })
+        .collect();
+    let total = selectors.iter().map(|(_, n)| n).sum();
+    SeriesEstimate { selectors, total }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    /// 1000 series per metric, 10000 in total, equal matchers keep a tenth
+    /// and the others half of them.
+    struct Stats;
+
+    impl SelectivityEstimator for Stats {
+        fn series_count(&self, metric: Option<&str>) -> f64 {
+            metric.map_or(10000.0, |_| 1000.0)
+        }
+
+        fn selectivity(&self, _metric: Option<&str>, matcher: &Matcher) -> f64 {
+            match matcher.op {
+                MatchOp::Equal => 0.1,
+                _ => 0.5,
+            }
+        }
+    }
+
+    struct Unknown;
+
+    impl SelectivityEstimator for Unknown {
+        fn series_count(&self, _metric: Option<&str>) -> f64 {
+            -1.0
+        }
+    }
+
+    #[test]
+    fn test_estimate_series() {
+        let cases = vec![
+            ("1 + 2", vec![], 0.0),
+            ("foo", vec![1000.0], 1000.0),
+            (r#"{__name__="foo"}"#, vec![1000.0], 1000.0),
+            (r#"{__name__=~"foo|bar"}"#, vec![5000.0], 5000.0),
+            (r#"foo{job="api", path!="/"}"#, vec![50.0], 50.0),
+            (r#"{job="api"}"#, vec![1000.0], 1000.0),
+            (
+                r#"rate(foo{job="api"}[5m]) / on (job) bar"#,
+                vec![100.0, 1000.0],
+                1100.0,
+            ),
+            (
+                r#"topk(scalar(count(foo)), bar{job=~"a.*"} offset 1m)"#,
+                vec![1000.0, 500.0],
+                1500.0,
+            ),
+        ];
+        for (query, selectors, total) in cases {
+            let estimate = estimate_series(&parse(query).unwrap(), &Stats);
+            let found: Vec<f64> = estimate.selectors.iter().map(|(_, n)| *n).collect();
+            assert_eq!(found, selectors, "{query}");
+            assert_eq!(estimate.total, total, "{query}");
+        }
+
+        // negative counts are taken as no series
+        let estimate = estimate_series(&parse(r#"foo{job="api"}"#).unwrap(), &Unknown);
+        assert_eq!(estimate.total, 0.0);
+    }
+}
@@ -0,0 +1,172 @@
+// Copyright 2023 Greptime Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! helpers for the `match[]` parameters of the series and labels APIs,
+//! like `/api/v1/series?match[]=up{job="api"}`.
+
+use crate::label::{MatchOp, Matcher, Matchers, METRIC_NAME};
+use crate::parser::{self, Expr, VectorSelector};
+
+/// render the metric name and matchers as a series selector, like `up{job="api"}`.
+///
+/// the `__name__` matcher equal to `name` is omitted, since the name already covers it.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::label::{Matcher, Matchers};
+/// use promql_parser::util::series::selector_to_string;
+///
+/// let matchers = Matchers::one(Matcher::eq("job", "api"));
+/// assert_eq!(selector_to_string(Some("up"), &matchers), r#"up{job="api"}"#);
+/// assert_eq!(selector_to_string(None, &matchers), r#"{job="api"}"#);
+/// ```
+pub fn selector_to_string(name: Option<&str>, matchers: &Matchers) -> String {
+    selector_to_string_with(name, matchers, Matcher::to_string)
+}
+
+/// same as [`selector_to_string`], with each matcher rendered by `matcher`,
+/// the rendered matchers are sorted like in the `Display` of [`Matchers`].
+pub(crate) fn selector_to_string_with(
+    name: Option<&str>,
+    matchers: &Matchers,
+    matcher: impl Fn(&Matcher) -> String,
+) -> String {
+    let mut matchers: Vec<String> = matchers
+        .matchers
+        .iter()
+        .filter(|m| {
+            !(m.op == MatchOp::Equal && m.name == METRIC_NAME && Some(m.value.as_str()) == name)
+        })
+        .map(matcher)
+        .collect();
+    matchers.sort();
+    let matchers = matchers.join(",");
+
+    match name {
+        Some(name) if matchers.is_empty() => name.to_string(),
+        Some(name) => format!("{name}{{{matchers}}}"),
+        None => format!("{{{matchers}}}"),
+    }
+}
+
+/// parse a series selector into the metric name and matchers, the matchers
+/// contain the `__name__` matcher if the name is present.
+///
+/// only plain vector selectors are accepted, modifiers like `offset` and `@` are not.
+pub fn selector_from_str(s: &str) -> Result<(Option<String>, Matchers), String> {
+    match parser::parse(s)? {
+        Expr::VectorSelector(VectorSelector {
+            name,
+            matchers,
+            offset: None,
+            at: None,
+        }) => Ok((name, matchers)),
+        _ => Err(format!(
+            "invalid series selector {s}, expected a vector selector"
+        )),
+    }
+}
+
+/// encode the selectors as the repeated `match[]` query parameter,
+/// like `match[]=up&match[]=process_start_time_seconds{job="prometheus"}`.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::util::series::encode_match_params;
+///
+/// assert_eq!(
+///     encode_match_params(["up", r#"{job="api"}"#]),
+///     "match%5B%5D=up&match%5B%5D=%7Bjob%3D%22api%22%7D"
+/// );
+/// ```
+#[cfg(feature = "url")]
+pub fn encode_match_params<I, S>(selectors: I) -> String
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    selectors
+        .into_iter()
+        .map(|s| format!("match%5B%5D={}", urlencoding::encode(s.as_ref())))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::label::Matcher;
+
+    #[test]
+    fn test_selector_round_trip() {
+        let cases = vec![
+            ("up", "up"),
+            (r#"up{job="api"}"#, r#"up{job="api"}"#),
+            (
+                r#"{__name__="up",job!~"api|web"}"#,
+                r#"{__name__="up",job!~"api|web"}"#,
+            ),
+            (
+                r#"http_requests_total{method="GET",code=~"5.."}"#,
+                r#"http_requests_total{code=~"5..",method="GET"}"#,
+            ),
+        ];
+        for (input, expected) in cases {
+            let (name, matchers) = selector_from_str(input).unwrap();
+            assert_eq!(selector_to_string(name.as_deref(), &matchers), expected);
+        }
+    }
+
+    #[test]
+    fn test_selector_to_string() {
+        let matchers = Matchers::one(Matcher::eq(METRIC_NAME, "up"));
+        assert_eq!(selector_to_string(Some("up"), &matchers), "up");
+        assert_eq!(selector_to_string(None, &matchers), r#"{__name__="up"}"#);
+        assert_eq!(selector_to_string(Some("up"), &Matchers::empty()), "up");
+    }
+
+    #[test]
+    fn test_selector_from_str_fail() {
+        let cases = vec![
+            (
+                "rate(up[5m])",
+                "invalid series selector rate(up[5m]), expected a vector selector",
+            ),
+            (
+                "up offset 5m",
+                "invalid series selector up offset 5m, expected a vector selector",
+            ),
+            (
+                "up[5m]",
+                "invalid series selector up[5m], expected a vector selector",
+            ),
+        ];
+        for (input, err) in cases {
+            assert_eq!(selector_from_str(input).unwrap_err(), err);
+        }
+        assert!(selector_from_str("up{").is_err());
+    }
+
+    #[cfg(feature = "url")]
+    #[test]
+    fn test_encode_match_params() {
+        assert_eq!(encode_match_params(Vec::<String>::new()), "");
+        assert_eq!(
+            encode_match_params([r#"up{job=~"a|b"}"#]),
+            "match%5B%5D=up%7Bjob%3D~%22a%7Cb%22%7D"
+        );
+    }
+}
@@ -0,0 +1,212 @@
+// Copyright 2023 Greptime Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::parser::{
+    AtModifier, BinModifier, Expr, LabelModifier, Offset, VectorMatchCardinality, VectorSelector,
+};
+use crate::util::series::selector_to_string_with;
+
+/// placeholder of the literals, label values, durations and timestamps.
+const PLACEHOLDER: &str = "?";
+
+/// render the shape of the expr, which is the expr with literals, label values,
+/// durations and timestamps replaced by `?`, while the structure, metric names
+/// and grouping labels are kept. Queries only differing in those values
+/// have the same shape, so it is a low-cardinality key for query analytics.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::parse;
+/// use promql_parser::util::shape::shape;
+///
+/// let expr = parse(r#"sum by (job) (rate(http_requests_total{job="api"}[5m])) > 10"#).unwrap();
+/// assert_eq!(
+///     shape(&expr),
+///     "sum by (job) (rate(http_requests_total{job=?}[?])) > ?"
+/// );
+/// ```
+pub fn shape(expr: &Expr) -> String {
+    let mut s = String::new();
+    write_shape(&mut s, expr);
+    s
+}
+
+fn write_shape(s: &mut String, expr: &Expr) {
+    match expr {
+        Expr::Aggregate(ex) => {
+            s.push_str(&ex.op.to_string());
+            if let Some(modifier) = &ex.modifier {
+                s.push_str(&format!(" {modifier} "));
+            }
+            s.push('(');
+            if let Some(param) = &ex.param {
+                write_shape(s, param);
+                s.push_str(", ");
+            }
+            write_shape(s, &ex.expr);
+            s.push(')');
+        }
+        Expr::Unary(ex) => {
+            s.push('-');
+            write_shape(s, &ex.expr);
+        }
+        Expr::Binary(ex) => {
+            write_shape(s, &ex.lhs);
+            s.push_str(&format!(" {}", ex.op));
+            if let Some(modifier) = &ex.modifier {
+                write_bin_modifier(s, modifier);
+            }
+            s.push(' ');
+            write_shape(s, &ex.rhs);
+        }
+        Expr::Paren(ex) => {
+            s.push('(');
+            write_shape(s, &ex.expr);
+            s.push(')');
+        }
+        Expr::Subquery(ex) => {
+            write_shape(s, &ex.expr);
+            let step = if ex.step.duration().is_some() {
+                PLACEHOLDER
+            } else {
+                ""
+            };
+            s.push_str(&format!("[{PLACEHOLDER}:{step}]"));
+            write_modifiers(s, &ex.at, &ex.offset);
+        }
+        Expr::NumberLiteral(_) | Expr::StringLiteral(_) => s.push_str(PLACEHOLDER),
+        Expr::VectorSelector(vs) => {
+            write_selector(s, vs);
+            write_modifiers(s, &vs.at, &vs.offset);
+        }
+        Expr::MatrixSelector(ms) => {
+            let vs = &ms.vector_selector;
+            write_selector(s, vs);
+            s.push_str(&format!("[{PLACEHOLDER}]"));
+            write_modifiers(s, &vs.at, &vs.offset);
+        }
+        Expr::Call(call) => {
+            s.push_str(call.func.name);
+            write_args(s, call.args.args.iter().map(|arg| arg.as_ref()));
+        }
+        Expr::Extension(ext) => {
+            s.push_str(ext.expr.name());
+            write_args(s, ext.expr.children().iter());
+        }
+    }
+}
+
+fn write_args<'a>(s: &mut String, args: impl Iterator<Item = &'a Expr>) {
+    s.push('(');
+    for (i, arg) in args.enumerate() {
+        if i > 0 {
+            s.push_str(", ");
+        }
+        write_shape(s, arg);
+    }
+    s.push(')');
+}
+
+fn write_bin_modifier(s: &mut String, modifier: &BinModifier) {
+    if modifier.return_bool {
+        s.push_str(" bool");
+    }
+    let Some(matching) = &modifier.matching else {
+        return;
+    };
+    let (tag, labels) = match matching {
+        LabelModifier::Include(labels) => ("on", labels),
+        LabelModifier::Exclude(labels) => ("ignoring", labels),
+    };
+    s.push_str(&format!(" {tag} ({})", sorted(labels.iter())));
+    match &modifier.card {
+        VectorMatchCardinality::ManyToOne(labels) => {
+            s.push_str(&format!(" group_left ({})", sorted(labels.iter())))
+        }
+        VectorMatchCardinality::OneToMany(labels) => {
+            s.push_str(&format!(" group_right ({})", sorted(labels.iter())))
+        }
+        _ => {}
+    }
+}
+
+/// the metric name is kept, also when it is given by the `__name__` matcher,
+/// the values of the other matchers are replaced.
+fn write_selector(s: &mut String, vs: &VectorSelector) {
+    let selector = selector_to_string_with(vs.metric_name(), &vs.matchers, |m| {
+        format!("{}{}{PLACEHOLDER}", m.name, m.op)
+    });
+    s.push_str(&selector);
+}
+
+fn write_modifiers(s: &mut String, at: &Option<AtModifier>, offset: &Option<Offset>) {
+    match at {
+        Some(AtModifier::At(_)) => s.push_str(&format!(" @ {PLACEHOLDER}")),
+        Some(at) => s.push_str(&format!(" {at}")),
+        None => {}
+    }
+    if offset.is_some() {
+        s.push_str(&format!(" offset {PLACEHOLDER}"));
+    }
+}
+
+fn sorted<S: AsRef<str>>(items: impl Iterator<Item = S>) -> String {
+    let mut items: Vec<String> = items.map(|s| s.as_ref().to_string()).collect();
+    items.sort();
+    items.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    #[test]
+    fn test_shape() {
+        let cases = vec![
+            ("foo", "foo"),
+            (r#"foo{job="api", code=~"5.."}"#, "foo{code=~?,job=?}"),
+            (r#"{__name__="foo"}"#, "foo"),
+            (r#"{__name__="foo", job="a"}"#, "foo{job=?}"),
+            (r#"{__name__=~"foo|bar"}"#, "{__name__=~?}"),
+            ("-foo offset 5m", "-foo offset ?"),
+            ("foo[5m] @ 1609746000", "foo[?] @ ?"),
+            ("foo @ start()", "foo @ start()"),
+            ("rate(foo[5m])[1h:]", "rate(foo[?])[?:]"),
+            ("rate(foo[5m])[1h:1m] @ end()", "rate(foo[?])[?:?] @ end()"),
+            ("topk by (job) (5, foo)", "topk by (job) (?, foo)"),
+            (r#"count_values("code", foo)"#, "count_values(?, foo)"),
+            (
+                "a / on(job) group_left(env) b == bool 1",
+                "a / on (job) group_left (env) b == bool ?",
+            ),
+            ("(a + 1) * 2", "(a + ?) * ?"),
+            (
+                r#"label_replace(up, "a", "$1", "b", "(.*)")"#,
+                "label_replace(up, ?, ?, ?, ?)",
+            ),
+        ];
+        for (query, expected) in cases {
+            assert_eq!(shape(&parse(query).unwrap()), expected, "{query}");
+        }
+    }
+
+    #[test]
+    fn test_same_shape() {
+        let a = parse(r#"sum(rate(foo{job="a"}[1m])) > 1"#).unwrap();
+        let b = parse(r#"sum(rate(foo{job="b"}[5m])) > 2"#).unwrap();
+        assert_eq!(shape(&a), shape(&b));
+    }
+}
@@ -0,0 +1,270 @@
+// Copyright 2023 Greptime Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! source maps from the nodes of a rewritten expr to the query it is parsed from.
+
+use crate::parser::{Expr, NodeId, Spans};
+use crate::util::intern::{ExprCache, ExprId};
+use std::collections::HashMap;
+use std::mem::discriminant;
+use std::ops::Range;
+
+/// SourceMap links the nodes of a rewritten expr to the spans of the nodes
+/// they derive from in the original query, so errors about the rewritten
+/// expr can be reported against the query as it was written.
+///
+/// The rewritten tree is matched with the original from the root down. A
+/// subtree equal to one of the original keeps its spans, wherever it is moved,
+/// preferring the equal subtree at the same position, and then one within the
+/// original node it derives from. Otherwise the children of a node of the same
+/// kind and with as many children as the original node are matched in order,
+/// and any other node, like one wrapping or replacing an original node, gets
+/// the span of the original node at its position.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::label::Matcher;
+/// use promql_parser::parser::{parse_with_spans, Expr, NodeId, ParseOptions};
+/// use promql_parser::util::source_map::SourceMap;
+///
+/// let query = "rate(foo[5m]) / bar";
+/// let (expr, spans) = parse_with_spans(query, &ParseOptions::new()).unwrap();
+///
+/// // scope the query to a tenant, and wrap it in an aggregation
+/// let mut scoped = expr.clone();
+/// let Expr::Binary(binary) = &mut scoped else { unreachable!() };
+/// let Expr::VectorSelector(bar) = &mut *binary.rhs else { unreachable!() };
+/// bar.matchers.matchers.insert(Matcher::eq("tenant", "a"));
+/// let rewritten = Expr::from(scoped.sum().unwrap());
+/// assert_eq!(rewritten.to_string(), r#"sum(rate(foo[5m]) / bar{tenant="a"})"#);
+///
+/// let map = SourceMap::new(&expr, &spans, &rewritten);
+/// let texts: Vec<&str> = rewritten
+///     .nodes()
+///     .into_iter()
+///     .map(|(id, _)| map.text(id, query).unwrap())
+///     .collect();
+/// assert_eq!(
+///     texts,
+///     ["rate(foo[5m]) / bar", "rate(foo[5m]) / bar", "rate(foo[5m])", "foo[5m]", "bar"]
+/// );
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceMap {
+    spans: Vec<Option<Range<usize>>>,
+}
+
+impl SourceMap {
+    /// map the nodes of `rewritten` to the spans of `original`, which are the
+    /// spans of the query `original` is parsed from.
+    pub fn new(original: &Expr, spans: &Spans, rewritten: &Expr) -> Self {
+        let mut cache = ExprCache::new();
+        let original_ids = cache.intern_nodes(original);
+        let mut occurrences: HashMap<ExprId, Vec<usize>> = HashMap::new();
+        for (index, id) in original_ids.iter().enumerate() {
+            occurrences.entry(*id).or_default().push(index);
+        }
+        let mut sizes = Vec::with_capacity(original_ids.len());
+        subtree_sizes(original, &mut sizes);
+        let linker = Linker {
+            original: original.nodes().into_iter().map(|(_, node)| node).collect(),
+            original_ids,
+            sizes,
+            occurrences,
+            rewritten_ids: cache.intern_nodes(rewritten),
+        };
+        let mut map = SourceMap {
+            spans: Vec::with_capacity(rewritten.node_count()),
+        };
+        linker.link(&mut map, spans, 0, rewritten);
+        map
+    }
+
+    /// the span in the original query of the node of the rewritten expr.
+    pub fn span(&self, id: NodeId) -> Option<Range<usize>> {
+        self.spans.get(id.index()).cloned().flatten()
+    }
+
+    /// the text in the original query of the node of the rewritten expr.
+    pub fn text<'a>(&self, id: NodeId, input: &'a str) -> Option<&'a str> {
+        self.span(id).and_then(|span| input.get(span))
+    }
+}
+
+/// the nodes of the original and the rewritten expr by their pre-order index,
+/// which is the index of their [`NodeId`].
+struct Linker<'a> {
+    original: Vec<&'a Expr>,
+    original_ids: Vec<ExprId>,
+    /// the number of nodes in the subtree of each original node.
+    sizes: Vec<usize>,
+    /// the pre-order indexes of the original subtrees, in order.
+    occurrences: HashMap<ExprId, Vec<usize>>,
+    rewritten_ids: Vec<ExprId>,
+}
+
+impl Linker<'_> {
+    /// map `new` and its descendants, `from` is the index of the node of the
+    /// original it derives from. The spans are pushed in pre-order, which is the
+    /// order of the ids, so the index of `new` is the number of spans.
+    fn link(&self, map: &mut SourceMap, spans: &Spans, from: usize, new: &Expr) {
+        map.spans.push(spans.get(NodeId::new(from)));
+        let from_node = self.original[from];
+        let mut from_children = Vec::with_capacity(from_node.children().len());
+        let mut child = from + 1;
+        for _ in 0..from_node.children().len() {
+            from_children.push(child);
+            child += self.sizes[child];
+        }
+        let new_children = new.children();
+        let same_shape = discriminant(from_node) == discriminant(new)
+            && from_children.len() == new_children.len();
+        for (i, new) in new_children.into_iter().enumerate() {
+            let id = self.rewritten_ids[map.spans.len()];
+            let positional = same_shape.then(|| from_children[i]);
+            let from = positional
+                .filter(|c| self.original_ids[*c] == id)
+                .or_else(|| self.moved(id, from))
+                .or(positional)
+                .unwrap_or(from);
+            self.link(map, spans, from, new);
+        }
+    }
+
+    /// the original subtree equal to the one of the id, the first one within
+    /// the subtree of `from`, or else the first one.
+    fn moved(&self, id: ExprId, from: usize) -> Option<usize> {
+        let occurrences = self.occurrences.get(&id)?;
+        let first_within = occurrences.partition_point(|i| *i < from);
+        occurrences
+            .get(first_within)
+            .filter(|i| **i < from + self.sizes[from])
+            .or(occurrences.first())
+            .copied()
+    }
+}
+
+/// push the size of the subtree of each node in pre-order, and give the size of
+/// the expr.
+fn subtree_sizes(expr: &Expr, sizes: &mut Vec<usize>) -> usize {
+    let slot = sizes.len();
+    sizes.push(1);
+    let size = 1 + expr
+        .children()
+        .into_iter()
+        .map(|child| subtree_sizes(child, sizes))
+        .sum::<usize>();
+    sizes[slot] = size;
+    size
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{parse, parse_with_spans, ParseOptions};
+    use crate::util::lint::{apply_fixes, lint, LintOptions};
+
+    fn texts(query: &str, rewrite: impl Fn(&Expr) -> Expr) -> Vec<(String, &str)> {
+        let (expr, spans) = parse_with_spans(query, &ParseOptions::new()).unwrap();
+        let rewritten = rewrite(&expr);
+        let map = SourceMap::new(&expr, &spans, &rewritten);
+        rewritten
+            .nodes()
+            .into_iter()
+            .map(|(id, node)| (node.to_string(), map.text(id, query).unwrap()))
+            .collect()
+    }
+
+    fn pairs<'a>(pairs: &[(&str, &'a str)]) -> Vec<(String, &'a str)> {
+        pairs.iter().map(|(a, b)| (a.to_string(), *b)).collect()
+    }
+
+    #[test]
+    fn test_source_map() {
+        // the same tree maps to the original spans
+        assert_eq!(
+            texts("sum(foo) + bar", |e| e.clone()),
+            pairs(&[
+                ("sum(foo) + bar", "sum(foo) + bar"),
+                ("sum(foo)", "sum(foo)"),
+                ("foo", "foo"),
+                ("bar", "bar"),
+            ])
+        );
+
+        // a replaced node maps to the span of the node it replaces
+        assert_eq!(
+            texts("(foo) + bar", |_| parse("foo + sum(bar)").unwrap()),
+            pairs(&[
+                ("foo + sum(bar)", "(foo) + bar"),
+                ("foo", "foo"),
+                ("sum(bar)", "bar"),
+                ("bar", "bar"),
+            ])
+        );
+
+        // a moved subtree keeps its span
+        assert_eq!(
+            texts("rate(foo[5m]) / (bar - 1)", |_| parse(
+                "bar - rate(foo[5m])"
+            )
+            .unwrap()),
+            pairs(&[
+                ("bar - rate(foo[5m])", "rate(foo[5m]) / (bar - 1)"),
+                ("bar", "bar"),
+                ("rate(foo[5m])", "rate(foo[5m])"),
+                ("foo[5m]", "foo[5m]"),
+            ])
+        );
+
+        let query = "rate(sum(foo_total)[5m:])";
+        let fixed = texts(query, |e| apply_fixes(e, &lint(e, &LintOptions::new())));
+        assert_eq!(
+            fixed,
+            pairs(&[
+                ("sum(rate(foo_total[5m]))", query),
+                ("rate(foo_total[5m])", query),
+                ("foo_total[5m]", "sum(foo_total)[5m:]"),
+            ])
+        );
+
+        // a subtree moved out of the node it derived from keeps its span
+        assert_eq!(
+            texts("sum(foo) / max(bar)", |_| parse("sum(bar) / max(foo)")
+                .unwrap()),
+            pairs(&[
+                ("sum(bar) / max(foo)", "sum(foo) / max(bar)"),
+                ("sum(bar)", "sum(foo)"),
+                ("bar", "bar"),
+                ("max(foo)", "max(bar)"),
+                ("foo", "foo"),
+            ])
+        );
+
+        // equal subtrees at the same position keep their own spans
+        let query = "sum(foo) + foo";
+        let (expr, spans) = parse_with_spans(query, &ParseOptions::new()).unwrap();
+        let map = SourceMap::new(&expr, &spans, &expr);
+        let mapped: Vec<_> = expr.nodes().iter().map(|(id, _)| map.span(*id)).collect();
+        let expected: Vec<_> = expr.nodes().iter().map(|(id, _)| spans.get(*id)).collect();
+        assert_eq!(mapped, expected);
+
+        let (expr, spans) = parse_with_spans("foo", &ParseOptions::new()).unwrap();
+        let map = SourceMap::new(&expr, &spans, &expr);
+        assert_eq!(map.span(NodeId::ROOT), Some(0..3));
+        assert_eq!(map.span(expr.nodes()[0].0), Some(0..3));
+        assert_eq!(map.text(NodeId::ROOT, "fo"), None);
+    }
+}
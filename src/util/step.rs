@@ -0,0 +1,187 @@
+// Copyright 2023 Greptime Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! checks of the step of a range query against the structure of the query.
+
+use crate::parser::{Expr, NodeId};
+use std::time::Duration;
+
+/// the most points of a series in the result of a range query, as in the
+/// Prometheus API.
+pub const MAX_POINTS: u64 = 11000;
+
+/// the result of [`check_step`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StepCheck {
+    /// the smallest step which keeps the points of the result within [`MAX_POINTS`].
+    pub min_step: Duration,
+    /// the largest step which does not skip samples between the windows of
+    /// the range vectors, None if the query has none.
+    pub max_step: Option<Duration>,
+    /// the matrix selectors and subqueries evaluated at the step with a range
+    /// shorter than the step, in pre-order.
+    pub short_ranges: Vec<NodeId>,
+    /// the step is below `min_step`.
+    pub too_many_points: bool,
+}
+
+impl StepCheck {
+    /// whether the step is within the bounds.
+    pub fn is_ok(&self) -> bool {
+        self.short_ranges.is_empty() && !self.too_many_points
+    }
+}
+
+/// check the step of a range query over `duration`. A range vector evaluated
+/// every step only reads the samples of its range, so with a longer step the
+/// samples in between are missed, like for `rate(foo[1m])` at a 5m step.
+/// The range vectors inside subqueries are evaluated at the subquery step,
+/// so only the ranges of the outermost subqueries count, and the ones pinned
+/// with `@` are evaluated at the same time at every step, so they do not count.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::parse;
+/// use promql_parser::util::step::check_step;
+/// use std::time::Duration;
+///
+/// let expr = parse("rate(foo[1m]) / rate(bar[5m])").unwrap();
+/// let day = Duration::from_secs(86400);
+/// let check = check_step(&expr, day, Duration::from_secs(120)).unwrap();
+/// assert!(!check.is_ok());
+/// assert_eq!(check.short_ranges.len(), 1);
+/// assert_eq!(expr.get_node(check.short_ranges[0]).unwrap().to_string(), "foo[1m]");
+/// assert_eq!(check.max_step, Some(Duration::from_secs(60)));
+///
+/// let check = check_step(&expr, day, Duration::from_secs(1)).unwrap();
+/// assert!(check.too_many_points);
+/// assert_eq!(check.min_step, Duration::from_millis(7855));
+/// ```
+pub fn check_step(expr: &Expr, duration: Duration, step: Duration) -> Result<StepCheck, String> {
+    let step_millis = step.as_millis();
+    if step_millis == 0 {
+        return Err(
+            "zero or negative query resolution step widths are not accepted. Try a positive integer"
+                .into(),
+        );
+    }
+    let duration_millis = duration.as_millis();
+    let min_step = Duration::from_millis(duration_millis.div_ceil(MAX_POINTS as u128) as u64);
+    let too_many_points = duration_millis / step_millis > MAX_POINTS as u128;
+
+    let mut ranges = vec![];
+    step_ranges(expr, &mut 0, &mut ranges);
+    let mut max_step: Option<Duration> = None;
+    let mut short_ranges = vec![];
+    for (id, range) in ranges {
+        max_step = Some(max_step.map_or(range, |max| max.min(range)));
+        if range < step {
+            short_ranges.push(id);
+        }
+    }
+
+    Ok(StepCheck {
+        min_step,
+        max_step,
+        short_ranges,
+        too_many_points,
+    })
+}
+
+/// collect the ranges evaluated at the step with the ids of their nodes,
+/// `next` is the id of the expr in pre-order, it is advanced past the subtree.
+fn step_ranges(expr: &Expr, next: &mut usize, ranges: &mut Vec<(NodeId, Duration)>) {
+    let id = NodeId::new(*next);
+    let (range, at) = match expr {
+        Expr::MatrixSelector(ms) => (ms.range, &ms.vector_selector.at),
+        Expr::Subquery(sq) => (sq.range, &sq.at),
+        _ => {
+            *next += 1;
+            for child in expr.children() {
+                step_ranges(child, next, ranges);
+            }
+            return;
+        }
+    };
+    if at.is_none() {
+        ranges.push((id, range));
+    }
+    // the nodes inside a subquery are evaluated at its step
+    *next += expr.node_count();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    #[test]
+    fn test_check_step() {
+        let hour = Duration::from_secs(3600);
+        let minute = Duration::from_secs(60);
+        let cases = vec![
+            ("foo", None, vec![]),
+            ("rate(foo[5m])", Some(5), vec![]),
+            ("rate(foo[30s]) + rate(bar[2m])", Some(0), vec!["foo[30s]"]),
+            ("max_over_time(rate(foo[10s])[5m:10s])", Some(5), vec![]),
+            (
+                "max_over_time(rate(foo[10s])[30s:10s]) + sum(rate(bar[2m]))",
+                Some(0),
+                vec!["rate(foo[10s])[30s:10s]"],
+            ),
+            (
+                "topk(scalar(count_over_time(foo[30s])), bar)",
+                Some(0),
+                vec!["foo[30s]"],
+            ),
+            (
+                "rate(foo[30s] @ 100) + rate(bar[30s] @ end())",
+                None,
+                vec![],
+            ),
+            (
+                "max_over_time(rate(foo[10s])[30s:10s] @ start()) / rate(bar[30s])",
+                Some(0),
+                vec!["bar[30s]"],
+            ),
+        ];
+        for (query, max_step, short) in cases {
+            let expr = parse(query).unwrap();
+            let check = check_step(&expr, hour, minute).unwrap();
+            let found: Vec<String> = check
+                .short_ranges
+                .iter()
+                .map(|id| expr.get_node(*id).unwrap().to_string())
+                .collect();
+            assert_eq!(found, short, "{query}");
+            // the max step in whole minutes
+            assert_eq!(
+                check.max_step.map(|d| d.as_secs() / 60),
+                max_step,
+                "{query}"
+            );
+            assert_eq!(check.is_ok(), short.is_empty(), "{query}");
+            assert_eq!(check.min_step, Duration::from_millis(328));
+        }
+
+        let expr = parse("foo").unwrap();
+        let check = check_step(&expr, hour * 11, Duration::from_millis(3600)).unwrap();
+        assert!(!check.too_many_points);
+        assert_eq!(check.min_step, Duration::from_millis(3600));
+        let check = check_step(&expr, hour * 11, Duration::from_millis(3599)).unwrap();
+        assert!(check.too_many_points);
+        assert!(check_step(&expr, hour, Duration::ZERO).is_err());
+    }
+}
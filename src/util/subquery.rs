@@ -0,0 +1,147 @@
+// Copyright 2023 Greptime Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::parser::Expr;
+use crate::util::{walk_expr, ExprVisitor};
+use std::convert::Infallible;
+use std::time::Duration;
+
+/// the evaluation steps of a single subquery.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubquerySteps {
+    pub range: Duration,
+    /// the resolved step, which is the default interval if the subquery has none.
+    pub step: Duration,
+    /// number of inner evaluations for a single evaluation of the subquery.
+    pub steps: u64,
+    /// number of inner evaluations including the ones of the enclosing subqueries,
+    /// which is the product of the steps of all subqueries from the root to this one.
+    pub total_steps: u64,
+}
+
+/// estimate the evaluation steps of every subquery in the expr, in depth-first order.
+/// `default_interval` is used for subqueries without a step, like `foo[1h:]`.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::parse;
+/// use promql_parser::util::subquery::subquery_steps;
+/// use std::time::Duration;
+///
+/// let expr = parse("max_over_time(rate(foo[5m])[1h:1m])[1d:]").unwrap();
+/// let steps = subquery_steps(&expr, Duration::from_secs(3600));
+/// assert_eq!(steps.len(), 2);
+/// assert_eq!((steps[0].steps, steps[0].total_steps), (24, 24));
+/// assert_eq!((steps[1].steps, steps[1].total_steps), (60, 24 * 60));
+/// ```
+pub fn subquery_steps(expr: &Expr, default_interval: Duration) -> Vec<SubquerySteps> {
+    let mut visitor = StepsVisitor {
+        default_interval,
+        factors: vec![],
+        steps: vec![],
+    };
+    let _ = walk_expr(&mut visitor, expr);
+    visitor.steps
+}
+
+/// the largest total steps of all subqueries in the expr, 1 if there is no subquery.
+pub fn max_total_steps(expr: &Expr, default_interval: Duration) -> u64 {
+    subquery_steps(expr, default_interval)
+        .iter()
+        .map(|s| s.total_steps)
+        .max()
+        .unwrap_or(1)
+}
+
+struct StepsVisitor {
+    default_interval: Duration,
+    /// total steps of the enclosing subqueries.
+    factors: Vec<u64>,
+    steps: Vec<SubquerySteps>,
+}
+
+impl ExprVisitor for StepsVisitor {
+    type Error = Infallible;
+
+    fn pre_visit(&mut self, expr: &Expr) -> Result<bool, Self::Error> {
+        if let Expr::Subquery(sq) = expr {
+            let step = sq.resolve_step(self.default_interval);
+            // a zero step is counted as the smallest step of 1ms.
+            let steps = sq.range.as_millis().div_ceil(step.as_millis().max(1));
+            let steps = u64::try_from(steps).unwrap_or(u64::MAX);
+            let parent = self.factors.last().copied().unwrap_or(1);
+            let total_steps = parent.saturating_mul(steps);
+            self.factors.push(total_steps);
+            self.steps.push(SubquerySteps {
+                range: sq.range,
+                step,
+                steps,
+                total_steps,
+            });
+        }
+        Ok(true)
+    }
+
+    fn post_visit(&mut self, expr: &Expr) -> Result<bool, Self::Error> {
+        if let Expr::Subquery(_) = expr {
+            self.factors.pop();
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    fn steps(query: &str) -> Vec<(u64, u64)> {
+        subquery_steps(&parse(query).unwrap(), Duration::from_secs(60))
+            .iter()
+            .map(|s| (s.steps, s.total_steps))
+            .collect()
+    }
+
+    #[test]
+    fn test_subquery_steps() {
+        assert_eq!(steps("rate(foo[5m])"), vec![]);
+        assert_eq!(steps("foo[1h:]"), vec![(60, 60)]);
+        assert_eq!(steps("foo[1h:7m]"), vec![(9, 9)]);
+        assert_eq!(
+            steps("max_over_time(rate(foo[5m])[1h:5m])[1d:1h]"),
+            vec![(24, 24), (12, 24 * 12)]
+        );
+        assert_eq!(
+            steps("max_over_time(foo[10m:5m]) + max_over_time(max_over_time(bar[30m:])[1h:10m])"),
+            vec![(2, 2), (6, 6), (30, 180)]
+        );
+        assert_eq!(
+            steps("topk(scalar(max_over_time(foo[10m:])), bar)"),
+            vec![(10, 10)]
+        );
+    }
+
+    #[test]
+    fn test_max_total_steps() {
+        let expr =
+            parse("max_over_time(foo[10m:5m]) + max_over_time(max_over_time(bar[30m:])[1h:10m])")
+                .unwrap();
+        assert_eq!(max_total_steps(&expr, Duration::from_secs(60)), 180);
+        let expr = parse("foo").unwrap();
+        assert_eq!(max_total_steps(&expr, Duration::from_secs(60)), 1);
+        let expr = parse("foo[1h:]").unwrap();
+        assert_eq!(max_total_steps(&expr, Duration::ZERO), 3_600_000);
+    }
+}
@@ -0,0 +1,113 @@
+// Copyright 2023 Greptime Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::parser::token::token_display;
+use crate::parser::Expr;
+use crate::util::{walk_expr, ExprVisitor};
+use std::collections::BTreeSet;
+use std::convert::Infallible;
+
+/// the functions, binary operators and aggregations used by an expr.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Summary {
+    /// function names, like `rate`.
+    pub functions: BTreeSet<&'static str>,
+    /// binary operators, like `+` or `and`.
+    pub binary_operators: BTreeSet<&'static str>,
+    /// aggregation operators, like `sum`.
+    pub aggregations: BTreeSet<&'static str>,
+}
+
+/// collect the functions, binary operators and aggregations used by the expr,
+/// including the ones in aggregation params.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::parse;
+/// use promql_parser::util::summary::summarize;
+///
+/// let expr = parse("sum(rate(foo[5m])) / sum(rate(bar[5m])) > 0.5").unwrap();
+/// let summary = summarize(&expr);
+/// assert_eq!(summary.functions.into_iter().collect::<Vec<_>>(), vec!["rate"]);
+/// assert_eq!(summary.binary_operators.into_iter().collect::<Vec<_>>(), vec!["/", ">"]);
+/// assert_eq!(summary.aggregations.into_iter().collect::<Vec<_>>(), vec!["sum"]);
+/// ```
+pub fn summarize(expr: &Expr) -> Summary {
+    let mut summary = Summary::default();
+    let _ = walk_expr(&mut summary, expr);
+    summary
+}
+
+impl ExprVisitor for Summary {
+    type Error = Infallible;
+
+    fn pre_visit(&mut self, expr: &Expr) -> Result<bool, Self::Error> {
+        match expr {
+            Expr::Aggregate(ex) => {
+                self.aggregations.insert(token_display(ex.op.id()));
+            }
+            Expr::Binary(ex) => {
+                self.binary_operators.insert(token_display(ex.op.id()));
+            }
+            Expr::Call(call) => {
+                self.functions.insert(call.func.name);
+            }
+            _ => {}
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    fn sorted(set: &BTreeSet<&'static str>) -> Vec<&'static str> {
+        set.iter().copied().collect()
+    }
+
+    #[test]
+    fn test_summarize() {
+        let cases = vec![
+            ("foo", vec![], vec![], vec![]),
+            ("1 + 2 * 3", vec![], vec!["*", "+"], vec![]),
+            (
+                "topk(scalar(count(up)), rate(foo[5m]))",
+                vec!["rate", "scalar"],
+                vec![],
+                vec!["count", "topk"],
+            ),
+            (
+                "foo and on(job) bar or absent(baz)",
+                vec!["absent"],
+                vec!["and", "or"],
+                vec![],
+            ),
+            (
+                "max_over_time(sum by (job) (foo)[1h:5m]) atan2 -bar",
+                vec!["max_over_time"],
+                vec!["atan2"],
+                vec!["sum"],
+            ),
+        ];
+        for (query, functions, operators, aggregations) in cases {
+            let summary = summarize(&parse(query).unwrap());
+            assert_eq!(sorted(&summary.functions), functions, "{query}");
+            assert_eq!(sorted(&summary.binary_operators), operators, "{query}");
+            assert_eq!(sorted(&summary.aggregations), aggregations, "{query}");
+        }
+    }
+}
@@ -0,0 +1,367 @@
+// Copyright 2023 Greptime Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! query templates with typed named parameters.
+
+use crate::parser::{parse, Expr, NumberLiteral};
+use crate::util::display_duration;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Duration;
+
+/// the type of a template parameter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParamType {
+    /// a duration, like the range of `foo[$window]`.
+    Duration,
+    /// a label value, like `foo{job=$job}`, which is quoted when bound.
+    LabelValue,
+    Number,
+}
+
+impl fmt::Display for ParamType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParamType::Duration => write!(f, "duration"),
+            ParamType::LabelValue => write!(f, "label value"),
+            ParamType::Number => write!(f, "number"),
+        }
+    }
+}
+
+/// the value bound to a template parameter.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParamValue {
+    Duration(Duration),
+    LabelValue(String),
+    Number(f64),
+}
+
+impl ParamValue {
+    pub fn param_type(&self) -> ParamType {
+        match self {
+            ParamValue::Duration(_) => ParamType::Duration,
+            ParamValue::LabelValue(_) => ParamType::LabelValue,
+            ParamValue::Number(_) => ParamType::Number,
+        }
+    }
+
+    /// the PromQL text of the value, label values are quoted and escaped and
+    /// negative numbers are parenthesized, so they can not change the structure
+    /// of the query, like `-2` in `$t ^ 2`.
+    fn to_promql(&self) -> String {
+        match self {
+            ParamValue::Duration(d) => display_duration(d),
+            ParamValue::LabelValue(s) => {
+                format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+            }
+            ParamValue::Number(n) if n.is_sign_negative() && !n.is_nan() => {
+                format!("({})", NumberLiteral::new(*n))
+            }
+            ParamValue::Number(n) => NumberLiteral::new(*n).to_string(),
+        }
+    }
+}
+
+impl From<Duration> for ParamValue {
+    fn from(d: Duration) -> Self {
+        ParamValue::Duration(d)
+    }
+}
+
+impl From<&str> for ParamValue {
+    fn from(s: &str) -> Self {
+        ParamValue::LabelValue(s.to_string())
+    }
+}
+
+impl From<String> for ParamValue {
+    fn from(s: String) -> Self {
+        ParamValue::LabelValue(s)
+    }
+}
+
+impl From<f64> for ParamValue {
+    fn from(n: f64) -> Self {
+        ParamValue::Number(n)
+    }
+}
+
+/// a part of the template text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    Param(String),
+}
+
+/// QueryTemplate is a query with named parameters like `$window`, which are
+/// declared with their types. Binding values to the parameters gives a
+/// checked [Expr], the values are written as typed PromQL literals instead
+/// of being pasted into the query.
+///
+/// Parameters are only recognized outside of string literals, so `"$1"` in
+/// `label_replace` is kept as is.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::parse;
+/// use promql_parser::util::template::{ParamType, QueryTemplate};
+/// use std::time::Duration;
+///
+/// let template = QueryTemplate::new(
+///     "sum(rate(http_requests_total{job=$job}[$window])) > $threshold",
+///     [
+///         ("job", ParamType::LabelValue),
+///         ("window", ParamType::Duration),
+///         ("threshold", ParamType::Number),
+///     ],
+/// )
+/// .unwrap();
+///
+/// let expr = template
+///     .bind([
+///         ("job", "api".into()),
+///         ("window", Duration::from_secs(300).into()),
+///         ("threshold", 0.5.into()),
+///     ])
+///     .unwrap();
+/// assert_eq!(
+///     expr,
+///     parse(r#"sum(rate(http_requests_total{job="api"}[5m])) > 0.5"#).unwrap()
+/// );
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryTemplate {
+    parts: Vec<Part>,
+    params: BTreeMap<String, ParamType>,
+}
+
+impl QueryTemplate {
+    /// parse the template, every parameter in it must be declared. The template
+    /// is checked by binding a sample value to each parameter.
+    pub fn new<'a>(
+        input: &str,
+        params: impl IntoIterator<Item = (&'a str, ParamType)>,
+    ) -> Result<Self, String> {
+        let params: BTreeMap<String, ParamType> = params
+            .into_iter()
+            .map(|(n, t)| (n.to_string(), t))
+            .collect();
+        let parts = split_params(input);
+        for part in &parts {
+            match part {
+                Part::Param(name) if !params.contains_key(name) => {
+                    return Err(format!("undeclared parameter ${name} in query template"))
+                }
+                _ => {}
+            }
+        }
+
+        let template = Self { parts, params };
+        let samples: Vec<(&str, ParamValue)> = template
+            .params
+            .iter()
+            .map(|(name, t)| {
+                let sample = match t {
+                    ParamType::Duration => ParamValue::Duration(Duration::from_secs(60)),
+                    ParamType::LabelValue => ParamValue::LabelValue(String::new()),
+                    ParamType::Number => ParamValue::Number(1.0),
+                };
+                (name.as_str(), sample)
+            })
+            .collect();
+        template.bind(samples)?;
+        Ok(template)
+    }
+
+    /// the declared parameters with their types.
+    pub fn params(&self) -> &BTreeMap<String, ParamType> {
+        &self.params
+    }
+
+    /// bind the values to the parameters and parse the result. Every used
+    /// parameter must have a value of its declared type.
+    pub fn bind<'a>(
+        &self,
+        values: impl IntoIterator<Item = (&'a str, ParamValue)>,
+    ) -> Result<Expr, String> {
+        let mut bound = BTreeMap::new();
+        for (name, value) in values {
+            match self.params.get(name) {
+                None => return Err(format!("unknown parameter ${name}")),
+                Some(t) if *t != value.param_type() => {
+                    return Err(format!(
+                        "parameter ${name} expects a {t}, got a {}",
+                        value.param_type()
+                    ))
+                }
+                // PromQL durations are whole milliseconds, the rest would be cut off
+                Some(_) => match &value {
+                    ParamValue::Duration(d) if d.subsec_nanos() % 1_000_000 != 0 => {
+                        return Err(format!(
+                            "parameter ${name} expects a duration of whole milliseconds, got {d:?}"
+                        ))
+                    }
+                    _ => bound.insert(name, value.to_promql()),
+                },
+            };
+        }
+
+        let mut query = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => query.push_str(text),
+                Part::Param(name) => match bound.get(name.as_str()) {
+                    Some(value) => query.push_str(value),
+                    None => return Err(format!("missing value for parameter ${name}")),
+                },
+            }
+        }
+        parse(&query)
+    }
+}
+
+/// split the input into text and `$name` parameters, skipping string literals.
+fn split_params(input: &str) -> Vec<Part> {
+    let mut parts = vec![];
+    let mut text = String::new();
+    let mut chars = input.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '"' | '\'' | '`' => {
+                text.push(ch);
+                while let Some(c) = chars.next() {
+                    text.push(c);
+                    if c == '\\' && ch != '`' {
+                        text.extend(chars.next());
+                    } else if c == ch {
+                        break;
+                    }
+                }
+            }
+            '$' if chars.peek().is_some_and(|c| is_param_char(*c)) => {
+                let mut name = String::new();
+                while let Some(c) = chars.next_if(|c| is_param_char(*c)) {
+                    name.push(c);
+                }
+                parts.push(Part::Text(std::mem::take(&mut text)));
+                parts.push(Part::Param(name));
+            }
+            _ => text.push(ch),
+        }
+    }
+    parts.push(Part::Text(text));
+    parts
+}
+
+fn is_param_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_template_bind() {
+        let template = QueryTemplate::new(
+            r#"label_replace(foo{job=$job, env!=$job} offset $__param_offset, "a", "$1", "b", "(.*)")"#,
+            [
+                ("job", ParamType::LabelValue),
+                ("__param_offset", ParamType::Duration),
+            ],
+        )
+        .unwrap();
+        assert_eq!(template.params().len(), 2);
+
+        let expr = template
+            .bind([
+                ("job", r#"a"} or vector(1) # "#.into()),
+                ("__param_offset", Duration::from_secs(90).into()),
+            ])
+            .unwrap();
+        let expected = parse(
+            r#"label_replace(foo{job="a\"} or vector(1) # ", env!="a\"} or vector(1) # "} offset 1m30s, "a", "$1", "b", "(.*)")"#,
+        )
+        .unwrap();
+        assert_eq!(expr, expected);
+
+        let template = QueryTemplate::new(
+            "topk($k, foo) > $t",
+            [("k", ParamType::Number), ("t", ParamType::Number)],
+        )
+        .unwrap();
+        let expr = template
+            .bind([("k", 3.0.into()), ("t", (-1.5).into())])
+            .unwrap();
+        assert_eq!(expr, parse("topk(3, foo) > (-1.5)").unwrap());
+
+        let template = QueryTemplate::new("$t ^ 2", [("t", ParamType::Number)]).unwrap();
+        let expr = template.bind([("t", (-2.0).into())]).unwrap();
+        assert_eq!(expr, parse("(-2) ^ 2").unwrap());
+        assert_ne!(expr, parse("-2 ^ 2").unwrap());
+    }
+
+    #[test]
+    fn test_template_fail() {
+        assert_eq!(
+            QueryTemplate::new("foo[$window]", []),
+            Err("undeclared parameter $window in query template".into())
+        );
+        assert!(QueryTemplate::new("foo{job=$job}", [("job", ParamType::Number)]).is_err());
+        assert!(QueryTemplate::new("sum(foo", []).is_err());
+
+        let template =
+            QueryTemplate::new("rate(foo[$window])", [("window", ParamType::Duration)]).unwrap();
+        assert_eq!(
+            template.bind([("window", 1.0.into())]),
+            Err("parameter $window expects a duration, got a number".into())
+        );
+        assert_eq!(
+            template.bind([("window", Duration::from_micros(1500).into())]),
+            Err("parameter $window expects a duration of whole milliseconds, got 1.5ms".into())
+        );
+        assert_eq!(
+            template.bind([("window", Duration::from_micros(500).into())]),
+            Err("parameter $window expects a duration of whole milliseconds, got 500µs".into())
+        );
+        assert!(template
+            .bind([("window", Duration::from_millis(1500).into())])
+            .is_ok());
+        assert_eq!(
+            template.bind([]),
+            Err("missing value for parameter $window".into())
+        );
+        assert_eq!(
+            template.bind([("other", 1.0.into())]),
+            Err("unknown parameter $other".into())
+        );
+    }
+
+    #[test]
+    fn test_split_params() {
+        assert_eq!(
+            split_params(r#"a$b "$c\"$d" `$e` '$f'$g_1"#),
+            vec![
+                Part::Text("a".into()),
+                Part::Param("b".into()),
+                Part::Text(r#" "$c\"$d" `$e` '$f'"#.into()),
+                Part::Param("g_1".into()),
+                Part::Text("".into()),
+            ]
+        );
+        assert_eq!(split_params("$ foo"), vec![Part::Text("$ foo".into())]);
+    }
+}
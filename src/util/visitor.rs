@@ -34,7 +34,9 @@ pub trait ExprVisitor {
     }
 }
 
-/// A util function that traverses an AST [Expr] in depth-first order. Returns
+/// A util function that traverses an AST [Expr] in depth-first order, the
+/// children in the order of [`Expr::children`], so the param of an aggregation
+/// is visited before its expr. Returns
 /// `Ok(true)` if all nodes were visited, and `Ok(false)` if any call to
 /// [`pre_visit`](ExprVisitor::pre_visit) or [`post_visit`](ExprVisitor::post_visit)
 /// returned `Ok(false)` and may have cut short the recursion.
@@ -44,10 +46,17 @@ pub fn walk_expr<V: ExprVisitor>(visitor: &mut V, expr: &Expr) -> Result<bool, V
     }
 
     let recurse = match expr {
-        Expr::Aggregate(AggregateExpr { expr, .. }) => walk_expr(visitor, expr)?,
+        Expr::Aggregate(AggregateExpr { expr, param, .. }) => {
+            if let Some(param) = param {
+                if !walk_expr(visitor, param)? {
+                    return Ok(false);
+                }
+            }
+            walk_expr(visitor, expr)?
+        }
         Expr::Unary(UnaryExpr { expr }) => walk_expr(visitor, expr)?,
         Expr::Binary(BinaryExpr { lhs, rhs, .. }) => {
-            walk_expr(visitor, lhs)? || walk_expr(visitor, rhs)?
+            walk_expr(visitor, lhs)? && walk_expr(visitor, rhs)?
         }
         Expr::Paren(ParenExpr { expr }) => walk_expr(visitor, expr)?,
         Expr::Subquery(SubqueryExpr { expr, .. }) => walk_expr(visitor, expr)?,
@@ -84,6 +93,117 @@ pub fn walk_expr<V: ExprVisitor>(visitor: &mut V, expr: &Expr) -> Result<bool, V
     Ok(true)
 }
 
+/// The ancestors of the visited node, from the root to the immediate parent.
+#[derive(Debug, Clone, Copy)]
+pub struct Ancestors<'a, 'b> {
+    path: &'b [&'a Expr],
+}
+
+impl<'a> Ancestors<'a, '_> {
+    pub fn parent(&self) -> Option<&'a Expr> {
+        self.path.last().copied()
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.path.is_empty()
+    }
+
+    pub fn depth(&self) -> usize {
+        self.path.len()
+    }
+
+    /// the ancestors from the immediate parent up to the root.
+    pub fn iter(&self) -> impl Iterator<Item = &'a Expr> + '_ {
+        self.path.iter().rev().copied()
+    }
+
+    /// whether the node is inside the expr or the param of an aggregation.
+    pub fn inside_aggregation(&self) -> bool {
+        self.iter().any(|e| matches!(e, Expr::Aggregate(_)))
+    }
+
+    /// whether the node is inside an argument of the function, any function if `name` is None.
+    pub fn inside_call(&self, name: Option<&str>) -> bool {
+        self.iter().any(|e| match e {
+            Expr::Call(call) => name.is_none_or(|name| call.func.name == name),
+            _ => false,
+        })
+    }
+}
+
+/// Same as [ExprVisitor], except the ancestors of the node are given as well,
+/// for checks depending on where the node is, like `rate` inside `rate`.
+pub trait AncestorVisitor {
+    type Error;
+
+    /// Called before any children are visited. Return `Ok(false)` to cut short the recursion
+    /// (skip traversing and return).
+    fn pre_visit(&mut self, expr: &Expr, ancestors: Ancestors) -> Result<bool, Self::Error>;
+
+    /// Called after all children are visited. Return `Ok(false)` to cut short the recursion
+    /// (skip traversing and return).
+    fn post_visit(&mut self, _expr: &Expr, _ancestors: Ancestors) -> Result<bool, Self::Error> {
+        Ok(true)
+    }
+}
+
+/// Same as [walk_expr], except it calls an [AncestorVisitor] with the ancestors of each
+/// node.
+///
+/// # Examples
+///
+/// ``` rust
+/// use promql_parser::parser::{parse, Expr};
+/// use promql_parser::util::{walk_expr_with_ancestors, Ancestors, AncestorVisitor};
+///
+/// /// finds range functions over range functions, like `rate(rate(foo[5m])[5m:])`.
+/// struct NestedRate(Vec<String>);
+///
+/// impl AncestorVisitor for NestedRate {
+///     type Error = ();
+///
+///     fn pre_visit(&mut self, expr: &Expr, ancestors: Ancestors) -> Result<bool, ()> {
+///         if let Expr::Call(call) = expr {
+///             if call.func.name == "rate" && ancestors.inside_call(Some("rate")) {
+///                 self.0.push(expr.to_string());
+///             }
+///         }
+///         Ok(true)
+///     }
+/// }
+///
+/// let expr = parse("rate(rate(foo[5m])[1h:]) + rate(bar[5m])").unwrap();
+/// let mut visitor = NestedRate(vec![]);
+/// walk_expr_with_ancestors(&mut visitor, &expr).unwrap();
+/// assert_eq!(visitor.0, vec!["rate(foo[5m])"]);
+/// ```
+pub fn walk_expr_with_ancestors<V: AncestorVisitor>(
+    visitor: &mut V,
+    expr: &Expr,
+) -> Result<bool, V::Error> {
+    walk_with_path(visitor, expr, &mut vec![])
+}
+
+fn walk_with_path<'a, V: AncestorVisitor>(
+    visitor: &mut V,
+    expr: &'a Expr,
+    path: &mut Vec<&'a Expr>,
+) -> Result<bool, V::Error> {
+    if !visitor.pre_visit(expr, Ancestors { path })? {
+        return Ok(false);
+    }
+
+    path.push(expr);
+    for child in expr.children() {
+        if !walk_with_path(visitor, child, path)? {
+            return Ok(false);
+        }
+    }
+    path.pop();
+
+    visitor.post_visit(expr, Ancestors { path })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -193,4 +313,111 @@ mod tests {
         let ast = parser::parse(r#""1""#).unwrap();
         assert!(!walk_expr(&mut visitor, &ast).unwrap());
     }
+
+    /// records the parents of the nodes, and whether they are inside an aggregation.
+    struct ParentVisitor(Vec<(String, Option<String>, bool)>);
+
+    impl AncestorVisitor for ParentVisitor {
+        type Error = ();
+
+        fn pre_visit(&mut self, expr: &Expr, ancestors: Ancestors) -> Result<bool, ()> {
+            let parent = ancestors.parent().map(|p| p.to_string());
+            self.0
+                .push((expr.to_string(), parent, ancestors.inside_aggregation()));
+            Ok(true)
+        }
+    }
+
+    #[test]
+    fn test_walk_expr_with_ancestors() {
+        let ast = parser::parse("topk(1, foo) / abs(-bar)").unwrap();
+        let mut visitor = ParentVisitor(vec![]);
+        assert!(walk_expr_with_ancestors(&mut visitor, &ast).unwrap());
+
+        let root = "topk(1, foo) / abs(-bar)".to_string();
+        let expected = vec![
+            (root.clone(), None, false),
+            ("topk(1, foo)".into(), Some(root.clone()), false),
+            ("1".into(), Some("topk(1, foo)".into()), true),
+            ("foo".into(), Some("topk(1, foo)".into()), true),
+            ("abs(-bar)".into(), Some(root), false),
+            ("-bar".into(), Some("abs(-bar)".into()), false),
+            ("bar".into(), Some("-bar".into()), false),
+        ];
+        assert_eq!(visitor.0, expected);
+    }
+
+    struct Visited(Vec<String>);
+
+    impl ExprVisitor for Visited {
+        type Error = ();
+
+        fn pre_visit(&mut self, expr: &Expr) -> Result<bool, ()> {
+            self.0.push(expr.to_string());
+            Ok(true)
+        }
+    }
+
+    #[test]
+    fn test_walk_expr_params() {
+        let ast = parser::parse("topk(scalar(bar), quantile(0.9, foo))").unwrap();
+        let mut visitor = Visited(vec![]);
+        assert!(walk_expr(&mut visitor, &ast).unwrap());
+        let expected: Vec<String> = ast.nodes().iter().map(|(_, n)| n.to_string()).collect();
+        assert_eq!(visitor.0, expected);
+        assert_eq!(visitor.0.iter().filter(|n| *n == "bar").count(), 1);
+    }
+
+    /// records the nodes visited until it meets the given one.
+    struct VisitedUntil(Vec<String>, &'static str);
+
+    impl ExprVisitor for VisitedUntil {
+        type Error = ();
+
+        fn pre_visit(&mut self, expr: &Expr) -> Result<bool, ()> {
+            self.0.push(expr.to_string());
+            Ok(expr.to_string() != self.1)
+        }
+    }
+
+    #[test]
+    fn test_walk_expr_binary() {
+        // the rhs is visited once the lhs was walked in full
+        let ast = parser::parse("foo + bar * baz").unwrap();
+        let mut visitor = Visited(vec![]);
+        assert!(walk_expr(&mut visitor, &ast).unwrap());
+        let expected: Vec<String> = ast.nodes().iter().map(|(_, n)| n.to_string()).collect();
+        assert_eq!(visitor.0, expected);
+
+        // and is skipped once the walk of the lhs was cut short
+        let mut visitor = VisitedUntil(vec![], "foo");
+        assert!(!walk_expr(&mut visitor, &ast).unwrap());
+        assert_eq!(visitor.0, vec!["foo + bar * baz", "foo"]);
+    }
+
+    struct DepthVisitor(Vec<usize>);
+
+    impl AncestorVisitor for DepthVisitor {
+        type Error = ();
+
+        fn pre_visit(&mut self, expr: &Expr, ancestors: Ancestors) -> Result<bool, ()> {
+            self.0.push(ancestors.depth());
+            assert_eq!(ancestors.is_root(), ancestors.depth() == 0);
+            // stop at the first call
+            Ok(!matches!(expr, Expr::Call(_)))
+        }
+
+        fn post_visit(&mut self, _expr: &Expr, ancestors: Ancestors) -> Result<bool, ()> {
+            assert!(!ancestors.inside_call(None));
+            Ok(true)
+        }
+    }
+
+    #[test]
+    fn test_walk_expr_with_ancestors_cut_short() {
+        let ast = parser::parse("(foo + sum(abs(bar)))").unwrap();
+        let mut visitor = DepthVisitor(vec![]);
+        assert!(!walk_expr_with_ancestors(&mut visitor, &ast).unwrap());
+        assert_eq!(visitor.0, vec![0, 1, 2, 2, 3]);
+    }
 }